@@ -1,14 +1,67 @@
-use criterion::{criterion_group, criterion_main, Criterion};
-use iridium::vm::VM;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use iridium::{
+    assembler::{program::Program, Assembler},
+    benchmark::{counted_loop, float_kernel, generated_program},
+    parse::parse_complete,
+    vm::VM,
+};
+
+const LOOP_ITERATIONS: u32 = 1_000_000;
+const KERNEL_ITERATIONS: u32 = 100_000;
+const PROGRAM_LINES: usize = 500;
 
 fn execute_add() {
     let mut test_vm = VM::get_test_vm();
     test_vm.program = vec![1, 0, 1, 2];
-    test_vm.run_once();
+    test_vm.run_once().unwrap();
+}
+
+/// A fresh VM with the bytecode `image` loaded, ready to run
+fn loaded_vm(image: &[u8]) -> VM {
+    let mut vm = VM::new();
+    vm.load_bytecode(image.to_vec()).unwrap();
+    vm
 }
 
 fn criterion_benchmark(c: &mut Criterion) {
-    c.bench_function("execute_add", |b| b.iter(|| execute_add()));
+    c.bench_function("execute_add", |b| b.iter(execute_add));
+
+    let mut group = c.benchmark_group("run");
+    group.sample_size(10);
+    for (name, source, iterations) in [
+        (
+            "counted_loop",
+            counted_loop(LOOP_ITERATIONS),
+            LOOP_ITERATIONS,
+        ),
+        (
+            "float_kernel",
+            float_kernel(KERNEL_ITERATIONS),
+            KERNEL_ITERATIONS,
+        ),
+    ] {
+        let image = Assembler::new().assemble(&source).unwrap();
+        group.throughput(Throughput::Elements(iterations.into()));
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || loaded_vm(&image),
+                |mut vm| vm.run(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+
+    let source = generated_program(PROGRAM_LINES);
+    let mut group = c.benchmark_group("assemble");
+    group.throughput(Throughput::Elements(PROGRAM_LINES as u64));
+    group.bench_function("assemble", |b| {
+        b.iter(|| Assembler::new().assemble(&source).unwrap())
+    });
+    group.bench_function("parse", |b| {
+        b.iter(|| parse_complete::<Program>(&source).unwrap())
+    });
+    group.finish();
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -1,8 +1,8 @@
 use nom::{
     branch::alt,
     bytes::complete::tag,
-    character::complete::{multispace0, multispace1},
-    combinator::{map, opt},
+    character::complete::{char, multispace0, multispace1, space0},
+    combinator::{map, opt, recognize},
     error::context,
     sequence::{preceded, tuple},
 };
@@ -13,25 +13,33 @@ use super::{
     symbols::SymbolTable,
     token::{
         parse_directive, parse_int_operand, parse_label_declaration, parse_label_usage,
-        parse_opcode, parse_register, parse_str_operand, Token,
+        parse_opcode, parse_register, parse_str_operand, spanned, Span, Spanned, Token,
     },
 };
 
+/// The operands of an instruction or directive, in order
+type Operands = (
+    Option<Spanned<Token>>,
+    Option<Spanned<Token>>,
+    Option<Spanned<Token>>,
+);
+
+/// One line of assembly, each part with where it is in the source
 #[derive(Debug, PartialEq)]
 pub struct AssemblerInstruction {
-    pub opcode: Option<Token>,
-    pub label: Option<Token>,
-    pub directive: Option<Token>,
-    pub operand1: Option<Token>,
-    pub operand2: Option<Token>,
-    pub operand3: Option<Token>,
+    pub opcode: Option<Spanned<Token>>,
+    pub label: Option<Spanned<Token>>,
+    pub directive: Option<Spanned<Token>>,
+    pub operand1: Option<Spanned<Token>>,
+    pub operand2: Option<Spanned<Token>>,
+    pub operand3: Option<Spanned<Token>>,
 }
 
 impl AssemblerInstruction {
     /// Convert entire instruction to bytes
     pub fn to_bytes(&self, symbol_table: &SymbolTable) -> Vec<u8> {
         let mut results = Vec::new();
-        match &self.opcode {
+        match self.opcode.as_deref() {
             Some(Token::Op { code }) => results.push(*code as u8),
             _ => {
                 println!("Non-opcode found in opcode field");
@@ -39,11 +47,13 @@ impl AssemblerInstruction {
             }
         };
 
-        for token in [&self.operand1, &self.operand2, &self.operand3]
-            .iter()
-            .copied()
-            .flatten()
-        {
+        // a label used as in `prts @hello` is parsed into the label, not an operand
+        let usage = self.label.as_deref().filter(|_| self.is_label_usage());
+        let operands = [&self.operand1, &self.operand2, &self.operand3];
+        let operands = operands
+            .into_iter()
+            .filter_map(|operand| operand.as_deref());
+        for token in usage.into_iter().chain(operands) {
             AssemblerInstruction::extract_operand(token, &mut results, symbol_table)
         }
 
@@ -90,12 +100,15 @@ impl AssemblerInstruction {
 
     /// If this instruction contains a label declaration
     pub fn is_label_declaration(&self) -> bool {
-        matches!(&self.label, Some(Token::LabelDeclaration { name: _ }))
+        matches!(
+            self.label.as_deref(),
+            Some(Token::LabelDeclaration { name: _ })
+        )
     }
 
     /// If this instruction contains a label usage
     pub fn is_label_usage(&self) -> bool {
-        matches!(&self.label, Some(Token::LabelUsage { name: _ }))
+        matches!(self.label.as_deref(), Some(Token::LabelUsage { name: _ }))
     }
 
     /// If this instruction contains a directive
@@ -111,7 +124,7 @@ impl AssemblerInstruction {
     /// If contained label declaration, return label; Else None
     pub fn get_label_declaration_name(&self) -> Option<String> {
         assert!(self.label.is_some());
-        self.label.as_ref().and_then(|tok| match tok {
+        self.label.as_deref().and_then(|tok| match tok {
             Token::LabelDeclaration { name } => Some(name.to_owned()),
             _ => None,
         })
@@ -120,7 +133,7 @@ impl AssemblerInstruction {
     /// If contained label usage, return label; Else None
     pub fn get_label_usage_name(&self) -> Option<String> {
         assert!(self.label.is_some());
-        self.label.as_ref().and_then(|tok| match tok {
+        self.label.as_deref().and_then(|tok| match tok {
             Token::LabelUsage { name } => Some(name.to_owned()),
             _ => None,
         })
@@ -129,7 +142,7 @@ impl AssemblerInstruction {
     /// If contained directive, return name; Else None
     pub fn get_directive_name(&self) -> Option<String> {
         assert!(self.directive.is_some());
-        self.directive.as_ref().and_then(|tok| match tok {
+        self.directive.as_deref().and_then(|tok| match tok {
             Token::Directive { name } => Some(name.to_owned()),
             _ => None,
         })
@@ -138,11 +151,48 @@ impl AssemblerInstruction {
     /// If contained string constant, return string; Else None
     pub fn get_string_constant(&self) -> Option<String> {
         assert!(self.operand1.is_some());
-        self.operand1.as_ref().and_then(|tok| match tok {
+        self.operand1.as_deref().and_then(|tok| match tok {
             Token::StringOperand { value } => Some(value.to_owned()),
             _ => None,
         })
     }
+
+    /// The parts of this instruction in the order they were written
+    fn parts(&self) -> impl Iterator<Item = &Spanned<Token>> {
+        let label = self.label.iter().filter(|_| self.is_label_declaration());
+        let usage = self.label.iter().filter(|_| self.is_label_usage());
+        label
+            .chain(&self.opcode)
+            .chain(&self.directive)
+            .chain(usage)
+            .chain(&self.operand1)
+            .chain(&self.operand2)
+            .chain(&self.operand3)
+    }
+
+    /// Where the instruction is in the source, from its first part to the end of its last
+    pub fn span(&self) -> Span {
+        let mut parts = self.parts().map(|part| part.span);
+        let first = parts.next().unwrap_or_default();
+        let end = parts.last().unwrap_or(first);
+        Span::new(first.offset, end.offset + end.len - first.offset)
+    }
+
+    /// Move the spans of this instruction, parsed on its own, to where it is in a source
+    /// that has `by` bytes before it
+    pub(crate) fn shift(&mut self, by: usize) {
+        let parts = [
+            &mut self.opcode,
+            &mut self.label,
+            &mut self.directive,
+            &mut self.operand1,
+            &mut self.operand2,
+            &mut self.operand3,
+        ];
+        for part in parts.into_iter().flatten() {
+            part.span = part.span.shifted(by);
+        }
+    }
 }
 
 impl<'a> Parse<'a> for AssemblerInstruction {
@@ -152,7 +202,12 @@ impl<'a> Parse<'a> for AssemblerInstruction {
             alt((
                 // <opcode> <label_usage>
                 map(
-                    tuple((parse_opcode, multispace1, parse_label_usage, opt(tag("\n")))),
+                    tuple((
+                        spanned(input, parse_opcode),
+                        multispace1,
+                        spanned(input, parse_label_usage),
+                        opt(tag("\n")),
+                    )),
                     |(opcode, _, label, _)| AssemblerInstruction {
                         opcode: Some(opcode),
                         label: Some(label),
@@ -165,9 +220,9 @@ impl<'a> Parse<'a> for AssemblerInstruction {
                 // <label_decl> <directive> <tok1>
                 map(
                     tuple((
-                        parse_label_declaration,
-                        preceded(multispace1, parse_directive),
-                        preceded(multispace1, parse_str_operand),
+                        spanned(input, parse_label_declaration),
+                        preceded(multispace1, spanned(input, parse_directive)),
+                        preceded(multispace1, spanned(input, parse_str_operand)),
                         opt(tag("\n")),
                     )),
                     |(label, directive, tok, _)| AssemblerInstruction {
@@ -182,24 +237,13 @@ impl<'a> Parse<'a> for AssemblerInstruction {
                 // [label_decl] <opcode> [tok1] [tok2] [tok3]
                 map(
                     tuple((
-                        opt(parse_label_declaration),
+                        opt(spanned(input, parse_label_declaration)),
                         multispace0,
-                        parse_opcode,
-                        opt(preceded(
-                            multispace1,
-                            alt((parse_register, parse_int_operand)),
-                        )),
-                        opt(preceded(
-                            multispace1,
-                            alt((parse_register, parse_int_operand)),
-                        )),
-                        opt(preceded(
-                            multispace1,
-                            alt((parse_register, parse_int_operand)),
-                        )),
+                        spanned(input, parse_opcode),
+                        parse_operands(input),
                         opt(tag("\n")),
                     )),
-                    |(label, _, opcode, tok1, tok2, tok3, _)| AssemblerInstruction {
+                    |(label, _, opcode, (tok1, tok2, tok3), _)| AssemblerInstruction {
                         opcode: Some(opcode),
                         label,
                         directive: None,
@@ -211,22 +255,11 @@ impl<'a> Parse<'a> for AssemblerInstruction {
                 // <directive> [tok1] [tok2] [tok3]
                 map(
                     tuple((
-                        parse_directive,
-                        opt(preceded(
-                            multispace1,
-                            alt((parse_register, parse_int_operand)),
-                        )),
-                        opt(preceded(
-                            multispace1,
-                            alt((parse_register, parse_int_operand)),
-                        )),
-                        opt(preceded(
-                            multispace1,
-                            alt((parse_register, parse_int_operand)),
-                        )),
+                        spanned(input, parse_directive),
+                        parse_operands(input),
                         opt(tag("\n")),
                     )),
-                    |(directive, tok1, tok2, tok3, _)| AssemblerInstruction {
+                    |(directive, (tok1, tok2, tok3), _)| AssemblerInstruction {
                         opcode: None,
                         label: None,
                         directive: Some(directive),
@@ -242,22 +275,52 @@ impl<'a> Parse<'a> for AssemblerInstruction {
     }
 }
 
+/// Up to three register or integer operands, the first after whitespace and each of the
+/// others after a separator, with where they are in `source`
+fn parse_operands<'a>(source: &'a str) -> impl FnMut(&'a str) -> parse::ParseResult<'a, Operands> {
+    let operand = move || spanned(source, alt((parse_register, parse_int_operand)));
+    map(
+        opt(tuple((
+            preceded(multispace1, operand()),
+            opt(tuple((
+                preceded(operand_separator, operand()),
+                opt(preceded(operand_separator, operand())),
+            ))),
+        ))),
+        |operands| match operands {
+            None => (None, None, None),
+            Some((tok1, None)) => (Some(tok1), None, None),
+            Some((tok1, Some((tok2, tok3)))) => (Some(tok1), Some(tok2), tok3),
+        },
+    )
+}
+
+/// What comes between two operands: whitespace, or a comma with spaces around it or not
+fn operand_separator(input: &str) -> parse::ParseResult<'_, &str> {
+    alt((recognize(tuple((space0, char(','), space0))), multispace1))(input)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::instruction::Opcode;
 
     use super::*;
 
+    fn at(value: Token, offset: usize, len: usize) -> Option<Spanned<Token>> {
+        let span = Span::new(offset, len);
+        Some(Spanned { value, span })
+    }
+
     #[test]
     fn test_parse_instruction_form_one() {
         let (_, value) = AssemblerInstruction::parse("load $0 #100\n").unwrap();
 
         let expected = AssemblerInstruction {
-            opcode: Some(Token::Op { code: Opcode::LOAD }),
+            opcode: at(Token::Op { code: Opcode::LOAD }, 0, 4),
             label: None,
             directive: None,
-            operand1: Some(Token::Register { reg_num: 0 }),
-            operand2: Some(Token::IntegerOperand { value: 100 }),
+            operand1: at(Token::Register { reg_num: 0 }, 5, 2),
+            operand2: at(Token::IntegerOperand { value: 100 }, 8, 4),
             operand3: None,
         };
 
@@ -268,7 +331,7 @@ mod tests {
     fn test_parse_instruction_form_two() {
         let (_, value) = AssemblerInstruction::parse("hlt\n").unwrap();
         let expected = AssemblerInstruction {
-            opcode: Some(Token::Op { code: Opcode::HLT }),
+            opcode: at(Token::Op { code: Opcode::HLT }, 0, 3),
             label: None,
             directive: None,
             operand1: None,
@@ -283,12 +346,16 @@ mod tests {
     fn test_parse_instruction_form_three() {
         let (_, value) = AssemblerInstruction::parse("test: inc $0\n").unwrap();
         let expected = AssemblerInstruction {
-            opcode: Some(Token::Op { code: Opcode::INC }),
-            label: Some(Token::LabelDeclaration {
-                name: "test".to_string(),
-            }),
+            opcode: at(Token::Op { code: Opcode::INC }, 6, 3),
+            label: at(
+                Token::LabelDeclaration {
+                    name: "test".to_string(),
+                },
+                0,
+                5,
+            ),
             directive: None,
-            operand1: Some(Token::Register { reg_num: 0 }),
+            operand1: at(Token::Register { reg_num: 0 }, 10, 2),
             operand2: None,
             operand3: None,
         };
@@ -302,9 +369,13 @@ mod tests {
         let expected = AssemblerInstruction {
             opcode: None,
             label: None,
-            directive: Some(Token::Directive {
-                name: "asciiz".to_string(),
-            }),
+            directive: at(
+                Token::Directive {
+                    name: "asciiz".to_string(),
+                },
+                0,
+                7,
+            ),
             operand1: None,
             operand2: None,
             operand3: None,
@@ -317,10 +388,14 @@ mod tests {
     fn test_parse_instruction_form_five() {
         let (_, value) = AssemblerInstruction::parse("jmpe @test\n").unwrap();
         let expected = AssemblerInstruction {
-            opcode: Some(Token::Op { code: Opcode::JMPE }),
-            label: Some(Token::LabelUsage {
-                name: "test".to_string(),
-            }),
+            opcode: at(Token::Op { code: Opcode::JMPE }, 0, 4),
+            label: at(
+                Token::LabelUsage {
+                    name: "test".to_string(),
+                },
+                5,
+                5,
+            ),
             directive: None,
             operand1: None,
             operand2: None,
@@ -335,19 +410,60 @@ mod tests {
         let (_, value) = AssemblerInstruction::parse("test: .asciiz 'Hello'\n").unwrap();
         let expected = AssemblerInstruction {
             opcode: None,
-            label: Some(Token::LabelDeclaration {
-                name: "test".to_string(),
-            }),
-            directive: Some(Token::Directive {
-                name: "asciiz".to_string(),
-            }),
-            operand1: Some(Token::StringOperand {
-                value: "Hello".to_string(),
-            }),
+            label: at(
+                Token::LabelDeclaration {
+                    name: "test".to_string(),
+                },
+                0,
+                5,
+            ),
+            directive: at(
+                Token::Directive {
+                    name: "asciiz".to_string(),
+                },
+                6,
+                7,
+            ),
+            operand1: at(
+                Token::StringOperand {
+                    value: "Hello".to_string(),
+                },
+                14,
+                7,
+            ),
             operand2: None,
             operand3: None,
         };
 
         assert_eq!(expected, value);
     }
+
+    #[test]
+    fn test_comma_separated_operands() {
+        let tokens = |i: &AssemblerInstruction| -> Vec<String> {
+            i.parts().map(|part| format!("{:?}", part.value)).collect()
+        };
+        let (_, spaced) = AssemblerInstruction::parse("add $0 $1 $2\n").unwrap();
+        for source in ["add $0, $1, $2\n", "add $0 ,$1,  $2\n", "add $0,$1 $2\n"] {
+            let (rest, value) = AssemblerInstruction::parse(source).unwrap();
+            assert_eq!(tokens(&value), tokens(&spaced), "{:?}", source);
+            assert_eq!(rest, "", "{:?}", source);
+        }
+        let (_, value) = AssemblerInstruction::parse("add $0 ,$1,  $2\n").unwrap();
+        assert_eq!(value.operand2.unwrap().span, Span::new(8, 2));
+        assert_eq!(value.operand3.unwrap().span, Span::new(13, 2));
+        let (_, value) = AssemblerInstruction::parse("load $0, #100\n").unwrap();
+        assert_eq!(
+            value.operand2,
+            at(Token::IntegerOperand { value: 100 }, 9, 4)
+        );
+
+        // a separator has to be followed by another operand
+        let (rest, value) = AssemblerInstruction::parse("add $0, $1,\n").unwrap();
+        assert_eq!(value.operand3, None);
+        assert_eq!(rest, ",\n");
+        let (rest, value) = AssemblerInstruction::parse("add, $0\n").unwrap();
+        assert_eq!(value.operand1, None);
+        assert_eq!(rest, ", $0\n");
+    }
 }
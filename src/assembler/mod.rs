@@ -2,7 +2,7 @@ use std::vec;
 
 use crate::{
     error::{AssemblerError, IridiumError, Result},
-    parse::Parse,
+    parse::parse_complete,
 };
 
 use self::{
@@ -13,6 +13,8 @@ use self::{
 
 pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
 pub const PIE_HEADER_LENGTH: usize = 64;
+/// Errors the first phase collects before it only counts the rest
+pub const DEFAULT_MAX_ERRORS: usize = 20;
 
 #[derive(Debug, PartialEq, Clone, Default)]
 pub enum AssemblerPhase {
@@ -24,7 +26,7 @@ pub enum AssemblerPhase {
 /// <header> -> header prefix + read-only section len + padding: 64 bytes
 /// <read-only data> -> store constants
 /// <executable data>
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct Assembler {
     pub phase: AssemblerPhase,       // Tracks which phase the assember is in
     pub symbols: SymbolTable,        // Symbol table for constants and variables
@@ -34,7 +36,15 @@ pub struct Assembler {
     sections: Vec<AssemblerSection>, // list of all the sections in the code
     curr_section: Option<AssemblerSection>, // current section the assembler is in
     curr_instruction: u32,           // current instruction the assembler is converting to bytecode
-    errors: Vec<AssemblerError>,     // all errors
+    errors: Vec<AssemblerError>,     // all errors, up to max_errors
+    max_errors: usize,               // errors kept before the rest are only counted
+    errors_dropped: usize,           // errors found past max_errors
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Assembler {
@@ -49,63 +59,90 @@ impl Assembler {
             curr_section: None,
             curr_instruction: 0,
             errors: Vec::new(),
+            max_errors: DEFAULT_MAX_ERRORS,
+            errors_dropped: 0,
         }
     }
 
+    /// Keep at most `max_errors` errors, ending them with one saying how many more there were
+    pub fn with_max_errors(mut self, max_errors: usize) -> Self {
+        self.max_errors = max_errors.max(1);
+        self
+    }
+
+    /// Note `error`, unless it repeats the one before it or enough were noted already
+    fn push_error(&mut self, error: AssemblerError) {
+        if self.errors.last() == Some(&error) {
+            return;
+        }
+        if self.errors.len() >= self.max_errors {
+            self.errors_dropped += 1;
+            return;
+        }
+        self.errors.push(error);
+    }
+
     /// Convert a raw string to bytecode
     /// i.e. LOAD $0 $1
     pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>> {
-        match Program::parse(raw) {
-            Ok((remainder, program)) => {
-                assert_eq!(remainder, "");
-
-                self.process_first_phase(&program);
+        let program = parse_complete::<Program>(raw)?;
+        self.process_first_phase(&program, raw);
 
-                if !self.errors.is_empty() {
-                    return Err(IridiumError::Assemble(self.errors.clone()));
-                }
+        if !self.errors.is_empty() {
+            return Err(IridiumError::Assemble(self.errors.clone()));
+        }
 
-                if self.sections.len() != 2 {
-                    self.errors.push(AssemblerError::InsufficientSections);
-                    return Err(IridiumError::Assemble(self.errors.clone()));
-                }
+        if self.sections.len() != 2 {
+            self.errors.push(AssemblerError::InsufficientSections);
+            return Err(IridiumError::Assemble(self.errors.clone()));
+        }
 
-                let mut body = self.process_second_phase(&program);
-                let mut assembled_program = self.write_pie_header();
+        let mut body = self.process_second_phase(&program, raw);
+        let mut assembled_program = self.write_pie_header();
 
-                assembled_program.append(&mut body);
-                Ok(assembled_program)
-            }
-            Err(e) => {
-                eprintln!("There was an error parsing the code: {:?}", e);
-                Err(IridiumError::Assemble(vec![AssemblerError::ParsingError]))
-            }
-        }
+        assembled_program.extend_from_slice(&self.ro);
+        assembled_program.append(&mut body);
+        Ok(assembled_program)
     }
 
-    /// Extract program labels
-    fn process_first_phase(&mut self, p: &Program) {
+    /// Extract program labels, checking that those used are declared. `source` is what
+    /// `p` was parsed from, for saying where errors are
+    fn process_first_phase(&mut self, p: &Program, source: &str) {
         for i in &p.instructions {
+            // declared before its directive runs, so .asciiz can give the label its offset
+            if i.is_label_declaration() && self.curr_section.is_some() {
+                self.process_label_declaration(i, source);
+            }
             if i.is_directive() {
-                self.process_directive(i);
+                self.process_directive(i, source);
             }
 
-            match self.curr_section {
-                None => self.errors.push(AssemblerError::NoSegmentDeclarationFound(
-                    self.curr_instruction,
-                )),
-                Some(_) => match i.is_label_declaration() {
-                    true => self.process_label_declaration(i),
-                    false => {} // process label usage
-                },
+            if self.curr_section.is_none() {
+                self.push_error(AssemblerError::NoSegmentDeclarationFound(
+                    i.span().location(source),
+                ));
             }
             self.curr_instruction += 1;
         }
+        let undeclared: Vec<AssemblerError> = p
+            .undeclared_labels(&self.symbols)
+            .into_iter()
+            .map(|(name, span)| {
+                AssemblerError::UnknownLabel(name.to_string(), span.location(source))
+            })
+            .collect();
+        for error in undeclared {
+            self.push_error(error);
+        }
+        if self.errors_dropped > 0 {
+            self.errors
+                .push(AssemblerError::MoreErrors(self.errors_dropped));
+        }
         self.phase = AssemblerPhase::Second;
     }
 
     /// Extract program instruction bytes
-    fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
+    fn process_second_phase(&mut self, p: &Program, source: &str) -> Vec<u8> {
         self.curr_instruction = 0;
         let mut program = Vec::new();
         for i in &p.instructions {
@@ -114,7 +151,7 @@ impl Assembler {
                 program.append(&mut bytes);
             }
             if i.is_directive() {
-                self.process_directive(i);
+                self.process_directive(i, source);
             }
             self.curr_instruction += 1
         }
@@ -122,7 +159,7 @@ impl Assembler {
     }
 
     /// Handles directives
-    fn process_directive(&mut self, i: &AssemblerInstruction) {
+    fn process_directive(&mut self, i: &AssemblerInstruction, source: &str) {
         let directive_name = i.get_directive_name().unwrap();
         if i.contain_operands() {
             match directive_name.as_ref() {
@@ -134,8 +171,10 @@ impl Assembler {
                     todo!()
                 }
                 _ => {
-                    self.errors.push(AssemblerError::UnknownDirectiveFound(
+                    let at = i.directive.as_ref().map_or(i.span(), |d| d.span);
+                    self.push_error(AssemblerError::UnknownDirectiveFound(
                         directive_name.clone(),
+                        at.location(source),
                     ));
                 }
             }
@@ -146,10 +185,11 @@ impl Assembler {
 
     /// Handles the declaration of a label such as:
     /// hello: .asciiz 'Hello'
-    fn process_label_declaration(&mut self, i: &AssemblerInstruction) {
+    fn process_label_declaration(&mut self, i: &AssemblerInstruction, source: &str) {
         let label_name = i.get_label_declaration_name().unwrap();
         if self.symbols.contain_symbol(&label_name) {
-            self.errors.push(AssemblerError::SymbolAlreadyDeclared);
+            let at = i.label.as_ref().map_or(i.span(), |label| label.span);
+            self.push_error(AssemblerError::SymbolAlreadyDeclared(at.location(source)));
             return;
         }
 
@@ -215,7 +255,7 @@ pub enum AssemblerSection {
     Unknown,
 }
 
-impl<'a> From<&'a str> for AssemblerSection {
+impl From<&str> for AssemblerSection {
     fn from(name: &str) -> AssemblerSection {
         match name {
             "data" => AssemblerSection::Data(None),
@@ -227,15 +267,14 @@ impl<'a> From<&'a str> for AssemblerSection {
 
 #[cfg(test)]
 mod tests {
-    use crate::vm::VM;
+    use crate::{error::Location, instruction::Opcode, vm::VM};
 
     use super::*;
 
     #[test]
     fn test_assemble_program() {
         let mut asm = Assembler::new();
-        let test_string =
-            "load $0 #100\nload $1 #1\nload $2 #0\ntest: inc $0\nneq $0 $2\njmpe @test\nhlt";
+        let test_string = ".data\n.code\nload $0 #100\nload $1 #1\nload $2 #0\ntest: inc $0\nneq $0 $2\njmpe @test\nhlt";
         let program = asm.assemble(test_string).unwrap();
         let mut vm = VM::new();
         assert_eq!(program.len(), 92);
@@ -250,6 +289,115 @@ mod tests {
         let program = asm.assemble(test_string).unwrap();
         assert_eq!(program[4], 6);
     }
+
+    #[test]
+    fn test_string_label_offsets() {
+        let mut asm = Assembler::new();
+        let test_string = ".data\nhello: .asciiz 'Hello'\nbye: .asciiz 'Bye'\n.code\nhlt";
+        asm.assemble(test_string).unwrap();
+        assert_eq!(asm.symbols.symbol_value("hello"), Some(0));
+        assert_eq!(asm.symbols.symbol_value("bye"), Some(6));
+    }
+
+    #[test]
+    fn test_string_labels_encoded() {
+        let mut asm = Assembler::new();
+        let test_string =
+            ".data\nhello: .asciiz 'Hello'\nbye: .asciiz 'Bye'\n.code\nprts @hello\nprts @bye";
+        let program = asm.assemble(test_string).unwrap();
+        let code = &program[PIE_HEADER_LENGTH + 10..];
+        assert_eq!(code, [21, 0, 0, 0, 21, 0, 6, 0]);
+        assert_eq!(Opcode::from(code[0]), Opcode::PRTS);
+    }
+
+    #[test]
+    fn test_error_flood_capped() {
+        let headerless = "load $0 #1\n".repeat(100);
+        let errors = match Assembler::new().assemble(&headerless) {
+            Err(IridiumError::Assemble(errors)) => errors,
+            other => panic!("expected assembler errors, got {:?}", other),
+        };
+        assert_eq!(errors.len(), DEFAULT_MAX_ERRORS + 1);
+        assert_eq!(
+            errors[0],
+            AssemblerError::NoSegmentDeclarationFound(Location { line: 1, column: 1 })
+        );
+        assert_eq!(
+            errors[DEFAULT_MAX_ERRORS],
+            AssemblerError::MoreErrors(100 - DEFAULT_MAX_ERRORS)
+        );
+        assert_eq!(
+            errors[DEFAULT_MAX_ERRORS].to_string(),
+            "[ASM007] ... and 80 more errors"
+        );
+
+        let mut asm = Assembler::new().with_max_errors(5);
+        let err = asm.assemble(&headerless).unwrap_err();
+        assert!(matches!(err, IridiumError::Assemble(errors)
+            if errors.len() == 6 && errors[5] == AssemblerError::MoreErrors(95)));
+    }
+
+    #[test]
+    fn test_repeated_errors_collapsed() {
+        let mut asm = Assembler::new();
+        let source = ".data\n.code\ntwice: inc $0\ntwice: inc $0\ntwice: inc $0\nload $0 #1";
+        let err = asm.assemble(source).unwrap_err();
+        // each redeclaration is somewhere else, so none repeats the one before
+        let redeclared = |line| AssemblerError::SymbolAlreadyDeclared(Location { line, column: 1 });
+        assert!(matches!(err, IridiumError::Assemble(errors)
+            if errors == [redeclared(4), redeclared(5)]));
+    }
+
+    #[test]
+    fn test_syntax_error_located() {
+        let mut asm = Assembler::new();
+        let err = asm
+            .assemble(".data\n.code\nload $0 #100\nadd $0 $1 %2")
+            .unwrap_err();
+        assert!(matches!(err, IridiumError::Parse(_)), "{:?}", err);
+        let message = err.to_string();
+        assert!(message.contains("line 4, column 11"), "{}", message);
+        assert!(message.contains("`%2`"), "{}", message);
+    }
+
+    #[test]
+    fn test_comma_separated_operands() {
+        let spaced = ".data\n.code\nload $0 #100\nload $1 #2\nadd $0 $1 $2\nhlt";
+        let commas = ".data\n.code\nload $0, #100\nload $1,#2\nadd $0 ,$1,  $2\nhlt";
+        let program = Assembler::new().assemble(commas).unwrap();
+        assert_eq!(program, Assembler::new().assemble(spaced).unwrap());
+        let mut vm = VM::new();
+        vm.load_bytecode(program).unwrap();
+        vm.run();
+        assert_eq!(vm.registers[2], 102);
+
+        let err = Assembler::new()
+            .assemble(".data\n.code\nadd $0, $1,\nhlt")
+            .unwrap_err();
+        assert!(matches!(err, IridiumError::Parse(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_errors_located() {
+        let source = ".data\nhi: .asciiz 'Hi'\n.datum #1\n.code\nload $0 #1\njmpe @nowhere\nhlt";
+        let err = Assembler::new().assemble(source).unwrap_err();
+        let expected = [
+            AssemblerError::UnknownDirectiveFound(
+                "datum".to_string(),
+                Location { line: 3, column: 1 },
+            ),
+            AssemblerError::UnknownLabel("nowhere".to_string(), Location { line: 6, column: 6 }),
+        ];
+        assert!(
+            matches!(&err, IridiumError::Assemble(errors) if errors == &expected),
+            "{:?}",
+            err
+        );
+        assert_eq!(
+            expected[1].to_string(),
+            "[ASM008] Unknown label: nowhere at: line 6, column 6"
+        );
+    }
 }
 
 pub mod assem_instruction;
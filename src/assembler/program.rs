@@ -2,7 +2,11 @@ use nom::{error::context, multi::many1};
 
 use crate::parse::Parse;
 
-use super::{assem_instruction::AssemblerInstruction, symbols::SymbolTable};
+use super::{
+    assem_instruction::AssemblerInstruction,
+    symbols::SymbolTable,
+    token::{Span, Token},
+};
 
 #[derive(Debug, PartialEq)]
 pub struct Program {
@@ -21,12 +25,32 @@ impl Program {
     pub fn clear(&mut self) {
         self.instructions.clear();
     }
+
+    /// Labels used that aren't in `symbols`, with where each use is
+    pub fn undeclared_labels(&self, symbols: &SymbolTable) -> Vec<(&str, Span)> {
+        self.instructions
+            .iter()
+            .filter_map(|i| i.label.as_ref())
+            .filter_map(|label| match &label.value {
+                Token::LabelUsage { name } if !symbols.contain_symbol(name) => {
+                    Some((name.as_str(), label.span))
+                }
+                _ => None,
+            })
+            .collect()
+    }
 }
 
 impl<'a> Parse<'a> for Program {
     fn parse(input: &'a str) -> crate::parse::ParseResult<'a, Self> {
-        let (remaining_input, instructions) =
-            context("Program", many1(AssemblerInstruction::parse))(input)?;
+        // each instruction is parsed from where the last left off, so its spans are moved
+        // to count from the start of the program
+        let instruction = |rest: &'a str| {
+            let (remaining, mut instruction) = AssemblerInstruction::parse(rest)?;
+            instruction.shift(input.len() - rest.len());
+            Ok((remaining, instruction))
+        };
+        let (remaining_input, instructions) = context("Program", many1(instruction))(input)?;
         Ok((remaining_input, Program { instructions }))
     }
 }
@@ -42,6 +66,19 @@ mod tests {
         assert_eq!(2, p.instructions.len());
     }
 
+    #[test]
+    fn test_spans_count_from_program_start() {
+        let (_, p) = Program::parse("load $0 #1\njmpe @nowhere\nhlt").unwrap();
+        let jmpe = &p.instructions[1];
+        assert_eq!(jmpe.opcode.as_ref().unwrap().span, Span::new(11, 4));
+        assert_eq!(jmpe.span(), Span::new(11, 13));
+        assert_eq!(p.instructions[2].span(), Span::new(25, 3));
+        assert_eq!(
+            p.undeclared_labels(&SymbolTable::new()),
+            vec![("nowhere", Span::new(16, 8))]
+        );
+    }
+
     #[test]
     fn test_program_to_bytes() {
         let (_, program) = Program::parse("load $0 #100\n").unwrap();
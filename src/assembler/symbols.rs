@@ -1,13 +1,16 @@
-#[derive(Debug)]
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
 pub enum SymbolType {
     Label,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Symbol {
-    name: String,
-    offset: Option<u32>,
-    symbol_type: SymbolType,
+    pub name: String,
+    pub offset: Option<u32>,
+    #[serde(rename = "type")]
+    pub symbol_type: SymbolType,
 }
 
 impl Symbol {
@@ -55,7 +58,7 @@ impl SymbolTable {
         self.symbols
             .iter_mut()
             .find(|s| s.name == name)
-            .map_or(false, |s| {
+            .is_some_and(|s| {
                 s.offset = Some(offset);
                 true
             })
@@ -72,11 +75,12 @@ mod tests {
         let new_symbol = Symbol::new("test".to_string(), SymbolType::Label);
         sym.add_symbol(new_symbol);
         assert_eq!(sym.symbols.len(), 1);
+        assert!(sym.set_symbol_offset("test", 12));
         let v = sym.symbol_value("test");
-        assert_eq!(true, v.is_some());
+        assert!(v.is_some());
         let v = v.unwrap();
         assert_eq!(v, 12);
         let v = sym.symbol_value("does_not_exist");
-        assert_eq!(v.is_some(), false);
+        assert!(v.is_none());
     }
 }
@@ -1,3 +1,5 @@
+use std::ops::Deref;
+
 use nom::{
     bytes::complete::{tag, take_until},
     character::complete::{alpha1, alphanumeric1, digit1},
@@ -6,7 +8,11 @@ use nom::{
     sequence::{preceded, terminated, tuple},
 };
 
-use crate::{instruction::Opcode, parse::ParseResult};
+use crate::{
+    error::Location,
+    instruction::Opcode,
+    parse::{line_column, ParseResult},
+};
 
 #[derive(Debug, PartialEq)]
 pub enum Token {
@@ -20,6 +26,57 @@ pub enum Token {
     Directive { name: String },
 }
 
+/// Where a token is in the source, in bytes from its start
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl Span {
+    pub fn new(offset: usize, len: usize) -> Self {
+        Self { offset, len }
+    }
+
+    /// The same span in a source `by` bytes longer at the start
+    pub fn shifted(self, by: usize) -> Self {
+        Self::new(self.offset + by, self.len)
+    }
+
+    /// The line and column of the span in `source`
+    pub fn location(&self, source: &str) -> Location {
+        let (line, column) = line_column(source, self.offset);
+        Location { line, column }
+    }
+}
+
+/// A token and where in the source it was found
+#[derive(Debug, PartialEq)]
+pub struct Spanned<T> {
+    pub value: T,
+    pub span: Span,
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+/// `parser`, with where what it parsed lies in `source`, which what it is given is the end of
+pub fn spanned<'a, T>(
+    source: &'a str,
+    mut parser: impl FnMut(&'a str) -> ParseResult<'a, T>,
+) -> impl FnMut(&'a str) -> ParseResult<'a, Spanned<T>> {
+    move |input: &'a str| {
+        let (remaining, value) = parser(input)?;
+        let span = Span::new(source.len() - input.len(), input.len() - remaining.len());
+        Ok((remaining, Spanned { value, span }))
+    }
+}
+
 pub fn parse_str_operand(input: &str) -> ParseResult<'_, Token> {
     let (remaining, (_, token, _)) = context(
         "String Operand",
@@ -110,7 +167,7 @@ pub fn parse_float_operand(input: &str) -> ParseResult<'_, Token> {
             |(sign, left, _, right)| {
                 let value = format!("{}.{}", left, right).parse::<f64>().unwrap();
                 match sign {
-                    Some(_) => -1.0 * value,
+                    Some(_) => -value,
                     None => value,
                 }
             },
@@ -182,6 +239,19 @@ mod tests {
         assert_eq!(value, expected);
     }
 
+    #[test]
+    fn test_spanned() {
+        let source = "load $1 #54";
+        let (_, value) = spanned(source, parse_register)(&source[5..]).unwrap();
+        assert_eq!(value.span, Span::new(5, 2));
+        assert_eq!(*value, Token::Register { reg_num: 1 });
+
+        let source = "hlt\n  load $1 #54";
+        let span = Span::new(11, 2);
+        assert_eq!(span.location(source), Location { line: 2, column: 8 });
+        assert_eq!(span.shifted(3), Span::new(14, 2));
+    }
+
     #[test]
     fn test_parse_str_operand() {
         let expected = Token::StringOperand {
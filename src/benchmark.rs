@@ -0,0 +1,216 @@
+//! Timing a program run over and over, each time on a fresh VM, as `!bench` and
+//! `iridium --bench` do. The first runs may be left out as warm-up, so caches and the
+//! allocator have settled by the time it is measured.
+
+use std::time::{Duration, Instant};
+
+use crate::{
+    assembler::PIE_HEADER_LENGTH,
+    decode::INSTRUCTION_LENGTH,
+    error::{IridiumError, Result},
+    vm::VM,
+};
+
+/// Timed runs when not told how many
+pub const DEFAULT_ITERATIONS: usize = 10;
+
+/// How the timed runs of a program went
+#[derive(Debug, Clone, PartialEq)]
+pub struct Benchmark {
+    pub instructions: u64,  // executed over all the timed runs
+    timings: Vec<Duration>, // of each timed run, fastest first
+}
+
+impl Benchmark {
+    /// Run the bytecode image `program` `warmup` times untimed, then `iterations` times timed.
+    /// Fails if it doesn't load or crashes on any run
+    pub fn run(program: &[u8], iterations: usize, warmup: usize) -> Result<Self> {
+        if iterations == 0 {
+            return Err(IridiumError::StringError(
+                "A benchmark needs at least one iteration".to_string(),
+            ));
+        }
+        let mut timings = Vec::with_capacity(iterations);
+        let mut instructions = 0;
+        for run in 0..warmup + iterations {
+            let mut vm = VM::new();
+            vm.load_bytecode(program.to_vec())?;
+            let start = Instant::now();
+            vm.run();
+            let elapsed = start.elapsed();
+            if let Some(e) = vm.error() {
+                return Err(e.clone().into());
+            }
+            if run >= warmup {
+                timings.push(elapsed);
+                instructions += vm.instruction_count();
+            }
+        }
+        timings.sort();
+        Ok(Self {
+            instructions,
+            timings,
+        })
+    }
+
+    /// Runs that were timed
+    pub fn iterations(&self) -> usize {
+        self.timings.len()
+    }
+
+    /// Time spent in the timed runs altogether
+    pub fn total(&self) -> Duration {
+        self.timings.iter().sum()
+    }
+
+    pub fn min(&self) -> Duration {
+        self.timings[0]
+    }
+
+    pub fn median(&self) -> Duration {
+        self.timings[self.timings.len() / 2]
+    }
+
+    pub fn max(&self) -> Duration {
+        self.timings[self.timings.len() - 1]
+    }
+
+    /// Instructions executed per second of the timed runs
+    pub fn per_second(&self) -> f64 {
+        self.instructions as f64 / self.total().as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// A program that counts `iterations` (at least 1) down to zero in `$0`, adding one to `$4`
+/// each time round
+pub fn counted_loop(iterations: u32) -> String {
+    counted(iterations, &[], &["inc $4"])
+}
+
+/// A program that multiplies and adds floats `iterations` (at least 1) times, leaving
+/// `6 * iterations` in float register `$3`
+pub fn float_kernel(iterations: u32) -> String {
+    counted(
+        iterations,
+        &["loadf64 $0 #3", "loadf64 $1 #2"],
+        &[
+            "mulf64 $0 $1 $2",
+            "addf64 $2 $3 $3",
+            "divf64 $3 $1 $4",
+            "subf64 $4 $0 $5",
+        ],
+    )
+}
+
+/// `setup` followed by `body` run `iterations` times, counted down in `$0`. Code labels
+/// have no offsets, so the loop jumps back to an address worked out here
+fn counted(iterations: u32, setup: &[&str], body: &[&str]) -> String {
+    // the count is checked after the body runs, so none would wrap round past zero
+    assert!(iterations > 0, "a counted loop runs at least once");
+    let mut lines = vec![
+        ".data".to_string(),
+        ".code".to_string(),
+        format!("load $0 #{}", iterations >> 16),
+        "shl $0 #16".to_string(),
+        format!("load $1 #{}", iterations & 0xffff),
+        "add $0 $1 $0".to_string(),
+        "load $2 #0".to_string(),
+    ];
+    lines.extend(setup.iter().map(|line| line.to_string()));
+    // the instructions so far and the one loading the address itself
+    let start = PIE_HEADER_LENGTH + (lines.len() - 1) * INSTRUCTION_LENGTH;
+    lines.push(format!("load $3 #{}", start));
+    lines.extend(body.iter().map(|line| line.to_string()));
+    lines.extend(["dec $0", "neq $0 $2", "jmpe $3", "hlt"].map(String::from));
+    lines.join("\n")
+}
+
+/// A program of `lines` lines (at least 5) mixing arithmetic, comparisons and strings, for
+/// timing the assembler. It assembles but isn't meant to be run
+pub fn generated_program(lines: usize) -> String {
+    let strings = (lines / 20).max(1);
+    let mut source = vec![".data".to_string()];
+    for s in 0..strings {
+        source.push(format!("s{}: .asciiz 'Message number {}'", s, s));
+    }
+    source.push(".code".to_string());
+    for i in 0..lines.saturating_sub(strings + 3) {
+        let (a, b, c) = (i % 32, (i + 1) % 32, (i + 2) % 32);
+        source.push(match i % 8 {
+            0 => format!("load ${} #{}", a, i),
+            1 => format!("add ${} ${} ${}", a, b, c),
+            2 => format!("mul ${}, ${}, ${}", a, b, c),
+            3 => format!("sub ${} ${} ${}", a, b, c),
+            4 => format!("eq ${} ${}", a, b),
+            5 => format!("inc ${}", a),
+            6 => format!("prts @s{}", i % strings),
+            _ => format!("loadf64 ${} #{}", a, i),
+        });
+    }
+    source.push("hlt".to_string());
+    source.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assembler::Assembler,
+        decode::{DecodedProgram, FromBytes},
+    };
+
+    #[test]
+    fn test_arithmetic() {
+        let program = Assembler::new()
+            .assemble(".data\n.code\nload $0 #1\nload $1 #2\nadd $0 $1 $2\nhlt")
+            .unwrap();
+        let bench = Benchmark::run(&program, 3, 1).unwrap();
+        assert_eq!(bench.iterations(), 3);
+        // the warm-up run isn't counted
+        assert_eq!(bench.instructions, 12);
+        assert!(bench.min() <= bench.median() && bench.median() <= bench.max());
+        assert!(bench.total() >= bench.max() && bench.total() <= bench.max() * 3);
+        let expected = bench.instructions as f64 / bench.total().as_secs_f64();
+        assert!((bench.per_second() - expected).abs() < 1e-6 * expected);
+    }
+
+    #[test]
+    fn test_generated_programs() {
+        let mut vm = VM::new();
+        let image = Assembler::new().assemble(&counted_loop(70_000)).unwrap();
+        vm.load_bytecode(image).unwrap();
+        vm.run();
+        assert!(vm.error().is_none());
+        assert_eq!((vm.registers[0], vm.registers[4]), (0, 70_000));
+
+        let mut vm = VM::new();
+        let image = Assembler::new().assemble(&float_kernel(1_000)).unwrap();
+        vm.load_bytecode(image).unwrap();
+        vm.run();
+        assert!(vm.error().is_none());
+        assert_eq!(vm.float_registers[3], 6_000.0);
+
+        let source = generated_program(500);
+        assert_eq!(source.lines().count(), 500);
+        let image = Assembler::new().assemble(&source).unwrap();
+        let decoded = DecodedProgram::from_bytes(&image, 0).unwrap();
+        assert_eq!(decoded.instructions.len(), 500 - 25 - 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least once")]
+    fn test_counted_loop_of_none() {
+        counted_loop(0);
+    }
+
+    #[test]
+    fn test_failures() {
+        let program = Assembler::new().assemble(".data\n.code\nhlt").unwrap();
+        assert!(Benchmark::run(&program, 0, 0).is_err());
+        let crashes = Assembler::new()
+            .assemble(".data\n.code\nload $0 #1\ndiv $0 $1 $2")
+            .unwrap();
+        let err = Benchmark::run(&crashes, 3, 0).unwrap_err();
+        assert_eq!(err.code(), "VM001");
+    }
+}
@@ -1,19 +1,70 @@
-use std::{fs::File, io::Read, net::SocketAddr, path::Path, thread};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Receiver,
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use clap::{arg, Command};
+use clap::{arg, builder::RangedU64ValueParser, ArgAction, ArgMatches, Command};
 use iridium::{
     assembler,
+    benchmark::{self, Benchmark},
+    cluster::{reconnect::ReconnectPolicy, role::NodeRole, NodeAddress, NodeAlias},
+    config::{ClusterConfig, Config, NodeConfig, RemoteConfig, CONFIG_FILE_NAME},
+    disassembler::Disassembler,
     error::{IridiumError, Result},
-    remote::server::Server,
-    repl,
-    vm::VM,
+    remote::{
+        allowlist::Cidr,
+        console,
+        server::{Server, DEFAULT_MAX_CLIENTS},
+        upload,
+    },
+    repl::{self, Flow},
+    report,
+    scheduler::ShutdownSummary,
+    vm::{check_alias, prepare_data_dir, VMEvent, VMEventType, VM},
 };
+use log::{debug, error, info, warn};
 
 const DEFAULT_CLIENT_LISTENING_ADDRESS: &str = "127.0.0.1:2244";
 const DEFAULT_PEER_LISTENING_HOST: &str = "127.0.0.1";
 const DEFAULT_PEER_LISTENING_PORT: &str = "2254";
-const DEFAULT_NODE_ALIAS: &str = "";
 const DEFAULT_DATA_DIR: &str = "/var/lib/iridium";
+const INIT_SCRIPT_NAME: &str = ".iridiumrc";
+const DEFAULT_REMOTE_IDLE_TIMEOUT_SECS: u64 = 600;
+/// Longest a node running without a REPL goes between checks for a shutdown request
+const HEADLESS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Runs of a program --bench leaves out of its timings when not told
+const DEFAULT_BENCH_WARMUP: usize = 1;
+/// What a program given as `--file -` is called in messages
+const STDIN_NAME: &str = "<stdin>";
+/// Line printed before the JSON array of events with --events-json, so it can be found in
+/// whatever the program printed
+const EVENTS_JSON_SENTINEL: &str = "VM Events (JSON)";
+
+/// Exit code of a program given with --file that didn't assemble
+const EXIT_ASSEMBLER_ERROR: i32 = 101;
+/// Exit code of a program given with --file that crashed the VM, or of bytecode that was
+/// refused
+const EXIT_VM_CRASH: i32 = 102;
+/// Exit code when a file couldn't be read or the node couldn't start
+const EXIT_IO_ERROR: i32 = 103;
+/// Exit code of bytecode given to the run subcommand that ended with $0 outside 0 to
+/// `MAX_PROGRAM_EXIT`, which it can't exit with as is
+const EXIT_OUT_OF_RANGE: i32 = 104;
+/// Highest $0 the run subcommand exits with as is, below the codes iridium exits with itself
+const MAX_PROGRAM_EXIT: i32 = 100;
+/// Exit code of a program stopped by SIGINT or SIGTERM, or of a second signal forcing the
+/// node to exit, as shells report a process ended by SIGINT
+const EXIT_INTERRUPTED: i32 = 130;
 
 /// Attempts to read a file and return the contents. Exits if unable to read the file for any reason.
 fn read_file(tmp: &str) -> Result<String> {
@@ -23,106 +74,1489 @@ fn read_file(tmp: &str) -> Result<String> {
     Ok(contents)
 }
 
-/// Start a remote server in a background thread
-fn start_remote_server(addr: SocketAddr) {
+/// Assemble and run the program in `filename` on `vm`, printing the events it recorded, and
+/// return the exit code saying how it ended: 0 once it halted or ran out of instructions
+fn run_file(filename: &str, vm: &mut VM, output: &EventsOutput) -> i32 {
+    match File::open(filename) {
+        Ok(file) => run_source(filename, file, vm, output),
+        Err(e) => {
+            let context = format!("There was an error opening {}", filename);
+            eprintln!("{}", report::error(&context, &IridiumError::from(e)));
+            EXIT_IO_ERROR
+        }
+    }
+}
+
+/// Run the program in `filename` as `run_file` does, reading it from stdin if it is `-`
+fn run_named(filename: &str, vm: &mut VM, output: &EventsOutput) -> i32 {
+    match filename {
+        "-" => run_source(STDIN_NAME, io::stdin().lock(), vm, output),
+        filename => run_file(filename, vm, output),
+    }
+}
+
+/// How one program of a batch given with --file went
+#[derive(Debug)]
+struct FileRun {
+    file: String,
+    code: i32, // what run_file returned
+    duration: Duration,
+}
+
+impl fmt::Display for FileRun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self.code {
+            0 => "PASS",
+            EXIT_VM_CRASH => "CRASH",
+            EXIT_INTERRUPTED => "INTERRUPTED",
+            _ => "ERROR",
+        };
+        write!(
+            f,
+            "{:<5}  {}  {:.1?}  exit {}",
+            status, self.file, self.duration, self.code
+        )
+    }
+}
+
+/// Run each of `files` in order on a VM of its own from `new_vm`, printing how each went.
+/// Stops at the first that fails unless `keep_going`, and at one that was interrupted anyway
+fn run_batch<F>(
+    files: &[&str],
+    mut new_vm: F,
+    keep_going: bool,
+    output: &EventsOutput,
+) -> Vec<FileRun>
+where
+    F: FnMut() -> Result<VM>,
+{
+    let mut runs = vec![];
+    for file in files {
+        let started = Instant::now();
+        let code = match new_vm() {
+            Ok(mut vm) => run_named(file, &mut vm, output),
+            Err(e) => {
+                eprintln!("{}", report::error("Unable to start a VM", &e));
+                EXIT_IO_ERROR
+            }
+        };
+        let run = FileRun {
+            file: file.to_string(),
+            code,
+            duration: started.elapsed(),
+        };
+        println!("{}", run);
+        runs.push(run);
+        if code == EXIT_INTERRUPTED || (code != 0 && !keep_going) {
+            break;
+        }
+    }
+    let failed = runs.iter().filter(|run| run.code != 0).count();
+    println!(
+        "{} passed, {} failed, {} not run",
+        runs.len() - failed,
+        failed,
+        files.len() - runs.len()
+    );
+    runs
+}
+
+/// Benchmark the program in `filename`, printing the report. Returns the exit code, 0 once the
+/// report is printed
+fn bench_file(filename: &str, iterations: usize, warmup: usize) -> i32 {
+    let source = match read_file(filename) {
+        Ok(source) => source,
+        Err(e) => {
+            let context = format!("There was an error opening {}", filename);
+            eprintln!("{}", report::error(&context, &e));
+            return EXIT_IO_ERROR;
+        }
+    };
+    let program = match assembler::Assembler::new().assemble(&source) {
+        Ok(program) => program,
+        Err(e) => {
+            let context = format!("Unable to assemble {}", filename);
+            eprintln!("{}", report::error(&context, &e));
+            return EXIT_ASSEMBLER_ERROR;
+        }
+    };
+    match Benchmark::run(&program, iterations, warmup) {
+        Ok(bench) => {
+            println!("{}", bench_report(filename, warmup, &bench));
+            0
+        }
+        Err(e) => {
+            let context = format!("Unable to benchmark {}", filename);
+            eprintln!("{}", report::error(&context, &e));
+            EXIT_VM_CRASH
+        }
+    }
+}
+
+/// What --bench prints about the program `name`
+fn bench_report(name: &str, warmup: usize, bench: &Benchmark) -> String {
+    let millis = |d: Duration| d.as_secs_f64() * 1000.0;
+    format!(
+        "Benchmark of {} ({} iterations after {} warm-up)\n\
+         instructions: {}\n\
+         total time: {:.3} ms\n\
+         instructions/sec: {:.0}\n\
+         min: {:.3} ms\n\
+         median: {:.3} ms\n\
+         max: {:.3} ms",
+        name,
+        bench.iterations(),
+        warmup,
+        bench.instructions,
+        millis(bench.total()),
+        bench.per_second(),
+        millis(bench.min()),
+        millis(bench.median()),
+        millis(bench.max())
+    )
+}
+
+/// Exit code of a batch: that of the first program that failed, 0 if none did
+fn batch_exit_code(runs: &[FileRun]) -> i32 {
+    runs.iter()
+        .map(|run| run.code)
+        .find(|code| *code != 0)
+        .unwrap_or(0)
+}
+
+/// Assemble and run the program read from `input` to its end, called `name` in messages,
+/// the way `run_file` does
+fn run_source<R: Read>(name: &str, mut input: R, vm: &mut VM, output: &EventsOutput) -> i32 {
+    let mut source = String::new();
+    if let Err(e) = input.read_to_string(&mut source) {
+        let context = format!("There was an error reading {}", name);
+        eprintln!("{}", report::error(&context, &IridiumError::from(e)));
+        return EXIT_IO_ERROR;
+    }
+    let program = match assembler::Assembler::new().assemble(&source) {
+        Ok(program) => program,
+        Err(e) => {
+            let context = format!("Unable to assemble {}", name);
+            eprintln!("{}", report::error(&context, &e));
+            return EXIT_ASSEMBLER_ERROR;
+        }
+    };
+    if let Err(e) = vm.load_bytecode(program) {
+        let context = "Unable to load the assembled program";
+        eprintln!("{}", report::error(context, &e));
+        return EXIT_VM_CRASH;
+    }
+    match run_loaded(vm, output) {
+        Ok(()) => 0,
+        Err(code) => code,
+    }
+}
+
+/// Run the bytecode image in `filename`, as written by the assemble subcommand, on `vm`,
+/// printing the events it recorded. Returns the exit code: what is in $0 once the program
+/// halted or ran out of instructions, or `EXIT_OUT_OF_RANGE` if that isn't 0 to
+/// `MAX_PROGRAM_EXIT`
+fn run_bytecode(filename: &str, vm: &mut VM, output: &EventsOutput) -> i32 {
+    let image = match fs::read(filename) {
+        Ok(image) => image,
+        Err(e) => {
+            let context = format!("There was an error opening {}", filename);
+            eprintln!("{}", report::error(&context, &e));
+            return EXIT_IO_ERROR;
+        }
+    };
+    if let Err(e) = vm.load_bytecode(image) {
+        let context = format!("Unable to load {}", filename);
+        eprintln!("{}", report::error(&context, &e));
+        if matches!(&e, IridiumError::Header(e) if e.looks_like_text()) {
+            eprintln!(
+                "hint: to run assembly source, use iridium --file {}",
+                filename
+            );
+        }
+        return EXIT_VM_CRASH;
+    }
+    match run_loaded(vm, output) {
+        Ok(()) => program_exit_code(vm.registers[0]),
+        Err(code) => code,
+    }
+}
+
+/// Exit code of a program that ended with `value` in $0. The process can only exit with
+/// 0 to 255, and the codes above `MAX_PROGRAM_EXIT` are iridium's own
+fn program_exit_code(value: i32) -> i32 {
+    if (0..=MAX_PROGRAM_EXIT).contains(&value) {
+        return value;
+    }
+    eprintln!(
+        "warning: $0 is {}, outside 0 to {}, so exiting with {}",
+        value, MAX_PROGRAM_EXIT, EXIT_OUT_OF_RANGE
+    );
+    EXIT_OUT_OF_RANGE
+}
+
+/// How a program run from the command line reports the events it recorded
+#[derive(Debug, Default)]
+struct EventsOutput {
+    json: bool, // print them as a JSON array after EVENTS_JSON_SENTINEL, not a table
+    file: Option<PathBuf>, // also write them to this file as a JSON array
+}
+
+impl EventsOutput {
+    fn from_args(args: &ArgMatches) -> Self {
+        Self {
+            json: args.get_flag("events-json"),
+            file: args.get_one::<String>("events-out").map(PathBuf::from),
+        }
+    }
+
+    fn report(&self, events: &[VMEvent]) -> Result<()> {
+        if self.json {
+            println!("{}", EVENTS_JSON_SENTINEL);
+            println!("{}", serde_json::to_string(events)?);
+        } else {
+            println!("VM Events");
+            println!("--------------------------");
+            for event in events {
+                println!("{}", event);
+            }
+        }
+        if let Some(path) = &self.file {
+            fs::write(path, serde_json::to_vec(events)?).map_err(|e| {
+                IridiumError::StringError(format!(
+                    "Unable to write events to {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Run the program already loaded on `vm`, reporting the events it recorded as `output`
+/// says. Fails with the exit code to end with if it crashed or its events couldn't be written
+fn run_loaded(vm: &mut VM, output: &EventsOutput) -> std::result::Result<(), i32> {
+    let events = vm.run();
+    if let Err(e) = output.report(&events) {
+        eprintln!("{}", report::error("Unable to report the VM events", &e));
+        return Err(EXIT_IO_ERROR);
+    }
+    match (vm.error(), events.last().map(|e| &e.event)) {
+        (Some(e), _) => eprintln!("{}", report::error("Program crashed", e)),
+        (None, Some(VMEventType::Crash)) => eprintln!("error: Program crashed"),
+        (None, Some(VMEventType::Interrupted)) => {
+            eprintln!("error: Program interrupted");
+            return Err(EXIT_INTERRUPTED);
+        }
+        _ => return Ok(()),
+    }
+    Err(EXIT_VM_CRASH)
+}
+
+/// Assemble the program in `input` into a bytecode image written to `output`, or to stdout
+/// if it is `-`, and print what went into it. Returns the exit code, 0 once it is written
+fn assemble_file(input: &str, output: &str) -> i32 {
+    let source = match read_file(input) {
+        Ok(source) => source,
+        Err(e) => {
+            let context = format!("There was an error opening {}", input);
+            eprintln!("{}", report::error(&context, &e));
+            return EXIT_IO_ERROR;
+        }
+    };
+    let mut asm = assembler::Assembler::new();
+    let image = match asm.assemble(&source) {
+        Ok(image) => image,
+        Err(e) => {
+            let context = format!("Unable to assemble {}", input);
+            eprintln!("{}", report::error(&context, &e));
+            return EXIT_ASSEMBLER_ERROR;
+        }
+    };
+    let written = if output == "-" {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(&image).and_then(|_| stdout.flush())
+    } else {
+        fs::write(output, &image)
+    };
+    if let Err(e) = written {
+        let context = format!("Unable to write {}", output);
+        eprintln!("{}", report::error(&context, &e));
+        return EXIT_IO_ERROR;
+    }
+
+    let summary = format!(
+        "Assembled {} into {}: {} bytes of read-only data, {} bytes of code, {} symbols",
+        input,
+        if output == "-" { "stdout" } else { output },
+        asm.ro.len(),
+        image.len() - assembler::PIE_HEADER_LENGTH - asm.ro.len(),
+        asm.symbols.symbols.len()
+    );
+    // the image itself may be going to stdout
+    if output == "-" {
+        eprintln!("{}", summary);
+    } else {
+        println!("{}", summary);
+    }
+    0
+}
+
+/// Write a listing of the bytecode in `input` to `output`, or to stdout if it is `-`.
+/// Returns the exit code, 0 once it is written
+fn disassemble_file(input: &str, output: &str, disassembler: &Disassembler) -> i32 {
+    let image = match fs::read(input) {
+        Ok(image) => image,
+        Err(e) => {
+            let context = format!("There was an error opening {}", input);
+            eprintln!("{}", report::error(&context, &e));
+            return EXIT_IO_ERROR;
+        }
+    };
+    let listing = match disassembler.disassemble(&image) {
+        Ok(listing) => listing,
+        Err(e) => {
+            let context = format!("Unable to disassemble {}", input);
+            eprintln!("{}", report::error(&context, &e));
+            return EXIT_VM_CRASH;
+        }
+    };
+    let written = if output == "-" {
+        let mut stdout = io::stdout().lock();
+        stdout
+            .write_all(listing.as_bytes())
+            .and_then(|_| stdout.flush())
+    } else {
+        fs::write(output, listing)
+    };
+    if let Err(e) = written {
+        let context = format!("Unable to write {}", output);
+        eprintln!("{}", report::error(&context, &e));
+        return EXIT_IO_ERROR;
+    }
+    0
+}
+
+/// Print what the REPL sends until it is dropped. Once stdout can't be written to the rest
+/// is only drained, so the REPL never waits on a pipe nobody empties
+fn print_output(chan: Receiver<String>) {
+    let mut stdout = io::stdout();
+    let mut writable = true;
+    for msg in chan {
+        if !writable {
+            continue;
+        }
+        if let Err(e) = stdout
+            .write_all(msg.as_bytes())
+            .and_then(|_| stdout.flush())
+        {
+            error!(
+                "Unable to write to stdout, discarding further output: {}",
+                e
+            );
+            writable = false;
+        }
+    }
+    debug!("REPL closed its output pipe");
+}
+
+/// Bind a remote server to `addr` and serve clients from a background thread. Returns the
+/// address it is listening on, which has the port picked for it when `addr`'s is 0
+fn start_remote_server(addr: SocketAddr, server: Server) -> Result<SocketAddr> {
+    let bound = TcpListener::bind(addr).and_then(|listener| {
+        let bound = listener.local_addr()?;
+        Ok((listener, bound))
+    });
+    let (listener, bound) = bound.map_err(|e| {
+        IridiumError::StringError(format!(
+            "Unable to listen for remote clients on {}: {}",
+            addr, e
+        ))
+    })?;
     thread::spawn(move || -> Result<()> {
-        let mut server = Server::new();
-        server.run(addr)
+        let mut server = server;
+        server.serve(listener)
     });
+    Ok(bound)
 }
 
-fn main() -> Result<()> {
-    env_logger::builder()
-        .filter_level(log::LevelFilter::Info)
-        .init();
-    let default_client_addr = DEFAULT_CLIENT_LISTENING_ADDRESS
-        .parse::<SocketAddr>()
-        .unwrap();
-    let default_peer_host = DEFAULT_PEER_LISTENING_HOST.to_string();
-    let default_peer_port = DEFAULT_PEER_LISTENING_PORT.to_string();
-    let default_node_alias = DEFAULT_NODE_ALIAS.to_string();
+/// A node alias, which can't be empty or hold whitespace or ':'
+fn parse_alias(alias: &str) -> std::result::Result<String, String> {
+    check_alias(alias)
+        .map(|_| alias.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// An address to listen on, such as 127.0.0.1:2244 or 0.0.0.0:2244
+fn parse_listen_addr(addr: &str) -> std::result::Result<SocketAddr, String> {
+    addr.parse().map_err(|_| {
+        format!(
+            "`{}` is not an address to listen on, such as 127.0.0.1:2244 or 0.0.0.0:2244",
+            addr
+        )
+    })
+}
+
+/// On SIGINT or SIGTERM, set `interrupted`, which stops running programs, the REPL and a node
+/// running without one so they can shut down cleanly. A second signal exits right away
+fn handle_signals(interrupted: Arc<AtomicBool>) -> Result<()> {
+    let main = thread::current();
+    ctrlc::set_handler(move || {
+        if interrupted.swap(true, Ordering::SeqCst) {
+            eprintln!("Interrupted again, exiting now");
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        info!("Interrupted, shutting down (interrupt again to exit now)");
+        // wakes a node running without a REPL
+        main.unpark();
+    })
+    .map_err(|e| IridiumError::StringError(format!("Unable to handle signals: {}", e)))
+}
+
+/// Make sure a node started with --no-repl has something to serve
+fn check_headless(args: &ArgMatches, settings: &Config) -> Result<()> {
+    if args.get_flag("no-repl")
+        && settings.remote.enabled != Some(true)
+        && settings.node.alias.is_none()
+        && settings.cluster.join.is_empty()
+    {
+        return Err(IridiumError::StringError(
+            "--no-repl needs --enable-remote, --node-alias or --join, or there is nothing to serve"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Leave the remote and cluster servers started for `vm` serving, without reading stdin,
+/// until `shutdown` is set. Then give the programs peers sent a chance to finish and leave
+/// the cluster
+fn run_headless(vm: &Mutex<VM>, shutdown: &AtomicBool) {
+    info!("Running without a REPL until shut down");
+    while !shutdown.load(Ordering::SeqCst) {
+        thread::park_timeout(HEADLESS_POLL_INTERVAL);
+    }
+    info!("Shutting down");
+    let vm = vm.lock().unwrap_or_else(|e| e.into_inner());
+    let summary = vm.cluster_scheduler().shutdown(repl::SHUTDOWN_GRACE);
+    if summary != ShutdownSummary::default() {
+        info!(
+            "Tasks at shutdown: {} completed, {} cancelled, {} abandoned",
+            summary.completed, summary.cancelled, summary.abandoned
+        );
+    }
+    vm.leave_cluster();
+}
+
+/// Join the cluster through the node at `target` with `join`, trying again with the backoff
+/// of `policy` while it can't be reached, as when it is still starting up. A node that
+/// answers and turns this one away, over its secret or alias say, isn't asked again
+fn join_with_retry<J>(join: &J, target: &str, policy: &ReconnectPolicy) -> Result<NodeAlias>
+where
+    J: Fn(&str) -> Result<(NodeAlias, Vec<NodeAddress>)>,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match join(target) {
+            Ok((hub, _)) => return Ok(hub),
+            Err(e) if !matches!(e, IridiumError::Io(_)) || policy.gave_up(attempts) => {
+                return Err(e)
+            }
+            Err(e) => {
+                let delay = policy.delay(attempts);
+                debug!(
+                    "Unable to join the cluster through {}, trying again in {:?}: {}",
+                    target, delay, e
+                );
+                thread::sleep(delay);
+            }
+        }
+    }
+}
 
-    let args = Command::new("iridium")
+/// Join the cluster through each of `targets` in turn, logging how each went. Startup only
+/// waits for the joins when they are `required`, and stops if one fails; otherwise they go
+/// on in the background. Returns the thread joining in the background, if there is one
+fn join_targets(
+    vm: &VM,
+    targets: &[String],
+    required: bool,
+) -> Result<Option<thread::JoinHandle<()>>> {
+    if targets.is_empty() {
+        return Ok(None);
+    }
+    let join = match vm.cluster_joiner(None) {
+        Ok(join) => join,
+        Err(e) if required => return Err(e),
+        Err(e) => {
+            eprintln!("{}", report::error("Unable to join the cluster", &e));
+            return Ok(None);
+        }
+    };
+    let mut policy = vm.reconnect_policy().clone();
+    if !required {
+        let targets = targets.to_vec();
+        return Ok(Some(thread::spawn(move || {
+            for target in &targets {
+                match join_with_retry(&join, target, &policy) {
+                    Ok(hub) => info!("Joined the cluster through node {} at {}", hub, target),
+                    Err(e) => {
+                        let context = format!("Unable to join the cluster through {}", target);
+                        eprintln!("{}", report::error(&context, &e));
+                    }
+                }
+            }
+        })));
+    }
+    // a target that never comes would leave startup neither done nor failed
+    if policy.max_attempts.is_none() {
+        policy.max_attempts = ReconnectPolicy::default().max_attempts;
+    }
+    for target in targets {
+        let hub = join_with_retry(&join, target, &policy).map_err(|e| {
+            IridiumError::StringError(format!(
+                "Unable to join the cluster through {}: {}",
+                target, e
+            ))
+        })?;
+        info!("Joined the cluster through node {} at {}", hub, target);
+    }
+    Ok(None)
+}
+
+/// The settings given as flags, as a configuration that overrides the file's
+fn cli_config(args: &ArgMatches) -> Config {
+    Config {
+        node: NodeConfig {
+            alias: args.get_one::<String>("node-alias").cloned(),
+            data_dir: args.get_one::<String>("data-dir").map(PathBuf::from),
+            threads: args.get_one::<usize>("threads").copied(),
+        },
+        remote: RemoteConfig {
+            enabled: Some(true).filter(|_| args.get_flag("enable-remote")),
+            addr: args.get_one::<SocketAddr>("addr").copied(),
+            token: args.get_one::<String>("remote-token").cloned(),
+            max_clients: args.get_one::<usize>("remote-max-clients").copied(),
+        },
+        cluster: ClusterConfig {
+            peer_host: args.get_one::<String>("peer-host").cloned(),
+            peer_port: args.get_one::<String>("peer-port").cloned(),
+            join: args
+                .get_many::<String>("join")
+                .unwrap_or_default()
+                .cloned()
+                .collect(),
+            secret: args.get_one::<String>("cluster-secret").cloned(),
+        },
+    }
+}
+
+/// The configuration file given with --config, or else the first of
+/// `$DATA_DIR/iridium.toml` and `./iridium.toml` there is, with the flags in `args` taking
+/// the place of its settings
+fn load_settings(args: &ArgMatches) -> Result<Config> {
+    let overrides = cli_config(args);
+    let path = match args.get_one::<String>("config") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => {
+            let data_dir = overrides
+                .node
+                .data_dir
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_DATA_DIR));
+            [
+                data_dir.join(CONFIG_FILE_NAME),
+                PathBuf::from(CONFIG_FILE_NAME),
+            ]
+            .into_iter()
+            .find(|path| path.is_file())
+        }
+    };
+    match path {
+        Some(path) => {
+            debug!("Reading configuration from {}", path.display());
+            Ok(Config::load(&path)?.merge(overrides))
+        }
+        None => Ok(overrides),
+    }
+}
+
+/// `settings` with the defaults filled in for what neither the file nor the flags set
+fn effective_config(settings: &Config) -> Config {
+    let defaults = Config {
+        node: NodeConfig {
+            alias: None,
+            data_dir: Some(PathBuf::from(DEFAULT_DATA_DIR)),
+            threads: Some(num_cpus::get()),
+        },
+        remote: RemoteConfig {
+            enabled: Some(false),
+            addr: DEFAULT_CLIENT_LISTENING_ADDRESS.parse().ok(),
+            token: None,
+            max_clients: Some(DEFAULT_MAX_CLIENTS),
+        },
+        cluster: ClusterConfig {
+            peer_host: Some(DEFAULT_PEER_LISTENING_HOST.to_string()),
+            peer_port: Some(DEFAULT_PEER_LISTENING_PORT.to_string()),
+            join: vec![],
+            secret: None,
+        },
+    };
+    defaults.merge(settings.clone())
+}
+
+/// The VM `settings` and the command line ask for, storing its data in `data_dir` if it has
+/// one
+fn build_vm(args: &ArgMatches, settings: &Config, data_dir: Option<&Path>) -> Result<VM> {
+    let num_threads = settings.node.threads.unwrap_or_else(num_cpus::get);
+    debug!("Running programs on {} threads", num_threads);
+
+    let cluster = &settings.cluster;
+    let peer_host = cluster
+        .peer_host
+        .clone()
+        .unwrap_or_else(|| DEFAULT_PEER_LISTENING_HOST.to_string());
+    let peer_port = cluster
+        .peer_port
+        .clone()
+        .unwrap_or_else(|| DEFAULT_PEER_LISTENING_PORT.to_string());
+
+    let mut reconnect_policy = ReconnectPolicy::default();
+    if let Some(attempts) = args.get_one::<u32>("peer-reconnect-attempts") {
+        reconnect_policy.max_attempts = Some(*attempts).filter(|n| *n > 0);
+    }
+
+    let mut vm = VM::new()
+        .with_cluster_bind(&peer_host, &peer_port)
+        .with_reconnect_policy(reconnect_policy)
+        .with_cluster_secret(cluster.secret.clone())
+        .with_event_coordinator(args.get_one::<String>("event-coordinator").cloned())
+        .with_role(
+            args.get_one::<NodeRole>("role")
+                .copied()
+                .unwrap_or_default(),
+        )
+        .with_logical_cores(num_threads);
+    // an alias given on the command line or in the file wins over one made from the VM's id
+    match &settings.node.alias {
+        Some(alias) => vm = vm.with_alias(alias),
+        None => {
+            vm.ensure_alias();
+        }
+    }
+    let vm = match data_dir {
+        Some(data_dir) => vm.with_data_dir(data_dir.to_path_buf()),
+        None => vm,
+    };
+    #[cfg(feature = "tls")]
+    let vm = match (
+        args.get_one::<String>("cluster-cert"),
+        args.get_one::<String>("cluster-key"),
+    ) {
+        (Some(cert), Some(key)) => {
+            let transport =
+                iridium::cluster::transport::Transport::tls(Path::new(cert), Path::new(key))?;
+            vm.with_cluster_transport(transport)
+        }
+        _ => vm,
+    };
+    Ok(vm)
+}
+
+/// The command line iridium takes
+fn cli() -> Command {
+    let cmd = Command::new("iridium")
         .version("1.0")
         .author("Vivi W. <polarsatellitest@gmail.com>")
         .about("Interpreter for the Iridium language")
-        .arg(
-            arg!(--file <INPUT_FILE> "Path to the .iasm or .ir file to run")
-                .index(1)
-                .short('f'),
-        )
-        .arg(arg!(--threads <THREADS> "Number of OS threads the VM will utilize").short('t'))
+        // -h is --peer-host's, so help is only --help
+        .disable_help_flag(true)
+        .arg(arg!(--help "Print help").action(ArgAction::Help))
+        .arg(arg!(--file <INPUT_FILE> "Path to the .iasm or .ir file to run, - to read it from stdin; several are run in order, each on a fresh VM").short('f').num_args(1..))
+        .arg(arg!(--"keep-going" "Run the rest of the --file programs after one fails").requires("file"))
+        .arg(arg!(--bench "Time the --file programs instead of running them once, reporting instructions per second").requires("file"))
+        .arg(arg!(--iterations <N> "Timed runs of each program with --bench (default 10)").value_parser(RangedU64ValueParser::<usize>::new().range(1..)).requires("bench"))
+        .arg(arg!(--warmup <N> "Runs of each program with --bench left out of the timings (default 1)").value_parser(clap::value_parser!(usize)).requires("bench"))
+        .arg(arg!(--"events-json" "Print the events of a program run with --file or run as a JSON array, after a line saying \"VM Events (JSON)\"").global(true))
+        .arg(arg!(--"events-out" <PATH> "Also write the events of a program run with --file or run to this file as a JSON array").global(true))
+        .arg(arg!(--threads <THREADS> "Number of OS threads the VM will utilize, at least 1 (default: one for each CPU)").short('t').value_parser(RangedU64ValueParser::<usize>::new().range(1..)))
+        .arg(arg!(--connect <ADDR> "Connect to a remote Iridium REPL instead of starting a VM"))
         .arg(arg!(--"enable-remote" "Enables the remote server component of Iridium VM"))
-        .arg(arg!(--addr <ADDR> "Sets the listening address for remote connections from clients"))
+        .arg(arg!(--addr <ADDR> "Sets the listening address for remote connections from clients, port 0 to have one picked (default 127.0.0.1:2244)").value_parser(parse_listen_addr))
+        .arg(arg!(--"remote-token" <TOKEN> "Shared secret remote clients must send with AUTH before using the REPL, also used by --connect"))
+        .arg(arg!(--"remote-allow" <CIDR> "Only accept remote clients from this network, may be repeated (default: allow all)").action(ArgAction::Append).value_parser(clap::value_parser!(Cidr)))
+        .arg(arg!(--"remote-max-clients" <MAX> "Maximum number of remote clients connected at once (default 16)").value_parser(clap::value_parser!(usize)))
+        .arg(arg!(--"remote-workers" <WORKERS> "Threads serving remote sessions; clients beyond this wait for a free one (default: --remote-max-clients)").value_parser(clap::value_parser!(usize)))
+        .arg(arg!(--"remote-attach" "Attach remote clients to this node's VM instead of giving each a fresh one"))
+        .arg(arg!(--"remote-idle-timeout" <SECONDS> "Disconnect remote clients idle for this many seconds, 0 to never disconnect (default 600)").value_parser(clap::value_parser!(u64)))
         .arg(arg!(--"peer-host" <PEER_HOST> "Sets the listening address for remote connections from peer nodes").short('h'))
         .arg(arg!(--"peer-port" <PEER_PORT> "Sets the listening port for remote connections from peer nodes").short('p'))
+        .arg(arg!(--"cluster-secret" <SECRET> "Shared secret cluster peers must present to join this node, also sent when joining others"))
+        .arg(arg!(--"event-coordinator" <ALIAS> "Report VM events to this cluster member, collecting them here if it is this node's own alias"))
+        .arg(arg!(--"peer-reconnect-attempts" <ATTEMPTS> "Times to try reaching a lost cluster member before giving up, 0 to keep trying forever (default 10)").value_parser(clap::value_parser!(u32)))
         .arg(arg!(--"data-dir" <DATA_DIR> "Root directory where the Iridium VM should store its data"))
-        .arg(arg!(--"node-alias" <NODE_ALIAS> "An alias that can be used to refer to a running VM across a network"))
-        .get_matches();
+        .arg(arg!(--config <FILE> "TOML file of settings, overridden by flags (default: iridium.toml in the data directory, then in the working directory)"))
+        .arg(arg!(--"print-config" "Print the settings the configuration file and flags add up to, then exit"))
+        .arg(arg!(--rejoin "Start the cluster server and reconnect to the cluster members saved in the data directory"))
+        .arg(arg!(--join <HOST_PORT> "Start the cluster server and join the cluster through the node at this address, in the background and retried as --peer-reconnect-attempts says while it can't be reached; may be repeated").action(ArgAction::Append))
+        .arg(arg!(--"join-required" "Wait for the --join targets before starting, and exit when one can't be joined instead of starting without it").requires("join"))
+        .arg(arg!(--"node-alias" <NODE_ALIAS> "An alias that can be used to refer to a running VM across a network, without whitespace or ':' (default: made from the VM's id, such as node-1a2b3c)").value_parser(parse_alias))
+        .arg(arg!(--role <ROLE> "What this node does for the cluster: worker, coordinator or both (default both)").value_parser(clap::value_parser!(NodeRole)))
+        .arg(arg!(--init <INIT_FILE> "Script of REPL commands to run at startup (defaults to <DATA_DIR>/.iridiumrc)"))
+        .arg(arg!(--"no-repl" "Serve remote clients and cluster peers without reading stdin until shut down, as a background node").conflicts_with_all(["file", "init"]))
+        .subcommand(
+            Command::new("assemble")
+                .about("Assemble a program into a bytecode file without running it")
+                .arg(arg!(-h --help "Print help").action(ArgAction::Help))
+                .arg(arg!(<INPUT> "Path to the .iasm file to assemble"))
+                .arg(arg!(-o --output <OUTPUT> "Where to write the bytecode, - for stdout (default: INPUT with a .bin extension)")),
+        )
+        .subcommand(
+            Command::new("run")
+                .about("Run a bytecode file written by assemble, exiting with what is in $0 once it ends (104 if it isn't 0 to 100)")
+                .arg(arg!(-h --help "Print help").action(ArgAction::Help))
+                .arg(arg!(<PROGRAM> "Path to the bytecode file to run")),
+        )
+        .subcommand(
+            Command::new("disassemble")
+                .about("List the data and instructions in a bytecode file")
+                .arg(arg!(-h --help "Print help").action(ArgAction::Help))
+                .arg(arg!(<PROGRAM> "Path to the bytecode file to list"))
+                .arg(arg!(-o --output <OUTPUT> "Where to write the listing (default: stdout)"))
+                .arg(arg!(--bytes "Show the bytes of each line in hex"))
+                .arg(arg!(--"no-data" "Leave out the read-only data")),
+        );
+    #[cfg(feature = "tls")]
+    let cmd = cmd
+        .arg(arg!(--"remote-cert" <CERT_FILE> "PEM certificate chain used to serve remote connections over TLS").requires("remote-key"))
+        .arg(arg!(--"remote-key" <KEY_FILE> "PEM private key for --remote-cert").requires("remote-cert"))
+        .arg(arg!(--"cluster-cert" <CERT_FILE> "PEM certificate chain every cluster member presents, encrypting cluster connections with TLS").requires("cluster-key"))
+        .arg(arg!(--"cluster-key" <KEY_FILE> "PEM private key for --cluster-cert").requires("cluster-cert"));
+    cmd
+}
 
-    if args.contains_id("enable-remote") {
-        let addr = args
-            .get_one::<SocketAddr>("addr")
-            .unwrap_or(&default_client_addr);
-        start_remote_server(*addr);
+fn main() {
+    if let Err(e) = start() {
+        eprintln!("{}", report::error("Unable to run iridium", &e));
+        std::process::exit(EXIT_IO_ERROR);
     }
+}
 
-    let num_threads = match args.get_one::<usize>("threads") {
-        Some(thread_cnt) => *thread_cnt,
-        None => num_cpus::get(),
-    };
+fn start() -> Result<()> {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+    let default_client_addr = DEFAULT_CLIENT_LISTENING_ADDRESS
+        .parse::<SocketAddr>()
+        .unwrap();
+
+    let args = cli().get_matches();
 
-    let node_alias = args
-        .get_one::<String>("node-alias")
-        .unwrap_or(&default_node_alias);
+    if let Some(("assemble", args)) = args.subcommand() {
+        let input = args.get_one::<String>("INPUT").unwrap();
+        let output = match args.get_one::<String>("output") {
+            Some(output) => output.clone(),
+            None => Path::new(input)
+                .with_extension("bin")
+                .to_string_lossy()
+                .into_owned(),
+        };
+        std::process::exit(assemble_file(input, &output));
+    }
+    if let Some(("disassemble", args)) = args.subcommand() {
+        let input = args.get_one::<String>("PROGRAM").unwrap();
+        let output = args.get_one::<String>("output").map_or("-", String::as_str);
+        let disassembler = Disassembler::new()
+            .with_bytes(args.get_flag("bytes"))
+            .with_no_data(args.get_flag("no-data"));
+        std::process::exit(disassemble_file(input, output, &disassembler));
+    }
 
-    let peer_host = args
-        .get_one::<String>("peer-host")
-        .unwrap_or(&default_peer_host);
-    let peer_port = args
-        .get_one::<String>("peer-port")
-        .unwrap_or(&default_peer_port);
+    if let Some(addr) = args.get_one::<String>("connect") {
+        let token = args.get_one::<String>("remote-token").map(String::as_str);
+        return console::run(addr.as_str(), token, io::stdin().lock(), io::stdout());
+    }
 
-    let mut vm = VM::new()
-        .with_alias(node_alias)
-        .with_cluster_bind(peer_host, peer_port);
-    vm.logical_cores = num_threads;
-
-    if let Some(filename) = args.get_one::<String>("file") {
-        match read_file(filename) {
-            Ok(program) => {
-                let mut asm = assembler::Assembler::new();
-                let program = asm.assemble(&program)?;
-                vm.add_bytes(program);
-                let events = vm.run();
-                println!("VM Events");
-                println!("--------------------------");
-                for event in &events {
-                    println!("{:#?}", event);
-                }
-                std::process::exit(0);
-            }
+    let settings = load_settings(&args)?;
+    if args.get_flag("print-config") {
+        print!("{}", effective_config(&settings).to_toml()?);
+        return Ok(());
+    }
+    check_headless(&args, &settings)?;
+    let data_dir = settings
+        .node
+        .data_dir
+        .as_deref()
+        .unwrap_or(Path::new(DEFAULT_DATA_DIR));
+    debug!("Using data directory {}", data_dir.display());
+    // a program run on its own only needs the data directory to rejoin the cluster
+    let runs_program = args.subcommand_matches("run").is_some() || args.contains_id("file");
+    let writable_data_dir = if runs_program && !args.get_flag("rejoin") {
+        None
+    } else {
+        match prepare_data_dir(data_dir) {
+            Ok(()) => Some(data_dir),
+            Err(e) if settings.node.data_dir.is_some() => return Err(e),
             Err(e) => {
-                eprintln!("There was an error opening that file: {:?}", e);
+                warn!(
+                    "{}; history, cluster membership and uploads won't be saved (choose another with --data-dir)",
+                    e
+                );
+                None
             }
         }
+    };
+    let interrupted = Arc::new(AtomicBool::new(false));
+    handle_signals(interrupted.clone())?;
+    let mut vm = build_vm(&args, &settings, writable_data_dir)?.with_interrupt(interrupted.clone());
+    if !runs_program && settings.node.alias.is_none() {
+        if let Some(alias) = &vm.alias {
+            info!("No --node-alias given, this node is {}", alias);
+        }
+    }
+    let joins = &settings.cluster.join;
+    if !joins.is_empty() && !vm.role().coordinates() {
+        return Err(IridiumError::StringError(format!(
+            "This node is a {}, --join needs the coordinator role",
+            vm.role()
+        )));
+    }
+    if args.get_flag("rejoin") {
+        let rejoined = vm.bind_cluster_server().and_then(|addr| {
+            info!("Cluster server listening on {}", addr);
+            vm.rejoin_cluster()
+        });
+        match rejoined {
+            Ok(rejoining) => info!("Rejoining {} saved cluster members", rejoining),
+            Err(e) => eprintln!("{}", report::error("Unable to rejoin the cluster", &e)),
+        }
+    } else if !joins.is_empty() || (args.get_flag("no-repl") && settings.node.alias.is_some()) {
+        // members the node joins connect back to it, and without a REPL there is no
+        // !start_cluster, so it listens for peers right away
+        let addr = vm.bind_cluster_server()?;
+        info!("Cluster server listening on {}", addr);
+    }
+    join_targets(&vm, joins, args.get_flag("join-required"))?;
+    let vm = Arc::new(Mutex::new(vm));
+
+    let mut connections = None;
+    let mut remote_metrics = None;
+    if settings.remote.enabled == Some(true) {
+        let addr = settings.remote.addr.unwrap_or(default_client_addr);
+        let token = settings.remote.token.clone();
+        let idle_secs = args
+            .get_one::<u64>("remote-idle-timeout")
+            .copied()
+            .unwrap_or(DEFAULT_REMOTE_IDLE_TIMEOUT_SECS);
+        let idle_timeout = Some(Duration::from_secs(idle_secs)).filter(|t| !t.is_zero());
+        let max_clients = settings.remote.max_clients.unwrap_or(DEFAULT_MAX_CLIENTS);
+        let workers = args
+            .get_one::<usize>("remote-workers")
+            .copied()
+            .unwrap_or(max_clients);
+        let server = Server::new()
+            .with_token(token)
+            .with_idle_timeout(idle_timeout)
+            .with_max_clients(max_clients)
+            .with_workers(workers)
+            .with_allowlist(
+                args.get_many::<Cidr>("remote-allow")
+                    .unwrap_or_default()
+                    .copied()
+                    .collect(),
+            );
+        #[cfg(feature = "tls")]
+        let server = match (
+            args.get_one::<String>("remote-cert"),
+            args.get_one::<String>("remote-key"),
+        ) {
+            (Some(cert), Some(key)) => {
+                let config = iridium::remote::tls::server_config(Path::new(cert), Path::new(key))?;
+                server.with_tls(config)
+            }
+            _ => server,
+        };
+        let server = match writable_data_dir {
+            Some(data_dir) => server.with_staging_dir(upload::uploads_dir(data_dir)),
+            None => server,
+        };
+        let server = if args.get_flag("remote-attach") {
+            server.with_shared_vm(vm.clone())
+        } else {
+            server
+        };
+        connections = Some(server.connections());
+        remote_metrics = Some(server.metrics());
+        let bound = start_remote_server(addr, server)?;
+        info!("Remote server listening on {}", bound);
+    }
+
+    if let Some(("run", args)) = args.subcommand() {
+        let filename = args.get_one::<String>("PROGRAM").unwrap();
+        let mut vm = vm.lock().unwrap_or_else(|e| e.into_inner());
+        let code = run_bytecode(filename, &mut vm, &EventsOutput::from_args(args));
+        std::process::exit(code);
+    } else if let Some(files) = args.get_many::<String>("file") {
+        let files: Vec<&str> = files.map(String::as_str).collect();
+        let output = EventsOutput::from_args(&args);
+        let code = if args.get_flag("bench") {
+            let iterations = args
+                .get_one::<usize>("iterations")
+                .copied()
+                .unwrap_or(benchmark::DEFAULT_ITERATIONS);
+            let warmup = args
+                .get_one::<usize>("warmup")
+                .copied()
+                .unwrap_or(DEFAULT_BENCH_WARMUP);
+            let codes: Vec<i32> = files
+                .iter()
+                .map(|file| bench_file(file, iterations, warmup))
+                .collect();
+            codes.into_iter().find(|code| *code != 0).unwrap_or(0)
+        } else {
+            match files[..] {
+                [filename] => {
+                    let mut vm = vm.lock().unwrap_or_else(|e| e.into_inner());
+                    run_named(filename, &mut vm, &output)
+                }
+                _ => {
+                    let new_vm = || {
+                        build_vm(&args, &settings, None)
+                            .map(|vm| vm.with_interrupt(interrupted.clone()))
+                    };
+                    let runs = run_batch(&files, new_vm, args.get_flag("keep-going"), &output);
+                    batch_exit_code(&runs)
+                }
+            }
+        };
+        std::process::exit(code);
+    } else if args.get_flag("no-repl") {
+        run_headless(&vm, &interrupted);
     } else {
-        let mut repl = repl::REPL::new(vm);
-        let rx = repl.rx_pipe.take();
-        thread::spawn(move || -> Result<()> {
-            let chan = rx.unwrap();
-            loop {
-                match chan.recv() {
-                    Ok(msg) => {
-                        print!("{}", msg);
-                        Ok(())
-                    }
-                    Err(e) => Err(IridiumError::Recv(e)),
-                }?
+        let init_script = match args.get_one::<String>("init") {
+            Some(path) => Some(PathBuf::from(path)),
+            None => Some(data_dir.join(INIT_SCRIPT_NAME)).filter(|rc| rc.exists()),
+        };
+
+        let mut repl = repl::REPL::shared(vm.clone()).with_interrupt(interrupted.clone());
+        if let Some(data_dir) = writable_data_dir {
+            repl = repl.with_history_file(repl::history_file(data_dir));
+        }
+        if let Some(connections) = connections.clone() {
+            repl = repl.with_connections(connections);
+        }
+        if let Some(metrics) = remote_metrics {
+            repl = repl.with_remote_metrics(metrics);
+        }
+        let rx = repl.rx_pipe.take().unwrap();
+        let printer = thread::spawn(move || print_output(*rx));
+        let mut flow = Flow::Continue;
+        if let Some(script) = init_script {
+            match repl.run_script(&script) {
+                Ok(script_flow) => flow = script_flow,
+                Err(e) => {
+                    let context = format!("Unable to run init script {}", script.display());
+                    eprintln!("{}", report::error(&context, &e));
+                }
             }
-        });
-        repl.run()?;
+        }
+        if flow != Flow::Quit {
+            repl.run()?;
+        }
+        // lets spawned tasks wrap up, when the session ended without !quit doing it
+        repl.shutdown()?;
+        // so peers drop this node right away instead of waiting to notice it is gone
+        vm.lock().unwrap_or_else(|e| e.into_inner()).leave_cluster();
+        // closing the REPL's pipe lets the printer finish the last messages and stop
+        drop(repl);
+        let _ = printer.join();
+    }
+    // remote clients see their session end rather than a node that stopped answering
+    if let Some(connections) = connections {
+        connections.close_all();
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    fn run_text(source: &str) -> i32 {
+        run_source(
+            STDIN_NAME,
+            source.as_bytes(),
+            &mut VM::new(),
+            &EventsOutput::default(),
+        )
+    }
+
+    #[test]
+    fn test_run_file_exit_codes() {
+        assert_eq!(run_text(".data\n.code\nload $0 #1\nhlt"), 0);
+        assert_eq!(run_text(".data\n.code\nload $0 #1"), 0);
+        assert_eq!(run_text(".data\n.code\nload $0 ???"), EXIT_ASSEMBLER_ERROR);
+        assert_eq!(
+            run_text(".data\n.code\nload $0 #1\ndiv $0 $1 $2"),
+            EXIT_VM_CRASH
+        );
+        // not UTF-8, so not source
+        let binary = run_source(
+            STDIN_NAME,
+            &[0xff, 0xfe][..],
+            &mut VM::new(),
+            &EventsOutput::default(),
+        );
+        assert_eq!(binary, EXIT_IO_ERROR);
+
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b".data\n.code\nload $0 #1\nhlt").unwrap();
+        assert_eq!(
+            run_file(
+                file.path().to_str().unwrap(),
+                &mut VM::new(),
+                &EventsOutput::default()
+            ),
+            0
+        );
+        let missing = run_file(
+            "/no/such/program.iasm",
+            &mut VM::new(),
+            &EventsOutput::default(),
+        );
+        assert_eq!(missing, EXIT_IO_ERROR);
+    }
+
+    /// Path of a program in the examples directory
+    fn example(name: &str) -> String {
+        format!("{}/examples/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    #[test]
+    fn test_assemble_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("hello.bin");
+        let output = output.to_str().unwrap();
+        assert_eq!(assemble_file(&example("hello.iasm"), output), 0);
+        let image = fs::read(output).unwrap();
+        assert_eq!(image[..4], assembler::PIE_HEADER_PREFIX);
+        assert_eq!(image[4..8], [6, 0, 0, 0]);
+        assert_eq!(
+            image[assembler::PIE_HEADER_LENGTH..],
+            [b'H', b'e', b'l', b'l', b'o', 0, 21, 0, 0, 0, 5, 0, 0, 0]
+        );
+
+        let mut broken = NamedTempFile::new().unwrap();
+        broken.write_all(b".data\n.code\nload $0 ???").unwrap();
+        let unwritten = dir.path().join("broken.bin");
+        let code = assemble_file(broken.path().to_str().unwrap(), unwritten.to_str().unwrap());
+        assert_eq!(code, EXIT_ASSEMBLER_ERROR);
+        assert!(!unwritten.exists());
+
+        let missing = assemble_file("/no/such/program.iasm", output);
+        assert_eq!(missing, EXIT_IO_ERROR);
+        let unwritable = assemble_file(&example("hlt.iasm"), "/no/such/dir/hlt.bin");
+        assert_eq!(unwritable, EXIT_IO_ERROR);
+    }
+
+    /// Assemble `source` with the assemble subcommand, then run what it wrote
+    fn assemble_and_run(source: &str) -> i32 {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(source.as_bytes()).unwrap();
+        let output = NamedTempFile::new().unwrap();
+        let output = output.path().to_str().unwrap();
+        assert_eq!(assemble_file(file.path().to_str().unwrap(), output), 0);
+        run_bytecode(output, &mut VM::new(), &EventsOutput::default())
+    }
+
+    #[test]
+    fn test_run_bytecode_exit_codes() {
+        assert_eq!(assemble_and_run(".data\n.code\nload $0 #7\nhlt"), 7);
+        let sum = ".data\n.code\nload $1 #20\nload $2 #22\nadd $1 $2 $0";
+        assert_eq!(assemble_and_run(sum), 42);
+        assert_eq!(assemble_and_run(".data\n.code\nhlt"), 0);
+        assert_eq!(assemble_and_run(".data\n.code\nload $0 #100"), 100);
+        let codes = [101, 102, 103, 256].map(|code| {
+            let source = format!(".data\n.code\nload $0 #{}", code);
+            assemble_and_run(&source)
+        });
+        assert_eq!(codes, [EXIT_OUT_OF_RANGE; 4]);
+        let negative = ".data\n.code\nload $1 #1\nsub $0 $1 $0";
+        assert_eq!(assemble_and_run(negative), EXIT_OUT_OF_RANGE);
+        assert_eq!(
+            assemble_and_run(".data\n.code\nload $0 #1\ndiv $0 $1 $2"),
+            EXIT_VM_CRASH
+        );
+
+        // source isn't bytecode
+        let source = run_bytecode(
+            &example("hlt.iasm"),
+            &mut VM::new(),
+            &EventsOutput::default(),
+        );
+        assert_eq!(source, EXIT_VM_CRASH);
+        let missing = run_bytecode(
+            "/no/such/program.bin",
+            &mut VM::new(),
+            &EventsOutput::default(),
+        );
+        assert_eq!(missing, EXIT_IO_ERROR);
+    }
+
+    #[test]
+    fn test_threads() {
+        let args = cli().get_matches_from(["iridium", "--threads", "3"]);
+        let vm = build_vm(&args, &cli_config(&args), None).unwrap();
+        assert_eq!(vm.logical_cores, 3);
+        assert_eq!(vm.cluster_scheduler().workers(), 3);
+        let repl = repl::REPL::new(vm);
+        assert_eq!(repl.scheduler().workers(), 3);
+
+        let args = cli().get_matches_from(["iridium"]);
+        let vm = build_vm(&args, &cli_config(&args), None).unwrap();
+        assert_eq!(vm.logical_cores, num_cpus::get());
+
+        for threads in ["0", "-2", "three"] {
+            let parsed = cli().try_get_matches_from(["iridium", "--threads", threads]);
+            assert!(parsed.is_err(), "--threads {} was accepted", threads);
+        }
+    }
+
+    #[test]
+    fn test_data_dir() {
+        let root = tempfile::tempdir().unwrap();
+        let data_dir = root.path().join("iridium");
+        prepare_data_dir(&data_dir).unwrap();
+        let args = cli().get_matches_from(["iridium", "--data-dir", data_dir.to_str().unwrap()]);
+        let vm = build_vm(&args, &cli_config(&args), Some(&data_dir)).unwrap();
+        assert_eq!(vm.data_dir(), Some(data_dir.as_path()));
+
+        // a file where the directory should be
+        let file = root.path().join("file");
+        fs::write(&file, "").unwrap();
+        let err = prepare_data_dir(&file).unwrap_err();
+        assert!(
+            err.to_string().contains(&file.display().to_string()),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_run_batch() {
+        let (hlt, crash, hello) = (
+            example("hlt.iasm"),
+            example("crash.iasm"),
+            example("hello.iasm"),
+        );
+        let files = [hlt.as_str(), crash.as_str(), hello.as_str()];
+        let output = EventsOutput::default();
+        let new_vm = || Ok(VM::new());
+
+        let runs = run_batch(&files, new_vm, false, &output);
+        let codes: Vec<i32> = runs.iter().map(|run| run.code).collect();
+        assert_eq!(codes, [0, EXIT_VM_CRASH]);
+        assert_eq!(batch_exit_code(&runs), EXIT_VM_CRASH);
+        assert!(runs[1].to_string().starts_with("CRASH  "), "{}", runs[1]);
+        assert!(runs[1].to_string().ends_with("  exit 102"), "{}", runs[1]);
+
+        let runs = run_batch(&files, new_vm, true, &output);
+        let codes: Vec<i32> = runs.iter().map(|run| run.code).collect();
+        assert_eq!(codes, [0, EXIT_VM_CRASH, 0]);
+        assert_eq!(batch_exit_code(&runs), EXIT_VM_CRASH);
+        assert!(runs[2].to_string().starts_with("PASS   "), "{}", runs[2]);
+
+        let runs = run_batch(&[hlt.as_str(), hello.as_str()], new_vm, false, &output);
+        assert_eq!(batch_exit_code(&runs), 0);
+
+        let args = cli().get_matches_from(["iridium", "-f", "a.iasm", "b.iasm", "--keep-going"]);
+        assert_eq!(args.get_many::<String>("file").unwrap().len(), 2);
+        assert!(cli()
+            .try_get_matches_from(["iridium", "--keep-going"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_bench_file() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b".data\n.code\nload $0 #1\nload $1 #2\nadd $0 $1 $2\nhlt")
+            .unwrap();
+        let program = assembler::Assembler::new()
+            .assemble(&fs::read_to_string(file.path()).unwrap())
+            .unwrap();
+        let bench = Benchmark::run(&program, 3, 2).unwrap();
+        let report = bench_report("sum.iasm", 2, &bench);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines[0],
+            "Benchmark of sum.iasm (3 iterations after 2 warm-up)"
+        );
+        assert_eq!(lines[1], "instructions: 12");
+        let value = |line: &str| -> f64 {
+            let value = line.split(": ").nth(1).unwrap();
+            value.trim_end_matches(" ms").parse().unwrap()
+        };
+        let total_ms = value(lines[2]);
+        let min_ms = value(lines[4]);
+        let (median_ms, max_ms) = (value(lines[5]), value(lines[6]));
+        assert!(min_ms <= median_ms && median_ms <= max_ms);
+        assert!(max_ms <= total_ms + 0.001);
+        assert_eq!(value(lines[3]), bench.per_second().round());
+
+        let path = file.path().to_str().unwrap();
+        assert_eq!(bench_file(path, 3, 0), 0);
+        assert_eq!(bench_file(&example("crash.iasm"), 3, 0), EXIT_VM_CRASH);
+        assert_eq!(bench_file("/no/such/program.iasm", 3, 0), EXIT_IO_ERROR);
+        let iterations =
+            cli().try_get_matches_from(["iridium", "-f", path, "--bench", "--iterations", "0"]);
+        assert!(iterations.is_err());
+    }
+
+    #[test]
+    fn test_events_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.json");
+        let output = EventsOutput {
+            json: true,
+            file: Some(path.clone()),
+        };
+        let source = ".data\n.code\nload $0 #1\nhlt";
+        assert_eq!(
+            run_source(STDIN_NAME, source.as_bytes(), &mut VM::new(), &output),
+            0
+        );
+        let events: Vec<VMEvent> = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let types: Vec<_> = events.iter().map(|e| e.event.clone()).collect();
+        assert_eq!(types, [VMEventType::Start, VMEventType::Stop]);
+        assert_eq!(events[0].app_id, events[1].app_id);
+        assert!(events[0].at <= events[1].at);
+
+        let crash = ".data\n.code\nload $0 #1\ndiv $0 $1 $2";
+        let code = run_source(STDIN_NAME, crash.as_bytes(), &mut VM::new(), &output);
+        assert_eq!(code, EXIT_VM_CRASH);
+        let events: Vec<VMEvent> = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(events[1].event, VMEventType::Crash);
+        assert!(events[1].context.is_some());
+
+        // the flags may follow the run subcommand too
+        let args = cli().get_matches_from(["iridium", "run", "prog.bin", "--events-json"]);
+        let (_, args) = args.subcommand().unwrap();
+        assert!(EventsOutput::from_args(args).json);
+        let unwritable = EventsOutput {
+            json: false,
+            file: Some(dir.path().join("no/such/dir/events.json")),
+        };
+        let code = run_source(STDIN_NAME, source.as_bytes(), &mut VM::new(), &unwritable);
+        assert_eq!(code, EXIT_IO_ERROR);
+    }
+
+    #[test]
+    fn test_join_at_startup() {
+        let mut hub = VM::new()
+            .with_alias(&"hub".to_string())
+            .with_cluster_bind(&"127.0.0.1".to_string(), &"0".to_string());
+        let hub_addr = hub.bind_cluster_server().unwrap().to_string();
+        let args = cli().get_matches_from([
+            "iridium",
+            "--peer-port",
+            "0",
+            "--join",
+            &hub_addr,
+            "--join",
+            "127.0.0.1:1",
+        ]);
+        let targets: Vec<String> = args.get_many::<String>("join").unwrap().cloned().collect();
+        let quick = ReconnectPolicy {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(20),
+            max_attempts: Some(3),
+        };
+        let mut node = build_vm(&args, &cli_config(&args), None)
+            .unwrap()
+            .with_reconnect_policy(quick);
+        let alias = node.alias.clone().unwrap();
+        node.bind_cluster_server().unwrap();
+
+        // the unreachable target is only fatal when joining is required
+        let err = join_targets(&node, &targets, true).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Unable to join the cluster through 127.0.0.1:1"),
+            "{}",
+            err
+        );
+        // otherwise startup goes on while the targets are joined in the background
+        let joining = join_targets(&node, &targets, false).unwrap().unwrap();
+        joining.join().unwrap();
+        let members = hub.conn_manager.read().unwrap().get_client_names();
+        assert_eq!(members, [alias]);
+
+        // a target still starting up is retried until it's there
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let late = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(30));
+            let mut late = VM::new()
+                .with_alias(&"late".to_string())
+                .with_cluster_bind(&"127.0.0.1".to_string(), &port.to_string());
+            late.bind_cluster_server().unwrap();
+            late
+        });
+        let patient = ReconnectPolicy {
+            max_attempts: Some(20),
+            ..node.reconnect_policy().clone()
+        };
+        let target = format!("127.0.0.1:{}", port);
+        let join = node.cluster_joiner(None).unwrap();
+        let joined = join_with_retry(&join, &target, &patient).unwrap();
+        assert_eq!(joined, "late");
+        drop(late.join().unwrap());
+
+        // but a node that turns this one away isn't asked again
+        let mut guarded = VM::new()
+            .with_alias(&"guarded".to_string())
+            .with_cluster_bind(&"127.0.0.1".to_string(), &"0".to_string())
+            .with_cluster_secret(Some("s3cret".to_string()));
+        let target = guarded.bind_cluster_server().unwrap().to_string();
+        let slow = ReconnectPolicy {
+            initial_delay: Duration::from_secs(10),
+            ..patient
+        };
+        let started = Instant::now();
+        let err = join_with_retry(&join, &target, &slow).unwrap_err();
+        assert!(err.to_string().contains("secret"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        assert!(cli()
+            .try_get_matches_from(["iridium", "--join-required"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_config_file() {
+        let fixture = example("iridium.toml");
+        let args = cli().get_matches_from(["iridium", "--config", &fixture, "--threads", "2"]);
+        let settings = load_settings(&args).unwrap();
+        assert_eq!(settings.node.threads, Some(2));
+        assert_eq!(settings.node.alias.as_deref(), Some("edge-1"));
+        assert_eq!(settings.remote.max_clients, Some(32));
+        assert_eq!(settings.cluster.join, vec!["10.0.0.2:2254".to_string()]);
+        let vm = build_vm(&args, &settings, None).unwrap();
+        assert_eq!(vm.alias.as_deref(), Some("edge-1"));
+
+        let args = cli().get_matches_from(["iridium", "--config", "/no/such/iridium.toml"]);
+        assert!(load_settings(&args).is_err());
+
+        let effective = effective_config(&cli_config(&cli().get_matches_from(["iridium"])));
+        assert_eq!(effective.remote.enabled, Some(false));
+        assert_eq!(effective.remote.max_clients, Some(DEFAULT_MAX_CLIENTS));
+        let printed = effective.to_toml().unwrap();
+        assert!(printed.contains("peer_port = \"2254\""), "{}", printed);
+    }
+
+    #[test]
+    fn test_node_alias() {
+        let args = cli().get_matches_from(["iridium"]);
+        let vm = build_vm(&args, &Config::default(), None).unwrap();
+        let alias = vm.alias.clone().unwrap();
+        assert!(alias.starts_with("node-") && alias.len() == 11, "{}", alias);
+
+        let args = cli().get_matches_from(["iridium", "--node-alias", "edge-1"]);
+        let vm = build_vm(&args, &cli_config(&args), None).unwrap();
+        assert_eq!(vm.alias.as_deref(), Some("edge-1"));
+
+        for bad in ["", "edge 1", "edge:1"] {
+            let args = cli().try_get_matches_from(["iridium", "--node-alias", bad]);
+            assert!(args.is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_headless() {
+        let args = cli().get_matches_from(["iridium", "--no-repl"]);
+        let err = check_headless(&args, &cli_config(&args)).unwrap_err();
+        assert!(err.to_string().contains("nothing to serve"), "{}", err);
+        let args = cli().get_matches_from(["iridium", "--no-repl", "--enable-remote"]);
+        assert!(check_headless(&args, &cli_config(&args)).is_ok());
+        let args = cli().try_get_matches_from(["iridium", "--no-repl", "--file", "prog.iasm"]);
+        assert!(args.is_err());
+
+        let args = cli().get_matches_from([
+            "iridium",
+            "--no-repl",
+            "--node-alias",
+            "headless",
+            "--peer-port",
+            "0",
+        ]);
+        let mut vm = build_vm(&args, &cli_config(&args), None).unwrap();
+        let addr = vm.bind_cluster_server().unwrap();
+        let vm = Arc::new(Mutex::new(vm));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let node = {
+            let (vm, shutdown) = (vm.clone(), shutdown.clone());
+            thread::spawn(move || run_headless(&vm, &shutdown))
+        };
+        // serving peers all the while, with no REPL and so nothing reading stdin
+        thread::sleep(Duration::from_millis(50));
+        assert!(!node.is_finished());
+        assert!(std::net::TcpStream::connect(addr).is_ok());
+
+        shutdown.store(true, Ordering::SeqCst);
+        node.thread().unpark();
+        node.join().unwrap();
+    }
+
+    #[test]
+    fn test_addr() {
+        let err = cli()
+            .try_get_matches_from(["iridium", "--addr", "localhost"])
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("`localhost` is not an address to listen on"),
+            "{}",
+            err
+        );
+
+        let args = cli().get_matches_from(["iridium", "--addr", "0.0.0.0:2245"]);
+        let addr = args.get_one::<SocketAddr>("addr").unwrap();
+        assert_eq!(addr.to_string(), "0.0.0.0:2245");
+
+        // the port picked for port 0 is the one reported
+        let bound = start_remote_server("127.0.0.1:0".parse().unwrap(), Server::new()).unwrap();
+        assert_ne!(bound.port(), 0);
+        assert!(std::net::TcpStream::connect(bound).is_ok());
+        let err = start_remote_server(bound, Server::new()).unwrap_err();
+        let expected = format!("Unable to listen for remote clients on {}", bound);
+        assert!(err.to_string().contains(&expected), "{}", err);
+    }
+
+    #[test]
+    fn test_disassemble_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("tour.bin");
+        let image = image.to_str().unwrap();
+        assert_eq!(assemble_file(&example("tour.iasm"), image), 0);
+        let listing = dir.path().join("tour.listing");
+        let listing = listing.to_str().unwrap();
+        assert_eq!(disassemble_file(image, listing, &Disassembler::new()), 0);
+        let expected = fs::read_to_string(example("tour.listing")).unwrap();
+        assert_eq!(fs::read_to_string(listing).unwrap(), expected);
+
+        let source = disassemble_file(&example("tour.iasm"), "-", &Disassembler::new());
+        assert_eq!(source, EXIT_VM_CRASH);
+        let missing = disassemble_file("/no/such/program.bin", "-", &Disassembler::new());
+        assert_eq!(missing, EXIT_IO_ERROR);
+    }
+}
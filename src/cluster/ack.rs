@@ -0,0 +1,163 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use log::debug;
+use uuid::Uuid;
+
+use super::message::IridiumMessage;
+use crate::error::{IridiumError, Result};
+
+/// Ids of reliable messages remembered to spot retries of ones already handled
+pub const DELIVERED_HISTORY: usize = 4096;
+
+/// Reliable messages awaiting their ack, by id
+pub type Acks = Arc<Mutex<HashMap<Uuid, Sender<()>>>>;
+
+/// How long to wait for a message that needs delivery to be acknowledged, and how many
+/// times to send it before giving up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AckPolicy {
+    pub timeout: Duration, // for each attempt
+    pub attempts: u32,
+}
+
+impl Default for AckPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(1),
+            attempts: 3,
+        }
+    }
+}
+
+/// Reliable messages this node already handled, so that a retry of one whose ack was lost
+/// isn't handled twice
+#[derive(Debug, Default)]
+pub struct Delivered {
+    order: VecDeque<Uuid>,
+    ids: HashSet<Uuid>,
+}
+
+impl Delivered {
+    /// Note that the message `id` arrived. False if it already had
+    pub fn first(&mut self, id: Uuid) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        if self.order.len() == DELIVERED_HISTORY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.order.push_back(id);
+        true
+    }
+}
+
+/// Make `attempt` send a message up to `policy.attempts` times, until the ack it returns a
+/// receiver for arrives. Fails straight away if the receiver can never get one, like when
+/// the connection closed
+pub fn retry<F>(policy: AckPolicy, mut attempt: F) -> Result<()>
+where
+    F: FnMut(u32) -> Result<Receiver<()>>,
+{
+    for number in 1..=policy.attempts {
+        match attempt(number)?.recv_timeout(policy.timeout) {
+            Ok(()) => return Ok(()),
+            Err(RecvTimeoutError::Timeout) => debug!("No ack after attempt {}", number),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(IridiumError::StringError(
+                    "Connection closed before the message was acknowledged".to_string(),
+                ))
+            }
+        }
+    }
+    Err(IridiumError::StringError(format!(
+        "Not acknowledged after {} attempts",
+        policy.attempts
+    )))
+}
+
+/// Unwrap a message that arrived: a reliable one is acknowledged through `ack` and handed
+/// back only the first time it arrives. Anything else is handed back as it is
+pub fn receive<F>(
+    msg: IridiumMessage,
+    delivered: &Mutex<Delivered>,
+    ack: F,
+) -> Option<IridiumMessage>
+where
+    F: FnOnce(IridiumMessage),
+{
+    let (id, message) = match msg {
+        IridiumMessage::Reliable { id, message } => (id, message),
+        msg => return Some(msg),
+    };
+    // acknowledged even when it is a duplicate, as the earlier ack may be what was lost
+    ack(IridiumMessage::Ack { id });
+    let first = delivered
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .first(id);
+    if !first {
+        debug!("Dropping duplicate of message {}", id);
+        return None;
+    }
+    Some(*message)
+}
+
+/// Tell whoever sent the reliable message `id` that it arrived. False if nobody is waiting
+pub fn acknowledge(acks: &Acks, id: &Uuid) -> bool {
+    let waiting = acks.lock().unwrap_or_else(|e| e.into_inner()).remove(id);
+    waiting.is_some_and(|waiting| waiting.send(()).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+
+    #[test]
+    fn test_delivered_forgets_oldest() {
+        let mut delivered = Delivered::default();
+        let first = Uuid::new_v4();
+        assert!(delivered.first(first));
+        assert!(!delivered.first(first));
+        for _ in 0..DELIVERED_HISTORY {
+            delivered.first(Uuid::new_v4());
+        }
+        assert!(delivered.first(first));
+    }
+
+    #[test]
+    fn test_retry_until_acknowledged() {
+        let policy = AckPolicy {
+            timeout: Duration::from_millis(10),
+            attempts: 3,
+        };
+        let mut senders = vec![];
+        let acked_on_second = retry(policy, |number| {
+            let (tx, rx) = channel();
+            if number == 2 {
+                tx.send(()).unwrap();
+            }
+            senders.push(tx);
+            Ok(rx)
+        });
+        assert!(acked_on_second.is_ok());
+        assert_eq!(senders.len(), 2);
+
+        let never = retry(policy, |_| {
+            let (tx, rx) = channel();
+            senders.push(tx);
+            Ok(rx)
+        });
+        assert!(never.unwrap_err().to_string().contains("after 3 attempts"));
+    }
+}
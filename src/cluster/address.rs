@@ -0,0 +1,110 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+};
+
+use log::debug;
+
+use crate::error::{IridiumError, Result};
+
+/// `host:port`, with an IPv6 literal host in brackets so the port can be told apart
+pub fn join_host_port(host: &str, port: &str) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Every address `addr`, a `host:port` whose host may be a name, resolves to. A name that
+/// can't be looked up is an I/O error, as it may resolve later
+pub fn resolve(addr: &str) -> Result<Vec<SocketAddr>> {
+    let resolved: Vec<SocketAddr> = addr
+        .to_socket_addrs()
+        .map_err(|e| io::Error::new(e.kind(), format!("Could not resolve {}: {}", addr, e)))?
+        .collect();
+    if resolved.is_empty() {
+        return Err(IridiumError::StringError(format!(
+            "{} resolved to no addresses",
+            addr
+        )));
+    }
+    Ok(resolved)
+}
+
+/// Connect to the first address `addr` resolves to that takes the connection. Failing to
+/// reach any is an I/O error of the kind the last one failed with
+pub fn connect(addr: &str) -> Result<TcpStream> {
+    let resolved = resolve(addr)?;
+    let mut failures = Vec::with_capacity(resolved.len());
+    let mut kind = io::ErrorKind::NotFound;
+    for candidate in resolved {
+        match TcpStream::connect(candidate) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                debug!("Unable to connect to {} for {}: {}", candidate, addr, e);
+                failures.push(format!("{} ({})", candidate, e));
+                kind = e.kind();
+            }
+        }
+    }
+    let msg = format!(
+        "Unable to connect to {}, tried {}",
+        addr,
+        failures.join(", ")
+    );
+    Err(io::Error::new(kind, msg).into())
+}
+
+/// Listen on the first address `host` and `port` resolve to
+pub fn bind(host: &str, port: &str) -> Result<TcpListener> {
+    let addr = join_host_port(host, port);
+    let resolved = resolve(&addr)
+        .map_err(|e| IridiumError::StringError(format!("Invalid peer bind {}: {}", addr, e)))?;
+    Ok(TcpListener::bind(resolved[0])?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_host_port() {
+        assert_eq!(join_host_port("10.0.0.7", "2254"), "10.0.0.7:2254");
+        assert_eq!(join_host_port("::1", "2254"), "[::1]:2254");
+        assert_eq!(join_host_port("[::1]", "2254"), "[::1]:2254");
+        assert_eq!(
+            join_host_port("node2.internal", "2254"),
+            "node2.internal:2254"
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(
+            resolve("[::1]:2254").unwrap(),
+            ["[::1]:2254".parse::<SocketAddr>().unwrap()]
+        );
+        let localhost = resolve("localhost:2254").unwrap();
+        assert!(localhost
+            .iter()
+            .all(|addr| addr.ip().is_loopback() && addr.port() == 2254));
+        let err = resolve("no-such-node.invalid:2254")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("Could not resolve no-such-node.invalid:2254"),
+            "{}",
+            err
+        );
+        assert!(connect("no-such-node.invalid:2254").is_err());
+    }
+
+    #[test]
+    fn test_connect_over_ipv6() {
+        let listener = bind("::1", "0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let stream = connect(&join_host_port("::1", &port)).unwrap();
+        assert!(stream.peer_addr().unwrap().is_ipv6());
+    }
+}
@@ -1,30 +1,83 @@
-use serde::Deserialize;
-use serde_json::{de::IoRead, Deserializer};
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
 use std::{
+    collections::HashMap,
     io::{BufReader, BufWriter, Write},
-    net::TcpStream,
+    mem,
+    net::{Shutdown, TcpStream},
     sync::{
-        mpsc::{channel, Receiver, Sender},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
         Arc, Mutex,
     },
     thread,
+    time::{Duration, Instant},
 };
+use uuid::Uuid;
+
+#[cfg(test)]
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use crate::{
-    common::w,
+    common::{encode_message, read_message, write_message, MAX_FRAME_LENGTH},
     error::{IridiumError, Result},
-    repl::{self},
+    remote::stream::Stream,
+    scheduler::Scheduler,
+};
+
+use super::{
+    ack::{self, AckPolicy, Acks, Delivered},
+    message::{
+        Encoding, Handshake, HandshakeResponse, HelloResponse, IridiumMessage, NodeMetadata,
+        NodeStatus, TaskResult, Welcome, PROTOCOL_VERSION,
+    },
+    transport::Transport,
+    Departure, NodeAlias,
 };
 
-use super::message::{HelloResponse, IridiumMessage};
+/// Longest a write to another node may block before the connection is given up on, so a
+/// peer that stops reading can't stall whoever is sending to it
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Writer shared by everything sending on one cluster connection, so messages never interleave
+pub type PeerWriter = Arc<Mutex<BufWriter<Box<dyn Stream>>>>;
+/// When the pong to a ping arrived, and the load the node at the other end reported in it
+pub type Pong = (Instant, Option<usize>);
+/// What an inspected node sent back: its status, or why it couldn't give one
+pub type InspectResult = std::result::Result<NodeStatus, String>;
 
+/// One connection to another cluster node. Everything sent on it goes through one shared
+/// writer a whole frame at a time, so hellos, pings and programs sent from different threads
+/// never interleave. Once its reader thread is spawned, that thread dispatches everything
+/// that arrives
 pub struct ClusterClient {
-    pub reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
-    rx: Option<Arc<Mutex<Receiver<String>>>>, // add for Arc + Mutex for thread-safety
-    tx: Option<Arc<Mutex<Sender<String>>>>, //If something wants to send something to this client, they can clone the `tx` channel.
-    stream: TcpStream,
+    reader: BufReader<Box<dyn Stream>>,
+    writer: PeerWriter,
+    max_frame_length: usize, // longest message accepted from the other end
+    stream: TcpStream,       // the socket under reader and writer, which may wrap it in TLS
+    transport: Transport,    // what the connection is wrapped in after the handshake
+    connection: Uuid,        // tells this connection apart from later ones to the same node
     alias: Option<String>,
+    announce: Option<(String, String)>, // host and port this node listens on, sent in Hello
+    metadata: NodeMetadata,             // what else this node says about itself in Hello
+    peer_listen: Option<(String, String)>, // where the node at the other end listens, if it said
+    peer_id: Option<Uuid>,              // VM id of the node at the other end, if it said
+    liveness: Option<(NodeAlias, Sender<Departure>)>, // alias sent when the reader sees the connection close
+    tasks: Arc<Mutex<HashMap<Uuid, Sender<TaskResult>>>>, // submitted programs awaiting their result
+    pings: Arc<Mutex<HashMap<Uuid, Sender<Pong>>>>,       // pings awaiting their pong
+    inspections: Arc<Mutex<HashMap<Uuid, Sender<InspectResult>>>>, // inspections awaiting the status
+    output: Option<Sender<String>>, // where what arrives is reported, stdout if None
+    peer: Option<NodeAlias>,        // alias of the node at the other end, once it said
+    protocol: Option<u32>,          // version agreed on in the handshake, once there was one
+    secret: Option<String>,         // shared cluster secret sent in Hello
+    scheduler: Option<Arc<Scheduler>>, // whose load is reported when answering pings
+    reading: bool, // whether something other than `reader` handles incoming messages
+    acks: Acks,    // reliable messages sent on this connection awaiting their ack
+    closed: Arc<AtomicBool>, // set once the connection is known to be closed, ending reliable sends
+    delivered: Arc<Mutex<Delivered>>, // reliable messages already handled, shared by every connection the manager holds
+    ack_policy: AckPolicy,
+    #[cfg(test)]
+    dropped_sends: Arc<AtomicU32>, // reliable sends to skip, like they were lost
 }
 
 impl ClusterClient {
@@ -35,16 +88,37 @@ impl ClusterClient {
         // bind_port: String,
         // alias: String,
     ) -> Result<Self> {
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
         let tcp_reader = stream.try_clone()?;
         let tcp_writer = stream.try_clone()?;
-        let (tx, rx) = channel();
         Ok(Self {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-            writer: BufWriter::new(tcp_writer),
+            reader: BufReader::new(Box::new(tcp_reader)),
+            writer: Arc::new(Mutex::new(BufWriter::new(Box::new(tcp_writer)))),
+            max_frame_length: MAX_FRAME_LENGTH,
             stream,
-            tx: Some(Arc::new(Mutex::new(tx))),
-            rx: Some(Arc::new(Mutex::new(rx))),
+            transport: Transport::plain(),
+            connection: Uuid::new_v4(),
             alias: None,
+            announce: None,
+            metadata: NodeMetadata::default(),
+            peer_listen: None,
+            peer_id: None,
+            liveness: None,
+            tasks: Arc::new(Mutex::new(HashMap::new())),
+            pings: Arc::new(Mutex::new(HashMap::new())),
+            inspections: Arc::new(Mutex::new(HashMap::new())),
+            output: None,
+            peer: None,
+            protocol: None,
+            secret: None,
+            scheduler: None,
+            reading: false,
+            acks: Arc::new(Mutex::new(HashMap::new())),
+            closed: Arc::new(AtomicBool::new(false)),
+            delivered: Arc::new(Mutex::new(Delivered::default())),
+            ack_policy: AckPolicy::default(),
+            #[cfg(test)]
+            dropped_sends: Default::default(),
         })
     }
 
@@ -54,57 +128,743 @@ impl ClusterClient {
         self
     }
 
-    /// Send alias to the cluster just joined
+    /// Announce the host and port this node listens on for peers when saying hello
+    pub fn with_announce(mut self, host: String, port: String) -> Self {
+        self.announce = Some((host, port));
+        self
+    }
+
+    /// Reject messages from the other end longer than `max` bytes
+    pub fn with_max_frame_length(mut self, max: usize) -> Self {
+        self.max_frame_length = max;
+        self
+    }
+
+    /// Report what arrives from the other node, and the connection closing, on `output`
+    pub fn with_output(mut self, output: Sender<String>) -> Self {
+        self.output = Some(output);
+        self
+    }
+
+    /// Prove membership with the shared cluster secret when saying hello
+    pub fn with_secret(mut self, secret: Option<String>) -> Self {
+        self.secret = secret;
+        self
+    }
+
+    /// Report how many programs `scheduler` is running when answering pings
+    pub fn with_scheduler(mut self, scheduler: Arc<Scheduler>) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
+    /// Describe this node with `metadata` when saying hello
+    pub fn with_metadata(mut self, metadata: NodeMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Record where the node at the other end listens for peers, as announced in its hello
+    pub fn with_peer_listen(mut self, host: String, port: String) -> Self {
+        self.peer_listen = Some((host, port));
+        self
+    }
+
+    /// Record the VM id the node at the other end said it runs, which tells it apart from
+    /// other nodes going by the same alias
+    pub fn with_peer_id(mut self, id: Uuid) -> Self {
+        self.peer_id = Some(id);
+        self
+    }
+
+    /// VM id of the node at the other end, if it said
+    pub fn peer_id(&self) -> Option<Uuid> {
+        self.peer_id
+    }
+
+    /// Resend messages that need delivery according to `policy` until acknowledged
+    pub fn with_ack_policy(mut self, policy: AckPolicy) -> Self {
+        self.ack_policy = policy;
+        self
+    }
+
+    /// Carry the connection over `transport` once the handshake is done. The node at the
+    /// other end must carry its connections the same way
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Spot retries of reliable messages in `delivered`, shared with other connections so a
+    /// retry arriving over a new one is spotted too
+    pub fn with_delivered(mut self, delivered: Arc<Mutex<Delivered>>) -> Self {
+        self.delivered = delivered;
+        self
+    }
+
+    /// For a connection a ClusterServer reads: share its writer, and leave reading to it
+    pub fn with_server_loop(mut self, writer: PeerWriter) -> Self {
+        self.writer = writer;
+        self.reading = true;
+        self
+    }
+
+    /// Identifies this connection, as reported when it closes
+    pub fn connection_id(&self) -> Uuid {
+        self.connection
+    }
+
+    /// Give up on sends that block for longer than `timeout`
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        Ok(self.stream.set_write_timeout(timeout)?)
+    }
+
+    /// Close the connection in both directions, ending whatever is reading it
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    /// Handle on the reliable messages awaiting their ack, for whatever reads the connection
+    pub fn acks(&self) -> Acks {
+        self.acks.clone()
+    }
+
+    /// Handle on the writer, for sending from other threads
+    pub fn writer(&self) -> PeerWriter {
+        self.writer.clone()
+    }
+
+    /// Report `peer` on `notifier` once the reader thread finds the connection closed
+    pub fn with_liveness(mut self, peer: NodeAlias, notifier: Sender<Departure>) -> Self {
+        self.peer = Some(peer.clone());
+        self.liveness = Some((peer, notifier));
+        self
+    }
+
+    /// Host and port the node at the other end said it listens on, or was reached at
+    pub fn peer_listen(&self) -> Option<&(String, String)> {
+        self.peer_listen.as_ref()
+    }
+
+    /// Host and port the node at the other end listens on for peers. Outgoing connections
+    /// were made to exactly that; incoming ones only know it if the peer announced it
+    pub fn peer_listen_addr(&self) -> Result<(String, String)> {
+        if let Some(listen) = &self.peer_listen {
+            return Ok(listen.clone());
+        }
+        let addr = self.stream.peer_addr()?;
+        Ok((addr.ip().to_string(), addr.port().to_string()))
+    }
+
+    /// Agree on a protocol version with the node at the other end, which must happen before
+    /// anything else is sent to it. Returns the version agreed on
+    pub fn handshake(&mut self) -> Result<u32> {
+        let encrypts = self.transport.encrypts();
+        let handshake = Handshake {
+            version: PROTOCOL_VERSION,
+            encoding: Encoding::Json,
+            encrypted: encrypts,
+        };
+        self.send_frame(&encode_message(&handshake)?)?;
+        let response: serde_json::Value = self.read_message()?;
+        if let Ok(response) = serde_json::from_value::<HandshakeResponse>(response.clone()) {
+            return match response {
+                // a node from before encryption accepts without having looked
+                HandshakeResponse::Accepted { encrypted, .. } if encrypted != encrypts => {
+                    Err(IridiumError::StringError(
+                        "The other node does not encrypt cluster connections".to_string(),
+                    ))
+                }
+                HandshakeResponse::Accepted { version, encrypted } => {
+                    if encrypted {
+                        self.secure()?;
+                    }
+                    self.protocol = Some(version);
+                    Ok(version)
+                }
+                HandshakeResponse::Rejected { reason } => Err(IridiumError::StringError(format!(
+                    "The other node rejected the connection: {}",
+                    reason
+                ))),
+                HandshakeResponse::Unsupported { min, max } => {
+                    Err(IridiumError::StringError(format!(
+                        "Unsupported cluster protocol version {}, the other node speaks {}..{}",
+                        PROTOCOL_VERSION, min, max
+                    )))
+                }
+            };
+        }
+        // nodes from before handshakes existed take it for a message they can't parse
+        match serde_json::from_value::<HelloResponse>(response) {
+            Ok(HelloResponse::Err(msg)) => Err(IridiumError::StringError(format!(
+                "The other node does not speak cluster protocol version {}: {}",
+                PROTOCOL_VERSION, msg
+            ))),
+            _ => Err(IridiumError::StringError(
+                "Unexpected response to the cluster handshake".to_string(),
+            )),
+        }
+    }
+
+    /// Go on over the transport, which both ends just agreed to encrypt
+    fn secure(&mut self) -> Result<()> {
+        if !self.reader.buffer().is_empty() {
+            return Err(IridiumError::StringError(
+                "The other node sent messages before starting TLS".to_string(),
+            ));
+        }
+        let secured = self.transport.connect(&self.stream)?;
+        self.reader = BufReader::new(secured.try_clone()?);
+        self.writer = Arc::new(Mutex::new(BufWriter::new(secured)));
+        Ok(())
+    }
+
+    /// Send alias to the cluster just joined, after the handshake if there hasn't been one
     pub fn send_hello(&mut self) -> Result<()> {
+        if self.protocol.is_none() {
+            self.handshake()?;
+        }
+        let (peer_host, peer_port) = self.announce.clone().unzip();
         let msg = IridiumMessage::Hello {
             alias: self.alias.as_ref().unwrap().to_owned(),
+            peer_host,
+            peer_port,
+            logical_cores: self.metadata.logical_cores,
+            vm_id: self.metadata.vm_id,
+            secret: self.secret.clone(),
+            role: self.metadata.role,
         };
-        serde_json::to_writer(&mut self.stream, &msg)?;
-        self.writer.flush()?;
+        self.send(&msg)
+    }
+
+    /// Send a message to the node at the other end
+    pub fn send(&self, msg: &IridiumMessage) -> Result<()> {
+        self.send_frame(&encode_message(msg)?)
+    }
+
+    /// Send a message already encoded with `encode_message`, so one message can go to many nodes
+    pub fn send_frame(&self, frame: &[u8]) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer.write_all(frame)?;
+        writer.flush()?;
 
         Ok(())
     }
 
-    /// Read from server response
-    pub fn read(&mut self) -> Result<String> {
-        let resp = HelloResponse::deserialize(&mut self.reader)?;
-        match resp {
-            HelloResponse::Ok(value) => Ok(value),
-            HelloResponse::Err(msg) => Err(IridiumError::StringError(msg)),
-        }
+    /// Hand a result to whoever submitted its task through this client, giving it back if
+    /// nobody is waiting for it
+    pub fn deliver(&self, result: TaskResult) -> Option<TaskResult> {
+        let task_id = result.task_id;
+        hand_over(&self.tasks, &task_id, result)
     }
 
-    /// Write ">>>"
-    fn write_prompt(&mut self) -> Result<()> {
-        w(&mut self.writer, repl::PROMPT)?;
+    /// Tell whoever sent the ping `nonce` through this client that its pong arrived, with
+    /// the load reported in it. False if nobody is waiting for it
+    pub fn deliver_pong(&self, nonce: &Uuid, running_tasks: Option<usize>) -> bool {
+        hand_over(&self.pings, nonce, (Instant::now(), running_tasks)).is_none()
+    }
+
+    /// Hand the answer to inspection `request_id` to whoever requested it through this
+    /// client. False if nobody is waiting for it
+    pub fn deliver_inspection(&self, request_id: &Uuid, status: InspectResult) -> bool {
+        hand_over(&self.inspections, request_id, status).is_none()
+    }
+
+    /// Hand every message the other node sends from now on to `on_message`, on a thread of
+    /// its own, until the connection closes. The liveness notifier, if any, is told then.
+    /// Results of programs submitted through this client go to whoever is waiting for them,
+    /// and pings are answered right away
+    pub fn spawn_reader<F>(&mut self, mut on_message: F) -> Result<()>
+    where
+        F: FnMut(IridiumMessage) + Send + 'static,
+    {
+        // the current reader may already hold buffered messages, so it goes to the thread
+        let fresh = BufReader::new(Box::new(self.stream.try_clone()?) as Box<dyn Stream>);
+        let mut reader = mem::replace(&mut self.reader, fresh);
+        let max_frame_length = self.max_frame_length;
+        let liveness = self.liveness.clone();
+        let connection = self.connection;
+        let report = self.reporter();
+        let tasks = self.tasks.clone();
+        let pings = self.pings.clone();
+        let inspections = self.inspections.clone();
+        let acks = self.acks.clone();
+        let closed = self.closed.clone();
+        let delivered = self.delivered.clone();
+        let writer = self.writer.clone();
+        let scheduler = self.scheduler.clone();
+        self.reading = true;
+        thread::spawn(move || {
+            loop {
+                let msg = match read_message(&mut reader, max_frame_length) {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => {
+                        debug!("Cluster connection closed");
+                        report.send("connection closed".to_string());
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("Cluster connection failed: {}", e);
+                        report.send(format!("connection failed: {}", e));
+                        break;
+                    }
+                };
+                let msg = match ack::receive(msg, &delivered, |ack| answer(&writer, &ack)) {
+                    Some(msg) => msg,
+                    None => continue,
+                };
+                match msg {
+                    IridiumMessage::ExecuteResult {
+                        task_id,
+                        events,
+                        registers,
+                    } => {
+                        report.send(format!("result of task {}", task_id));
+                        let result = TaskResult {
+                            task_id,
+                            events,
+                            registers,
+                        };
+                        if let Some(result) = hand_over(&tasks, &task_id, result) {
+                            on_message(IridiumMessage::ExecuteResult {
+                                task_id: result.task_id,
+                                events: result.events,
+                                registers: result.registers,
+                            });
+                        }
+                    }
+                    IridiumMessage::ExecuteRefused { task_id, reason } => {
+                        report.send(format!("task {} refused: {}", task_id, reason));
+                        warn!("Task {} was refused: {}", task_id, reason);
+                        // dropping the sender tells whoever waits that no result is coming
+                        tasks
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .remove(&task_id);
+                    }
+                    IridiumMessage::Ping { nonce } => {
+                        let pong = IridiumMessage::Pong {
+                            nonce,
+                            running_tasks: scheduler.as_ref().map(|s| s.running_tasks()),
+                        };
+                        answer(&writer, &pong);
+                    }
+                    IridiumMessage::Pong {
+                        nonce,
+                        running_tasks,
+                    } => {
+                        if hand_over(&pings, &nonce, (Instant::now(), running_tasks)).is_some() {
+                            debug!("Pong {} arrived after its ping timed out", nonce);
+                        }
+                    }
+                    IridiumMessage::InspectResponse { request_id, status } => {
+                        if hand_over(&inspections, &request_id, status).is_some() {
+                            debug!("Status for inspection {} arrived too late", request_id);
+                        }
+                    }
+                    IridiumMessage::Ack { id } => {
+                        if !ack::acknowledge(&acks, &id) {
+                            debug!("Ack for message {} arrived too late", id);
+                        }
+                    }
+                    msg => {
+                        // gossip comes every round, so it would drown everything else out
+                        if !matches!(msg, IridiumMessage::Gossip { .. }) {
+                            report.send(msg.to_string());
+                        }
+                        on_message(msg)
+                    }
+                }
+            }
+            // nothing more will arrive, so stop anyone still waiting for a result
+            tasks.lock().unwrap_or_else(|e| e.into_inner()).clear();
+            pings.lock().unwrap_or_else(|e| e.into_inner()).clear();
+            inspections
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clear();
+            // marked closed before the acks go, so a resend racing this can't wait on one
+            closed.store(true, Ordering::SeqCst);
+            acks.lock().unwrap_or_else(|e| e.into_inner()).clear();
+            if let Some((peer, notifier)) = liveness {
+                let _ = notifier.send((peer, connection));
+            }
+        });
+
         Ok(())
     }
 
-    /// Listen for input and send to client
-    fn recv_loop(&mut self) -> Result<()> {
-        let chan = self.rx.take().unwrap();
-        let mut writer = BufWriter::new(self.stream.try_clone()?);
+    /// Send a program to run on the node at the other end. The result arrives on the
+    /// returned channel
+    pub fn submit(&mut self, program: Vec<u8>) -> Result<(Uuid, Receiver<TaskResult>)> {
+        let task_id = Uuid::new_v4();
+        let result = self.submit_as(task_id, program)?;
+        Ok((task_id, result))
+    }
 
-        thread::spawn(move || -> Result<()> {
-            if let Ok(locked_rx) = chan.lock() {
-                match locked_rx.recv() {
-                    Ok(msg) => w(&mut writer, &msg),
-                    Err(_e) => Err(IridiumError::Recv(_e)),
-                }?;
+    /// Send a program to run under a task id picked by the caller, like one shared by every
+    /// member a program is fanned out to
+    pub fn submit_as(&mut self, task_id: Uuid, program: Vec<u8>) -> Result<Receiver<TaskResult>> {
+        if !self.reading {
+            self.spawn_reader(|msg| warn!("Unexpected cluster message: {:?}", msg))?;
+        }
+        let (tx, rx) = channel();
+        self.tasks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(task_id, tx);
+        let delivery = match self.send_reliably(IridiumMessage::ExecuteProgram { program, task_id })
+        {
+            Ok(delivery) => delivery,
+            Err(e) => {
+                self.forget_task(&task_id);
+                return Err(e);
             }
+        };
+        let tasks = self.tasks.clone();
+        thread::spawn(move || {
+            if let Ok(Err(e)) = delivery.recv() {
+                warn!("Task {} never reached the node: {}", task_id, e);
+                tasks
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .remove(&task_id);
+            }
+        });
 
-            Ok(())
+        Ok(rx)
+    }
+
+    /// Send a message that must not get lost: it is resent until the other end acknowledges
+    /// it, or the ack policy gives up. Resending stops as soon as the connection is found
+    /// closed, as nothing sent on it can arrive any more. Only the first send fails here;
+    /// whether a later one got through arrives on the returned channel, and failures are
+    /// logged whether or not anyone is listening
+    pub fn send_reliably(&mut self, msg: IridiumMessage) -> Result<Receiver<Result<()>>> {
+        if !self.reading {
+            self.spawn_reader(|msg| warn!("Unexpected cluster message: {:?}", msg))?;
+        }
+        let id = Uuid::new_v4();
+        let description = msg.to_string();
+        let frame = encode_message(&IridiumMessage::Reliable {
+            id,
+            message: Box::new(msg),
+        })?;
+        let acks = self.acks.clone();
+        let closed = self.closed.clone();
+        let writer = self.writer.clone();
+        #[cfg(test)]
+        let dropped_sends = self.dropped_sends.clone();
+        let send = move || -> Result<Receiver<()>> {
+            let (acked, ack) = channel();
+            acks.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(id, acked);
+            if closed.load(Ordering::SeqCst) {
+                acks.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+                return Err(IridiumError::StringError(
+                    "Connection closed before the message was acknowledged".to_string(),
+                ));
+            }
+            #[cfg(test)]
+            if dropped_sends
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return Ok(ack);
+            }
+            let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+            let sent = writer.write_all(&frame).and_then(|_| writer.flush());
+            if let Err(e) = sent {
+                // a frame may have been cut short, so nothing after it could be read
+                closed.store(true, Ordering::SeqCst);
+                acks.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+                return Err(e.into());
+            }
+            Ok(ack)
+        };
+        let mut first = Some(send()?);
+
+        let acks = self.acks.clone();
+        let policy = self.ack_policy;
+        let peer = self.peer.clone().or_else(|| self.alias.clone());
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let delivered = ack::retry(policy, |attempt| match first.take() {
+                Some(ack) => Ok(ack),
+                None => {
+                    debug!("Sending {} again (attempt {})", description, attempt);
+                    send()
+                }
+            });
+            acks.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+            if let Err(e) = &delivered {
+                warn!(
+                    "Unable to deliver {} to {}: {}",
+                    description,
+                    peer.as_deref().unwrap_or("cluster member"),
+                    e
+                );
+            }
+            let _ = tx.send(delivered);
         });
+        Ok(rx)
+    }
 
-        Ok(())
+    /// Skip the next `count` reliable sends, as if they were lost on the way
+    #[cfg(test)]
+    pub fn drop_reliable_sends(&self, count: u32) {
+        self.dropped_sends.store(count, Ordering::SeqCst);
+    }
+
+    /// Run a program on the node at the other end and wait up to `timeout` for its result
+    pub fn execute(&mut self, program: Vec<u8>, timeout: Duration) -> Result<TaskResult> {
+        let (task_id, result) = self.submit(program)?;
+        match result.recv_timeout(timeout) {
+            Ok(result) => Ok(result),
+            Err(RecvTimeoutError::Timeout) => {
+                self.forget_task(&task_id);
+                Err(IridiumError::StringError(format!(
+                    "Timed out waiting for the result of task {}",
+                    task_id
+                )))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(IridiumError::StringError(format!(
+                "Connection closed before task {} finished",
+                task_id
+            ))),
+        }
+    }
+
+    /// Wait for the pong to the ping `nonce`, which the caller sends. It arrives on the
+    /// returned channel
+    pub fn expect_pong(&mut self, nonce: Uuid) -> Result<Receiver<Pong>> {
+        if !self.reading {
+            self.spawn_reader(|msg| warn!("Unexpected cluster message: {:?}", msg))?;
+        }
+        let (tx, rx) = channel();
+        self.pings
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(nonce, tx);
+        Ok(rx)
+    }
+
+    /// Stop waiting for the pong to the ping `nonce`
+    pub fn forget_ping(&self, nonce: &Uuid) {
+        self.pings
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(nonce);
+    }
+
+    /// Wait for the answer to the inspection `request_id`, which the caller sends. It
+    /// arrives on the returned channel
+    pub fn expect_inspection(&mut self, request_id: Uuid) -> Result<Receiver<InspectResult>> {
+        if !self.reading {
+            self.spawn_reader(|msg| warn!("Unexpected cluster message: {:?}", msg))?;
+        }
+        let (tx, rx) = channel();
+        self.inspections
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(request_id, tx);
+        Ok(rx)
+    }
+
+    /// Stop waiting for the answer to the inspection `request_id`
+    pub fn forget_inspection(&self, request_id: &Uuid) {
+        self.inspections
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(request_id);
+    }
+
+    /// Stop waiting for a task's result
+    pub fn forget_task(&self, task_id: &Uuid) {
+        self.tasks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(task_id);
     }
 
-    /// Set up REPL for client
-    pub fn run(&mut self) -> Result<()> {
-        self.recv_loop()?;
-        loop {
-            let server_res = self.read()?;
-            println!("{}", server_res);
+    /// Read the HelloAck that follows a successful hello: the server's alias, the
+    /// (alias, ip, port) of every other node it knows, and what it knows about them all
+    pub fn read_hello_ack(&mut self) -> Result<Welcome> {
+        let msg = self.read_message()?;
+        if let IridiumMessage::HelloAck { alias, .. } = &msg {
+            self.peer = Some(alias.clone());
         }
+        self.reporter().send(msg.to_string());
+        match msg {
+            IridiumMessage::HelloAck {
+                alias,
+                nodes,
+                metadata,
+            } => Ok(Welcome {
+                alias,
+                nodes,
+                metadata,
+            }),
+            msg => Err(IridiumError::StringError(format!(
+                "Expected HelloAck, got {:?}",
+                msg
+            ))),
+        }
+    }
+
+    /// Read from server response
+    pub fn read(&mut self) -> Result<String> {
+        let response = self.read_message()?;
+        let report = self.reporter();
+        match response {
+            HelloResponse::Ok(value) => {
+                report.send(value.clone());
+                Ok(value)
+            }
+            HelloResponse::Err(msg) => {
+                report.send(format!("rejected: {}", msg));
+                Err(IridiumError::StringError(msg))
+            }
+        }
+    }
+
+    /// Reports what arrives, named after the node at the other end
+    fn reporter(&self) -> Reporter {
+        let name = match (&self.peer, self.stream.peer_addr()) {
+            (Some(peer), _) => peer.clone(),
+            (None, Ok(addr)) => addr.to_string(),
+            (None, Err(_)) => "unknown".to_string(),
+        };
+        Reporter {
+            prefix: format!("[cluster {}]", name),
+            output: self.output.clone(),
+        }
+    }
+
+    /// Block until the next message arrives
+    fn read_message<T: DeserializeOwned>(&mut self) -> Result<T> {
+        read_message(&mut self.reader, self.max_frame_length)?
+            .ok_or_else(|| IridiumError::StringError("Cluster connection closed".to_string()))
+    }
+}
+
+/// Sends lines about one connection to the client's output, or stdout without one
+struct Reporter {
+    prefix: String,
+    output: Option<Sender<String>>,
+}
+
+impl Reporter {
+    fn send(&self, text: String) {
+        let line = format!("{} {}", self.prefix, text);
+        let unsent = match &self.output {
+            Some(output) => output.send(line).err().map(|e| e.0),
+            None => Some(line),
+        };
+        if let Some(line) = unsent {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Send `msg` on a connection's shared writer, logging rather than failing
+pub fn answer(writer: &PeerWriter, msg: &IridiumMessage) {
+    let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+    if let Err(e) = write_message(&mut *writer, msg) {
+        warn!("Unable to send {}: {}", msg, e);
+    }
+}
+
+/// Send `value` to whoever is waiting for the reply `id`, or give it back if there is nobody
+fn hand_over<T>(waiting: &Mutex<HashMap<Uuid, Sender<T>>>, id: &Uuid, value: T) -> Option<T> {
+    let waiting = waiting.lock().unwrap_or_else(|e| e.into_inner()).remove(id);
+    match waiting {
+        // a receiver that gave up has nobody to hand the value back to either
+        Some(waiting) => {
+            let _ = waiting.send(value);
+            None
+        }
+        None => Some(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_pipelined_messages_arrive_intact() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let client = Arc::new(ClusterClient::new(stream).unwrap());
+        // big enough that writes from different threads would interleave if they could
+        let program = vec![7; 256 * 1024];
+
+        let senders: Vec<_> = (0..3)
+            .map(|i| {
+                let client = client.clone();
+                let program = program.clone();
+                thread::spawn(move || {
+                    let msg = match i {
+                        0 => IridiumMessage::Ping {
+                            nonce: Uuid::new_v4(),
+                        },
+                        1 => IridiumMessage::Goodbye {
+                            alias: "node".to_string(),
+                        },
+                        _ => IridiumMessage::ExecuteProgram {
+                            program,
+                            task_id: Uuid::new_v4(),
+                        },
+                    };
+                    client.send(&msg).unwrap();
+                })
+            })
+            .collect();
+        // read while they send, so a full socket buffer can't hold them up
+        let mut reader = BufReader::new(accepted);
+        let mut received = vec![];
+        for _ in 0..3 {
+            let msg: IridiumMessage = read_message(&mut reader, MAX_FRAME_LENGTH)
+                .unwrap()
+                .unwrap();
+            received.push(msg);
+        }
+        for sender in senders {
+            sender.join().unwrap();
+        }
+        assert!(received
+            .iter()
+            .any(|msg| matches!(msg, IridiumMessage::Ping { .. })));
+        assert!(received
+            .iter()
+            .any(|msg| matches!(msg, IridiumMessage::Goodbye { alias } if alias == "node")));
+        assert!(received.iter().any(
+            |msg| matches!(msg, IridiumMessage::ExecuteProgram { program: p, .. } if *p == program)
+        ));
+    }
+
+    #[test]
+    fn test_reliable_send_stops_once_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let (notifier, departed) = channel();
+        let mut client = ClusterClient::new(stream)
+            .unwrap()
+            .with_liveness("peer".to_string(), notifier);
+        client.spawn_reader(|_| {}).unwrap();
+
+        drop(accepted);
+        departed.recv_timeout(Duration::from_secs(5)).unwrap();
+        let goodbye = IridiumMessage::Goodbye {
+            alias: "node".to_string(),
+        };
+        assert!(client.send_reliably(goodbye).is_err());
     }
 }
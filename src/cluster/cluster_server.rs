@@ -1,18 +1,40 @@
-use log::{debug, error, info};
-use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
+use log::{debug, error, info, warn};
+use std::io::{self, BufReader, BufWriter};
 use std::net::{TcpListener, TcpStream, ToSocketAddrs};
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 
-use crate::cluster::message::{HelloResponse, IridiumMessage};
-use crate::error::Result;
+use crate::cluster::cluster_client::{answer, ClusterClient, PeerWriter, WRITE_TIMEOUT};
+use crate::cluster::message::{
+    Encoding, Handshake, HandshakeResponse, HelloResponse, IridiumMessage, NodeMetadata,
+    TaskResult, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::cluster::transport::Transport;
+use crate::common::{read_message, secrets_match, write_message, MAX_FRAME_LENGTH};
+use crate::error::{IridiumError, Result};
+use crate::remote::stream::Stream;
+use crate::scheduler::Scheduler;
+use uuid::Uuid;
 
-use super::manager::Manager;
+use super::{
+    ack::{self, Acks},
+    event_log::ReportedEvent,
+    gossip, inspect,
+    manager::Manager,
+    Departure,
+};
 
+#[derive(Clone)]
 pub struct ClusterServer {
     conn_manager: Arc<RwLock<Manager>>,
     alias: String,
+    listening: Arc<AtomicBool>,
+    scheduler: Arc<Scheduler>, // runs programs peers submit
+    metadata: NodeMetadata,    // what this node tells joiners about itself
+    max_frame_length: usize,   // longest message accepted from a peer
+    secret: Option<String>,    // peers must say hello with the same secret, or none if None
+    transport: Transport,      // what connections are wrapped in after the handshake
 }
 
 impl ClusterServer {
@@ -23,56 +45,965 @@ impl ClusterServer {
         Self {
             conn_manager,
             alias,
+            listening: Arc::new(AtomicBool::new(false)),
+            scheduler: Arc::new(Scheduler::new()),
+            metadata: NodeMetadata::default(),
+            max_frame_length: MAX_FRAME_LENGTH,
+            secret: None,
+            transport: Transport::plain(),
         }
     }
 
+    /// Reject messages longer than `max` bytes, closing the connection they arrived on
+    pub fn with_max_frame_length(mut self, max: usize) -> Self {
+        self.max_frame_length = max;
+        self
+    }
+
+    /// Only accept peers that say hello with this secret. Without one, only peers that send
+    /// none are accepted
+    pub fn with_secret(mut self, secret: Option<String>) -> Self {
+        self.secret = secret;
+        self
+    }
+
+    /// Carry peer connections over `transport`, turning away peers that carry theirs
+    /// differently
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Describe this node with `metadata` in the HelloAck joiners receive
+    pub fn with_metadata(mut self, metadata: NodeMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Run programs peers submit on this scheduler
+    pub fn with_scheduler(mut self, scheduler: Arc<Scheduler>) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Shares a flag that is set while the server is accepting connections
+    pub fn with_listening_flag(mut self, listening: Arc<AtomicBool>) -> Self {
+        self.listening = listening;
+        self
+    }
+
     /// Run the server listening on the given address
     pub fn listen<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
-        info!("Initializing Cluster server...");
+        info!("Initializing Cluster server for node {}...", self.alias);
         let listener = TcpListener::bind(addr)?;
+        self.listen_on(listener)
+    }
+
+    /// Accept peer connections on an already bound listener
+    pub fn listen_on(&mut self, listener: TcpListener) -> Result<()> {
+        Manager::supervise(&self.conn_manager);
+        self.listening.store(true, Ordering::SeqCst);
 
         for stream in listener.incoming() {
             info!("New Node connected!");
             match stream {
                 Ok(stream) => {
+                    let server = self.clone();
                     thread::spawn(move || -> Result<()> {
-                        Self::serve(stream)?;
+                        server.serve(stream)?;
                         Ok(())
                     });
                 }
                 Err(e) => error!("Connection failed: {}", e),
             }
         }
+        self.listening.store(false, Ordering::SeqCst);
         Ok(())
     }
 
-    /// Read messages and write response to the stream
-    pub fn serve(tcp: TcpStream) -> Result<()> {
+    /// Read messages and write response to the stream. A node saying hello is registered
+    /// in the manager and told, with a HelloAck, which other nodes are in the cluster. It is
+    /// reported to the manager's supervisor when its connection ends. Programs are run on
+    /// the scheduler and their results sent back once they stop. A peer sending anything
+    /// before its hello succeeded is turned away
+    pub fn serve(&self, tcp: TcpStream) -> Result<()> {
+        let mut peer = None;
+        let result = self.serve_messages(&tcp, &mut peer);
+        if let Some(peer) = peer {
+            let manager = self.conn_manager.read().unwrap_or_else(|e| e.into_inner());
+            let _ = manager.disconnect_notifier().send(peer);
+        }
+        result
+    }
+
+    /// Handle messages until the connection ends, recording the alias registered by a hello
+    fn serve_messages(&self, tcp: &TcpStream, registered: &mut Option<Departure>) -> Result<()> {
         let peer_addr = tcp.peer_addr()?;
-        let reader = BufReader::new(&tcp);
-        let mut writer = BufWriter::new(&tcp);
-        let req_reader = Deserializer::from_reader(reader).into_iter::<IridiumMessage>();
+        tcp.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let alias = self.alias.as_str();
+        let conn_manager = &self.conn_manager;
+        let mut reader = BufReader::new(Box::new(tcp.try_clone()?) as Box<dyn Stream>);
+        // task results are written from their own threads
+        let mut writer: PeerWriter =
+            Arc::new(Mutex::new(BufWriter::new(Box::new(tcp.try_clone()?))));
 
         macro_rules! send_resp {
             ($resp:expr) => {{
                 let resp = $resp;
-                serde_json::to_writer(&mut writer, &resp)?;
-                writer.flush()?;
+                let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+                write_message(&mut *writer, &resp)?;
                 debug!("Response sent to {}: {:?}", peer_addr, resp);
             }};
         }
 
-        for req in req_reader {
-            let req = req?;
+        // every connection opens with a handshake settling the protocol version
+        let handshake =
+            match read_message::<_, serde_json::Value>(&mut reader, self.max_frame_length) {
+                Ok(Some(handshake)) => handshake,
+                Ok(None) => return Ok(()),
+                Err(IridiumError::Io(e)) if e.kind() != io::ErrorKind::InvalidData => {
+                    return Err(e.into())
+                }
+                Err(e) => {
+                    warn!("Closing connection from {}: {}", peer_addr, e);
+                    send_resp!(HelloResponse::Err(e.to_string()));
+                    return Ok(());
+                }
+            };
+        match serde_json::from_value::<Handshake>(handshake) {
+            Ok(Handshake {
+                version,
+                encoding: Encoding::Json,
+                encrypted,
+            }) if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) => {
+                if let Some(reason) = self.transport.mismatch(encrypted) {
+                    warn!(
+                        "Rejecting cluster connection from {}: {}",
+                        peer_addr, reason
+                    );
+                    send_resp!(HandshakeResponse::Rejected { reason });
+                    return Ok(());
+                }
+                send_resp!(HandshakeResponse::Accepted { version, encrypted });
+                if encrypted {
+                    if !reader.buffer().is_empty() {
+                        warn!("{} sent messages before starting TLS", peer_addr);
+                        return Ok(());
+                    }
+                    let secured = self.transport.accept(tcp)?;
+                    reader = BufReader::new(secured.try_clone()?);
+                    writer = Arc::new(Mutex::new(BufWriter::new(secured)));
+                }
+            }
+            Ok(Handshake { version, .. }) => {
+                warn!(
+                    "{} speaks unsupported protocol version {}",
+                    peer_addr, version
+                );
+                send_resp!(HandshakeResponse::Unsupported {
+                    min: MIN_PROTOCOL_VERSION,
+                    max: PROTOCOL_VERSION,
+                });
+                return Ok(());
+            }
+            Err(_) => {
+                // a node from before handshakes, which expects a HelloResponse to its hello
+                warn!("{} did not start with a handshake", peer_addr);
+                send_resp!(HelloResponse::Err(format!(
+                    "Unsupported cluster protocol, this node speaks versions {}..{}",
+                    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                )));
+                return Ok(());
+            }
+        }
+
+        let delivered = conn_manager
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .delivered();
+        // reliable messages sent to the peer, once it said hello and became a client
+        let mut acks: Option<Acks> = None;
+        // whether the peer said hello with the right secret
+        let mut authenticated = false;
+        loop {
+            let req = match read_message(&mut reader, self.max_frame_length) {
+                Ok(Some(req)) => req,
+                Ok(None) => break,
+                Err(IridiumError::Io(e)) if e.kind() != io::ErrorKind::InvalidData => {
+                    return Err(e.into())
+                }
+                Err(e) => {
+                    // oversized or garbled: the stream can't be trusted past this point
+                    warn!("Closing connection from {}: {}", peer_addr, e);
+                    send_resp!(HelloResponse::Err(e.to_string()));
+                    return Ok(());
+                }
+            };
             info!("Receive request from {}: {:?}", peer_addr, req);
+            if !authenticated && !matches!(req, IridiumMessage::Hello { .. }) {
+                warn!("Closing connection from {}: it never said hello", peer_addr);
+                send_resp!(HelloResponse::Err(
+                    "Say hello before sending anything else".to_string()
+                ));
+                return Ok(());
+            }
+            let req = match ack::receive(req, &delivered, |ack| answer(&writer, &ack)) {
+                Some(req) => req,
+                None => continue,
+            };
+            if let Some((peer, _)) = registered.as_ref() {
+                conn_manager
+                    .write()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .touch(peer);
+            }
             match req {
-                IridiumMessage::Hello { alias } => send_resp!(HelloResponse::Ok(format!(
-                    "Received hello from node {}",
-                    alias
-                ))),
-                IridiumMessage::HelloAck { alias: _, nodes: _ } => todo!(),
+                IridiumMessage::Hello {
+                    alias: peer,
+                    peer_host,
+                    peer_port,
+                    logical_cores,
+                    vm_id,
+                    secret,
+                    role,
+                } => {
+                    if !secrets_match(secret.as_deref(), self.secret.as_deref()) {
+                        warn!("Rejected hello from {}: wrong cluster secret", peer_addr);
+                        send_resp!(HelloResponse::Err("Cluster secret mismatch".to_string()));
+                        return Ok(());
+                    }
+                    let mut client = ClusterClient::new(tcp.try_clone()?)?
+                        .with_alias(peer.clone())
+                        .with_server_loop(writer.clone());
+                    if let (Some(host), Some(port)) = (peer_host, peer_port) {
+                        client = client.with_peer_listen(host, port);
+                    }
+                    if let Some(id) = vm_id {
+                        client = client.with_peer_id(id);
+                    }
+                    let (host, port) = client.peer_listen_addr()?;
+                    let connection = client.connection_id();
+                    let mut manager = conn_manager.write().unwrap_or_else(|e| e.into_inner());
+                    let nodes = manager.get_nodes();
+                    let mut metadata = manager.all_metadata();
+                    metadata.insert(alias.to_string(), self.metadata.clone());
+                    // the same VM saying hello again has reconnected; anyone else is an impostor
+                    let reconnected = vm_id.is_some() && manager.node_id(&peer) == vm_id;
+                    if peer == alias || (manager.has_client(&peer) && !reconnected) {
+                        drop(manager);
+                        warn!("Rejected hello from {}: alias {} in use", peer_addr, peer);
+                        send_resp!(HelloResponse::Err(format!("Alias {} already in use", peer)));
+                        return Ok(());
+                    }
+                    acks = Some(client.acks());
+                    manager.replace_client(peer.clone(), client);
+                    let peer_metadata = NodeMetadata {
+                        logical_cores,
+                        vm_id,
+                        role,
+                    };
+                    manager.set_metadata(&peer, peer_metadata);
+                    manager.announce_member(&(peer.clone(), host, port));
+                    *registered = Some((peer.clone(), connection));
+                    authenticated = true;
+                    drop(manager);
+
+                    send_resp!(HelloResponse::Ok(format!(
+                        "Received hello from node {}",
+                        peer
+                    )));
+                    send_resp!(IridiumMessage::HelloAck {
+                        alias: alias.to_string(),
+                        nodes,
+                        metadata,
+                    });
+                }
+                IridiumMessage::NewMember { alias, host, port } => {
+                    info!("Node {} at {}:{} joined the cluster", alias, host, port)
+                }
+                IridiumMessage::Goodbye { alias: leaving } => match registered.take() {
+                    Some((peer, _)) => {
+                        info!("Cluster member {} is leaving", peer);
+                        let mut manager = conn_manager.write().unwrap_or_else(|e| e.into_inner());
+                        manager.del_client(peer);
+                    }
+                    None => warn!(
+                        "Ignoring goodbye from {} ({}), which never said hello",
+                        peer_addr, leaving
+                    ),
+                },
+                IridiumMessage::Gossip { members } => match registered {
+                    Some((peer, _)) => gossip::receive(conn_manager, peer, members),
+                    None => warn!("Ignoring gossip from {}, which never said hello", peer_addr),
+                },
+                IridiumMessage::ExecuteProgram { program, task_id } => {
+                    let role = self.metadata.role.unwrap_or_default();
+                    match role.program_refusal(&self.alias) {
+                        Some(reason) => {
+                            send_resp!(IridiumMessage::ExecuteRefused { task_id, reason })
+                        }
+                        None => Self::run_task(&self.scheduler, program, task_id, writer.clone()),
+                    }
+                }
+                IridiumMessage::ExecuteRefused { task_id, reason } => {
+                    warn!("Task {} was refused: {}", task_id, reason);
+                    let manager = conn_manager.read().unwrap_or_else(|e| e.into_inner());
+                    if let Some(client) =
+                        registered.as_ref().and_then(|(p, _)| manager.get_client(p))
+                    {
+                        client.forget_task(&task_id);
+                    }
+                }
+                IridiumMessage::ExecuteResult {
+                    task_id,
+                    events,
+                    registers,
+                } => {
+                    let result = TaskResult {
+                        task_id,
+                        events,
+                        registers,
+                    };
+                    let manager = conn_manager.read().unwrap_or_else(|e| e.into_inner());
+                    let unclaimed =
+                        match registered.as_ref().and_then(|(p, _)| manager.get_client(p)) {
+                            Some(client) => client.deliver(result),
+                            None => Some(result),
+                        };
+                    if let Some(result) = unclaimed {
+                        warn!(
+                            "Unexpected result of task {} from {}",
+                            result.task_id, peer_addr
+                        )
+                    }
+                }
+                IridiumMessage::Ping { nonce } => send_resp!(IridiumMessage::Pong {
+                    nonce,
+                    running_tasks: Some(self.scheduler.running_tasks()),
+                }),
+                IridiumMessage::Pong {
+                    nonce,
+                    running_tasks,
+                } => {
+                    let manager = conn_manager.read().unwrap_or_else(|e| e.into_inner());
+                    let delivered = registered
+                        .as_ref()
+                        .and_then(|(p, _)| manager.get_client(p))
+                        .is_some_and(|client| client.deliver_pong(&nonce, running_tasks));
+                    if !delivered {
+                        debug!(
+                            "Pong {} from {} arrived after its ping timed out",
+                            nonce, peer_addr
+                        )
+                    }
+                }
+                IridiumMessage::InspectRequest { request_id } => {
+                    let manager = conn_manager.read().unwrap_or_else(|e| e.into_inner());
+                    // a peer that left has no role to be trusted with
+                    let peer_role = registered.as_ref().map(|(p, _)| manager.role_of(p));
+                    let vm = manager.vm();
+                    drop(manager);
+                    let role = self.metadata.role.unwrap_or_default();
+                    let refusal = match peer_role {
+                        Some(peer_role) => role.inspection_refusal(&self.alias, peer_role),
+                        None => Some(format!("{} only answers cluster members", self.alias)),
+                    };
+                    match refusal {
+                        Some(reason) => send_resp!(IridiumMessage::InspectResponse {
+                            request_id,
+                            status: Err(reason),
+                        }),
+                        None => inspect::answer(vm, request_id, writer.clone()),
+                    }
+                }
+                IridiumMessage::InspectResponse { request_id, status } => {
+                    let manager = conn_manager.read().unwrap_or_else(|e| e.into_inner());
+                    let delivered = registered
+                        .as_ref()
+                        .and_then(|(p, _)| manager.get_client(p))
+                        .is_some_and(|client| client.deliver_inspection(&request_id, status));
+                    if !delivered {
+                        debug!(
+                            "Status for inspection {} from {} arrived too late",
+                            request_id, peer_addr
+                        )
+                    }
+                }
+                IridiumMessage::EventReport { alias, event } => {
+                    Manager::record_event(conn_manager, ReportedEvent { alias, event })
+                }
+                IridiumMessage::Ack { id } => {
+                    if !acks
+                        .as_ref()
+                        .is_some_and(|acks| ack::acknowledge(acks, &id))
+                    {
+                        debug!("Ack for message {} from {} arrived too late", id, peer_addr)
+                    }
+                }
+                IridiumMessage::Reliable { id, .. } => {
+                    warn!(
+                        "Ignoring message {} nested in another from {}",
+                        id, peer_addr
+                    )
+                }
+                IridiumMessage::HelloAck { alias, .. } => {
+                    warn!("Unexpected HelloAck from {} ({})", alias, peer_addr)
+                }
             }
         }
+        // nothing more will arrive, so stop anyone still waiting for an ack
+        if let Some(acks) = acks {
+            acks.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        }
         Ok(())
     }
+
+    /// Run a program a peer submitted and send its result back through `writer` once it stops
+    pub fn run_task(scheduler: &Scheduler, program: Vec<u8>, task_id: Uuid, writer: PeerWriter) {
+        let task = match scheduler.execute(program) {
+            Ok(task) => task,
+            Err(e) => return warn!("Rejected task {}: {}", task_id, e),
+        };
+        thread::spawn(move || {
+            let vm = match task.recv() {
+                Ok(vm) => vm,
+                Err(_) => return error!("Task {} crashed", task_id),
+            };
+            let result = IridiumMessage::ExecuteResult {
+                task_id,
+                events: vm.events().to_vec(),
+                registers: vm.registers,
+            };
+            let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = write_message(&mut *writer, &result) {
+                error!("Unable to send result of task {}: {}", task_id, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::{
+        assembler::Assembler,
+        cluster::{ack::AckPolicy, role::NodeRole},
+        common::encode_message,
+        vm::{VMEventType, VM},
+    };
+
+    /// Connect to the hub and say hello as `alias`, returning the client and the hub's response
+    fn hello(addr: std::net::SocketAddr, alias: &str) -> (ClusterClient, Result<String>) {
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut client = ClusterClient::new(stream)
+            .unwrap()
+            .with_alias(alias.to_string());
+        client.send_hello().unwrap();
+        let response = client.read();
+        (client, response)
+    }
+
+    /// Say hello as `alias` from the VM `vm_id`, returning the client and the hub's response
+    fn hello_from(
+        addr: std::net::SocketAddr,
+        alias: &str,
+        vm_id: Uuid,
+    ) -> (ClusterClient, Result<String>) {
+        let metadata = NodeMetadata {
+            logical_cores: None,
+            vm_id: Some(vm_id),
+            role: None,
+        };
+        let mut client = ClusterClient::new(TcpStream::connect(addr).unwrap())
+            .unwrap()
+            .with_alias(alias.to_string())
+            .with_metadata(metadata);
+        client.send_hello().unwrap();
+        let response = client.read();
+        (client, response)
+    }
+
+    /// Aliases of the nodes listed in a HelloAck
+    fn listed(client: &mut ClusterClient) -> (String, Vec<String>) {
+        let welcome = client.read_hello_ack().unwrap();
+        let nodes = welcome.nodes.into_iter().map(|(alias, _, _)| alias);
+        (welcome.alias, nodes.collect())
+    }
+
+    #[test]
+    fn test_hello_ack_lists_joined_nodes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new()));
+        let mut hub = ClusterServer::new("hub".to_string(), manager.clone());
+        thread::spawn(move || hub.listen_on(listener));
+
+        let (mut first, response) = hello(addr, "first");
+        response.unwrap();
+        assert_eq!(listed(&mut first), ("hub".to_string(), vec![]));
+        let (mut second, response) = hello(addr, "second");
+        response.unwrap();
+        assert_eq!(
+            listed(&mut second),
+            ("hub".to_string(), vec!["first".to_string()])
+        );
+        assert_eq!(manager.read().unwrap().client_count(), 2);
+    }
+
+    #[test]
+    fn test_duplicate_alias_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new()));
+        let mut hub = ClusterServer::new("hub".to_string(), manager.clone());
+        thread::spawn(move || hub.listen_on(listener));
+
+        let (_first, response) = hello(addr, "node");
+        response.unwrap();
+        let (_second, response) = hello(addr, "node");
+        let err = response.unwrap_err().to_string();
+        assert!(err.contains("already in use"), "{}", err);
+        assert_eq!(manager.read().unwrap().get_client_names(), ["node"]);
+    }
+
+    #[test]
+    fn test_same_vm_replaces_its_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new()));
+        let mut hub = ClusterServer::new("hub".to_string(), manager.clone());
+        thread::spawn(move || hub.listen_on(listener));
+        let vm_id = Uuid::new_v4();
+
+        let (_first, response) = hello_from(addr, "node", vm_id);
+        response.unwrap();
+        let first_connection = manager
+            .read()
+            .unwrap()
+            .get_client("node")
+            .unwrap()
+            .connection_id();
+        let (_impostor, response) = hello_from(addr, "node", Uuid::new_v4());
+        let err = response.unwrap_err().to_string();
+        assert!(err.contains("already in use"), "{}", err);
+
+        let (_second, response) = hello_from(addr, "node", vm_id);
+        response.unwrap();
+        // the old connection closing doesn't take the new one with it
+        thread::sleep(Duration::from_millis(100));
+        let manager = manager.read().unwrap();
+        assert_eq!(manager.get_client_names(), ["node"]);
+        let connection = manager.get_client("node").unwrap().connection_id();
+        assert_ne!(connection, first_connection);
+    }
+
+    #[test]
+    fn test_cluster_secret() {
+        let hub = |secret: Option<&str>| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let manager = Arc::new(RwLock::new(Manager::new()));
+            let mut hub = ClusterServer::new("hub".to_string(), manager.clone())
+                .with_secret(secret.map(str::to_string));
+            thread::spawn(move || hub.listen_on(listener));
+            (addr, manager)
+        };
+        // the client is kept, so its connection can't close before the manager is checked
+        let join = |addr, secret: Option<&str>| {
+            let mut client = ClusterClient::new(TcpStream::connect(addr).unwrap())
+                .unwrap()
+                .with_alias("node".to_string())
+                .with_secret(secret.map(str::to_string));
+            client.send_hello().unwrap();
+            client.read().map(|_| client)
+        };
+
+        let (addr, manager) = hub(Some("s3cret"));
+        let _joined = join(addr, Some("s3cret")).unwrap();
+        assert_eq!(manager.read().unwrap().get_client_names(), ["node"]);
+
+        let (addr, manager) = hub(Some("s3cret"));
+        let err = join(addr, Some("guess")).err().unwrap().to_string();
+        assert!(err.contains("secret mismatch"), "{}", err);
+        assert!(join(addr, None).is_err());
+        assert!(manager.read().unwrap().get_client_names().is_empty());
+
+        let (addr, manager) = hub(None);
+        assert!(join(addr, Some("s3cret")).is_err());
+        assert!(manager.read().unwrap().get_client_names().is_empty());
+        join(addr, None).unwrap();
+    }
+
+    #[test]
+    fn test_nothing_served_before_hello() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new()));
+        let scheduler = Arc::new(Scheduler::new());
+        let mut hub = ClusterServer::new("hub".to_string(), manager.clone())
+            .with_secret(Some("s3cret".to_string()))
+            .with_scheduler(scheduler.clone());
+        thread::spawn(move || hub.listen_on(listener));
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut intruder = ClusterClient::new(stream).unwrap();
+        intruder.handshake().unwrap();
+        let program = Assembler::new()
+            .assemble(".data\n.code\nload $0 #100")
+            .unwrap();
+        intruder
+            .send(&IridiumMessage::ExecuteProgram {
+                program,
+                task_id: Uuid::new_v4(),
+            })
+            .unwrap();
+
+        match read_message(&mut reader, MAX_FRAME_LENGTH).unwrap() {
+            Some(HelloResponse::Err(msg)) => assert!(msg.contains("Say hello"), "{}", msg),
+            other => panic!("expected the program to be refused, got {:?}", other),
+        }
+        let closed: Option<IridiumMessage> = read_message(&mut reader, MAX_FRAME_LENGTH).unwrap();
+        assert!(closed.is_none());
+        assert_eq!(scheduler.metrics().submitted, 0);
+        assert_eq!(manager.read().unwrap().client_count(), 0);
+    }
+
+    #[test]
+    fn test_dropped_peer_is_removed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new()));
+        let mut hub = ClusterServer::new("hub".to_string(), manager.clone());
+        thread::spawn(move || hub.listen_on(listener));
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut peer = ClusterClient::new(stream)
+            .unwrap()
+            .with_alias("peer".to_string());
+        peer.send_hello().unwrap();
+        peer.read().unwrap();
+        assert_eq!(manager.read().unwrap().get_client_names(), ["peer"]);
+
+        drop(peer);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !manager.read().unwrap().get_client_names().is_empty() {
+            assert!(Instant::now() < deadline, "peer was never removed");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_execute_program_on_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new()));
+        let mut b = ClusterServer::new("b".to_string(), manager);
+        thread::spawn(move || b.listen_on(listener));
+
+        let program = Assembler::new()
+            .assemble(".data\n.code\nload $0 #100\nload $1 #7\nadd $0 $1 $2")
+            .unwrap();
+        let mut a = ClusterClient::new(TcpStream::connect(addr).unwrap())
+            .unwrap()
+            .with_alias("a".to_string());
+        a.send_hello().unwrap();
+        a.read().unwrap();
+        a.read_hello_ack().unwrap();
+
+        let result = a.execute(program, Duration::from_secs(5)).unwrap();
+        assert_eq!(result.registers[..3], [100, 7, 107]);
+        assert_eq!(result.events.first().unwrap().event, VMEventType::Start);
+        assert!(a
+            .execute(vec![1, 2, 3], Duration::from_millis(200))
+            .is_err());
+    }
+
+    #[test]
+    fn test_roles() {
+        let program = Assembler::new()
+            .assemble(".data\n.code\nload $0 #100")
+            .unwrap();
+        let start = |alias: &str, role| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let manager = Arc::new(RwLock::new(Manager::new()));
+            let metadata = NodeMetadata {
+                role: Some(role),
+                ..Default::default()
+            };
+            let mut server =
+                ClusterServer::new(alias.to_string(), manager.clone()).with_metadata(metadata);
+            thread::spawn(move || server.listen_on(listener));
+            (addr, manager)
+        };
+        let member = |addr, alias: &str, role| {
+            let metadata = NodeMetadata {
+                role: Some(role),
+                ..Default::default()
+            };
+            let mut client = ClusterClient::new(TcpStream::connect(addr).unwrap())
+                .unwrap()
+                .with_alias(alias.to_string())
+                .with_metadata(metadata);
+            client.send_hello().unwrap();
+            client.read().unwrap();
+            client.read_hello_ack().unwrap();
+            client
+        };
+        let inspect = |client: &mut ClusterClient| {
+            let request_id = Uuid::new_v4();
+            let answer = client.expect_inspection(request_id).unwrap();
+            client
+                .send(&IridiumMessage::InspectRequest { request_id })
+                .unwrap();
+            answer.recv_timeout(Duration::from_secs(5)).unwrap()
+        };
+
+        let (addr, manager) = start("worker", NodeRole::Worker);
+        let vm = Arc::new(Mutex::new(VM::new()));
+        manager.write().unwrap().set_vm(Arc::downgrade(&vm));
+        let mut coordinator = member(addr, "coordinator", NodeRole::Coordinator);
+        let mut other = member(addr, "other", NodeRole::Worker);
+        assert!(inspect(&mut coordinator).is_ok());
+        assert_eq!(
+            inspect(&mut other),
+            Err("worker is a worker and only answers coordinators".to_string())
+        );
+        // but it runs programs for anyone
+        let result = other.execute(program.clone(), Duration::from_secs(5));
+        assert_eq!(result.unwrap().registers[0], 100);
+
+        // having left, it is no longer trusted with anything a member would be
+        coordinator
+            .send(&IridiumMessage::Goodbye {
+                alias: "coordinator".to_string(),
+            })
+            .unwrap();
+        assert_eq!(
+            inspect(&mut coordinator),
+            Err("worker only answers cluster members".to_string())
+        );
+
+        let (addr, _) = start("boss", NodeRole::Coordinator);
+        let mut both = member(addr, "both", NodeRole::Both);
+        let started = Instant::now();
+        assert!(both.execute(program, Duration::from_secs(5)).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_hello_split_across_writes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new()));
+        let mut hub = ClusterServer::new("hub".to_string(), manager);
+        thread::spawn(move || hub.listen_on(listener));
+
+        let hello = IridiumMessage::Hello {
+            alias: "slow".to_string(),
+            peer_host: None,
+            peer_port: None,
+            logical_cores: None,
+            vm_id: None,
+            secret: None,
+            role: None,
+        };
+        let handshake = Handshake {
+            version: PROTOCOL_VERSION,
+            encoding: Encoding::Json,
+            encrypted: false,
+        };
+        let mut frames = encode_message(&handshake).unwrap();
+        frames.extend(encode_message(&hello).unwrap());
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+        for chunk in frames.chunks(3) {
+            stream.write_all(chunk).unwrap();
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let mut reader = BufReader::new(stream);
+        let accepted = read_message(&mut reader, MAX_FRAME_LENGTH).unwrap();
+        assert_eq!(
+            accepted,
+            Some(HandshakeResponse::Accepted {
+                version: PROTOCOL_VERSION,
+                encrypted: false,
+            })
+        );
+        let response = read_message(&mut reader, MAX_FRAME_LENGTH).unwrap();
+        assert!(matches!(response, Some(HelloResponse::Ok(_))));
+        let ack = read_message(&mut reader, MAX_FRAME_LENGTH).unwrap();
+        assert!(matches!(ack, Some(IridiumMessage::HelloAck { .. })));
+    }
+
+    #[test]
+    fn test_protocol_version_mismatch() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new()));
+        let mut hub = ClusterServer::new("hub".to_string(), manager.clone());
+        thread::spawn(move || hub.listen_on(listener));
+
+        // a client from the future
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let handshake = Handshake {
+            version: PROTOCOL_VERSION + 1,
+            encoding: Encoding::Json,
+            encrypted: false,
+        };
+        write_message(&mut stream, &handshake).unwrap();
+        let mut reader = BufReader::new(stream);
+        let response = read_message(&mut reader, MAX_FRAME_LENGTH).unwrap();
+        assert_eq!(
+            response,
+            Some(HandshakeResponse::Unsupported {
+                min: MIN_PROTOCOL_VERSION,
+                max: PROTOCOL_VERSION
+            })
+        );
+
+        // and one from before handshakes, which says hello straight away
+        let old = ClusterClient::new(TcpStream::connect(addr).unwrap()).unwrap();
+        let mut old = old.with_alias("old".to_string());
+        let hello = IridiumMessage::Hello {
+            alias: "old".to_string(),
+            peer_host: None,
+            peer_port: None,
+            logical_cores: None,
+            vm_id: None,
+            secret: None,
+            role: None,
+        };
+        old.send(&hello).unwrap();
+        let err = old.read().unwrap_err().to_string();
+        assert!(err.contains("Unsupported cluster protocol"), "{}", err);
+        assert_eq!(manager.read().unwrap().client_count(), 0);
+    }
+
+    #[test]
+    fn test_handshake_with_old_server() {
+        // answers the way servers from before handshakes did to anything they can't parse
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let _: Option<serde_json::Value> = read_message(&mut reader, MAX_FRAME_LENGTH).unwrap();
+            let err = HelloResponse::Err("unknown variant `version`".to_string());
+            write_message(&mut stream, &err).unwrap();
+        });
+
+        let mut client = ClusterClient::new(TcpStream::connect(addr).unwrap())
+            .unwrap()
+            .with_alias("new".to_string());
+        let err = client.send_hello().unwrap_err().to_string();
+        assert!(
+            err.contains("does not speak cluster protocol version"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_oversized_frame_closes_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new()));
+        let mut hub = ClusterServer::new("hub".to_string(), manager).with_max_frame_length(64);
+        thread::spawn(move || hub.listen_on(listener));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&1000u32.to_le_bytes()).unwrap();
+
+        let mut reader = BufReader::new(stream);
+        match read_message(&mut reader, MAX_FRAME_LENGTH).unwrap() {
+            Some(HelloResponse::Err(msg)) => assert!(msg.contains("exceeds"), "{}", msg),
+            other => panic!("expected an error response, got {:?}", other),
+        }
+        let closed: Option<HelloResponse> = read_message(&mut reader, MAX_FRAME_LENGTH).unwrap();
+        assert!(closed.is_none());
+    }
+
+    #[test]
+    fn test_lost_program_is_sent_again() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new()));
+        let mut b = ClusterServer::new("b".to_string(), manager);
+        thread::spawn(move || b.listen_on(listener));
+
+        let program = Assembler::new()
+            .assemble(".data\n.code\nload $0 #100")
+            .unwrap();
+        let mut a = ClusterClient::new(TcpStream::connect(addr).unwrap())
+            .unwrap()
+            .with_alias("a".to_string())
+            .with_ack_policy(AckPolicy {
+                timeout: Duration::from_millis(100),
+                attempts: 3,
+            });
+        a.send_hello().unwrap();
+        a.read().unwrap();
+        a.read_hello_ack().unwrap();
+
+        a.drop_reliable_sends(1);
+        let result = a.execute(program, Duration::from_secs(5)).unwrap();
+        assert_eq!(result.registers[0], 100);
+    }
+
+    #[test]
+    fn test_repeated_message_handled_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new()));
+        let mut b = ClusterServer::new("b".to_string(), manager);
+        thread::spawn(move || b.listen_on(listener));
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut a = ClusterClient::new(stream)
+            .unwrap()
+            .with_alias("a".to_string());
+        a.send_hello().unwrap();
+        a.read().unwrap();
+        a.read_hello_ack().unwrap();
+
+        // as if the first ack was lost and the program sent again
+        let program = Assembler::new()
+            .assemble(".data\n.code\nload $0 #100")
+            .unwrap();
+        let id = Uuid::new_v4();
+        let frame = encode_message(&IridiumMessage::Reliable {
+            id,
+            message: Box::new(IridiumMessage::ExecuteProgram {
+                program,
+                task_id: Uuid::new_v4(),
+            }),
+        })
+        .unwrap();
+        a.send_frame(&frame).unwrap();
+        a.send_frame(&frame).unwrap();
+
+        reader
+            .get_ref()
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let (mut acks, mut results) = (0, 0);
+        while let Ok(Some(msg)) = read_message(&mut reader, MAX_FRAME_LENGTH) {
+            match msg {
+                IridiumMessage::Ack { id: acked } if acked == id => acks += 1,
+                IridiumMessage::ExecuteResult { .. } => results += 1,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!((acks, results), (2, 1));
+    }
 }
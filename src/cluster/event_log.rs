@@ -0,0 +1,193 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{mpsc::Receiver, RwLock, Weak},
+    thread,
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use super::{manager::Manager, message::IridiumMessage, NodeAlias};
+use crate::{
+    common::write_message,
+    error::{IridiumError, Result},
+    vm::VMEvent,
+};
+
+/// VM events a coordinator keeps for `!cluster_events log`
+pub const EVENT_LOG_LENGTH: usize = 1000;
+/// File under the data directory a coordinator appends reported events to
+pub const EVENT_LOG_FILE_NAME: &str = "cluster_events.jsonl";
+
+/// A VM event as a cluster member reported it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReportedEvent {
+    pub alias: NodeAlias, // member whose VM recorded the event
+    pub event: VMEvent,
+}
+
+impl fmt::Display for ReportedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}  {} {:?} (app {})",
+            self.event.at.format("%Y-%m-%d %H:%M:%S"),
+            self.alias,
+            self.event.event,
+            self.event.app_id
+        )
+    }
+}
+
+/// Events reported by cluster members, most recent kept in memory and, with a file, all of
+/// them on disk
+#[derive(Debug, Default)]
+pub struct EventLog {
+    events: VecDeque<ReportedEvent>,
+    file: Option<PathBuf>, // every reported event is appended here, one JSON object a line
+}
+
+impl EventLog {
+    /// Append reported events to `path` from now on
+    pub fn set_file(&mut self, path: PathBuf) {
+        self.file = Some(path);
+    }
+
+    /// File reported events are appended to, if there is one
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    /// Keep an event in memory, dropping the oldest once there are too many. Appending it to
+    /// the file is left to `save`, so nobody holds the log while the disk is slow
+    pub fn record(&mut self, reported: ReportedEvent) {
+        if self.events.len() == EVENT_LOG_LENGTH {
+            self.events.pop_front();
+        }
+        self.events.push_back(reported);
+    }
+
+    /// Reported events, oldest first
+    pub fn events(&self) -> Vec<ReportedEvent> {
+        self.events.iter().cloned().collect()
+    }
+}
+
+/// Where a node storing its data in `data_dir` appends the events members report
+pub fn event_log_file(data_dir: &Path) -> PathBuf {
+    data_dir.join(EVENT_LOG_FILE_NAME)
+}
+
+/// Append a reported event to the log file at `path`, warning if it can't be written
+pub fn save(path: &Path, reported: &ReportedEvent) {
+    if let Err(e) = append(path, reported) {
+        warn!("Unable to append to event log {}: {}", path.display(), e);
+    }
+}
+
+fn append(path: &Path, reported: &ReportedEvent) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut line = serde_json::to_vec(reported)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    Ok(())
+}
+
+/// Send every event arriving on `events` to `coordinator` on a thread of its own, so
+/// recording an event never waits on the network. Events are recorded here instead when
+/// this node is the coordinator, and dropped while it isn't connected to the coordinator or
+/// hasn't joined a cluster. Stops once the manager is dropped or every sender hangs up. The
+/// manager is only locked to look the coordinator up, never while sending
+pub fn forward(manager: Weak<RwLock<Manager>>, coordinator: NodeAlias, events: Receiver<VMEvent>) {
+    thread::spawn(move || {
+        for event in events {
+            let manager = match manager.upgrade() {
+                Some(manager) => manager,
+                None => return,
+            };
+            let (alias, writer) = {
+                let lock = manager.read().unwrap_or_else(|e| e.into_inner());
+                match lock.local_node() {
+                    Some(local) => (local.alias.clone(), lock.writer_to(&coordinator)),
+                    None => continue,
+                }
+            };
+            if alias == coordinator {
+                Manager::record_event(&manager, ReportedEvent { alias, event });
+                continue;
+            }
+            let report = IridiumMessage::EventReport { alias, event };
+            let sent = match writer {
+                Some(writer) => write_message(
+                    &mut *writer.lock().unwrap_or_else(|e| e.into_inner()),
+                    &report,
+                ),
+                None => Err(IridiumError::NotFound(coordinator.clone())),
+            };
+            if let Err(e) = sent {
+                debug!("Unable to report an event to {}: {}", coordinator, e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::vm::{VMEventType, VM};
+
+    fn node(alias: &str, data_dir: &Path) -> VM {
+        let mut vm = VM::new()
+            .with_alias(&alias.to_string())
+            .with_cluster_bind(&"127.0.0.1".to_string(), &"0".to_string())
+            .with_data_dir(data_dir.to_path_buf())
+            .with_event_coordinator(Some("coordinator".to_string()));
+        vm.bind_cluster_server().unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_crash_reported_to_coordinator() {
+        let dir = tempdir().unwrap();
+        let coordinator = node("coordinator", &dir.path().join("coordinator"));
+        let mut worker = node("worker", &dir.path().join("worker"));
+        let addr = format!("127.0.0.1:{}", coordinator.peer_port.clone().unwrap());
+        worker.join_cluster(&addr, None).unwrap();
+
+        // no header, so the program crashes as soon as it starts
+        worker.program = vec![1, 2, 3, 4];
+        worker.run();
+
+        let crashed = |manager: &Arc<RwLock<Manager>>| {
+            manager
+                .read()
+                .unwrap()
+                .event_log()
+                .into_iter()
+                .any(|e| e.alias == "worker" && e.event.event == VMEventType::Crash)
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !crashed(&coordinator.conn_manager) {
+            assert!(Instant::now() < deadline, "crash never reported");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let saved = fs::read_to_string(event_log_file(&dir.path().join("coordinator")));
+        assert!(saved.unwrap().contains(r#""alias":"worker""#));
+        // only the coordinator keeps a log
+        assert!(!crashed(&worker.conn_manager));
+    }
+}
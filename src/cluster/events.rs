@@ -0,0 +1,75 @@
+use std::{collections::VecDeque, fmt, sync::mpsc::Sender};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use super::NodeAlias;
+
+/// Membership changes kept for `!cluster_events`
+pub const MEMBERSHIP_HISTORY_LENGTH: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum MembershipEventKind {
+    Joined,
+    Left,    // removed on purpose
+    Evicted, // removed because its connection dropped
+}
+
+/// A node joining or leaving the cluster, as seen from this node
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MembershipEvent {
+    pub alias: NodeAlias,
+    pub kind: MembershipEventKind,
+    pub at: DateTime<Utc>,
+}
+
+impl fmt::Display for MembershipEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let change = match self.kind {
+            MembershipEventKind::Joined => "joined",
+            MembershipEventKind::Left => "left",
+            MembershipEventKind::Evicted => "was evicted",
+        };
+        write!(
+            f,
+            "{}  {} {}",
+            self.at.format("%Y-%m-%d %H:%M:%S"),
+            self.alias,
+            change
+        )
+    }
+}
+
+/// Recent membership events, and the subscribers told about new ones as they happen
+#[derive(Debug, Default)]
+pub struct MembershipLog {
+    history: VecDeque<MembershipEvent>,
+    subscribers: Vec<Sender<MembershipEvent>>,
+}
+
+impl MembershipLog {
+    /// Record that `alias` changed membership, telling every subscriber still listening
+    pub fn record(&mut self, alias: &str, kind: MembershipEventKind) {
+        let event = MembershipEvent {
+            alias: alias.to_owned(),
+            kind,
+            at: Utc::now(),
+        };
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        if self.history.len() == MEMBERSHIP_HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+        self.history.push_back(event);
+    }
+
+    /// Send every event from now on to `subscriber`, until it hangs up
+    pub fn subscribe(&mut self, subscriber: Sender<MembershipEvent>) {
+        self.subscribers.push(subscriber);
+    }
+
+    /// Recorded events, oldest first
+    pub fn history(&self) -> Vec<MembershipEvent> {
+        self.history.iter().cloned().collect()
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+
+use log::{debug, warn};
+use uuid::Uuid;
+
+use super::{
+    address, join,
+    manager::Manager,
+    message::{GossipEntry, IridiumMessage},
+    NodeAlias,
+};
+
+/// How often and how widely members share their view of the cluster
+#[derive(Debug, Clone, PartialEq)]
+pub struct GossipConfig {
+    pub interval: Duration, // between rounds
+    pub fanout: usize,      // members sent to each round, and when passing on news
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            fanout: 2,
+        }
+    }
+}
+
+/// Every `interval`, send this node's full view to `fanout` random members. Only the first
+/// call starts anything; the thread stops once the manager is dropped
+pub fn start(manager: &Arc<RwLock<Manager>>, config: GossipConfig) {
+    let started = manager
+        .write()
+        .map(|mut manager| manager.start_gossiping(config.clone()))
+        .unwrap_or(false);
+    if !started {
+        return;
+    }
+    let manager = Arc::downgrade(manager);
+    thread::spawn(move || loop {
+        thread::sleep(config.interval);
+        let manager = match manager.upgrade() {
+            Some(manager) => manager,
+            None => return,
+        };
+        let mut lock = manager.write().unwrap_or_else(|e| e.into_inner());
+        let members = lock.membership_view();
+        send_to_random(
+            &mut lock,
+            &IridiumMessage::Gossip { members },
+            config.fanout,
+            None,
+        );
+    });
+}
+
+/// Merge a view gossiped by `from`, pass on whatever was news to other members, and connect
+/// to members heard of for the first time
+pub fn receive(manager: &Arc<RwLock<Manager>>, from: &str, members: Vec<GossipEntry>) {
+    let mut lock = manager.write().unwrap_or_else(|e| e.into_inner());
+    let changed = lock.merge_view(members);
+    if changed.is_empty() {
+        return;
+    }
+    debug!("Gossip from {} changed {} members", from, changed.len());
+    let local = lock.local_node().cloned();
+    let unconnected: Vec<GossipEntry> = match &local {
+        Some(local) => changed
+            .iter()
+            // only one side of each pair connects, so the two don't race each other
+            .filter(|entry| entry.alive && local.alias < entry.alias)
+            .filter(|entry| !lock.has_client(&entry.alias))
+            .cloned()
+            .collect(),
+        None => vec![],
+    };
+    let fanout = lock.gossip_config().cloned().unwrap_or_default().fanout;
+    let news = IridiumMessage::Gossip { members: changed };
+    send_to_random(&mut lock, &news, fanout, Some(from));
+    drop(lock);
+
+    let local = match local {
+        Some(local) => local,
+        None => return,
+    };
+    for entry in unconnected {
+        let manager = manager.clone();
+        let local = local.clone();
+        thread::spawn(move || {
+            let addr = address::join_host_port(&entry.host, &entry.port);
+            if let Err(e) = join::connect(&local, &manager, &addr) {
+                warn!("Unable to connect to cluster member {}: {}", entry.alias, e);
+            }
+        });
+    }
+}
+
+/// Send to up to `count` members picked at random, leaving out `except`
+fn send_to_random(manager: &mut Manager, msg: &IridiumMessage, count: usize, except: Option<&str>) {
+    let mut candidates: Vec<NodeAlias> = manager
+        .get_client_names()
+        .into_iter()
+        .filter(|alias| Some(alias.as_str()) != except)
+        .collect();
+    for _ in 0..count.min(candidates.len()) {
+        let picked = (Uuid::new_v4().as_u128() % candidates.len() as u128) as usize;
+        let alias = candidates.swap_remove(picked);
+        if let Err(e) = manager.send_to(&alias, msg) {
+            debug!("Unable to gossip with {}: {}", alias, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::{
+        cluster::{cluster_server::ClusterServer, join::LocalNode},
+        scheduler::Scheduler,
+    };
+
+    fn start_node(alias: &str, config: &GossipConfig) -> (LocalNode, Arc<RwLock<Manager>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let local = LocalNode {
+            alias: alias.to_string(),
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port().to_string(),
+            metadata: Default::default(),
+            scheduler: Arc::new(Scheduler::new()),
+            output: None,
+            secret: None,
+            transport: Default::default(),
+        };
+        let manager = Arc::new(RwLock::new(Manager::new()));
+        manager.write().unwrap().set_local_node(local.clone());
+        let mut server = ClusterServer::new(alias.to_string(), manager.clone());
+        thread::spawn(move || server.listen_on(listener));
+        start(&manager, config.clone());
+        (local, manager)
+    }
+
+    fn alive(manager: &Arc<RwLock<Manager>>) -> Vec<String> {
+        let view = manager.read().unwrap().membership_view();
+        let mut aliases: Vec<_> = view
+            .into_iter()
+            .filter(|e| e.alive)
+            .map(|e| e.alias)
+            .collect();
+        aliases.sort();
+        aliases
+    }
+
+    fn entry(alias: &str, incarnation: u64, alive: bool) -> GossipEntry {
+        GossipEntry {
+            alias: alias.to_string(),
+            host: "127.0.0.1".to_string(),
+            port: "2254".to_string(),
+            incarnation,
+            alive,
+        }
+    }
+
+    #[test]
+    fn test_separate_pairs_converge() {
+        let config = GossipConfig {
+            interval: Duration::from_millis(20),
+            fanout: 2,
+        };
+        let nodes: Vec<_> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|alias| start_node(alias, &config))
+            .collect();
+        let addr = |i: usize| format!("{}:{}", nodes[i].0.host, nodes[i].0.port);
+        join::join(&nodes[1].0, &nodes[1].1, &addr(0)).unwrap();
+        join::join(&nodes[3].0, &nodes[3].1, &addr(2)).unwrap();
+        // the only link between the pairs
+        join::connect(&nodes[2].0, &nodes[2].1, &addr(1)).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        for (local, manager) in &nodes {
+            while alive(manager) != ["a", "b", "c", "d"] {
+                assert!(
+                    Instant::now() < deadline,
+                    "{} sees {:?}",
+                    local.alias,
+                    alive(manager)
+                );
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+
+    #[test]
+    fn test_tombstones_and_refutation() {
+        let (local, manager) = start_node("self", &GossipConfig::default());
+        let mut manager = manager.write().unwrap();
+
+        assert_eq!(manager.merge_view(vec![entry("x", 3, true)]).len(), 1);
+        // a tombstone beats the same incarnation, and stale news can't undo it
+        assert_eq!(manager.merge_view(vec![entry("x", 3, false)]).len(), 1);
+        assert!(manager.merge_view(vec![entry("x", 3, true)]).is_empty());
+        assert!(manager.merge_view(vec![entry("x", 2, true)]).is_empty());
+        // until the node itself comes back with a newer incarnation
+        assert_eq!(manager.merge_view(vec![entry("x", 4, true)]).len(), 1);
+
+        let own = manager.membership_view()[0].clone();
+        assert_eq!(own.alias, local.alias);
+        let refuted = manager.merge_view(vec![entry("self", own.incarnation, false)]);
+        assert_eq!(refuted.len(), 1);
+        assert!(refuted[0].alive && refuted[0].incarnation > own.incarnation);
+    }
+}
@@ -0,0 +1,71 @@
+use std::{
+    sync::{Mutex, TryLockError, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use uuid::Uuid;
+
+use crate::{common::write_message, vm::VM};
+
+use super::{
+    cluster_client::PeerWriter,
+    message::{IridiumMessage, NodeStatus},
+};
+
+/// How long answering an inspection waits for the VM, which a long command may be holding
+pub const INSPECT_LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long `!cluster_inspect` waits for an answer, long enough for a busy member to give up
+pub const INSPECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// Most recent VM events an inspection includes
+pub const INSPECT_EVENTS: usize = 10;
+
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Describe the VM behind `vm`, waiting up to `timeout` for its lock
+pub fn status(vm: &Weak<Mutex<VM>>, timeout: Duration) -> Result<NodeStatus, String> {
+    let vm = vm
+        .upgrade()
+        .ok_or_else(|| "this node has no VM to inspect".to_string())?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        match vm.try_lock() {
+            Ok(vm) => return Ok(describe(&vm)),
+            Err(TryLockError::Poisoned(e)) => return Ok(describe(&e.into_inner())),
+            Err(TryLockError::WouldBlock) if Instant::now() >= deadline => {
+                return Err(format!(
+                    "VM busy, its lock was held for more than {} ms",
+                    timeout.as_millis()
+                ))
+            }
+            Err(TryLockError::WouldBlock) => thread::sleep(LOCK_POLL_INTERVAL),
+        }
+    }
+}
+
+/// Answer the inspection `request_id` through `writer`, on a thread of its own since the
+/// VM may be busy for a while
+pub fn answer(vm: Weak<Mutex<VM>>, request_id: Uuid, writer: PeerWriter) {
+    thread::spawn(move || {
+        let response = IridiumMessage::InspectResponse {
+            request_id,
+            status: status(&vm, INSPECT_LOCK_TIMEOUT),
+        };
+        let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = write_message(&mut *writer, &response) {
+            warn!("Unable to answer inspection {}: {}", request_id, e);
+        }
+    });
+}
+
+fn describe(vm: &VM) -> NodeStatus {
+    let events = vm.events();
+    NodeStatus {
+        alias: vm.alias.clone(),
+        pc: vm.pc(),
+        program_length: vm.program.len(),
+        registers: vm.registers,
+        events: events[events.len().saturating_sub(INSPECT_EVENTS)..].to_vec(),
+    }
+}
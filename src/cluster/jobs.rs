@@ -0,0 +1,199 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::{message::TaskResult, NodeAlias};
+use crate::vm::VMEventType;
+
+/// How one member's run of a job's program turned out
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum NodeOutcome {
+    Pending,
+    Finished {
+        result: TaskResult,
+        duration: Duration, // from sending the program to getting its result back
+    },
+    Failed(String), // never answered, or its connection dropped
+}
+
+impl NodeOutcome {
+    pub fn is_pending(&self) -> bool {
+        matches!(self, NodeOutcome::Pending)
+    }
+}
+
+impl fmt::Display for NodeOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeOutcome::Pending => write!(f, "pending"),
+            NodeOutcome::Finished { result, duration } => {
+                let crashed = result.events.iter().any(|e| e.event == VMEventType::Crash);
+                let state = if crashed { "crashed" } else { "ok" };
+                write!(
+                    f,
+                    "{:<9}{:>10.2} ms",
+                    state,
+                    duration.as_secs_f64() * 1000.0
+                )?;
+                // registers still at zero say nothing about what the program did
+                for (register, value) in result.registers.iter().enumerate() {
+                    if *value != 0 {
+                        write!(f, "  ${}={}", register, value)?;
+                    }
+                }
+                Ok(())
+            }
+            NodeOutcome::Failed(reason) => write!(f, "{:<9}{}", "failed", reason),
+        }
+    }
+}
+
+/// A program sent to several members under one task id
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub task_id: Uuid,
+    pub started_at: DateTime<Utc>,
+    #[serde(skip)]
+    started: Instant,
+    pub nodes: BTreeMap<NodeAlias, NodeOutcome>, // every member the program was sent to
+}
+
+impl Job {
+    /// Whether every member has either answered or failed to
+    pub fn is_complete(&self) -> bool {
+        !self.nodes.values().any(NodeOutcome::is_pending)
+    }
+
+    /// How many members finished running the program, whether or not it crashed
+    pub fn finished(&self) -> usize {
+        self.nodes
+            .values()
+            .filter(|outcome| matches!(outcome, NodeOutcome::Finished { .. }))
+            .count()
+    }
+}
+
+/// Jobs fanned out with `!cluster_run all`, by task id
+#[derive(Debug, Default)]
+pub struct Jobs {
+    jobs: HashMap<Uuid, Job>,
+}
+
+impl Jobs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track a program sent to `nodes` as `task_id`, all of them pending
+    pub fn start(&mut self, task_id: Uuid, nodes: impl IntoIterator<Item = NodeAlias>) {
+        let job = Job {
+            task_id,
+            started_at: Utc::now(),
+            started: Instant::now(),
+            nodes: nodes
+                .into_iter()
+                .map(|alias| (alias, NodeOutcome::Pending))
+                .collect(),
+        };
+        self.jobs.insert(task_id, job);
+    }
+
+    /// Record the result `alias` sent back. True if that completed the job
+    pub fn finish(&mut self, task_id: &Uuid, alias: &str, result: TaskResult) -> bool {
+        self.settle(task_id, alias, |job| NodeOutcome::Finished {
+            result,
+            duration: job.started.elapsed(),
+        })
+    }
+
+    /// Record that `alias` won't be sending a result. True if that completed the job
+    pub fn fail(&mut self, task_id: &Uuid, alias: &str, reason: String) -> bool {
+        self.settle(task_id, alias, |_| NodeOutcome::Failed(reason))
+    }
+
+    pub fn get(&self, task_id: &Uuid) -> Option<&Job> {
+        self.jobs.get(task_id)
+    }
+
+    /// Every tracked job, oldest first
+    pub fn all(&self) -> Vec<&Job> {
+        let mut jobs: Vec<&Job> = self.jobs.values().collect();
+        jobs.sort_by_key(|job| job.started);
+        jobs
+    }
+
+    /// Only a pending member is settled, so whatever happened first to it sticks
+    fn settle(
+        &mut self,
+        task_id: &Uuid,
+        alias: &str,
+        outcome: impl FnOnce(&Job) -> NodeOutcome,
+    ) -> bool {
+        let job = match self.jobs.get_mut(task_id) {
+            Some(job) => job,
+            None => return false,
+        };
+        if !job.nodes.get(alias).is_some_and(NodeOutcome::is_pending) {
+            return false;
+        }
+        let outcome = outcome(job);
+        job.nodes.insert(alias.to_string(), outcome);
+        job.is_complete()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VMEvent;
+
+    fn result(task_id: Uuid, crashed: bool) -> TaskResult {
+        let mut registers = [0; 32];
+        registers[2] = 7;
+        TaskResult {
+            task_id,
+            events: vec![VMEvent {
+                event: if crashed {
+                    VMEventType::Crash
+                } else {
+                    VMEventType::Stop
+                },
+                at: Utc::now(),
+                app_id: Uuid::new_v4(),
+                context: None,
+            }],
+            registers,
+        }
+    }
+
+    #[test]
+    fn test_job_completes_once_every_node_settles() {
+        let mut jobs = Jobs::new();
+        let task_id = Uuid::new_v4();
+        jobs.start(task_id, ["a", "b", "c"].map(String::from));
+
+        assert!(!jobs.finish(&task_id, "a", result(task_id, false)));
+        assert!(!jobs.finish(&task_id, "b", result(task_id, true)));
+        // a result from a member the program wasn't sent to changes nothing
+        assert!(!jobs.finish(&task_id, "stranger", result(task_id, false)));
+        let job = jobs.get(&task_id).unwrap();
+        assert!(!job.is_complete());
+        assert_eq!(job.nodes["c"].to_string(), "pending");
+
+        assert!(jobs.fail(&task_id, "c", "timed out".to_string()));
+        // and a straggler turning up late doesn't undo the failure
+        assert!(!jobs.finish(&task_id, "c", result(task_id, false)));
+        let job = jobs.get(&task_id).unwrap();
+        assert_eq!(job.finished(), 2);
+        assert!(job.nodes["a"].to_string().starts_with("ok "));
+        assert!(job.nodes["a"].to_string().ends_with("  $2=7"));
+        assert!(job.nodes["b"].to_string().starts_with("crashed "));
+        assert_eq!(job.nodes["c"].to_string(), "failed   timed out");
+    }
+}
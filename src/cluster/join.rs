@@ -0,0 +1,442 @@
+use std::sync::{mpsc::Sender, Arc, RwLock, Weak};
+
+use log::{info, warn};
+
+use crate::{error::Result, scheduler::Scheduler};
+
+use super::{
+    address,
+    cluster_client::{answer, ClusterClient},
+    cluster_server::ClusterServer,
+    event_log::ReportedEvent,
+    gossip, inspect,
+    manager::Manager,
+    message::{IridiumMessage, NodeMetadata, Welcome},
+    role::NodeRole,
+    transport::Transport,
+    NodeAddress, NodeAlias,
+};
+
+/// How this node introduces itself to the rest of the cluster
+#[derive(Debug, Clone)]
+pub struct LocalNode {
+    pub alias: NodeAlias,
+    pub host: String, // where this node's cluster server listens
+    pub port: String,
+    pub metadata: NodeMetadata, // what this node says about itself in its hellos
+    pub scheduler: Arc<Scheduler>, // runs programs members submit over these connections
+    pub output: Option<Sender<String>>, // where these connections report what arrives, stdout if None
+    pub secret: Option<String>,         // shared cluster secret sent in hellos
+    pub transport: Transport,           // what these connections are carried over
+}
+
+/// Join the cluster through the node at `addr`, then connect to every other member it
+/// lists so the cluster is a full mesh. Members that can't be reached are skipped.
+/// Returns the alias of the node joined through and the members it listed
+pub fn join(
+    local: &LocalNode,
+    manager: &Arc<RwLock<Manager>>,
+    addr: &str,
+) -> Result<(NodeAlias, Vec<NodeAddress>)> {
+    let (hub, nodes) = connect(local, manager, addr)?;
+    for (alias, host, port) in &nodes {
+        let known = manager.read().map(|m| m.has_client(alias)).unwrap_or(false);
+        if *alias == local.alias || known {
+            continue;
+        }
+        if let Err(e) = connect(local, manager, &address::join_host_port(host, port)) {
+            warn!("Unable to connect to cluster member {}: {}", alias, e);
+        }
+    }
+
+    Ok((hub, nodes))
+}
+
+/// Say hello to the node at `addr` and register it under the alias from its HelloAck
+pub(crate) fn connect(
+    local: &LocalNode,
+    manager: &Arc<RwLock<Manager>>,
+    addr: &str,
+) -> Result<(NodeAlias, Vec<NodeAddress>)> {
+    let stream = address::connect(addr)?;
+    let reached = stream.peer_addr()?;
+    let mut client = ClusterClient::new(stream)?
+        .with_peer_listen(reached.ip().to_string(), reached.port().to_string())
+        .with_alias(local.alias.clone())
+        .with_announce(local.host.clone(), local.port.clone())
+        .with_metadata(local.metadata.clone())
+        .with_secret(local.secret.clone())
+        .with_scheduler(local.scheduler.clone())
+        .with_transport(local.transport.clone())
+        .with_delivered(
+            manager
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .delivered(),
+        );
+    if let Some(output) = &local.output {
+        client = client.with_output(output.clone());
+    }
+    client.send_hello()?;
+    client.read()?;
+    let Welcome {
+        alias,
+        nodes,
+        mut metadata,
+    } = client.read_hello_ack()?;
+    let notifier = manager
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .disconnect_notifier();
+    let mut client = client.with_liveness(alias.clone(), notifier);
+    let scheduler = local.scheduler.clone();
+    let role = local.metadata.role.unwrap_or_default();
+    let local_alias = local.alias.clone();
+    let writer = client.writer();
+    let peer = alias.clone();
+    let members = Arc::downgrade(manager);
+    client.spawn_reader(move |msg| {
+        if let Some(manager) = members.upgrade() {
+            manager
+                .write()
+                .unwrap_or_else(|e| e.into_inner())
+                .touch(&peer);
+        }
+        match msg {
+            IridiumMessage::NewMember { alias, host, port } => {
+                info!("Node {} at {}:{} joined the cluster", alias, host, port)
+            }
+            IridiumMessage::Gossip { members: view } => {
+                if let Some(manager) = members.upgrade() {
+                    gossip::receive(&manager, &peer, view);
+                }
+            }
+            IridiumMessage::Goodbye { .. } => {
+                info!("Cluster member {} is leaving", peer);
+                if let Some(manager) = members.upgrade() {
+                    let mut manager = manager.write().unwrap_or_else(|e| e.into_inner());
+                    manager.del_client(peer.clone());
+                }
+            }
+            IridiumMessage::ExecuteProgram { program, task_id } => {
+                match role.program_refusal(&local_alias) {
+                    Some(reason) => {
+                        answer(&writer, &IridiumMessage::ExecuteRefused { task_id, reason })
+                    }
+                    None => ClusterServer::run_task(&scheduler, program, task_id, writer.clone()),
+                }
+            }
+            IridiumMessage::InspectRequest { request_id } => {
+                let (vm, peer_role) = match members.upgrade() {
+                    Some(manager) => {
+                        let manager = manager.read().unwrap_or_else(|e| e.into_inner());
+                        (manager.vm(), manager.role_of(&peer))
+                    }
+                    None => (Weak::new(), NodeRole::default()),
+                };
+                match role.inspection_refusal(&local_alias, peer_role) {
+                    Some(reason) => answer(
+                        &writer,
+                        &IridiumMessage::InspectResponse {
+                            request_id,
+                            status: Err(reason),
+                        },
+                    ),
+                    None => inspect::answer(vm, request_id, writer.clone()),
+                }
+            }
+            IridiumMessage::EventReport { alias, event } => {
+                if let Some(manager) = members.upgrade() {
+                    Manager::record_event(&manager, ReportedEvent { alias, event });
+                }
+            }
+            msg => warn!("Unexpected cluster message: {:?}", msg),
+        }
+    })?;
+    let peer_metadata = metadata.remove(&alias);
+    if let Some(id) = peer_metadata.as_ref().and_then(|metadata| metadata.vm_id) {
+        client = client.with_peer_id(id);
+    }
+    let mut manager = manager.write().unwrap_or_else(|e| e.into_inner());
+    if manager.add_client(alias.clone(), client) {
+        if let Some(metadata) = peer_metadata {
+            manager.set_metadata(&alias, metadata);
+        }
+    }
+
+    Ok((alias, nodes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::BufReader,
+        net::{SocketAddr, TcpListener, TcpStream},
+        thread::{self, JoinHandle},
+        time::{Duration, Instant},
+    };
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        assembler::Assembler,
+        cluster::{
+            events::MembershipEventKind,
+            message::{Handshake, HandshakeResponse, HelloResponse},
+            reconnect::ReconnectPolicy,
+        },
+        common::{read_message, write_message, MAX_FRAME_LENGTH},
+        remote::test_util::wait_for,
+    };
+
+    /// Start a node's cluster server on an ephemeral port
+    fn start(alias: &str) -> (LocalNode, Arc<RwLock<Manager>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let manager = Arc::new(RwLock::new(Manager::new()));
+        let metadata = NodeMetadata {
+            logical_cores: Some(4),
+            vm_id: Some(Uuid::new_v4()),
+            role: None,
+        };
+        let mut server =
+            ClusterServer::new(alias.to_string(), manager.clone()).with_metadata(metadata.clone());
+        thread::spawn(move || server.listen_on(listener));
+        let local = LocalNode {
+            alias: alias.to_string(),
+            host: "127.0.0.1".to_string(),
+            port,
+            metadata,
+            scheduler: Arc::new(Scheduler::new()),
+            output: None,
+            secret: None,
+            transport: Transport::plain(),
+        };
+        manager.write().unwrap().set_local_node(local.clone());
+        (local, manager)
+    }
+
+    /// Answer one hello as node `a`, then crash, closing the listener too
+    fn short_lived_member(listener: TcpListener) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let handshake: Option<Handshake> = read_message(&mut reader, MAX_FRAME_LENGTH).unwrap();
+            let version = handshake.unwrap().version;
+            let accepted = HandshakeResponse::Accepted {
+                version,
+                encrypted: false,
+            };
+            write_message(&mut stream, &accepted).unwrap();
+            let hello: Option<IridiumMessage> =
+                read_message(&mut reader, MAX_FRAME_LENGTH).unwrap();
+            assert!(hello.is_some());
+            write_message(&mut stream, &HelloResponse::Ok("hello".to_string())).unwrap();
+            let ack = IridiumMessage::HelloAck {
+                alias: "a".to_string(),
+                nodes: vec![],
+                metadata: HashMap::new(),
+            };
+            write_message(&mut stream, &ack).unwrap();
+        })
+    }
+
+    /// Start `local` reconnecting quickly, then join the short lived member at `addr`
+    fn join_short_lived(
+        local: &LocalNode,
+        manager: &Arc<RwLock<Manager>>,
+        addr: SocketAddr,
+        max_attempts: Option<u32>,
+    ) {
+        let listener = TcpListener::bind(addr).unwrap();
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(20),
+            max_delay: Duration::from_millis(100),
+            max_attempts,
+        };
+        let mut lock = manager.write().unwrap();
+        lock.set_local_node(local.clone());
+        lock.enable_reconnect(policy);
+        drop(lock);
+        Manager::supervise(manager);
+        let crashed = short_lived_member(listener);
+        join(local, manager, &addr.to_string()).unwrap();
+        crashed.join().unwrap();
+    }
+
+    fn members(manager: &Arc<RwLock<Manager>>) -> Vec<String> {
+        let mut names = manager.read().unwrap().get_client_names();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_join_forms_full_mesh() {
+        let (a, a_manager) = start("a");
+        let (b, b_manager) = start("b");
+        let (c, c_manager) = start("c");
+        let hub = format!("{}:{}", a.host, a.port);
+
+        join(&b, &b_manager, &hub).unwrap();
+        let (joined, nodes) = join(&c, &c_manager, &hub).unwrap();
+        assert_eq!(joined, "a");
+        assert_eq!(nodes.len(), 1);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while members(&b_manager) != ["a", "c"] {
+            assert!(Instant::now() < deadline, "{:?}", members(&b_manager));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(members(&a_manager), ["b", "c"]);
+        assert_eq!(members(&c_manager), ["a", "b"]);
+        // metadata arrives with hellos on one side and HelloAcks on the other
+        for (manager, node) in [
+            (&a_manager, &b),
+            (&b_manager, &a),
+            (&b_manager, &c),
+            (&c_manager, &b),
+        ] {
+            let metadata = manager.read().unwrap().get_metadata(&node.alias).cloned();
+            assert_eq!(metadata.as_ref(), Some(&node.metadata));
+        }
+
+        // programs run both ways, whichever side opened the connection
+        let program = Assembler::new()
+            .assemble(".data\n.code\nload $0 #42")
+            .unwrap();
+        for (manager, peer) in [(&a_manager, "b"), (&b_manager, "a")] {
+            let (_, result) = manager
+                .write()
+                .unwrap()
+                .get_client_mut(peer)
+                .unwrap()
+                .submit(program.clone())
+                .unwrap();
+            let result = result.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(result.registers[0], 42);
+        }
+    }
+
+    #[test]
+    fn test_join_with_taken_alias_fails() {
+        let (a, a_manager) = start("a");
+        let (first, first_manager) = start("b");
+        let (second, second_manager) = start("b");
+        let hub = format!("{}:{}", a.host, a.port);
+
+        join(&first, &first_manager, &hub).unwrap();
+        let err = join(&second, &second_manager, &hub)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Alias b already in use"), "{}", err);
+        assert!(members(&second_manager).is_empty());
+        assert_eq!(members(&a_manager), ["b"]);
+    }
+
+    #[test]
+    fn test_join_skips_unreachable_members() {
+        let (a, a_manager) = start("a");
+        let (b, b_manager) = start("b");
+        let stale = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stale_port = stale.local_addr().unwrap().port().to_string();
+        drop(stale);
+        // a registers a member whose listener is gone
+        let ghost = TcpStream::connect(format!("{}:{}", a.host, a.port)).unwrap();
+        let mut ghost = ClusterClient::new(ghost)
+            .unwrap()
+            .with_alias("ghost".to_string())
+            .with_announce("127.0.0.1".to_string(), stale_port);
+        ghost.send_hello().unwrap();
+        ghost.read().unwrap();
+
+        let (_, nodes) = join(&b, &b_manager, &format!("{}:{}", a.host, a.port)).unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(members(&b_manager), ["a"]);
+        assert_eq!(members(&a_manager), ["b", "ghost"]);
+    }
+
+    #[test]
+    fn test_reconnects_after_member_restarts() {
+        let (b, b_manager) = start("b");
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        join_short_lived(&b, &b_manager, addr, None);
+
+        wait_for("a to be dropped", || members(&b_manager).is_empty());
+        let reconnecting = b_manager.read().unwrap().get_reconnecting();
+        assert_eq!(reconnecting.len(), 1);
+        assert_eq!(reconnecting[0].0 .0, "a");
+
+        // a comes back on the same port
+        let listener = TcpListener::bind(addr).unwrap();
+        let mut a = ClusterServer::new("a".to_string(), Arc::new(RwLock::new(Manager::new())));
+        thread::spawn(move || a.listen_on(listener));
+        wait_for("a to reappear", || members(&b_manager) == ["a"]);
+        assert!(b_manager.read().unwrap().get_reconnecting().is_empty());
+    }
+
+    #[test]
+    fn test_reconnect_gives_up() {
+        let (b, b_manager) = start("b");
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        join_short_lived(&b, &b_manager, addr, Some(2));
+
+        wait_for("b to give up", || {
+            let manager = b_manager.read().unwrap();
+            manager.client_count() == 0 && manager.get_reconnecting().is_empty()
+        });
+    }
+
+    #[test]
+    fn test_goodbye_removes_member_at_once() {
+        let (a, a_manager) = start("a");
+        let (b, b_manager) = start("b");
+        let (c, c_manager) = start("c");
+        let hub = format!("{}:{}", a.host, a.port);
+        join(&b, &b_manager, &hub).unwrap();
+        join(&c, &c_manager, &hub).unwrap();
+        wait_for("the mesh", || members(&b_manager) == ["a", "c"]);
+
+        // c opened its connections, so its goodbyes reach the other servers
+        assert_eq!(Manager::leave_cluster(&c_manager), 2);
+        assert!(members(&c_manager).is_empty());
+        wait_for("c to leave", || {
+            members(&a_manager) == ["b"] && members(&b_manager) == ["a"]
+        });
+        // and a's reaches the reader of b's connection to it
+        assert_eq!(Manager::leave_cluster(&a_manager), 1);
+        wait_for("a to leave", || members(&b_manager).is_empty());
+        let history = b_manager.read().unwrap().membership_history();
+        let last = history.last().unwrap();
+        assert_eq!(
+            (last.alias.as_str(), last.kind),
+            ("a", MembershipEventKind::Left)
+        );
+    }
+
+    #[test]
+    fn test_received_messages_reported_on_output() {
+        let (a, a_manager) = start("a");
+        let (mut b, b_manager) = start("b");
+        let (output, lines) = std::sync::mpsc::channel();
+        b.output = Some(output);
+
+        join(&b, &b_manager, &format!("{}:{}", a.host, a.port)).unwrap();
+        let hello = lines.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(hello.starts_with("[cluster 127.0.0.1:"), "{}", hello);
+        assert!(hello.ends_with("Received hello from node b"), "{}", hello);
+        let welcome = lines.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(welcome, "[cluster a] welcome from a, 0 other members");
+
+        Manager::leave_cluster(&a_manager);
+        let goodbye = lines.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(goodbye, "[cluster a] a is leaving");
+    }
+}
@@ -1,52 +1,1000 @@
-use std::collections::HashMap;
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, RwLock, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use log::error;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::Serialize;
+use uuid::Uuid;
 
-use super::{cluster_client::ClusterClient, NodeAlias};
+use crate::{
+    common::{encode_message, write_message},
+    error::{IridiumError, Result},
+    vm::VM,
+};
 
-#[derive(Default)]
+use super::{
+    ack::Delivered,
+    cluster_client::{ClusterClient, PeerWriter, Pong},
+    event_log::{self, EventLog, ReportedEvent},
+    events::{MembershipEvent, MembershipEventKind, MembershipLog},
+    gossip::GossipConfig,
+    join::LocalNode,
+    message::{GossipEntry, IridiumMessage, NodeMetadata, NodeStatus},
+    reconnect::{self, ReconnectPolicy},
+    role::NodeRole,
+    Departure, NodeAddress, NodeAlias,
+};
+
+/// How long leaving may spend saying goodbye, after which members not yet told are dropped
+pub const GOODBYE_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long `!ping` waits for members to answer
+pub const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where a cluster member listens, and when this node has heard from it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NodeInfo {
+    pub alias: NodeAlias,
+    pub host: String, // where the member listens for peers, as it said in its hello
+    pub port: String,
+    pub connected_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>, // last time a message arrived from it
+}
+
+/// A connected member, by the alias it goes by
+struct Member {
+    alias: NodeAlias,
+    client: ClusterClient,
+}
+
+/// Cluster members, each keyed by the VM id it said it runs so that two nodes configured
+/// with the same alias can't be mistaken for one another. Aliases name members to users
 pub struct Manager {
-    clients: HashMap<String, ClusterClient>,
+    members: HashMap<Uuid, Member>,
+    aliases: HashMap<NodeAlias, Uuid>, // id of the member going by each alias
+    nodes: HashMap<Uuid, NodeInfo>,    // where each client listens, and when it was heard from
+    metadata: HashMap<Uuid, NodeMetadata>, // what each client said about itself
+    disconnects: Sender<Departure>,    // readers report the node whose connection dropped
+    departures: Option<Mutex<Receiver<Departure>>>, // taken by the supervisor once it starts
+    local: Option<LocalNode>, // how this node introduces itself when it connects to members
+    reconnect: Option<ReconnectPolicy>, // how to reach lost members again, if at all
+    reconnecting: HashMap<NodeAlias, (String, String, u32)>, // last known host and port of lost members, and failed attempts so far
+    membership: MembershipLog,
+    incarnation: u64, // this node's own, raised whenever the cluster believes it gone
+    view: HashMap<NodeAlias, GossipEntry>, // every other node this one has heard of
+    gossip: Option<GossipConfig>, // set once the gossip thread has started
+    vm: Weak<Mutex<VM>>, // what members inspecting this node are shown
+    loads: HashMap<Uuid, usize>, // programs each member was running when it last said
+    saving_to: Option<PathBuf>, // file membership is saved to, once that has started
+    events: EventLog, // VM events members reported to this node as coordinator
+    delivered: Arc<Mutex<Delivered>>, // reliable messages already handled, over any connection
+}
+
+impl Default for Manager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Manager {
     pub fn new() -> Manager {
+        let (disconnects, departures) = channel();
         Manager {
-            clients: HashMap::new(),
+            members: HashMap::new(),
+            aliases: HashMap::new(),
+            nodes: HashMap::new(),
+            metadata: HashMap::new(),
+            disconnects,
+            departures: Some(Mutex::new(departures)),
+            local: None,
+            reconnect: None,
+            reconnecting: HashMap::new(),
+            membership: MembershipLog::default(),
+            // a restarted node starts above whatever the cluster remembers of its last run
+            incarnation: Utc::now().timestamp_millis().max(0) as u64,
+            view: HashMap::new(),
+            gossip: None,
+            vm: Weak::new(),
+            loads: HashMap::new(),
+            saving_to: None,
+            events: EventLog::default(),
+            delivered: Arc::new(Mutex::new(Delivered::default())),
+        }
+    }
+
+    /// Introduce this node as `local` when connecting to members on its own
+    pub fn set_local_node(&mut self, local: LocalNode) {
+        self.local = Some(local);
+    }
+
+    /// How this node introduces itself, once known
+    pub fn local_node(&self) -> Option<&LocalNode> {
+        self.local.as_ref()
+    }
+
+    /// Show members inspecting this node the VM behind `vm`
+    pub fn set_vm(&mut self, vm: Weak<Mutex<VM>>) {
+        self.vm = vm;
+    }
+
+    /// The VM members inspecting this node are shown, if it is still around
+    pub fn vm(&self) -> Weak<Mutex<VM>> {
+        self.vm.clone()
+    }
+
+    /// Try to reach members whose connection drops again, following `policy`. Needs the
+    /// local node to be set
+    pub fn enable_reconnect(&mut self, policy: ReconnectPolicy) {
+        self.reconnect = Some(policy);
+    }
+
+    /// Start removing clients whose connection drops, as reported through
+    /// `disconnect_notifier`, and reconnecting to them if enabled. Only the first call
+    /// starts anything
+    pub fn supervise(manager: &Arc<RwLock<Manager>>) {
+        let departures = match manager.write() {
+            Ok(mut lock) => lock.departures.take(),
+            Err(_) => None,
+        };
+        let departures = match departures {
+            Some(departures) => departures.into_inner().unwrap_or_else(|e| e.into_inner()),
+            None => return,
+        };
+        // the manager owns the sender, so a strong reference here would keep both alive forever
+        let manager = Arc::downgrade(manager);
+        thread::spawn(move || {
+            for (alias, connection) in departures {
+                let manager = match manager.upgrade() {
+                    Some(manager) => manager,
+                    None => return,
+                };
+                let mut lock = manager.write().unwrap_or_else(|e| e.into_inner());
+                let client = match lock.get_client(&alias) {
+                    Some(client) if client.connection_id() == connection => client,
+                    // a connection that was replaced, or never registered
+                    _ => continue,
+                };
+                info!("Lost connection to cluster member {}", alias);
+                let listen = client.peer_listen().cloned();
+                lock.evict_client(&alias);
+                drop(lock);
+                if let Some((host, port)) = listen {
+                    Manager::reconnect_to(&manager, alias, host, port);
+                }
+            }
+        });
+    }
+
+    /// Keep trying to reach the member `alias` at `host:port` in the background, following
+    /// the reconnect policy. False if reconnecting isn't enabled, or the member is connected
+    /// or being reconnected to already
+    pub fn reconnect_to(
+        manager: &Arc<RwLock<Manager>>,
+        alias: NodeAlias,
+        host: String,
+        port: String,
+    ) -> bool {
+        let mut lock = manager.write().unwrap_or_else(|e| e.into_inner());
+        let (local, policy) = match lock.local.clone().zip(lock.reconnect.clone()) {
+            Some(reconnect) => reconnect,
+            None => return false,
+        };
+        if lock.has_client(&alias) || lock.is_reconnecting(&alias) {
+            return false;
         }
+        lock.reconnecting
+            .insert(alias.clone(), (host.clone(), port.clone(), 0));
+        drop(lock);
+        reconnect::spawn(Arc::downgrade(manager), alias, host, port, local, policy);
+        true
+    }
+
+    /// Channel on which to report that the connection to a client has dropped
+    pub fn disconnect_notifier(&self) -> Sender<Departure> {
+        self.disconnects.clone()
     }
 
-    /// Adds a client as cluster member
+    /// Adds a client as cluster member, keyed by the VM id its node said it runs. A node
+    /// that never said gets an id of its own, so it is never taken for another
     pub fn add_client(&mut self, alias: NodeAlias, client: ClusterClient) -> bool {
-        if self.clients.contains_key(&alias) {
+        if self.aliases.contains_key(&alias) {
             error!("Tried to add a client that already existed");
             return false;
         }
-        self.clients.insert(alias, client);
+        let id = client.peer_id().unwrap_or_else(Uuid::new_v4);
+        if let Some(member) = self.members.get(&id) {
+            error!(
+                "Tried to add {} as {}, which is a member already",
+                alias, member.alias
+            );
+            return false;
+        }
+        self.reconnecting.remove(&alias);
+        self.membership.record(&alias, MembershipEventKind::Joined);
+        if let Ok((host, port)) = client.peer_listen_addr() {
+            let now = Utc::now();
+            let info = NodeInfo {
+                alias: alias.clone(),
+                host,
+                port,
+                connected_at: now,
+                last_seen: now,
+            };
+            self.nodes.insert(id, info);
+        }
+        if let Some((host, port)) = client.peer_listen() {
+            // connected right now, whatever gossip said before
+            let entry = self.view.entry(alias.clone()).or_insert(GossipEntry {
+                alias: alias.clone(),
+                host: host.clone(),
+                port: port.clone(),
+                incarnation: 0,
+                alive: true,
+            });
+            entry.alive = true;
+        }
+        self.aliases.insert(alias.clone(), id);
+        self.members.insert(id, Member { alias, client });
         true
     }
 
+    /// Members being reconnected to: where they were last seen and how many attempts failed
+    pub fn get_reconnecting(&self) -> Vec<(NodeAddress, u32)> {
+        self.reconnecting
+            .iter()
+            .map(|(alias, (host, port, attempts))| {
+                (
+                    (alias.to_owned(), host.to_owned(), port.to_owned()),
+                    *attempts,
+                )
+            })
+            .collect()
+    }
+
+    /// If `alias` is still to be reconnected to
+    pub fn is_reconnecting(&self, alias: &str) -> bool {
+        self.reconnecting.contains_key(alias)
+    }
+
+    /// Record another failed attempt to reconnect to `alias`
+    pub fn set_reconnect_attempts(&mut self, alias: &str, attempts: u32) {
+        if let Some(entry) = self.reconnecting.get_mut(alias) {
+            entry.2 = attempts;
+        }
+    }
+
+    /// Stop listing `alias` as reconnecting, whether it came back or was given up on
+    pub fn stop_reconnecting(&mut self, alias: &str) {
+        self.reconnecting.remove(alias);
+    }
+
+    /// Register a client, closing the connection of any already under its alias. For a node
+    /// that reconnected before its old connection was noticed dropping
+    pub fn replace_client(&mut self, alias: NodeAlias, client: ClusterClient) {
+        if let Some(old) = self.get_client(&alias) {
+            info!(
+                "Cluster member {} reconnected, replacing its old connection",
+                alias
+            );
+            old.close();
+            self.forget_member(&alias);
+        }
+        self.add_client(alias, client);
+    }
+
     /// Delete a client by alias
     pub fn del_client(&mut self, alias: NodeAlias) -> bool {
-        if !self.clients.contains_key(&alias) {
+        self.remove_client(&alias, MembershipEventKind::Left)
+    }
+
+    /// Delete a client whose connection dropped
+    pub fn evict_client(&mut self, alias: &str) -> bool {
+        self.remove_client(alias, MembershipEventKind::Evicted)
+    }
+
+    fn remove_client(&mut self, alias: &str, kind: MembershipEventKind) -> bool {
+        if !self.forget_member(alias) {
             error!("Tried to delete a client that doesn't exist");
             return false;
         }
-        self.clients.remove(&alias);
+        self.membership.record(alias, kind);
+        if let Some(entry) = self.view.get_mut(alias) {
+            entry.alive = false;
+        }
+        true
+    }
+
+    /// Drop everything known of the member going by `alias`, false if there is none
+    fn forget_member(&mut self, alias: &str) -> bool {
+        let id = match self.aliases.remove(alias) {
+            Some(id) => id,
+            None => return false,
+        };
+        self.members.remove(&id);
+        self.nodes.remove(&id);
+        self.metadata.remove(&id);
+        self.loads.remove(&id);
+        true
+    }
+
+    /// Everything this node knows of the cluster, itself included once the local node is set
+    pub fn membership_view(&self) -> Vec<GossipEntry> {
+        let own = self.local.as_ref().map(|local| GossipEntry {
+            alias: local.alias.clone(),
+            host: local.host.clone(),
+            port: local.port.clone(),
+            incarnation: self.incarnation,
+            alive: true,
+        });
+        own.into_iter().chain(self.view.values().cloned()).collect()
+    }
+
+    /// Take in another member's view, returning the entries that changed this one. News of
+    /// this node's own death is refuted by raising its incarnation above it
+    pub fn merge_view(&mut self, entries: Vec<GossipEntry>) -> Vec<GossipEntry> {
+        let own_alias = self.local.as_ref().map(|local| local.alias.clone());
+        let mut changed = vec![];
+        for entry in entries {
+            if Some(&entry.alias) == own_alias.as_ref() {
+                if entry.incarnation >= self.incarnation && !entry.alive {
+                    self.incarnation = entry.incarnation + 1;
+                    info!("Refuting report that this node left the cluster");
+                    changed.extend(self.membership_view().into_iter().take(1));
+                }
+                continue;
+            }
+            let newer = match self.view.get(&entry.alias) {
+                Some(known) => entry.supersedes(known),
+                None => true,
+            };
+            if newer {
+                self.view.insert(entry.alias.clone(), entry.clone());
+                changed.push(entry);
+            }
+        }
+        changed
+    }
+
+    /// Record that the gossip thread started with `config`, returning false if one already had
+    pub fn start_gossiping(&mut self, config: GossipConfig) -> bool {
+        if self.gossip.is_some() {
+            return false;
+        }
+        self.gossip = Some(config);
+        true
+    }
+
+    /// Note that membership is saved to `path` from now on. False if it already was being
+    /// saved, to this file or another
+    pub fn start_saving(&mut self, path: PathBuf) -> bool {
+        if self.saving_to.is_some() {
+            return false;
+        }
+        self.saving_to = Some(path);
         true
     }
 
+    /// What the gossip thread was started with, if it was
+    pub fn gossip_config(&self) -> Option<&GossipConfig> {
+        self.gossip.as_ref()
+    }
+
+    /// Tell every member this node is leaving and drop them all, without reconnecting.
+    /// Goodbyes go to every member at once and aren't acknowledged; members not told within
+    /// `GOODBYE_TIMEOUT` are dropped anyway. Returns how many were told
+    ///
+    /// The goodbyes are sent without holding the manager, as connections handed to the
+    /// server need it to take them in
+    pub fn leave_cluster(manager: &Arc<RwLock<Manager>>) -> usize {
+        let (goodbye, writers) = {
+            let mut manager = manager.write().unwrap_or_else(|e| e.into_inner());
+            let own = manager.local.as_ref().map(|local| local.alias.clone());
+            manager.reconnect = None;
+            manager.reconnecting.clear();
+            let writers: Vec<_> = manager
+                .members
+                .values()
+                .map(|member| {
+                    let _ = member.client.set_write_timeout(Some(GOODBYE_TIMEOUT));
+                    (member.alias.clone(), member.client.writer())
+                })
+                .collect();
+            let goodbye = IridiumMessage::Goodbye {
+                alias: own.unwrap_or_default(),
+            };
+            (goodbye, writers)
+        };
+        let deadline = Instant::now() + GOODBYE_TIMEOUT;
+        let members = writers.len();
+        let (tx, rx) = channel();
+        for (alias, writer) in writers {
+            let (tx, goodbye) = (tx.clone(), goodbye.clone());
+            // another send may be holding the writer, so waiting on it is bounded below
+            thread::spawn(move || {
+                let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+                let sent = write_message(&mut *writer, &goodbye);
+                if let Err(e) = &sent {
+                    warn!("Unable to say goodbye to {}: {}", alias, e);
+                }
+                let _ = tx.send(sent.is_ok());
+            });
+        }
+        let mut told = 0;
+        for _ in 0..members {
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(sent) => told += usize::from(sent),
+                Err(_) => break,
+            }
+        }
+        let mut manager = manager.write().unwrap_or_else(|e| e.into_inner());
+        for alias in manager.get_client_names() {
+            if let Some(client) = manager.get_client(&alias) {
+                client.close();
+            }
+            manager.del_client(alias);
+        }
+        told
+    }
+
+    /// Channel receiving every membership change from now on
+    pub fn subscribe_membership(&mut self) -> Receiver<MembershipEvent> {
+        let (tx, rx) = channel();
+        self.membership.subscribe(tx);
+        rx
+    }
+
+    /// Recent membership changes, oldest first
+    pub fn membership_history(&self) -> Vec<MembershipEvent> {
+        self.membership.history()
+    }
+
+    /// Keep a VM event a member reported, this node being its coordinator. It is appended
+    /// to the event log file after the manager is unlocked again
+    pub fn record_event(manager: &RwLock<Manager>, reported: ReportedEvent) {
+        let file = {
+            let mut lock = manager.write().unwrap_or_else(|e| e.into_inner());
+            lock.events.record(reported.clone());
+            lock.events.file().map(Path::to_path_buf)
+        };
+        if let Some(path) = file {
+            event_log::save(&path, &reported);
+        }
+    }
+
+    /// Append reported VM events to `path` as well as keeping them in memory
+    pub fn set_event_log_file(&mut self, path: PathBuf) {
+        self.events.set_file(path);
+    }
+
+    /// Recently reported VM events, oldest first
+    pub fn event_log(&self) -> Vec<ReportedEvent> {
+        self.events.events()
+    }
+
+    /// Reliable messages this node already handled, whichever connection they came over
+    pub fn delivered(&self) -> Arc<Mutex<Delivered>> {
+        self.delivered.clone()
+    }
+
+    /// What a member said it does for the cluster, both if it didn't say
+    pub fn role_of(&self, alias: &str) -> NodeRole {
+        self.get_metadata(alias)
+            .and_then(|metadata| metadata.role)
+            .unwrap_or_default()
+    }
+
+    /// Record what a client said about itself
+    pub fn set_metadata(&mut self, alias: &str, metadata: NodeMetadata) {
+        if let Some(id) = self.node_id(alias) {
+            self.metadata.insert(id, metadata);
+        }
+    }
+
+    /// What a client said about itself, if it said anything
+    pub fn get_metadata(&self, alias: &str) -> Option<&NodeMetadata> {
+        self.aliases.get(alias).and_then(|id| self.metadata.get(id))
+    }
+
+    /// Metadata of every client that sent any, by alias
+    pub fn all_metadata(&self) -> HashMap<NodeAlias, NodeMetadata> {
+        self.metadata
+            .iter()
+            .filter_map(|(id, metadata)| {
+                let member = self.members.get(id)?;
+                Some((member.alias.clone(), metadata.clone()))
+            })
+            .collect()
+    }
+
+    /// Record how many programs a client said it is running
+    pub fn set_load(&mut self, alias: &str, running_tasks: usize) {
+        if let Some(id) = self.node_id(alias) {
+            self.loads.insert(id, running_tasks);
+        }
+    }
+
+    /// How many programs a client last said it is running, if it ever did
+    pub fn get_load(&self, alias: &str) -> Option<usize> {
+        self.aliases
+            .get(alias)
+            .and_then(|id| self.loads.get(id))
+            .copied()
+    }
+
+    /// The member best placed to run another program: the one running the fewest, then the
+    /// one with the most logical cores. Members that never reported a load come last.
+    /// None without any members that run programs
+    pub fn pick_least_loaded(&self) -> Option<NodeAlias> {
+        self.program_runners().into_iter().min_by_key(|alias| {
+            let load = self.get_load(alias).unwrap_or(usize::MAX);
+            let cores = self.get_metadata(alias).and_then(|m| m.logical_cores);
+            (load, Reverse(cores.unwrap_or(0)), alias.clone())
+        })
+    }
+
+    /// Connected members whose role lets them run programs, sorted by alias
+    pub fn program_runners(&self) -> Vec<NodeAlias> {
+        let mut runners: Vec<NodeAlias> = self
+            .aliases
+            .keys()
+            .filter(|alias| self.role_of(alias).runs_programs())
+            .cloned()
+            .collect();
+        runners.sort();
+        runners
+    }
+
+    /// Number of connected cluster clients
+    pub fn client_count(&self) -> usize {
+        self.members.len()
+    }
+
     /// Get client names
     pub fn get_client_names(&self) -> Vec<String> {
-        self.clients.keys().map(|k| k.to_owned()).collect()
+        self.members
+            .values()
+            .map(|member| member.alias.clone())
+            .collect()
+    }
+
+    /// VM id of the member going by this alias, or the one it was given if it never said
+    pub fn node_id(&self, alias: &str) -> Option<Uuid> {
+        self.aliases.get(alias).copied()
+    }
+
+    /// Client registered under this alias
+    pub fn get_client(&self, alias: &str) -> Option<&ClusterClient> {
+        let id = self.aliases.get(alias)?;
+        self.members.get(id).map(|member| &member.client)
+    }
+
+    /// Client registered under this alias, for sending to it
+    pub fn get_client_mut(&mut self, alias: &str) -> Option<&mut ClusterClient> {
+        let id = self.aliases.get(alias)?;
+        self.members.get_mut(id).map(|member| &mut member.client)
+    }
+
+    /// If a client is registered under this alias
+    pub fn has_client(&self, alias: &str) -> bool {
+        self.aliases.contains_key(alias)
+    }
+
+    /// (alias, ip, port) every client listens on, as sent in HelloAck
+    pub fn get_nodes(&self) -> Vec<NodeAddress> {
+        self.nodes
+            .values()
+            .map(|info| (info.alias.clone(), info.host.clone(), info.port.clone()))
+            .collect()
+    }
+
+    /// Where a client listens and when it was heard from
+    pub fn node_info(&self, alias: &str) -> Option<&NodeInfo> {
+        self.aliases.get(alias).and_then(|id| self.nodes.get(id))
+    }
+
+    /// Where every client listens and when each was heard from, in no particular order
+    pub fn all_nodes(&self) -> Vec<NodeInfo> {
+        self.nodes.values().cloned().collect()
+    }
+
+    /// Note that a message just arrived from a client
+    pub fn touch(&mut self, alias: &str) {
+        let id = self.node_id(alias);
+        if let Some(info) = id.and_then(|id| self.nodes.get_mut(&id)) {
+            info.last_seen = Utc::now();
+        }
+    }
+
+    /// Send a message to every member, carrying on past the ones that fail
+    pub fn broadcast(&mut self, msg: &IridiumMessage) -> Vec<(NodeAlias, Result<()>)> {
+        let frame = match encode_message(msg) {
+            Ok(frame) => frame,
+            Err(e) => {
+                let e = e.to_string();
+                return self
+                    .aliases
+                    .keys()
+                    .map(|alias| (alias.to_owned(), Err(IridiumError::StringError(e.clone()))))
+                    .collect();
+            }
+        };
+        self.members
+            .values()
+            .map(|member| (member.alias.clone(), member.client.send_frame(&frame)))
+            .collect()
+    }
+
+    /// Writer of the connection to one member, to send on once the manager is unlocked
+    pub fn writer_to(&self, alias: &str) -> Option<PeerWriter> {
+        self.get_client(alias).map(ClusterClient::writer)
+    }
+
+    /// Send a message to one member
+    pub fn send_to(&mut self, alias: &str, msg: &IridiumMessage) -> Result<()> {
+        match self.get_client(alias) {
+            Some(client) => client.send(msg),
+            None => Err(IridiumError::NotFound(alias.to_owned())),
+        }
+    }
+
+    /// Ping the members named `aliases` all at once, and wait up to `timeout` for them to
+    /// answer. Gives each its round-trip time, or why there is none. The loads members
+    /// report in their pongs are recorded
+    pub fn ping(
+        manager: &Arc<RwLock<Manager>>,
+        aliases: &[NodeAlias],
+        timeout: Duration,
+    ) -> Vec<(NodeAlias, Result<Duration>)> {
+        let pings: Vec<_> = {
+            let mut lock = manager.write().unwrap_or_else(|e| e.into_inner());
+            aliases
+                .iter()
+                .map(|alias| (alias.clone(), lock.send_ping(alias)))
+                .collect()
+        };
+        let started = Instant::now();
+        pings
+            .into_iter()
+            .map(|(alias, ping)| {
+                let rtt = ping.and_then(|(nonce, sent, pong)| {
+                    let forget = |client: &ClusterClient| client.forget_ping(&nonce);
+                    let (arrived, running_tasks) =
+                        Self::await_reply(manager, &alias, pong, started, timeout, forget)?;
+                    let mut lock = manager.write().unwrap_or_else(|e| e.into_inner());
+                    lock.touch(&alias);
+                    if let Some(running_tasks) = running_tasks {
+                        lock.set_load(&alias, running_tasks);
+                    }
+                    Ok(arrived.saturating_duration_since(sent))
+                });
+                (alias, rtt)
+            })
+            .collect()
+    }
+
+    /// Ask the member `alias` what its VM is up to, waiting up to `timeout` for the answer
+    pub fn inspect(
+        manager: &Arc<RwLock<Manager>>,
+        alias: &str,
+        timeout: Duration,
+    ) -> Result<NodeStatus> {
+        let request_id = Uuid::new_v4();
+        let status = {
+            let mut lock = manager.write().unwrap_or_else(|e| e.into_inner());
+            let status = match lock.get_client_mut(alias) {
+                Some(client) => client.expect_inspection(request_id)?,
+                None => return Err(IridiumError::NotFound(alias.to_owned())),
+            };
+            if let Err(e) = lock.send_to(alias, &IridiumMessage::InspectRequest { request_id }) {
+                if let Some(client) = lock.get_client(alias) {
+                    client.forget_inspection(&request_id);
+                }
+                return Err(e);
+            }
+            status
+        };
+        let forget = |client: &ClusterClient| client.forget_inspection(&request_id);
+        Self::await_reply(manager, alias, status, Instant::now(), timeout, forget)?.map_err(|e| {
+            IridiumError::StringError(format!("{} could not be inspected: {}", alias, e))
+        })
+    }
+
+    /// Wait until `timeout` after `started` for the member `alias` to reply on `reply`. If it
+    /// doesn't, the request is withdrawn from its client with `forget`
+    fn await_reply<T>(
+        manager: &Arc<RwLock<Manager>>,
+        alias: &str,
+        reply: Receiver<T>,
+        started: Instant,
+        timeout: Duration,
+        forget: impl FnOnce(&ClusterClient),
+    ) -> Result<T> {
+        match reply.recv_timeout(timeout.saturating_sub(started.elapsed())) {
+            Ok(reply) => Ok(reply),
+            Err(RecvTimeoutError::Timeout) => {
+                let lock = manager.read().unwrap_or_else(|e| e.into_inner());
+                if let Some(client) = lock.get_client(alias) {
+                    forget(client);
+                }
+                Err(IridiumError::StringError(format!(
+                    "{} did not answer within {} ms",
+                    alias,
+                    timeout.as_millis()
+                )))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(IridiumError::StringError(format!(
+                "Connection to {} closed before it answered",
+                alias
+            ))),
+        }
+    }
+
+    /// Send a ping to `alias`, returning its nonce, when it was sent and where its pong
+    /// will arrive
+    fn send_ping(&mut self, alias: &str) -> Result<(Uuid, Instant, Receiver<Pong>)> {
+        let nonce = Uuid::new_v4();
+        let pong = match self.get_client_mut(alias) {
+            Some(client) => client.expect_pong(nonce)?,
+            None => return Err(IridiumError::NotFound(alias.to_owned())),
+        };
+        let sent = Instant::now();
+        if let Err(e) = self.send_to(alias, &IridiumMessage::Ping { nonce }) {
+            if let Some(client) = self.get_client(alias) {
+                client.forget_ping(&nonce);
+            }
+            return Err(e);
+        }
+        Ok((nonce, sent, pong))
+    }
+
+    /// Tell every other member that a node has joined, so they can connect to it
+    pub fn announce_member(&mut self, member: &NodeAddress) {
+        let (alias, host, port) = member;
+        let msg = IridiumMessage::NewMember {
+            alias: alias.to_owned(),
+            host: host.to_owned(),
+            port: port.to_owned(),
+        };
+        for other in self
+            .members
+            .values_mut()
+            .filter(|other| other.alias != *alias)
+        {
+            if let Err(e) = other.client.send_reliably(msg.clone()) {
+                warn!(
+                    "Unable to tell {} about new member {}: {}",
+                    other.alias, alias, e
+                );
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::Manager;
+    use std::net::{Shutdown, TcpListener, TcpStream};
+
+    use super::*;
+    use crate::common::{read_message, MAX_FRAME_LENGTH};
 
+    #[test]
     fn test_create_manager() {
         let test_manager = Manager::new();
+        assert!(test_manager.get_client_names().is_empty());
+    }
+
+    #[test]
+    fn test_broadcast_reports_each_member() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut manager = Manager::new();
+
+        let up = TcpStream::connect(addr).unwrap();
+        let (_up_peer, _) = listener.accept().unwrap();
+        manager.add_client("up".to_string(), ClusterClient::new(up).unwrap());
+        let down = TcpStream::connect(addr).unwrap();
+        let (_down_peer, _) = listener.accept().unwrap();
+        down.shutdown(Shutdown::Both).unwrap();
+        manager.add_client("down".to_string(), ClusterClient::new(down).unwrap());
+
+        let msg = IridiumMessage::NewMember {
+            alias: "new".to_string(),
+            host: "127.0.0.1".to_string(),
+            port: "2254".to_string(),
+        };
+        let mut results = manager.broadcast(&msg);
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(results[0].0, "down");
+        assert!(results[0].1.is_err());
+        assert_eq!(results[1].0, "up");
+        assert!(results[1].1.is_ok());
+
+        assert!(manager.send_to("up", &msg).is_ok());
+        assert!(manager.send_to("missing", &msg).is_err());
+    }
+
+    #[test]
+    fn test_send_to() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut manager = Manager::new();
+        let peer = TcpStream::connect(addr).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        manager.add_client("peer".to_string(), ClusterClient::new(peer).unwrap());
+        let msg = IridiumMessage::Goodbye {
+            alias: "me".to_string(),
+        };
+
+        manager.send_to("peer", &msg).unwrap();
+        let mut reader = std::io::BufReader::new(accepted);
+        let received: Option<IridiumMessage> = read_message(&mut reader, MAX_FRAME_LENGTH).unwrap();
+        assert!(matches!(received, Some(IridiumMessage::Goodbye { alias }) if alias == "me"));
+
+        match manager.send_to("nobody", &msg) {
+            Err(IridiumError::NotFound(alias)) => assert_eq!(alias, "nobody"),
+            other => panic!("expected NotFound, got {:?}", other),
+        }
+
+        manager.get_client("peer").unwrap().close();
+        assert!(manager.send_to("peer", &msg).is_err());
+    }
+
+    #[test]
+    fn test_members_keyed_by_node_id() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = |id: Option<Uuid>| {
+            let client = ClusterClient::new(TcpStream::connect(addr).unwrap()).unwrap();
+            match id {
+                Some(id) => client.with_peer_id(id),
+                None => client,
+            }
+        };
+        let mut manager = Manager::new();
+        let id = Uuid::new_v4();
+
+        assert!(manager.add_client("node".to_string(), client(Some(id))));
+        assert_eq!(manager.node_id("node"), Some(id));
+        // another node going by the same alias doesn't replace it
+        assert!(!manager.add_client("node".to_string(), client(Some(Uuid::new_v4()))));
+        assert_eq!(manager.node_id("node"), Some(id));
+        // nor does the same node join twice under two aliases
+        assert!(!manager.add_client("other".to_string(), client(Some(id))));
+        // nodes that never said their id are told apart all the same
+        assert!(manager.add_client("anonymous".to_string(), client(None)));
+        assert_ne!(manager.node_id("anonymous"), Some(id));
+
+        manager.set_load("node", 3);
+        assert_eq!(manager.get_load("node"), Some(3));
+        assert!(manager.del_client("node".to_string()));
+        assert_eq!(manager.node_id("node"), None);
+        assert_eq!(manager.get_load("node"), None);
+        assert!(manager.add_client("renamed".to_string(), client(Some(id))));
+        let mut names = manager.get_client_names();
+        names.sort();
+        assert_eq!(names, ["anonymous", "renamed"]);
+    }
+
+    #[test]
+    fn test_node_info() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut manager = Manager::new();
+        let client = ClusterClient::new(TcpStream::connect(addr).unwrap())
+            .unwrap()
+            .with_peer_listen("10.0.0.7".to_string(), "2254".to_string());
+        manager.add_client("peer".to_string(), client);
+
+        let info = manager.node_info("peer").unwrap().clone();
+        assert_eq!(
+            (info.host.as_str(), info.port.as_str()),
+            ("10.0.0.7", "2254")
+        );
+        assert_eq!(info.connected_at, info.last_seen);
+        manager.touch("peer");
+        assert!(manager.node_info("peer").unwrap().last_seen >= info.last_seen);
+        assert_eq!(manager.all_nodes().len(), 1);
+
+        manager.del_client("peer".to_string());
+        assert!(manager.node_info("peer").is_none());
+        assert!(manager.all_nodes().is_empty());
+    }
+
+    #[test]
+    fn test_pick_least_loaded() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut manager = Manager::new();
+        assert_eq!(manager.pick_least_loaded(), None);
+        for alias in ["busy", "idle", "quiet"] {
+            let stream = TcpStream::connect(addr).unwrap();
+            manager.add_client(alias.to_string(), ClusterClient::new(stream).unwrap());
+        }
+        let cores = |n| NodeMetadata {
+            logical_cores: Some(n),
+            vm_id: None,
+            role: None,
+        };
+        manager.set_metadata("busy", cores(8));
+        manager.set_metadata("idle", cores(2));
+
+        // quiet never reported a load, so it isn't picked over members that did
+        manager.set_load("busy", 3);
+        manager.set_load("idle", 1);
+        assert_eq!(manager.pick_least_loaded().as_deref(), Some("idle"));
+        manager.set_load("idle", 3);
+        assert_eq!(manager.pick_least_loaded().as_deref(), Some("busy"));
+        manager.del_client("busy".to_string());
+        assert_eq!(manager.get_load("busy"), None);
+        assert_eq!(manager.pick_least_loaded().as_deref(), Some("idle"));
+    }
+
+    #[test]
+    fn test_membership_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut manager = Manager::new();
+        let events = manager.subscribe_membership();
+
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        manager.add_client("fake".to_string(), ClusterClient::new(stream).unwrap());
+        manager.del_client("fake".to_string());
+        assert!(!manager.del_client("fake".to_string()));
+
+        let kinds: Vec<_> = events.try_iter().map(|e| (e.alias, e.kind)).collect();
+        let expected = [
+            ("fake".to_string(), MembershipEventKind::Joined),
+            ("fake".to_string(), MembershipEventKind::Left),
+        ];
+        assert_eq!(kinds, expected);
+        let history: Vec<_> = manager
+            .membership_history()
+            .into_iter()
+            .map(|e| (e.alias, e.kind))
+            .collect();
+        assert_eq!(history, expected);
+    }
+
+    #[test]
+    fn test_leaving_waits_on_nobody_for_long() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new()));
+        let mut accepted = vec![];
+        for alias in ["stuck", "up"] {
+            let client = ClusterClient::new(TcpStream::connect(addr).unwrap()).unwrap();
+            accepted.push(listener.accept().unwrap().0);
+            manager
+                .write()
+                .unwrap()
+                .add_client(alias.to_string(), client);
+        }
+        // a send that never finishes holds the writer of the connection to "stuck"
+        let stuck = manager
+            .read()
+            .unwrap()
+            .get_client("stuck")
+            .unwrap()
+            .writer();
+        let _sending = stuck.lock().unwrap();
+
+        let started = Instant::now();
+        assert_eq!(Manager::leave_cluster(&manager), 1);
+        assert!(
+            started.elapsed() < GOODBYE_TIMEOUT * 2,
+            "{:?}",
+            started.elapsed()
+        );
+        assert_eq!(manager.read().unwrap().client_count(), 0);
+        let mut reader = std::io::BufReader::new(accepted.remove(1));
+        let received: Option<IridiumMessage> = read_message(&mut reader, MAX_FRAME_LENGTH).unwrap();
+        assert!(matches!(received, Some(IridiumMessage::Goodbye { .. })));
     }
 }
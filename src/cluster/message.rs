@@ -1,16 +1,216 @@
+use std::{collections::HashMap, fmt};
+
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use super::NodeAlias;
+use crate::vm::VMEvent;
 
-#[derive(Debug, Serialize, Deserialize)]
+use super::{role::NodeRole, NodeAddress, NodeAlias};
+
+/// Cluster protocol version this node speaks by default
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest cluster protocol version this node still accepts
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// First frame on every cluster connection, sent by the side that opened it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Handshake {
+    pub version: u32,
+    pub encoding: Encoding, // how the messages after the handshake are encoded
+    #[serde(default)]
+    pub encrypted: bool, // whether the opening side goes on in TLS once accepted
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Encoding {
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum HandshakeResponse {
+    Accepted {
+        version: u32,
+        #[serde(default)]
+        encrypted: bool, // both sides go on in TLS
+    },
+    Unsupported {
+        min: u32,
+        max: u32,
+    }, // versions the answering node speaks, with JSON
+    Rejected {
+        reason: String,
+    }, // the version is fine, but something else isn't
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum IridiumMessage {
     Hello {
         alias: NodeAlias, // node alias of the node that wants to join the cluster
+        #[serde(default)]
+        peer_host: Option<String>, // host the joining node listens on for peers
+        #[serde(default)]
+        peer_port: Option<String>, // port the joining node listens on for peers
+        #[serde(default)]
+        logical_cores: Option<usize>,
+        #[serde(default)]
+        vm_id: Option<Uuid>,
+        #[serde(default)]
+        secret: Option<String>, // shared cluster secret, if the joining node has one
+        #[serde(default)]
+        role: Option<NodeRole>,
     },
     HelloAck {
-        alias: NodeAlias,                        // Receiver alias
-        nodes: Vec<(NodeAlias, String, String)>, // list of nodes (alias, IP, port)
+        alias: NodeAlias,        // Receiver alias
+        nodes: Vec<NodeAddress>, // list of nodes (alias, IP, port)
+        #[serde(default)]
+        metadata: HashMap<NodeAlias, NodeMetadata>, // what the receiver knows of itself and those nodes
+    },
+    NewMember {
+        alias: NodeAlias, // node that just joined through the sender
+        host: String,
+        port: String,
+    },
+    ExecuteProgram {
+        program: Vec<u8>, // assembled program, header included
+        task_id: Uuid,
+    },
+    ExecuteResult {
+        task_id: Uuid,
+        events: Vec<VMEvent>,
+        registers: [i32; 32], // registers of the VM once the program stopped
+    },
+    ExecuteRefused {
+        task_id: Uuid,
+        reason: String, // why the program won't be run, like the node's role
+    },
+    Gossip {
+        members: Vec<GossipEntry>, // the sender's view of the cluster, or what just changed in it
+    },
+    Goodbye {
+        alias: NodeAlias, // node leaving the cluster on purpose
+    },
+    Ping {
+        nonce: Uuid, // echoed back in the Pong
+    },
+    Pong {
+        nonce: Uuid,
+        #[serde(default)]
+        running_tasks: Option<usize>, // the sender's load, if it runs programs for members
+    },
+    InspectRequest {
+        request_id: Uuid, // echoed back in the InspectResponse
+    },
+    InspectResponse {
+        request_id: Uuid,
+        status: Result<NodeStatus, String>, // or why the VM couldn't be inspected
     },
+    EventReport {
+        alias: NodeAlias, // member whose VM recorded the event
+        event: VMEvent,
+    },
+    Reliable {
+        id: Uuid, // echoed back in the Ack, and how retries are told apart from new messages
+        message: Box<IridiumMessage>,
+    },
+    Ack {
+        id: Uuid,
+    },
+}
+
+impl fmt::Display for IridiumMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IridiumMessage::Hello { alias, .. } => write!(f, "hello from {}", alias),
+            IridiumMessage::HelloAck { alias, nodes, .. } => {
+                write!(f, "welcome from {}, {} other members", alias, nodes.len())
+            }
+            IridiumMessage::NewMember { alias, host, port } => {
+                write!(f, "{} joined at {}:{}", alias, host, port)
+            }
+            IridiumMessage::ExecuteProgram { program, task_id } => {
+                write!(f, "task {} to run ({} bytes)", task_id, program.len())
+            }
+            IridiumMessage::ExecuteResult { task_id, .. } => {
+                write!(f, "result of task {}", task_id)
+            }
+            IridiumMessage::ExecuteRefused { task_id, reason } => {
+                write!(f, "task {} refused: {}", task_id, reason)
+            }
+            IridiumMessage::Gossip { members } => {
+                write!(f, "gossip about {} members", members.len())
+            }
+            IridiumMessage::Goodbye { alias } => write!(f, "{} is leaving", alias),
+            IridiumMessage::Ping { nonce } => write!(f, "ping {}", nonce),
+            IridiumMessage::Pong { nonce, .. } => write!(f, "pong {}", nonce),
+            IridiumMessage::InspectRequest { request_id } => {
+                write!(f, "inspection {} requested", request_id)
+            }
+            IridiumMessage::InspectResponse { request_id, .. } => {
+                write!(f, "status for inspection {}", request_id)
+            }
+            IridiumMessage::EventReport { alias, event } => {
+                write!(f, "{:?} event from {}", event.event, alias)
+            }
+            IridiumMessage::Reliable { message, .. } => message.fmt(f),
+            IridiumMessage::Ack { id } => write!(f, "ack for {}", id),
+        }
+    }
+}
+
+/// One node as a member's view of the cluster has it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GossipEntry {
+    pub alias: NodeAlias,
+    pub host: String, // where the node listens for peers
+    pub port: String,
+    pub incarnation: u64, // raised by the node itself, so newer news about it wins
+    pub alive: bool,      // false marks a tombstone for a node that left or was evicted
+}
+
+impl GossipEntry {
+    /// If this is newer news about the node than `other`. At the same incarnation a
+    /// tombstone wins, so a stale view can't bring back a node that was removed
+    pub fn supersedes(&self, other: &GossipEntry) -> bool {
+        self.incarnation > other.incarnation
+            || (self.incarnation == other.incarnation && other.alive && !self.alive)
+    }
+}
+
+/// What a node says about itself in its hello. Nodes that predate a field leave it out
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NodeMetadata {
+    #[serde(default)]
+    pub logical_cores: Option<usize>,
+    #[serde(default)]
+    pub vm_id: Option<Uuid>,
+    #[serde(default)]
+    pub role: Option<NodeRole>,
+}
+
+/// What a node's VM is up to, as `!cluster_inspect` shows it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeStatus {
+    pub alias: Option<NodeAlias>,
+    pub pc: usize,
+    pub program_length: usize,
+    pub registers: [i32; 32],
+    pub events: Vec<VMEvent>, // the most recent ones, oldest first
+}
+
+/// Contents of the HelloAck a node is welcomed into the cluster with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Welcome {
+    pub alias: NodeAlias, // node that sent it
+    pub nodes: Vec<NodeAddress>,
+    pub metadata: HashMap<NodeAlias, NodeMetadata>,
+}
+
+/// What running a program on another node produced
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TaskResult {
+    pub task_id: Uuid,
+    pub events: Vec<VMEvent>,
+    pub registers: [i32; 32],
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,3 +218,28 @@ pub enum HelloResponse {
     Ok(String),
     Err(String),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hello_from_older_node_parses() {
+        let hello = r#"{"Hello":{"alias":"old"}}"#;
+        match serde_json::from_str(hello).unwrap() {
+            IridiumMessage::Hello {
+                alias,
+                peer_host,
+                logical_cores,
+                vm_id,
+                ..
+            } => {
+                assert_eq!(alias, "old");
+                assert_eq!((peer_host, logical_cores, vm_id), (None, None, None));
+            }
+            msg => panic!("{:?}", msg),
+        }
+        let ack = r#"{"HelloAck":{"alias":"old","nodes":[]}}"#;
+        assert!(serde_json::from_str::<IridiumMessage>(ack).is_ok());
+    }
+}
@@ -1,6 +1,20 @@
+pub mod ack;
+pub mod address;
 pub mod cluster_client;
 pub mod cluster_server;
+pub mod event_log;
+pub mod events;
+pub mod gossip;
+pub mod inspect;
+pub mod jobs;
+pub mod join;
 pub mod manager;
 pub mod message;
+pub mod persist;
+pub mod reconnect;
+pub mod role;
+pub mod transport;
 
-type NodeAlias = String;
+pub type NodeAlias = String;
+pub type NodeAddress = (NodeAlias, String, String); // (alias, ip, port) of a cluster member
+pub type Departure = (NodeAlias, uuid::Uuid); // member whose connection dropped, and which connection it was
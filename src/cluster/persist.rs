@@ -0,0 +1,236 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{mpsc::RecvTimeoutError, Arc, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+
+use super::{manager::Manager, NodeAlias};
+
+/// File under the data directory that remembers cluster members across restarts
+pub const MEMBERSHIP_FILE_NAME: &str = "cluster_members.json";
+/// Members not seen for this long are forgotten rather than rejoined
+pub const MAX_MEMBER_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// How long to wait for membership to settle before saving it
+const SAVE_DELAY: Duration = Duration::from_millis(500);
+
+/// A cluster member as remembered in the membership file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedMember {
+    pub alias: NodeAlias,
+    pub host: String, // where the member listens for peers
+    pub port: String,
+    pub last_seen: DateTime<Utc>, // last time it was connected to this node
+}
+
+/// Where the membership of a node storing its data in `data_dir` is saved
+pub fn membership_file(data_dir: &Path) -> PathBuf {
+    data_dir.join(MEMBERSHIP_FILE_NAME)
+}
+
+/// Members saved in `path` that were seen recently enough to rejoin. A missing or corrupt
+/// file is treated as empty
+pub fn load(path: &Path) -> Vec<SavedMember> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            warn!("No saved cluster members in {}: {}", path.display(), e);
+            return vec![];
+        }
+    };
+    match serde_json::from_slice::<Vec<SavedMember>>(&contents) {
+        Ok(members) => members.into_iter().filter(is_recent).collect(),
+        Err(e) => {
+            warn!(
+                "Ignoring corrupt cluster membership file {}: {}",
+                path.display(),
+                e
+            );
+            vec![]
+        }
+    }
+}
+
+/// Replace the members saved in `path`
+pub fn save(path: &Path, members: &[SavedMember]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // written aside and moved into place, so a crash mid-write can't corrupt the file
+    let partial = path.with_extension("json.tmp");
+    fs::write(&partial, serde_json::to_vec_pretty(members)?)?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+/// Save membership to `path` whenever it changes, once it has settled for a moment.
+/// Members that are gone stay saved with the last time they were seen, until they are too
+/// old to be worth rejoining. Only the first call starts anything; the thread stops once
+/// the manager is dropped
+pub fn start(manager: &Arc<RwLock<Manager>>, path: PathBuf) {
+    let changes = {
+        let mut lock = manager.write().unwrap_or_else(|e| e.into_inner());
+        if !lock.start_saving(path.clone()) {
+            return;
+        }
+        lock.subscribe_membership()
+    };
+    let manager = Arc::downgrade(manager);
+    thread::spawn(move || {
+        let mut saved = load(&path);
+        // a closed channel means the manager, and with it this node's membership, is gone
+        while changes.recv().is_ok() {
+            let settled = Instant::now() + SAVE_DELAY;
+            loop {
+                match changes.recv_timeout(settled.saturating_duration_since(Instant::now())) {
+                    Ok(_) => continue,
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+            let connected = match manager.upgrade() {
+                Some(manager) => manager
+                    .read()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .get_nodes(),
+                None => return,
+            };
+            let now = Utc::now();
+            saved.retain(|member| !connected.iter().any(|(alias, ..)| *alias == member.alias));
+            saved.extend(
+                connected
+                    .into_iter()
+                    .map(|(alias, host, port)| SavedMember {
+                        alias,
+                        host,
+                        port,
+                        last_seen: now,
+                    }),
+            );
+            saved.retain(is_recent);
+            saved.sort_by(|a, b| a.alias.cmp(&b.alias));
+            match save(&path, &saved) {
+                Ok(()) => info!(
+                    "Saved {} cluster members to {}",
+                    saved.len(),
+                    path.display()
+                ),
+                Err(e) => warn!(
+                    "Unable to save cluster members to {}: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+    });
+}
+
+fn is_recent(member: &SavedMember) -> bool {
+    let age = Utc::now().signed_duration_since(member.last_seen);
+    age.to_std().map_or(true, |age| age <= MAX_MEMBER_AGE)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{
+        cluster::{cluster_client::ClusterClient, reconnect::ReconnectPolicy},
+        vm::VM,
+    };
+
+    fn member(alias: &str, port: u16, last_seen: DateTime<Utc>) -> SavedMember {
+        SavedMember {
+            alias: alias.to_string(),
+            host: "127.0.0.1".to_string(),
+            port: port.to_string(),
+            last_seen,
+        }
+    }
+
+    #[test]
+    fn test_load_skips_bad_files_and_old_members() {
+        let dir = tempdir().unwrap();
+        let path = membership_file(dir.path());
+        assert!(load(&path).is_empty());
+        fs::write(&path, "{not json").unwrap();
+        assert!(load(&path).is_empty());
+
+        let long_ago = Utc::now() - chrono::Duration::days(30);
+        let recent = member("recent", 2254, Utc::now());
+        save(&path, &[recent.clone(), member("gone", 2255, long_ago)]).unwrap();
+        assert_eq!(load(&path), [recent]);
+    }
+
+    #[test]
+    fn test_membership_saved_when_it_changes() {
+        let dir = tempdir().unwrap();
+        let path = membership_file(&dir.path().join("nested"));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let manager = Arc::new(RwLock::new(Manager::new()));
+        start(&manager, path.clone());
+
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let client = ClusterClient::new(stream)
+            .unwrap()
+            .with_peer_listen("127.0.0.1".to_string(), "2254".to_string());
+        manager
+            .write()
+            .unwrap()
+            .add_client("peer".to_string(), client);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !path.exists() {
+            assert!(Instant::now() < deadline, "membership never saved");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let saved = load(&path);
+        assert_eq!(saved.len(), 1);
+        assert_eq!(
+            (saved[0].alias.as_str(), saved[0].port.as_str()),
+            ("peer", "2254")
+        );
+    }
+
+    #[test]
+    fn test_rejoin_reconnects_to_saved_members() {
+        let dir = tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let saved = [
+            member("peer", port, Utc::now()),
+            member("me", 1, Utc::now()),
+        ];
+        save(&membership_file(dir.path()), &saved).unwrap();
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let vm = VM::new()
+            .with_alias(&"me".to_string())
+            .with_cluster_bind(&"127.0.0.1".to_string(), &"1".to_string())
+            .with_reconnect_policy(policy)
+            .with_data_dir(dir.path().to_path_buf());
+
+        // itself isn't one of the members to reconnect to
+        assert_eq!(vm.rejoin_cluster().unwrap(), 1);
+        let (accepted, attempts) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = accepted.send(listener.accept().is_ok());
+        });
+        assert_eq!(attempts.recv_timeout(Duration::from_secs(5)), Ok(true));
+    }
+}
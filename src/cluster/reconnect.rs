@@ -0,0 +1,131 @@
+use std::{
+    sync::{Arc, RwLock, Weak},
+    thread,
+    time::Duration,
+};
+
+use log::{info, warn};
+use uuid::Uuid;
+
+use super::{
+    address,
+    join::{self, LocalNode},
+    manager::Manager,
+    NodeAlias,
+};
+
+/// How long to wait between attempts to reach a lost member, and when to stop trying
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration, // wait before the first attempt, doubled after each failure
+    pub max_delay: Duration,
+    pub max_attempts: Option<u32>, // None keeps trying forever
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: Some(10),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Wait before attempt number `attempt`, counting from 1. Somewhere between half and all
+    /// of the backoff, so members that lost the same node don't all retry at once
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let backoff = self
+            .initial_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |d| d.min(self.max_delay));
+        let half = backoff / 2;
+        let jitter = (Uuid::new_v4().as_u128() % 1000) as u32;
+        half + (backoff - half) * jitter / 1000
+    }
+
+    /// Whether to stop after `attempts` failed attempts
+    pub fn gave_up(&self, attempts: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempts >= max)
+    }
+}
+
+/// Keep trying to reach the member `alias` at `host:port` on a thread of its own, until it is
+/// back in the manager or the policy gives up. Stops early if the manager is dropped
+pub fn spawn(
+    manager: Weak<RwLock<Manager>>,
+    alias: NodeAlias,
+    host: String,
+    port: String,
+    local: LocalNode,
+    policy: ReconnectPolicy,
+) {
+    thread::spawn(move || {
+        let addr = address::join_host_port(&host, &port);
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            thread::sleep(policy.delay(attempts));
+            let manager = match manager.upgrade() {
+                Some(manager) => manager,
+                None => return,
+            };
+            let wanted = manager
+                .read()
+                .map(|m| m.is_reconnecting(&alias))
+                .unwrap_or(false);
+            if !wanted {
+                // it connected to us in the meantime, or this node left the cluster
+                return;
+            }
+            match join::connect(&local, &manager, &addr) {
+                Ok(_) => {
+                    info!("Reconnected to cluster member {} at {}", alias, addr);
+                    finish(&manager, &alias);
+                    return;
+                }
+                Err(e) if policy.gave_up(attempts) => {
+                    warn!(
+                        "Giving up on cluster member {} after {} attempts: {}",
+                        alias, attempts, e
+                    );
+                    finish(&manager, &alias);
+                    return;
+                }
+                Err(e) => {
+                    info!(
+                        "Unable to reconnect to cluster member {} (attempt {}): {}",
+                        alias, attempts, e
+                    );
+                    if let Ok(mut manager) = manager.write() {
+                        manager.set_reconnect_attempts(&alias, attempts);
+                    }
+                }
+            }
+        }
+    });
+}
+
+fn finish(manager: &Arc<RwLock<Manager>>, alias: &str) {
+    manager
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .stop_reconnecting(alias);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_backs_off_with_jitter() {
+        let policy = ReconnectPolicy::default();
+        for (attempt, full) in [(1, 1), (2, 2), (3, 4), (7, 60), (40, 60)] {
+            let delay = policy.delay(attempt);
+            let full = Duration::from_secs(full);
+            assert!(delay >= full / 2 && delay <= full, "{:?}", delay);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::IridiumError;
+
+/// What a node does for the cluster. Nodes that don't say, like those from before roles,
+/// are taken to do both
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeRole {
+    Worker,      // runs the programs it is sent, and otherwise only answers coordinators
+    Coordinator, // joins members, hands out and inspects work, but runs none of it
+    #[default]
+    Both,
+}
+
+impl NodeRole {
+    /// Whether programs members send are run here
+    pub fn runs_programs(self) -> bool {
+        self != NodeRole::Coordinator
+    }
+
+    /// Whether the node may direct others: join them, send them work and inspect them
+    pub fn coordinates(self) -> bool {
+        self != NodeRole::Worker
+    }
+
+    /// Why the node `alias`, in this role, won't run the programs members send it
+    pub fn program_refusal(self, alias: &str) -> Option<String> {
+        (!self.runs_programs()).then(|| format!("{} is a coordinator and runs no programs", alias))
+    }
+
+    /// Why the node `alias`, in this role, won't be inspected by a member in role `peer`
+    pub fn inspection_refusal(self, alias: &str, peer: NodeRole) -> Option<String> {
+        (self == NodeRole::Worker && !peer.coordinates())
+            .then(|| format!("{} is a worker and only answers coordinators", alias))
+    }
+}
+
+impl FromStr for NodeRole {
+    type Err = IridiumError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "worker" => Ok(NodeRole::Worker),
+            "coordinator" => Ok(NodeRole::Coordinator),
+            "both" => Ok(NodeRole::Both),
+            _ => Err(IridiumError::StringError(format!(
+                "Invalid node role {}, expected worker, coordinator or both",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for NodeRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let role = match self {
+            NodeRole::Worker => "worker",
+            NodeRole::Coordinator => "coordinator",
+            NodeRole::Both => "both",
+        };
+        write!(f, "{}", role)
+    }
+}
@@ -0,0 +1,182 @@
+use std::{fmt, net::TcpStream};
+
+#[cfg(feature = "tls")]
+use std::{path::Path, sync::Arc};
+
+use crate::{error::Result, remote::stream::Stream};
+
+#[cfg(feature = "tls")]
+use crate::remote::tls::{self, TlsStream};
+
+/// How cluster connections are carried once the version handshake settled it: as they are,
+/// or wrapped in TLS. Every member of a cluster must agree, so a node only talks to others
+/// carrying their connections the same way
+#[derive(Clone, Default)]
+pub struct Transport {
+    #[cfg(feature = "tls")]
+    tls: Option<(Arc<rustls::ServerConfig>, Arc<rustls::ClientConfig>)>, // accepting and connecting
+}
+
+impl Transport {
+    /// Cluster connections in the clear
+    pub fn plain() -> Self {
+        Self::default()
+    }
+
+    /// Cluster connections in TLS, every member presenting the certificate chain in
+    /// `cert_path` and trusting only peers that do the same, whichever end opened the
+    /// connection. Members are reached by IP, so the certificate must name the addresses
+    /// they listen on
+    #[cfg(feature = "tls")]
+    pub fn tls(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        let server = tls::mutual_server_config(cert_path, key_path)?;
+        let client = tls::mutual_client_config(cert_path, key_path)?;
+        Ok(Self {
+            tls: Some((server, client)),
+        })
+    }
+
+    /// Whether connections are encrypted
+    pub fn encrypts(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        return false;
+    }
+
+    /// Why a peer whose connections are encrypted or not, as `peer_encrypts` says, can't
+    /// talk to this node
+    pub fn mismatch(&self, peer_encrypts: bool) -> Option<String> {
+        match (self.encrypts(), peer_encrypts) {
+            (true, false) => Some("This node only accepts encrypted cluster connections".into()),
+            (false, true) => Some("This node does not encrypt cluster connections".into()),
+            _ => None,
+        }
+    }
+
+    /// Wrap the connection this node opened to a member
+    pub fn connect(&self, sock: &TcpStream) -> Result<Box<dyn Stream>> {
+        #[cfg(feature = "tls")]
+        if let Some((_, client)) = &self.tls {
+            let name = rustls::ServerName::IpAddress(sock.peer_addr()?.ip());
+            return Ok(Box::new(TlsStream::connect(
+                client.clone(),
+                name,
+                sock.try_clone()?,
+            )?));
+        }
+        Ok(Box::new(sock.try_clone()?))
+    }
+
+    /// Wrap a connection a member opened to this node
+    pub fn accept(&self, sock: &TcpStream) -> Result<Box<dyn Stream>> {
+        #[cfg(feature = "tls")]
+        if let Some((server, _)) = &self.tls {
+            return Ok(Box::new(TlsStream::accept(
+                server.clone(),
+                sock.try_clone()?,
+            )?));
+        }
+        Ok(Box::new(sock.try_clone()?))
+    }
+}
+
+impl fmt::Debug for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let carried = if self.encrypts() { "tls" } else { "plain" };
+        write!(f, "Transport({})", carried)
+    }
+}
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use std::{io::Write, net::TcpListener, thread, time::Duration};
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::{assembler::Assembler, cluster::cluster_client::ClusterClient, vm::VM};
+
+    /// A certificate and key for every member, naming the loopback address they listen on
+    fn certificate() -> (NamedTempFile, NamedTempFile) {
+        let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+        let mut cert_file = NamedTempFile::new().unwrap();
+        cert_file
+            .write_all(cert.serialize_pem().unwrap().as_bytes())
+            .unwrap();
+        let mut key_file = NamedTempFile::new().unwrap();
+        key_file
+            .write_all(cert.serialize_private_key_pem().as_bytes())
+            .unwrap();
+        (cert_file, key_file)
+    }
+
+    fn node(alias: &str, transport: Transport) -> (VM, String) {
+        let mut vm = VM::new()
+            .with_alias(&alias.to_string())
+            .with_cluster_bind(&"127.0.0.1".to_string(), &"0".to_string())
+            .with_cluster_transport(transport);
+        let addr = vm.bind_cluster_server().unwrap().to_string();
+        (vm, addr)
+    }
+
+    #[test]
+    fn test_join_and_run_over_tls() {
+        let (cert, key) = certificate();
+        let transport = Transport::tls(cert.path(), key.path()).unwrap();
+        let (_a, a_addr) = node("a", transport.clone());
+        let (b, _) = node("b", transport);
+
+        let (alias, _) = b.join_cluster(&a_addr, None).unwrap();
+        assert_eq!(alias, "a");
+        let program = Assembler::new()
+            .assemble(".data\n.code\nload $0 #100\nload $1 #7\nadd $0 $1 $2")
+            .unwrap();
+        let (_, result) = b
+            .conn_manager
+            .write()
+            .unwrap()
+            .get_client_mut("a")
+            .unwrap()
+            .submit(program)
+            .unwrap();
+        let result = result.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(result.registers[..3], [100, 7, 107]);
+    }
+
+    #[test]
+    fn test_mixed_transports_rejected() {
+        let (cert, key) = certificate();
+        let transport = Transport::tls(cert.path(), key.path()).unwrap();
+        let (encrypted, encrypted_addr) = node("encrypted", transport);
+        let (_plain, plain_addr) = node("plain", Transport::plain());
+
+        let mut client = ClusterClient::new(TcpStream::connect(&encrypted_addr).unwrap())
+            .unwrap()
+            .with_alias("plain".to_string());
+        let err = client.send_hello().unwrap_err().to_string();
+        assert!(err.contains("only accepts encrypted"), "{}", err);
+
+        let err = encrypted.join_cluster(&plain_addr, None).unwrap_err();
+        assert!(err.to_string().contains("does not encrypt"), "{}", err);
+        assert_eq!(encrypted.conn_manager.read().unwrap().client_count(), 0);
+    }
+
+    #[test]
+    fn test_peer_without_certificate_refused() {
+        let (cert, key) = certificate();
+        let transport = Transport::tls(cert.path(), key.path()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepting = thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            transport.accept(&sock).map(|_| ())
+        });
+
+        // trusts the cluster's certificate but has none of its own to present
+        let anonymous = tls::client_config(cert.path()).unwrap();
+        let name = rustls::ServerName::IpAddress(addr.ip());
+        let _ = TlsStream::connect(anonymous, name, TcpStream::connect(addr).unwrap());
+        assert!(accepting.join().unwrap().is_err());
+    }
+}
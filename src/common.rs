@@ -1,13 +1,191 @@
-use crate::error::Result;
-use std::{
-    io::{BufWriter, Write},
-    net::TcpStream,
-};
+use crate::error::{IridiumError, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{self, Read, Write};
+
+const FRAME_HEADER_LENGTH: usize = 4;
+// Largest frame accepted unless a connection is configured otherwise
+pub const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
 
 // Writes a message as bytes to the connected node
-pub fn w(writer: &mut BufWriter<TcpStream>, msg: &str) -> Result<()> {
+pub fn w<W: Write + ?Sized>(writer: &mut W, msg: &str) -> Result<()> {
     writer.write_all(msg.as_bytes())?;
     writer.flush()?;
 
     Ok(())
 }
+
+// Writes a message as a frame: its length as a little-endian u32 followed by the UTF-8 payload
+pub fn write_frame<W: Write + ?Sized>(writer: &mut W, msg: &str) -> Result<()> {
+    check_frame_length(msg.len(), MAX_FRAME_LENGTH)?;
+    write_payload(writer, &encode_frame(msg.as_bytes())?)
+}
+
+// Blocks until a whole frame no longer than `max_len` has been read
+pub fn read_frame<R: Read + ?Sized>(reader: &mut R, max_len: usize) -> Result<String> {
+    match read_payload(reader, max_len)? {
+        Some(payload) => frame_payload(payload),
+        None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+    }
+}
+
+// Removes the first frame from `buf` if it has fully arrived. Frames longer than `max_len` are
+// an error as soon as their header is in
+pub fn take_frame(buf: &mut Vec<u8>, max_len: usize) -> Result<Option<String>> {
+    if buf.len() < FRAME_HEADER_LENGTH {
+        return Ok(None);
+    }
+    let len = (&buf[..FRAME_HEADER_LENGTH]).read_u32::<LittleEndian>()? as usize;
+    check_frame_length(len, max_len)?;
+    if buf.len() < FRAME_HEADER_LENGTH + len {
+        return Ok(None);
+    }
+    let payload = buf[FRAME_HEADER_LENGTH..FRAME_HEADER_LENGTH + len].to_vec();
+    buf.drain(..FRAME_HEADER_LENGTH + len);
+    frame_payload(payload).map(Some)
+}
+
+// Encodes a message as one frame whose payload is its JSON, so it can be sent to many nodes
+pub fn encode_message<T: Serialize>(msg: &T) -> Result<Vec<u8>> {
+    encode_frame(&serde_json::to_vec(msg)?)
+}
+
+// Writes a message as one frame of JSON
+pub fn write_message<W: Write + ?Sized, T: Serialize>(writer: &mut W, msg: &T) -> Result<()> {
+    write_payload(writer, &encode_message(msg)?)
+}
+
+// Blocks until a whole frame of JSON no longer than `max_len` has arrived and decodes it.
+// Returns None if the connection closed cleanly between frames
+pub fn read_message<R: Read + ?Sized, T: DeserializeOwned>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<Option<T>> {
+    match read_payload(reader, max_len)? {
+        Some(payload) => Ok(Some(serde_json::from_slice(&payload)?)),
+        None => Ok(None),
+    }
+}
+
+fn encode_frame(payload: &[u8]) -> Result<Vec<u8>> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| invalid_frame(format!("frame of {} bytes is too long", payload.len())))?;
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LENGTH + payload.len());
+    frame.write_u32::<LittleEndian>(len)?;
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+fn write_payload<W: Write + ?Sized>(writer: &mut W, frame: &[u8]) -> Result<()> {
+    writer.write_all(frame)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn read_payload<R: Read + ?Sized>(reader: &mut R, max_len: usize) -> Result<Option<Vec<u8>>> {
+    let len = match reader.read_u32::<LittleEndian>() {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    check_frame_length(len, max_len)?;
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+fn check_frame_length(len: usize, max_len: usize) -> Result<()> {
+    if len > max_len {
+        return Err(invalid_frame(format!(
+            "frame of {} bytes exceeds the {} byte limit",
+            len, max_len
+        )));
+    }
+    Ok(())
+}
+
+fn frame_payload(payload: Vec<u8>) -> Result<String> {
+    String::from_utf8(payload).map_err(|e| invalid_frame(e.to_string()))
+}
+
+fn invalid_frame(msg: String) -> IridiumError {
+    IridiumError::Io(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+// Whether two shared secrets are the same, both being None counting as the same. Secrets of
+// equal length take as long to compare wherever they differ, so timing doesn't give them away
+pub fn secrets_match(a: Option<&str>, b: Option<&str>) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (Some(a), Some(b)) if a.len() == b.len() => {
+            let diff = a
+                .bytes()
+                .zip(b.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b));
+            diff == 0
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_round_trip() {
+        let mut buf = vec![];
+        write_frame(&mut buf, "hello").unwrap();
+        assert_eq!(buf, [5, 0, 0, 0, b'h', b'e', b'l', b'l', b'o']);
+        assert_eq!(read_frame(&mut buf.as_slice(), 64).unwrap(), "hello");
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let mut buf = vec![];
+        write_message(&mut buf, &"hello".to_string()).unwrap();
+        assert_eq!(&buf[..4], [7, 0, 0, 0]);
+        buf.extend_from_slice(&encode_message(&42).unwrap());
+
+        let mut reader = buf.as_slice();
+        let first: Option<String> = read_message(&mut reader, 64).unwrap();
+        assert_eq!(first.as_deref(), Some("hello"));
+        assert_eq!(read_message(&mut reader, 64).unwrap(), Some(42));
+        assert_eq!(read_message::<_, i32>(&mut reader, 64).unwrap(), None);
+    }
+
+    #[test]
+    fn test_take_frame_waits_for_whole_frame() {
+        let mut frames = vec![];
+        write_frame(&mut frames, "first").unwrap();
+        write_frame(&mut frames, "second").unwrap();
+
+        let mut buf = frames[..7].to_vec();
+        assert_eq!(take_frame(&mut buf, 64).unwrap(), None);
+        buf.extend_from_slice(&frames[7..]);
+        assert_eq!(take_frame(&mut buf, 64).unwrap().as_deref(), Some("first"));
+        assert_eq!(take_frame(&mut buf, 64).unwrap().as_deref(), Some("second"));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_secrets_match() {
+        assert!(secrets_match(Some("s3cret"), Some("s3cret")));
+        assert!(secrets_match(None, None));
+        assert!(!secrets_match(Some("s3cret"), Some("s3creT")));
+        assert!(!secrets_match(Some("s3cret"), Some("s3cre")));
+        assert!(!secrets_match(Some(""), None));
+        assert!(!secrets_match(None, Some("s3cret")));
+    }
+
+    #[test]
+    fn test_oversized_frame_rejected() {
+        let mut buf = ((MAX_FRAME_LENGTH + 1) as u32).to_le_bytes().to_vec();
+        assert!(take_frame(&mut buf, MAX_FRAME_LENGTH).is_err());
+
+        let frame = encode_message(&"x".repeat(100)).unwrap();
+        assert!(read_message::<_, String>(&mut frame.as_slice(), 64).is_err());
+        assert!(read_message::<_, String>(&mut frame.as_slice(), 128).is_ok());
+        assert!(read_frame(&mut frame.as_slice(), 64).is_err());
+    }
+}
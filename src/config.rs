@@ -0,0 +1,209 @@
+//! Settings for a node read from a TOML file, so they needn't all be passed as flags. The
+//! command line overrides the file, as in
+//!
+//! ```toml
+//! [node]
+//! alias = "edge-1"
+//! data_dir = "/var/lib/iridium"
+//! threads = 4
+//!
+//! [remote]
+//! enabled = true
+//! addr = "0.0.0.0:2244"
+//! token = "hunter2"
+//! max_clients = 32
+//!
+//! [cluster]
+//! peer_host = "10.0.0.5"
+//! peer_port = "2254"
+//! join = ["10.0.0.2:2254"]
+//! secret = "swordfish"
+//! ```
+
+use std::{fs, net::SocketAddr, path::Path, path::PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{IridiumError, Result},
+    vm::check_alias,
+};
+
+/// Name of the configuration file looked for in the data directory and the working directory
+pub const CONFIG_FILE_NAME: &str = "iridium.toml";
+
+/// Settings of a node, those left out being None
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub node: NodeConfig,
+    pub remote: RemoteConfig,
+    pub cluster: ClusterConfig,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NodeConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threads: Option<usize>, // OS threads programs run on
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RemoteConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>, // whether the remote server is started
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub addr: Option<SocketAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_clients: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClusterConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_port: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub join: Vec<String>, // members to join at startup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+impl Config {
+    /// Read the configuration file at `path`
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(|e| {
+            IridiumError::StringError(format!("Unable to read {}: {}", path.display(), e))
+        })?;
+        Self::parse(&text)
+            .map_err(|e| IridiumError::StringError(format!("In {}: {}", path.display(), e)))
+    }
+
+    /// Read a configuration from `text`, warning about keys it doesn't know
+    pub fn parse(text: &str) -> Result<Self> {
+        let (config, unknown) = Self::parse_with_unknown(text)?;
+        for key in unknown {
+            warn!("Ignoring unknown configuration key {}", key);
+        }
+        if let Some(alias) = &config.node.alias {
+            check_alias(alias)?;
+        }
+        if config.node.threads == Some(0) {
+            return Err(IridiumError::StringError(
+                "node.threads must be at least 1".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+
+    /// Read a configuration from `text`, along with the keys in it that no field took, as
+    /// `section.key`
+    fn parse_with_unknown(text: &str) -> Result<(Self, Vec<String>)> {
+        let table: toml::Table = text
+            .parse()
+            .map_err(|e: toml::de::Error| IridiumError::StringError(e.message().to_string()))?;
+        let mut unknown = vec![];
+        let config = serde_ignored::deserialize(toml::Value::Table(table), |path| {
+            unknown.push(path.to_string())
+        })
+        .map_err(|e: toml::de::Error| IridiumError::StringError(e.message().to_string()))?;
+        Ok((config, unknown))
+    }
+
+    /// This configuration with each setting `overrides` has taking its place
+    pub fn merge(self, overrides: Config) -> Self {
+        Self {
+            node: NodeConfig {
+                alias: overrides.node.alias.or(self.node.alias),
+                data_dir: overrides.node.data_dir.or(self.node.data_dir),
+                threads: overrides.node.threads.or(self.node.threads),
+            },
+            remote: RemoteConfig {
+                enabled: overrides.remote.enabled.or(self.remote.enabled),
+                addr: overrides.remote.addr.or(self.remote.addr),
+                token: overrides.remote.token.or(self.remote.token),
+                max_clients: overrides.remote.max_clients.or(self.remote.max_clients),
+            },
+            cluster: ClusterConfig {
+                peer_host: overrides.cluster.peer_host.or(self.cluster.peer_host),
+                peer_port: overrides.cluster.peer_port.or(self.cluster.peer_port),
+                join: match overrides.cluster.join.is_empty() {
+                    true => self.cluster.join,
+                    false => overrides.cluster.join,
+                },
+                secret: overrides.cluster.secret.or(self.cluster.secret),
+            },
+        }
+    }
+
+    /// The configuration as TOML, with the remote token and cluster secret hidden
+    pub fn to_toml(&self) -> Result<String> {
+        let mut shown = self.clone();
+        let hide = |secret: &mut Option<String>| {
+            if secret.is_some() {
+                *secret = Some("<hidden>".to_string());
+            }
+        };
+        hide(&mut shown.remote.token);
+        hide(&mut shown.cluster.secret);
+        toml::to_string(&shown).map_err(|e| IridiumError::StringError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fixture() {
+        let config = Config::parse(include_str!("../examples/iridium.toml")).unwrap();
+        assert_eq!(config.node.alias.as_deref(), Some("edge-1"));
+        assert_eq!(config.node.threads, Some(4));
+        assert_eq!(config.remote.enabled, Some(true));
+        assert_eq!(config.remote.addr, Some("0.0.0.0:2244".parse().unwrap()));
+        assert_eq!(config.remote.max_clients, Some(32));
+        assert_eq!(config.cluster.join, vec!["10.0.0.2:2254".to_string()]);
+        assert_eq!(config.cluster.secret.as_deref(), Some("swordfish"));
+    }
+
+    #[test]
+    fn test_merge() {
+        let file = Config::parse(include_str!("../examples/iridium.toml")).unwrap();
+        let mut overrides = Config::default();
+        overrides.node.threads = Some(2);
+        let merged = file.clone().merge(overrides);
+        assert_eq!(merged.node.threads, Some(2));
+        assert_eq!(merged.node.alias, file.node.alias);
+        assert_eq!(merged.cluster.join, file.cluster.join);
+
+        let toml = merged.to_toml().unwrap();
+        assert!(toml.contains("threads = 2"), "{}", toml);
+        assert!(!toml.contains("swordfish"), "{}", toml);
+    }
+
+    #[test]
+    fn test_unknown_and_invalid() {
+        let text = "color = true\n[node]\nalias = \"a\"\nname = \"b\"\n[remote]\nenabled = false";
+        let (config, unknown) = Config::parse_with_unknown(text).unwrap();
+        assert_eq!(unknown, vec!["color", "node.name"]);
+        assert_eq!(config.node.alias.as_deref(), Some("a"));
+        let (_, unknown) =
+            Config::parse_with_unknown(include_str!("../examples/iridium.toml")).unwrap();
+        assert!(unknown.is_empty(), "{:?}", unknown);
+
+        assert!(Config::parse("[node]\nthreads = \"many\"").is_err());
+        assert!(Config::parse("[node]\nthreads = 0").is_err());
+        assert!(Config::parse("[node]\nalias = \"edge 1\"").is_err());
+        assert!(Config::parse("[node").is_err());
+    }
+}
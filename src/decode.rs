@@ -0,0 +1,279 @@
+//! Bytecode read back into instructions, the counterpart of parsing assembly text. An image
+//! is a header, read-only data and then code, each instruction an opcode followed by the
+//! operands its `Operands` signature says, padded to `INSTRUCTION_LENGTH` bytes.
+
+use std::fmt;
+
+use crate::{
+    assembler::PIE_HEADER_LENGTH,
+    error::{VmError, VmErrorKind},
+    instruction::Opcode,
+    vm::check_header,
+};
+
+/// Bytes of an instruction, opcode and operands padded the way the assembler does
+pub const INSTRUCTION_LENGTH: usize = 4;
+
+/// Something decoded from bytecode
+pub trait FromBytes: Sized {
+    /// Decode what starts `bytes`, which are `offset` bytes into the image, for errors to
+    /// say where they are
+    fn from_bytes(bytes: &[u8], offset: usize) -> Result<Self, VmError>;
+}
+
+/// What follows an opcode, as written in assembly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operands {
+    None,               // hlt
+    Register,           // jmp $0
+    TwoRegisters,       // eq $0 $1
+    ThreeRegisters,     // add $0 $1 $2
+    RegisterAndInteger, // load $0 #100
+    Integer,            // cloop #10
+    ReadOnly,           // prts @hello
+}
+
+impl From<Opcode> for Operands {
+    fn from(opcode: Opcode) -> Self {
+        match opcode {
+            Opcode::HLT | Opcode::NOP | Opcode::RET | Opcode::IGL => Operands::None,
+            Opcode::JMP
+            | Opcode::JMPF
+            | Opcode::JMPB
+            | Opcode::JMPE
+            | Opcode::ALOC
+            | Opcode::INC
+            | Opcode::DEC
+            | Opcode::DJMPE
+            | Opcode::LOOP
+            | Opcode::PUSH
+            | Opcode::POP
+            | Opcode::CALL => Operands::Register,
+            Opcode::EQ
+            | Opcode::NEQ
+            | Opcode::GT
+            | Opcode::GTE
+            | Opcode::LT
+            | Opcode::LTE
+            | Opcode::EQF64
+            | Opcode::NEQF64
+            | Opcode::GTF64
+            | Opcode::GTEF64
+            | Opcode::LTF64
+            | Opcode::LTEF64
+            | Opcode::NOT
+            | Opcode::LOADM
+            | Opcode::SETM => Operands::TwoRegisters,
+            Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::ADDF64
+            | Opcode::SUBF64
+            | Opcode::MULF64
+            | Opcode::DIVF64
+            | Opcode::AND
+            | Opcode::OR
+            | Opcode::XOR => Operands::ThreeRegisters,
+            Opcode::LOAD | Opcode::LOADF64 | Opcode::LUI | Opcode::SHL | Opcode::SHR => {
+                Operands::RegisterAndInteger
+            }
+            Opcode::CLOOP => Operands::Integer,
+            Opcode::PRTS => Operands::ReadOnly,
+        }
+    }
+}
+
+/// An operand as encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Register(u8),
+    Integer(u16),
+    ReadOnly(u16), // offset into the read-only data
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Register(register) => write!(f, "${}", register),
+            Operand::Integer(integer) => write!(f, "#{}", integer),
+            // the image has no symbol table, so strings are named after their offsets
+            Operand::ReadOnly(offset) => write!(f, "@ro_{}", offset),
+        }
+    }
+}
+
+/// An instruction read from bytecode, written as assembly when displayed
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedInstruction {
+    pub offset: usize, // of its opcode in the image
+    pub opcode: Opcode,
+    pub operands: Vec<Operand>,
+}
+
+impl FromBytes for DecodedInstruction {
+    fn from_bytes(bytes: &[u8], offset: usize) -> Result<Self, VmError> {
+        let Some(bytes) = bytes.get(..INSTRUCTION_LENGTH) else {
+            return Err(VmError {
+                kind: VmErrorKind::PcOutOfBounds {
+                    target: (offset + INSTRUCTION_LENGTH) as i64,
+                    len: offset + bytes.len(),
+                },
+                pc: offset,
+                opcode: None,
+            });
+        };
+        let opcode = Opcode::from(bytes[0]);
+        if opcode == Opcode::IGL {
+            return Err(VmError {
+                kind: VmErrorKind::UnknownOpcode(bytes[0]),
+                pc: offset,
+                opcode: None,
+            });
+        }
+        let register = |at: usize| Operand::Register(bytes[at]);
+        let integer = |at: usize| u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+        let operands = match Operands::from(opcode) {
+            Operands::None => vec![],
+            Operands::Register => vec![register(1)],
+            Operands::TwoRegisters => vec![register(1), register(2)],
+            Operands::ThreeRegisters => vec![register(1), register(2), register(3)],
+            Operands::RegisterAndInteger => vec![register(1), Operand::Integer(integer(2))],
+            Operands::Integer => vec![Operand::Integer(integer(1))],
+            Operands::ReadOnly => vec![Operand::ReadOnly(integer(1))],
+        };
+        Ok(Self {
+            offset,
+            opcode,
+            operands,
+        })
+    }
+}
+
+impl fmt::Display for DecodedInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self.opcode).to_lowercase())?;
+        for operand in &self.operands {
+            write!(f, " {}", operand)?;
+        }
+        Ok(())
+    }
+}
+
+/// A whole bytecode image read back
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedProgram {
+    pub ro: Vec<u8>,       // read-only data, as the header sized it
+    pub code_start: usize, // offset of the first instruction in the image
+    pub instructions: Vec<DecodedInstruction>,
+}
+
+impl FromBytes for DecodedProgram {
+    fn from_bytes(bytes: &[u8], offset: usize) -> Result<Self, VmError> {
+        let refused = |kind| VmError {
+            kind,
+            pc: offset,
+            opcode: None,
+        };
+        check_header(bytes).map_err(|e| refused(VmErrorKind::Header(e)))?;
+        let ro_len = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
+        let code_start = PIE_HEADER_LENGTH + ro_len;
+        let Some(ro) = bytes.get(PIE_HEADER_LENGTH..code_start) else {
+            return Err(refused(VmErrorKind::ReadOnlyOutOfBounds {
+                offset: ro_len,
+                len: bytes.len() - PIE_HEADER_LENGTH,
+            }));
+        };
+        let instructions = (code_start..bytes.len())
+            .step_by(INSTRUCTION_LENGTH)
+            .map(|at| DecodedInstruction::from_bytes(&bytes[at..], offset + at))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            ro: ro.to_vec(),
+            code_start: offset + code_start,
+            instructions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assembler::{program::Program, token::Token, Assembler},
+        parse::parse_complete,
+    };
+
+    #[test]
+    fn test_decode_assembled() {
+        let source = include_str!("../examples/tour.iasm");
+        let mut asm = Assembler::new();
+        let image = asm.assemble(source).unwrap();
+        let decoded = DecodedProgram::from_bytes(&image, 0).unwrap();
+        assert_eq!(decoded.ro, b"Hello\0Bye\0");
+        assert_eq!(decoded.code_start, PIE_HEADER_LENGTH + 10);
+
+        let program = parse_complete::<Program>(source).unwrap();
+        let written: Vec<_> = program
+            .instructions
+            .iter()
+            .filter(|i| i.is_opcode())
+            .collect();
+        assert_eq!(decoded.instructions.len(), written.len());
+        for (at, (decoded, written)) in decoded.instructions.iter().zip(written).enumerate() {
+            assert_eq!(
+                decoded.offset,
+                PIE_HEADER_LENGTH + 10 + at * INSTRUCTION_LENGTH
+            );
+            assert_eq!(
+                Some(&Token::Op {
+                    code: decoded.opcode
+                }),
+                written.opcode.as_deref()
+            );
+            let usage = written.label.iter().filter(|_| written.is_label_usage());
+            let operands = [&written.operand1, &written.operand2, &written.operand3];
+            let expected: Vec<Operand> = usage
+                .chain(operands.into_iter().flatten())
+                .map(|token| match &token.value {
+                    Token::Register { reg_num } => Operand::Register(*reg_num),
+                    Token::IntegerOperand { value } => Operand::Integer(*value as u16),
+                    Token::LabelUsage { name } => {
+                        Operand::ReadOnly(asm.symbols.symbol_value(name).unwrap() as u16)
+                    }
+                    other => panic!("unexpected operand {:?}", other),
+                })
+                .collect();
+            assert_eq!(decoded.operands, expected, "{}", decoded);
+        }
+    }
+
+    #[test]
+    fn test_decode_instruction() {
+        let load = DecodedInstruction::from_bytes(&[0, 1, 1, 244], 70).unwrap();
+        assert_eq!(load.to_string(), "load $1 #500");
+        assert_eq!(load.offset, 70);
+        let cloop = DecodedInstruction::from_bytes(&[Opcode::CLOOP as u8, 0, 10, 0], 0).unwrap();
+        assert_eq!(cloop.operands, [Operand::Integer(10)]);
+
+        let err = DecodedInstruction::from_bytes(&[200, 0, 0, 0], 74).unwrap_err();
+        assert_eq!((err.kind, err.pc), (VmErrorKind::UnknownOpcode(200), 74));
+        let err = DecodedInstruction::from_bytes(&[5, 0], 74).unwrap_err();
+        assert_eq!(err.code(), "VM004");
+    }
+
+    #[test]
+    fn test_damaged_images() {
+        let image = Assembler::new()
+            .assemble(include_str!("../examples/hello.iasm"))
+            .unwrap();
+        let err = DecodedProgram::from_bytes(b".data\n.code\nhlt\n", 0).unwrap_err();
+        assert_eq!(err.code(), "VM009");
+        let err = DecodedProgram::from_bytes(&image[..PIE_HEADER_LENGTH + 2], 0).unwrap_err();
+        assert_eq!(err.code(), "VM005");
+        let mut image = image;
+        image.extend([5, 0]);
+        let err = DecodedProgram::from_bytes(&image, 0).unwrap_err();
+        assert_eq!((err.code(), err.pc), ("VM004", image.len() - 2));
+    }
+}
@@ -0,0 +1,184 @@
+//! Turns a bytecode image back into a listing of the assembly it came from: the header, the
+//! read-only strings as labeled `.asciiz` directives and the code as one instruction to a
+//! line, each after the byte it starts at in the image.
+//!
+//! Images carry no symbol table, so the strings get labels made from their offsets in the
+//! read-only section, `ro_0`, `ro_6` and so on, and `prts` refers to them by those.
+
+use std::fmt::Write;
+
+use crate::{
+    assembler::{PIE_HEADER_LENGTH, PIE_HEADER_PREFIX},
+    decode::{DecodedInstruction, FromBytes, INSTRUCTION_LENGTH},
+    error::{hex, IridiumError, Result, VmErrorKind},
+    vm::check_header,
+};
+
+/// Writes listings of bytecode images
+#[derive(Debug, Clone, Default)]
+pub struct Disassembler {
+    bytes: bool,   // whether each line shows the bytes it came from
+    no_data: bool, // whether the read-only section is left out
+}
+
+impl Disassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Show the bytes of each string and instruction in hex after it
+    pub fn with_bytes(mut self, bytes: bool) -> Self {
+        self.bytes = bytes;
+        self
+    }
+
+    /// Leave the read-only section out of the listing
+    pub fn with_no_data(mut self, no_data: bool) -> Self {
+        self.no_data = no_data;
+        self
+    }
+
+    /// A listing of `image`, a header followed by read-only data and code
+    pub fn disassemble(&self, image: &[u8]) -> Result<String> {
+        check_header(image)?;
+        let ro_len = u32::from_le_bytes([image[4], image[5], image[6], image[7]]) as usize;
+        let code_start = PIE_HEADER_LENGTH + ro_len;
+        let (ro, code) = match image.get(PIE_HEADER_LENGTH..code_start) {
+            Some(ro) => (ro, &image[code_start..]),
+            None => {
+                return Err(IridiumError::StringError(format!(
+                    "Bytecode is truncated: header declares {} bytes of read-only data but only {} are present",
+                    ro_len,
+                    image.len() - PIE_HEADER_LENGTH
+                )))
+            }
+        };
+
+        let mut listing = String::new();
+        let _ = writeln!(
+            listing,
+            "; header: {} bytes, prefix {}, {} bytes of read-only data, {} bytes of code",
+            PIE_HEADER_LENGTH,
+            hex(&PIE_HEADER_PREFIX),
+            ro.len(),
+            code.len()
+        );
+        if !self.no_data {
+            listing.push_str(".data\n");
+            self.list_data(ro, &mut listing);
+        }
+        listing.push_str(".code\n");
+        self.list_code(code, code_start, &mut listing);
+        Ok(listing)
+    }
+
+    /// Each zero-terminated string in `ro` as an `.asciiz` directive
+    fn list_data(&self, ro: &[u8], listing: &mut String) {
+        let mut offset = 0;
+        while offset < ro.len() {
+            let Some(length) = ro[offset..].iter().position(|&byte| byte == 0) else {
+                let _ = writeln!(
+                    listing,
+                    "; {} trailing bytes without a terminating zero: {}",
+                    ro.len() - offset,
+                    hex(&ro[offset..])
+                );
+                return;
+            };
+            let string = String::from_utf8_lossy(&ro[offset..offset + length]);
+            let line = format!("ro_{}: .asciiz '{}'", offset, string.escape_debug());
+            self.push_line(listing, &line, &ro[offset..=offset + length]);
+            offset += length + 1;
+        }
+    }
+
+    /// Each instruction in `code`, which starts `start` bytes into the image
+    fn list_code(&self, code: &[u8], start: usize, listing: &mut String) {
+        let mut chunks = code.chunks_exact(INSTRUCTION_LENGTH);
+        for (i, instruction) in chunks.by_ref().enumerate() {
+            let offset = start + i * INSTRUCTION_LENGTH;
+            let text = match DecodedInstruction::from_bytes(instruction, offset) {
+                Ok(decoded) => decoded.to_string(),
+                Err(e) => match e.kind {
+                    VmErrorKind::UnknownOpcode(opcode) => format!("; unknown opcode {}", opcode),
+                    _ => format!("; {}", e),
+                },
+            };
+            let line = format!("{:>6}  {}", offset, text);
+            self.push_line(listing, &line, instruction);
+        }
+        let rest = chunks.remainder();
+        if !rest.is_empty() {
+            let _ = writeln!(
+                listing,
+                "; {} trailing bytes too few for an instruction: {}",
+                rest.len(),
+                hex(rest)
+            );
+        }
+    }
+
+    /// Add `line` to the listing, with `bytes` after it if they are to be shown
+    fn push_line(&self, listing: &mut String, line: &str, bytes: &[u8]) {
+        if self.bytes {
+            let _ = writeln!(listing, "{:<32}; {}", line, hex(bytes));
+        } else {
+            let _ = writeln!(listing, "{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_listing_of_fixture() {
+        let image = Assembler::new()
+            .assemble(include_str!("../examples/tour.iasm"))
+            .unwrap();
+        let listing = Disassembler::new().disassemble(&image).unwrap();
+        assert_eq!(listing, include_str!("../examples/tour.listing"));
+    }
+
+    #[test]
+    fn test_bytes_and_no_data() {
+        let image = Assembler::new()
+            .assemble(include_str!("../examples/hello.iasm"))
+            .unwrap();
+        let listing = Disassembler::new()
+            .with_bytes(true)
+            .with_no_data(true)
+            .disassemble(&image)
+            .unwrap();
+        assert_eq!(
+            listing,
+            "; header: 64 bytes, prefix 2d 32 31 2d, 6 bytes of read-only data, 8 bytes of code\n\
+             .code\n    \
+             70  prts @ro_0              ; 15 00 00 00\n    \
+             74  hlt                     ; 05 00 00 00\n"
+        );
+    }
+
+    #[test]
+    fn test_damaged_images() {
+        let mut image = Assembler::new()
+            .assemble(include_str!("../examples/hello.iasm"))
+            .unwrap();
+        image.extend([200, 0, 0, 0, 5, 0]);
+        let listing = Disassembler::new().disassemble(&image).unwrap();
+        assert!(listing.ends_with(
+            "    78  ; unknown opcode 200\n; 2 trailing bytes too few for an instruction: 05 00\n"
+        ));
+
+        let err = Disassembler::new()
+            .disassemble(b".data\n.code\nhlt\n")
+            .unwrap_err();
+        assert_eq!(err.code(), "VM009");
+        let err = Disassembler::new()
+            .disassemble(&image[..PIE_HEADER_LENGTH + 2])
+            .unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{}", err);
+    }
+}
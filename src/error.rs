@@ -1,48 +1,285 @@
+//! Errors of the assembler, the VM and everything around them.
+//!
+//! Every variant has a short code that stays the same from one release to the next, so it
+//! can be logged, searched for and matched on while the wording of the message changes.
+//! `code()` returns it and the message starts with it in brackets, as in
+//! `[VM001] Division by zero in DIV at byte 68`.
+//!
+//! | Code   | Error                                                        |
+//! |--------|--------------------------------------------------------------|
+//! | ASM001 | `AssemblerError::InsufficientSections`                       |
+//! | ASM002 | `AssemblerError::ParsingError`                               |
+//! | ASM003 | `AssemblerError::NoSegmentDeclarationFound`                  |
+//! | ASM004 | `AssemblerError::StringConstantDeclaredWithoutLabel`         |
+//! | ASM005 | `AssemblerError::SymbolAlreadyDeclared`                      |
+//! | ASM006 | `AssemblerError::UnknownDirectiveFound`                      |
+//! | ASM007 | `AssemblerError::MoreErrors`                                 |
+//! | ASM008 | `AssemblerError::UnknownLabel`                               |
+//! | VM001  | `VmErrorKind::DivideByZero`                                  |
+//! | VM002  | `VmErrorKind::RegisterOutOfRange`                            |
+//! | VM003  | `VmErrorKind::HeapOutOfBounds`                               |
+//! | VM004  | `VmErrorKind::PcOutOfBounds`                                 |
+//! | VM005  | `VmErrorKind::ReadOnlyOutOfBounds`                           |
+//! | VM006  | `VmErrorKind::UnknownOpcode`                                 |
+//! | VM007  | `VmErrorKind::UnsupportedOpcode`                             |
+//! | VM008  | `HeaderError::TooShort`                                      |
+//! | VM009  | `HeaderError::BadMagic`                                      |
+//! | VM010  | `VmErrorKind::Overflow`                                      |
+//! | IR001  | `IridiumError::Io`                                           |
+//! | IR002  | `IridiumError::Serde`                                        |
+//! | IR003  | `IridiumError::Send`                                         |
+//! | IR004  | `IridiumError::Recv`                                         |
+//! | IR005  | `IridiumError::Assemble`, whose errors have codes of their own |
+//! | IR006  | `IridiumError::Tls`                                          |
+//! | IR007  | `IridiumError::NotFound`                                     |
+//! | IR008  | `IridiumError::StringError`                                  |
+//! | IR009  | `IridiumError::Parse`                                        |
+//!
+//! `IridiumError::Vm` takes the code of the `VmErrorKind` it carries, and `VmErrorKind::Header`
+//! and `IridiumError::Header` that of the `HeaderError`. The enums are
+//! `#[non_exhaustive]`: new variants get new codes, and codes are never reused.
+
 use std::{io, sync::mpsc};
 
 use nom_supreme::error::ErrorTree;
 use thiserror::Error;
 
+use crate::{
+    assembler::{PIE_HEADER_LENGTH, PIE_HEADER_PREFIX},
+    instruction::Opcode,
+};
+
 pub type ParseError<'a> = ErrorTree<&'a str>;
 
-#[derive(Debug, Error, Clone)]
+/// Where in a program's source something is, both counted from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum AssemblerError {
-    #[error("Insufficient sections")]
+    #[error("[ASM001] Insufficient sections")]
     InsufficientSections,
-    #[error("Error from parsing")]
+    #[error("[ASM002] Error from parsing")]
     ParsingError,
-    #[error("Label found outside segment at: {0}")]
-    NoSegmentDeclarationFound(u32),
-    #[error("String declared without label at: {0}")]
-    StringConstantDeclaredWithoutLabel(u32),
-    #[error("Symbol already declared")]
-    SymbolAlreadyDeclared,
-    #[error("Unknown directive: {0}")]
-    UnknownDirectiveFound(String),
+    #[error("[ASM003] Label found outside segment at: {0}")]
+    NoSegmentDeclarationFound(Location),
+    #[error("[ASM004] String declared without label at: {0}")]
+    StringConstantDeclaredWithoutLabel(Location),
+    #[error("[ASM005] Symbol already declared at: {0}")]
+    SymbolAlreadyDeclared(Location),
+    #[error("[ASM006] Unknown directive: {0} at: {1}")]
+    UnknownDirectiveFound(String, Location),
+    #[error("[ASM007] ... and {0} more errors")]
+    MoreErrors(usize),
+    #[error("[ASM008] Unknown label: {0} at: {1}")]
+    UnknownLabel(String, Location),
+}
+
+impl AssemblerError {
+    /// Stable code of this error, see the module docs
+    pub fn code(&self) -> &'static str {
+        match self {
+            AssemblerError::InsufficientSections => "ASM001",
+            AssemblerError::ParsingError => "ASM002",
+            AssemblerError::NoSegmentDeclarationFound(_) => "ASM003",
+            AssemblerError::StringConstantDeclaredWithoutLabel(_) => "ASM004",
+            AssemblerError::SymbolAlreadyDeclared(_) => "ASM005",
+            AssemblerError::UnknownDirectiveFound(..) => "ASM006",
+            AssemblerError::MoreErrors(_) => "ASM007",
+            AssemblerError::UnknownLabel(..) => "ASM008",
+        }
+    }
+}
+
+/// Why bytecode was refused before any of it ran
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HeaderError {
+    #[error(
+        "[VM008] Bytecode is only {len} (0x{len:x}) bytes, too short for the {} (0x{:x}) byte header",
+        PIE_HEADER_LENGTH,
+        PIE_HEADER_LENGTH
+    )]
+    TooShort { len: usize },
+    #[error(
+        "[VM009] Bytecode starts with {} instead of the header prefix {}{}",
+        hex(found),
+        hex(&PIE_HEADER_PREFIX),
+        if self.looks_like_text() {
+            " (it looks like text; assembly source has to be assembled first)"
+        } else {
+            ""
+        }
+    )]
+    BadMagic { found: [u8; 4] },
+}
+
+impl HeaderError {
+    /// Stable code of this error, see the module docs
+    pub fn code(&self) -> &'static str {
+        match self {
+            HeaderError::TooShort { .. } => "VM008",
+            HeaderError::BadMagic { .. } => "VM009",
+        }
+    }
+
+    /// Whether the bytes found instead of a header look like the start of a text file, such
+    /// as assembly source
+    pub fn looks_like_text(&self) -> bool {
+        match self {
+            HeaderError::BadMagic { found } => found
+                .iter()
+                .all(|byte| byte.is_ascii_graphic() || byte.is_ascii_whitespace()),
+            _ => false,
+        }
+    }
+}
+
+/// `bytes` in hex, as in `2d 32 31 2d`
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    bytes.join(" ")
+}
+
+/// Why an instruction couldn't be carried out
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum VmErrorKind {
+    #[error("[VM001] Division by zero")]
+    DivideByZero,
+    #[error("[VM002] There is no register ${0}, only $0 to $31")]
+    RegisterOutOfRange(u8),
+    #[error("[VM003] Heap offset {offset} is outside the {len} byte heap")]
+    HeapOutOfBounds { offset: i64, len: usize },
+    #[error("[VM004] Program counter {target} is outside the {len} byte program")]
+    PcOutOfBounds { target: i64, len: usize },
+    #[error("[VM005] Read-only data at {offset} runs past the {len} bytes there are")]
+    ReadOnlyOutOfBounds { offset: usize, len: usize },
+    #[error("[VM006] Unknown opcode {0}")]
+    UnknownOpcode(u8),
+    #[error("[VM007] Opcode is not supported by this VM")]
+    UnsupportedOpcode,
+    #[error("[VM010] Integer overflow")]
+    Overflow,
+    #[error("{0}")]
+    Header(#[from] HeaderError),
+}
+
+impl VmErrorKind {
+    /// Stable code of this error, see the module docs
+    pub fn code(&self) -> &'static str {
+        match self {
+            VmErrorKind::DivideByZero => "VM001",
+            VmErrorKind::RegisterOutOfRange(_) => "VM002",
+            VmErrorKind::HeapOutOfBounds { .. } => "VM003",
+            VmErrorKind::PcOutOfBounds { .. } => "VM004",
+            VmErrorKind::ReadOnlyOutOfBounds { .. } => "VM005",
+            VmErrorKind::UnknownOpcode(_) => "VM006",
+            VmErrorKind::UnsupportedOpcode => "VM007",
+            VmErrorKind::Overflow => "VM010",
+            VmErrorKind::Header(e) => e.code(),
+        }
+    }
+}
+
+/// An instruction the VM failed to carry out, and where it was
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct VmError {
+    pub kind: VmErrorKind,
+    pub pc: usize,              // of the failed instruction's opcode
+    pub opcode: Option<Opcode>, // None when the opcode itself was unknown
+}
+
+impl std::fmt::Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.opcode {
+            // refused before it ran, so not at any instruction
+            _ if matches!(self.kind, VmErrorKind::Header(_)) => write!(f, "{}", self.kind),
+            Some(opcode) => write!(f, "{} in {:?} at byte {}", self.kind, opcode, self.pc),
+            None => write!(f, "{} at byte {}", self.kind, self.pc),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+impl VmError {
+    /// Stable code of this error, that of its kind
+    pub fn code(&self) -> &'static str {
+        self.kind.code()
+    }
 }
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum IridiumError {
     /// IO error
-    #[error("Io Error: {0}")]
+    #[error("[IR001] Io Error: {0}")]
     Io(#[from] io::Error),
     /// serialization or deserialization error
-    #[error("serde_json error: {0}")]
+    #[error("[IR002] serde_json error: {0}")]
     Serde(#[from] serde_json::Error),
     /// Pipe send prompt/message error
-    #[error("Pipe send Error: {0}")]
+    #[error("[IR003] Pipe send Error: {0}")]
     Send(mpsc::SendError<String>),
     /// Pipe send prompt/message error
-    #[error("Pipe receive Error: {0}")]
+    #[error("[IR004] Pipe receive Error: {0}")]
     Recv(#[from] mpsc::RecvError),
+    /// Source that couldn't be parsed, saying where and why
+    #[error("[IR009] {0}")]
+    Parse(String),
     /// Assemble error
-    #[error("Assemble Error")]
+    #[error("[IR005] Assemble Error")]
     Assemble(Vec<AssemblerError>),
-    /// Error with a string message
+    /// TLS configuration or session error
+    #[cfg(feature = "tls")]
+    #[error("[IR006] TLS Error: {0}")]
+    Tls(#[from] rustls::Error),
+    /// An instruction the VM couldn't carry out
+    #[error("{0}")]
+    Vm(#[from] VmError),
+    /// Bytecode without a usable header
     #[error("{0}")]
+    Header(#[from] HeaderError),
+    /// No cluster member goes by this alias
+    #[error("[IR007] No cluster member named {0}")]
+    NotFound(String),
+    /// Error with a string message
+    #[error("[IR008] {0}")]
     StringError(String),
 }
 
+impl IridiumError {
+    /// Stable code of this error, see the module docs
+    pub fn code(&self) -> &'static str {
+        match self {
+            IridiumError::Io(_) => "IR001",
+            IridiumError::Serde(_) => "IR002",
+            IridiumError::Send(_) => "IR003",
+            IridiumError::Recv(_) => "IR004",
+            IridiumError::Assemble(_) => "IR005",
+            #[cfg(feature = "tls")]
+            IridiumError::Tls(_) => "IR006",
+            IridiumError::NotFound(_) => "IR007",
+            IridiumError::StringError(_) => "IR008",
+            IridiumError::Parse(_) => "IR009",
+            IridiumError::Vm(e) => e.code(),
+            IridiumError::Header(e) => e.code(),
+        }
+    }
+}
+
 impl From<mpsc::SendError<String>> for IridiumError {
     fn from(err: mpsc::SendError<String>) -> IridiumError {
         IridiumError::Send(err)
@@ -56,3 +293,55 @@ impl From<Vec<AssemblerError>> for IridiumError {
 }
 
 pub type Result<T> = std::result::Result<T, IridiumError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes_stable() {
+        let errors: Vec<(IridiumError, &str)> = vec![
+            (io::Error::other("disk on fire").into(), "IR001"),
+            (IridiumError::Assemble(vec![]), "IR005"),
+            (IridiumError::NotFound("node1".to_string()), "IR007"),
+            (IridiumError::StringError("oops".to_string()), "IR008"),
+            (IridiumError::Parse("Syntax error".to_string()), "IR009"),
+            (
+                VmError {
+                    kind: VmErrorKind::UnknownOpcode(200),
+                    pc: 64,
+                    opcode: None,
+                }
+                .into(),
+                "VM006",
+            ),
+        ];
+        for (error, code) in errors {
+            assert_eq!(error.code(), code);
+            assert!(
+                error.to_string().starts_with(&format!("[{}] ", code)),
+                "{}",
+                error
+            );
+        }
+
+        let at = Location { line: 3, column: 1 };
+        let assembler = [
+            (AssemblerError::InsufficientSections, "ASM001"),
+            (AssemblerError::NoSegmentDeclarationFound(at), "ASM003"),
+            (AssemblerError::SymbolAlreadyDeclared(at), "ASM005"),
+            (AssemblerError::MoreErrors(9), "ASM007"),
+            (
+                AssemblerError::UnknownLabel("loop".to_string(), at),
+                "ASM008",
+            ),
+        ];
+        for (error, code) in assembler {
+            assert_eq!(error.code(), code);
+            assert!(error.to_string().starts_with(&format!("[{}] ", code)));
+        }
+        assert_eq!(VmErrorKind::DivideByZero.code(), "VM001");
+        assert_eq!(VmErrorKind::UnsupportedOpcode.code(), "VM007");
+        assert_eq!(VmErrorKind::Overflow.code(), "VM010");
+    }
+}
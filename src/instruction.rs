@@ -22,7 +22,6 @@ pub enum Opcode {
     INC,
     DEC,
     DJMPE,
-    IGL,
     PRTS,
     LOADF64,
     ADDF64,
@@ -50,6 +49,8 @@ pub enum Opcode {
     POP,
     CALL,
     RET,
+    // last, so the opcodes before it are numbered as From<u8> reads them
+    IGL,
 }
 
 impl From<u8> for Opcode {
@@ -198,4 +199,14 @@ mod tests {
         let opcode = Opcode::from("illegal");
         assert_eq!(opcode, Opcode::IGL);
     }
+
+    #[test]
+    fn test_opcode_numbers() {
+        // the assembler writes an opcode as its number, the VM reads it back with From<u8>
+        for byte in 0..=Opcode::RET as u8 {
+            assert_eq!(Opcode::from(byte) as u8, byte);
+        }
+        assert_eq!(Opcode::PRTS as u8, 21);
+        assert_eq!(Opcode::from(Opcode::IGL as u8), Opcode::IGL);
+    }
 }
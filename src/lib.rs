@@ -1,10 +1,15 @@
 pub mod assembler;
+pub mod benchmark;
 pub mod cluster;
 pub mod common;
+pub mod config;
+pub mod decode;
+pub mod disassembler;
 pub mod error;
 pub mod instruction;
 pub mod parse;
 pub mod remote;
 pub mod repl;
+pub mod report;
 pub mod scheduler;
 pub mod vm;
@@ -1,6 +1,7 @@
 use nom::IResult;
+use nom_supreme::error::GenericErrorTree;
 
-use crate::error::ParseError;
+use crate::error::{IridiumError, ParseError, Result};
 
 pub type ParseResult<'a, T> = IResult<&'a str, T, ParseError<'a>>;
 
@@ -8,3 +9,103 @@ pub trait Parse<'a>: Sized {
     /// Parse the given string into self
     fn parse(input: &'a str) -> ParseResult<'a, Self>;
 }
+
+/// Parse the whole of `input`, failing with where and why it couldn't be when some of it
+/// is left over or doesn't parse at all
+pub fn parse_complete<'a, T: Parse<'a>>(input: &'a str) -> Result<T> {
+    let error = match T::parse(input) {
+        Ok((remainder, parsed)) if remainder.trim().is_empty() => return Ok(parsed),
+        // parsing again from what was left over says what stopped it there
+        Ok((remainder, _)) => match T::parse(remainder) {
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) => describe_error(input, &e),
+            _ => describe_location(input, remainder, "unexpected input"),
+        },
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) => describe_error(input, &e),
+        Err(nom::Err::Incomplete(_)) => describe_location(input, "", "incomplete input"),
+    };
+    Err(IridiumError::Parse(error))
+}
+
+/// Where in `input` parsing failed and what was expected there, from the alternative that
+/// got furthest
+pub fn describe_error(input: &str, error: &ParseError) -> String {
+    let (location, expected) = furthest(error);
+    describe_location(input, location, &expected)
+}
+
+/// The failure deepest into the input, and what was expected there
+fn furthest<'a>(error: &ParseError<'a>) -> (&'a str, String) {
+    match error {
+        GenericErrorTree::Base { location, kind } => (location, kind.to_string()),
+        GenericErrorTree::Stack { base, .. } => furthest(base),
+        GenericErrorTree::Alt(siblings) => siblings
+            .iter()
+            .map(furthest)
+            .min_by_key(|(location, _)| location.len())
+            .unwrap_or(("", "nothing parsed".to_string())),
+    }
+}
+
+/// "Syntax error on line 2, column 9: `reason` at `token`", `location` being the rest of
+/// `input` from where things went wrong
+fn describe_location(input: &str, location: &str, reason: &str) -> String {
+    let (line, column) = line_column(input, input.len().saturating_sub(location.len()));
+    let token = match location.split_whitespace().next() {
+        Some(token) => format!("`{}`", token),
+        None => "end of input".to_string(),
+    };
+    format!(
+        "Syntax error on line {}, column {}: {} at {}",
+        line, column, reason, token
+    )
+}
+
+/// Line and column, both from 1, of the byte `offset` into `input`
+pub fn line_column(input: &str, offset: usize) -> (usize, usize) {
+    let before = &input[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |at| at + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+/// The line of `input` holding the byte `offset`, with `^` under the `len` bytes from it
+pub fn underline(input: &str, offset: usize, len: usize) -> String {
+    let line_start = input[..offset].rfind('\n').map_or(0, |at| at + 1);
+    let line_end = input[offset..]
+        .find('\n')
+        .map_or(input.len(), |at| offset + at);
+    let indent = input[line_start..offset].chars().count();
+    let width = input[offset..(offset + len).min(line_end)]
+        .chars()
+        .count()
+        .max(1);
+    format!(
+        "{}\n{}{}",
+        &input[line_start..line_end],
+        " ".repeat(indent),
+        "^".repeat(width)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::program::Program;
+
+    #[test]
+    fn test_parse_error_location() {
+        let err = parse_complete::<Program>("load $0 #1\nload $1 ???\n").unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("line 2, column 9"), "{}", message);
+        assert!(message.contains("`???`"), "{}", message);
+
+        assert!(parse_complete::<Program>("load $0 #1\nhlt").is_ok());
+    }
+
+    #[test]
+    fn test_underline() {
+        let input = "load $0 #1\njmpe @nowhere\nhlt";
+        assert_eq!(line_column(input, 16), (2, 6));
+        assert_eq!(underline(input, 16, 8), "jmpe @nowhere\n     ^^^^^^^^");
+    }
+}
@@ -0,0 +1,103 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use crate::error::IridiumError;
+
+/// A network in CIDR notation, such as `10.1.0.0/16`; a bare address means just that host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Whether `addr` falls inside this network
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether a peer may connect: an empty allowlist lets everyone in
+pub fn is_allowed(allowlist: &[Cidr], addr: IpAddr) -> bool {
+    allowlist.is_empty() || allowlist.iter().any(|cidr| cidr.contains(addr))
+}
+
+impl FromStr for Cidr {
+    type Err = IridiumError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || IridiumError::StringError(format!("Invalid CIDR: {}", s));
+        let (network, prefix) = match s.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (s, None),
+        };
+        let network = network.parse::<IpAddr>().map_err(|_| invalid())?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+
+        Ok(Self { network, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_single_host() {
+        let cidr: Cidr = "192.168.1.10/32".parse().unwrap();
+        assert!(cidr.contains(ip("192.168.1.10")));
+        assert!(!cidr.contains(ip("192.168.1.11")));
+        assert_eq!("192.168.1.10".parse::<Cidr>().unwrap(), cidr);
+    }
+
+    #[test]
+    fn test_subnet() {
+        let cidr: Cidr = "10.1.2.0/24".parse().unwrap();
+        assert!(cidr.contains(ip("10.1.2.0")));
+        assert!(cidr.contains(ip("10.1.2.255")));
+        assert!(!cidr.contains(ip("10.1.3.1")));
+        assert!(!cidr.contains(ip("::1")));
+        assert!(cidr.contains(ip("::ffff:10.1.2.7")));
+    }
+
+    #[test]
+    fn test_invalid_cidr() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_empty_allowlist_allows_all() {
+        assert!(is_allowed(&[], ip("203.0.113.9")));
+        let allowlist = ["127.0.0.0/8".parse().unwrap()];
+        assert!(is_allowed(&allowlist, ip("127.0.0.1")));
+        assert!(!is_allowed(&allowlist, ip("203.0.113.9")));
+    }
+}
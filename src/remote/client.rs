@@ -1,76 +1,676 @@
 use std::{
-    io::{BufRead, BufReader, BufWriter},
-    net::TcpStream,
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write},
+    iter,
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
     thread,
+    time::{Duration, Instant},
 };
 
+use log::{debug, info, warn};
+
 use crate::{
-    common::w,
+    common::{secrets_match, take_frame, w, write_frame, MAX_FRAME_LENGTH},
     error::{IridiumError, Result},
-    repl::{self, REPL},
+    remote::{
+        connections::Connections,
+        framed::{FRAMED_MODE_OFFER, FRAMED_MODE_REQUEST},
+        message::{RemoteMessage, STRUCTURED_MODE_OFFER, STRUCTURED_MODE_REQUEST},
+        metrics::{Metered, RemoteMetrics},
+        stream::Stream,
+        upload::{Staging, MAX_UPLOAD_SIZE, UPLOAD_COMMAND},
+    },
+    repl::{self, Flow, REPL},
     vm::VM,
 };
 
+const MAX_AUTH_ATTEMPTS: usize = 3;
+const AUTH_FAILURE_DELAY: Duration = Duration::from_millis(500);
+
+type SessionWriter = BufWriter<Metered<Box<dyn Stream>>>;
+
+/// How a session exchanges commands and output, negotiated by the client's first line
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Lines,      // plain text lines, for telnet
+    Framed,     // length-prefixed frames in both directions
+    Structured, // lines in, RemoteMessage JSON lines out
+}
+
 pub struct Client {
     repl: repl::REPL,
-    reader: BufReader<TcpStream>,
-    writer: BufWriter<TcpStream>,
-    stream: TcpStream,
+    reader: BufReader<Box<dyn Stream>>,
+    writer: Arc<Mutex<SessionWriter>>, // the only way onto the socket, shared with the forwarder thread
+    stream: Box<dyn Stream>,
+    token: Option<String>, // shared secret the client must send before anything else
+    idle_timeout: Option<Duration>, // close the session when no command arrives for this long
+    mode: Mode,
+    partial_frame: Vec<u8>, // bytes of a frame that has not fully arrived yet
+    connections: Option<Connections>, // registry this session lists itself in while it runs
+    metrics: Arc<RemoteMetrics>,
+    staging: Option<Staging>, // files uploaded this session, deleted when it ends
+    staging_dir: Option<PathBuf>, // where uploads are staged, the temporary directory if unset
 }
 
 impl Client {
-    /// Create new client with writer and reader from TcpStream
-    pub fn new(stream: TcpStream) -> Result<Self> {
-        let tcp_reader = stream.try_clone()?;
-        let tcp_writer = stream.try_clone()?;
+    /// Create new client with writer and reader from a plain or TLS stream
+    pub fn new<S: Stream + 'static>(stream: S) -> Result<Self> {
+        let reader = stream.try_clone()?;
+        let writer = stream.try_clone()?;
+        let metrics = Arc::new(RemoteMetrics::new());
         Ok(Self {
-            reader: BufReader::new(tcp_reader),
-            writer: BufWriter::new(tcp_writer),
-            repl: REPL::new(VM::new()),
-            stream,
+            reader: BufReader::new(reader),
+            writer: Arc::new(Mutex::new(BufWriter::new(Metered::new(
+                writer,
+                metrics.clone(),
+            )))),
+            repl: REPL::new(VM::new()).with_remote_session(),
+            stream: Box::new(stream),
+            token: None,
+            idle_timeout: None,
+            mode: Mode::Lines,
+            partial_frame: vec![],
+            connections: None,
+            metrics,
+            staging: None,
+            staging_dir: None,
         })
     }
 
+    /// Operate on the given VM, shared with other sessions, instead of a fresh one
+    pub fn with_vm(mut self, vm: Arc<Mutex<VM>>) -> Self {
+        self.repl = REPL::shared(vm).with_remote_session();
+        self
+    }
+
+    /// List this session in the server's connection registry while it runs
+    pub fn with_connections(mut self, connections: Connections) -> Self {
+        self.connections = Some(connections);
+        self
+    }
+
+    /// Count this session's commands and output in the server's metrics
+    pub fn with_metrics(mut self, metrics: Arc<RemoteMetrics>) -> Self {
+        self.lock_writer().get_mut().metrics = metrics.clone();
+        self.metrics = metrics;
+        self
+    }
+
+    /// Stage this session's uploads under `dir` instead of the temporary directory
+    pub fn with_staging_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.staging_dir = dir;
+        self
+    }
+
+    /// Require the client to authenticate with `AUTH <token>` before accepting input
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Disconnect the client when no complete command arrives within `timeout`
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Lock the session's writer; holding it keeps the forwarder thread from writing in between
+    fn lock_writer(&self) -> MutexGuard<'_, SessionWriter> {
+        self.writer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
     /// Write ">>>"
     fn write_prompt(&mut self) -> Result<()> {
-        w(&mut self.writer, repl::PROMPT)?;
+        w(&mut *self.lock_writer(), repl::PROMPT)?;
         Ok(())
     }
 
-    /// Listen for input and send to client
+    /// Write a message as a line or, in framed mode, as a single frame. Structured sessions
+    /// only hear from the client itself when something ends the session, so it is sent as an error
+    fn send(&mut self, msg: &str) -> Result<()> {
+        match self.mode {
+            Mode::Lines => w(&mut *self.lock_writer(), msg),
+            Mode::Framed => write_frame(&mut *self.lock_writer(), msg),
+            Mode::Structured => {
+                let msg = RemoteMessage::Error(msg.trim_end().to_string());
+                w(&mut *self.lock_writer(), &msg.to_line()?)
+            }
+        }
+    }
+
+    /// In framed mode, collect everything the last command produced into one frame so
+    /// the client knows where its response ends
+    fn send_response(&mut self) -> Result<()> {
+        let response = match &self.repl.rx_pipe {
+            Some(rx) => rx.try_iter().collect::<String>(),
+            None => String::new(),
+        };
+        write_frame(&mut *self.lock_writer(), &response)
+    }
+
+    /// Listen for input and send to client. Ends when the REPL is dropped, or when the client
+    /// can no longer be written to, in which case the connection is shut down so the session ends too
     fn recv_loop(&mut self) -> Result<()> {
         let rx = self.repl.rx_pipe.take();
-        let writer = self.stream.try_clone()?;
+        let writer = self.writer.clone();
+        let stream = self.stream.try_clone()?;
         thread::spawn(move || -> Result<()> {
             let chan = rx.unwrap();
-            let mut writer = BufWriter::new(writer);
             loop {
-                match chan.recv() {
-                    Ok(msg) => w(&mut writer, &msg),
-                    Err(e) => Err(IridiumError::Recv(e)),
-                }?;
+                let msg = match chan.recv() {
+                    Ok(msg) => msg,
+                    Err(_) => {
+                        debug!("Session ended, nothing more to send to the remote client");
+                        return Ok(());
+                    }
+                };
+                let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+                // write out everything queued so far in one go, keeping a response together
+                let written = iter::once(msg)
+                    .chain(chan.try_iter())
+                    .try_for_each(|msg| writer.write_all(msg.as_bytes()))
+                    .and_then(|_| writer.flush());
+                if let Err(e) = written {
+                    warn!("Unable to write to remote client: {}", e);
+                    let _ = stream.shutdown();
+                    return Err(e.into());
+                }
             }
         });
 
         Ok(())
     }
 
+    /// Wait for `AUTH <token>` when a token is configured. Returns false if the client
+    /// disconnected or ran out of attempts, in which case the connection is closed
+    fn authenticate(&mut self) -> Result<bool> {
+        let token = match &self.token {
+            Some(token) => token.clone(),
+            None => return Ok(true),
+        };
+        w(
+            &mut *self.lock_writer(),
+            "Authentication required: AUTH <token>\n",
+        )?;
+
+        let mut buf = String::new();
+        for _ in 0..MAX_AUTH_ATTEMPTS {
+            match self.read_command(&mut buf)? {
+                Some(0) | None => return Ok(false),
+                Some(_) => {}
+            }
+            if secrets_match(buf.trim_end().strip_prefix("AUTH "), Some(token.as_str())) {
+                w(&mut *self.lock_writer(), "Authenticated\n")?;
+                return Ok(true);
+            }
+            thread::sleep(AUTH_FAILURE_DELAY);
+            w(&mut *self.lock_writer(), "Authentication failed\n")?;
+        }
+
+        w(
+            &mut *self.lock_writer(),
+            "Too many failed attempts, disconnecting\n",
+        )?;
+        self.stream.shutdown()?;
+        Ok(false)
+    }
+
+    /// Read the next line or frame from the client into `buf`, returning the number of bytes read,
+    /// which is 0 once the client has closed the connection. Returns None when nothing complete
+    /// arrived within the idle timeout, after telling the client and closing the connection
+    fn read_command(&mut self, buf: &mut String) -> Result<Option<usize>> {
+        let started = Instant::now();
+        buf.clear();
+        loop {
+            let read = if self.mode == Mode::Framed {
+                self.read_frame(buf)
+            } else {
+                self.reader.read_line(buf).map_err(IridiumError::from)
+            };
+            match read {
+                Ok(n) => return Ok(Some(n)),
+                Err(IridiumError::Io(e))
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    if self.idle_timeout.is_some_and(|t| started.elapsed() >= t) {
+                        self.send("Disconnecting due to inactivity\n")?;
+                        self.stream.shutdown()?;
+                        return Ok(None);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Read a frame into `buf`, keeping partial frames across read timeouts. Returns the frame's
+    /// length including its header, or 0 once the client has closed the connection
+    fn read_frame(&mut self, buf: &mut String) -> Result<usize> {
+        let mut chunk = [0; 4096];
+        loop {
+            let pending = self.partial_frame.len();
+            if let Some(frame) = take_frame(&mut self.partial_frame, MAX_FRAME_LENGTH)? {
+                buf.push_str(&frame);
+                return Ok(pending - self.partial_frame.len());
+            }
+            let n = self.reader.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.partial_frame.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Handle `!upload <name> <size>`, whose contents arrive as the next frame, staging the file
+    /// for `!load_file @<name>`. Returns None if the client disconnected before sending it
+    fn upload(&mut self, args: &[&str]) -> Result<Option<Flow>> {
+        if self.mode != Mode::Framed {
+            self.repl
+                .send_error("Uploads require framed mode".to_string())?;
+            return Ok(Some(Flow::Invalid));
+        }
+        let (name, size) = match args {
+            [name, size] => match size.parse::<usize>() {
+                Ok(size) => (*name, size),
+                Err(_) => return self.upload_error(format!("Invalid upload size: {}", size)),
+            },
+            _ => return self.upload_error("Usage: !upload <name> <size>".to_string()),
+        };
+        // the contents are the next frame however the upload turns out, so always consume it
+        let mut contents = String::new();
+        match self.read_command(&mut contents)? {
+            Some(0) | None => return Ok(None),
+            Some(_) => {}
+        }
+        if size > MAX_UPLOAD_SIZE {
+            return self.upload_error(format!(
+                "Upload too large: {} bytes, the limit is {}",
+                size, MAX_UPLOAD_SIZE
+            ));
+        }
+        if contents.len() != size {
+            return self.upload_error(format!(
+                "Upload size mismatch: expected {} bytes, got {}",
+                size,
+                contents.len()
+            ));
+        }
+
+        if self.staging.is_none() {
+            let staging = match &self.staging_dir {
+                Some(dir) => Staging::new_in(dir),
+                None => Staging::new(),
+            };
+            let staging = match staging {
+                Ok(staging) => staging,
+                Err(e) => return self.upload_error(e.to_string()),
+            };
+            self.repl.set_upload_dir(staging.path().to_path_buf());
+            self.staging = Some(staging);
+        }
+        if let Some(staging) = &self.staging {
+            if let Err(e) = staging.store(name, &contents) {
+                return self.upload_error(e.to_string());
+            }
+        }
+        self.repl
+            .send_message(format!("Uploaded {} ({} bytes)", name, size))?;
+        Ok(Some(Flow::Continue))
+    }
+
+    fn upload_error(&mut self, msg: String) -> Result<Option<Flow>> {
+        self.repl.send_error(msg)?;
+        Ok(Some(Flow::Invalid))
+    }
+
     /// Set up REPL for client
     pub fn run(&mut self) -> Result<()> {
-        self.recv_loop()?;
+        self.stream.set_read_timeout(self.idle_timeout)?;
+        let registration = match &self.connections {
+            Some(connections) => Some(connections.register(&*self.stream)?),
+            None => None,
+        };
         let mut buf = String::new();
-        let banner = repl::REMOTE_BANNER.to_owned() + "\n" + repl::PROMPT;
-        w(&mut self.writer, &banner)?;
+        let banner = format!(
+            "{}\n{}\n{}\n",
+            repl::REMOTE_BANNER,
+            FRAMED_MODE_OFFER,
+            STRUCTURED_MODE_OFFER
+        );
+        w(&mut *self.lock_writer(), &banner)?;
+        if !self.authenticate()? {
+            return Ok(());
+        }
+        self.write_prompt()?;
+
+        // A client that wants framed or structured mode asks for it with its first line;
+        // anything else is the first command of a plain line session
+        let mut has_command = match self.read_command(&mut buf)? {
+            None => return Ok(()),
+            Some(0) => {
+                info!("Remote client disconnected");
+                return Ok(());
+            }
+            Some(_) if buf.trim_end() == FRAMED_MODE_REQUEST => {
+                self.mode = Mode::Framed;
+                write_frame(&mut *self.lock_writer(), "Framed mode enabled\n")?;
+                false
+            }
+            Some(_) if buf.trim_end() == STRUCTURED_MODE_REQUEST => {
+                self.mode = Mode::Structured;
+                self.repl.enable_structured_messages();
+                let banner = RemoteMessage::Banner(repl::REMOTE_BANNER.to_string());
+                w(&mut *self.lock_writer(), &banner.to_line()?)?;
+                self.repl.send_prompt()?;
+                self.recv_loop()?;
+                false
+            }
+            Some(_) => {
+                self.recv_loop()?;
+                true
+            }
+        };
         loop {
-            match self.reader.read_line(&mut buf) {
-                Ok(_) => {
-                    buf.trim_end();
-                    self.repl.run_single(&buf)?;
+            if has_command {
+                let command = buf.trim_end();
+                let flow = match command.split_whitespace().collect::<Vec<_>>()[..] {
+                    [UPLOAD_COMMAND, ref args @ ..] => match self.upload(args)? {
+                        Some(flow) => flow,
+                        None => {
+                            info!("Remote client disconnected during upload");
+                            return Ok(());
+                        }
+                    },
+                    _ => self.repl.run_single(command)?,
+                };
+                self.metrics.command_processed(flow != Flow::Invalid);
+                if let Some(registration) = &registration {
+                    registration.command_processed();
+                }
+                if flow == Flow::Quit {
+                    if self.mode == Mode::Framed {
+                        self.send_response()?;
+                    }
+                    // the forwarder thread writes out the farewell before the REPL's pipe
+                    // closes, and the connection closes once it has let go of the stream
+                    info!("Remote client quit");
+                    return Ok(());
+                }
+                // the prompt goes through the same pipe as the output, so it always follows
+                // the whole response; framed clients know where a response ends without one
+                match self.mode {
+                    Mode::Framed => self.send_response()?,
+                    Mode::Lines | Mode::Structured => self.repl.send_prompt()?,
+                }
+            }
+            has_command = match self.read_command(&mut buf) {
+                Ok(Some(0)) => {
+                    // dropping the client drops its REPL, which stops the forwarder thread
+                    info!("Remote client disconnected");
+                    return Ok(());
+                }
+                Ok(Some(_)) => true,
+                Ok(None) => return Ok(()),
+                Err(IridiumError::Io(e)) if is_disconnect(e.kind()) => {
+                    info!("Remote client connection lost: {}", e);
+                    return Ok(());
                 }
+                // a bad frame leaves no way to find where the next one starts
+                Err(e) if self.mode == Mode::Framed => return Err(e),
                 Err(e) => {
                     println!("Error receiving: {:#?}", e);
+                    false
                 }
-            }
+            };
         }
     }
 }
+
+/// Errors meaning the client is gone rather than that one read went wrong
+fn is_disconnect(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::UnexpectedEof
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        time::Duration,
+    };
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::remote::test_util::read_until;
+
+    /// Serve a single remote session on an ephemeral port and connect to it
+    fn connect() -> TcpStream {
+        connect_with_token(None)
+    }
+
+    fn connect_with_token(token: Option<&str>) -> TcpStream {
+        let token = token.map(str::to_string);
+        serve(move |client| client.with_token(token))
+    }
+
+    /// Serve a single remote session configured by `setup` and connect to it
+    fn serve(setup: impl FnOnce(Client) -> Client + Send + 'static) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            setup(Client::new(stream).unwrap()).run()
+        });
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    }
+
+    /// Read from the stream until the server closes the connection
+    fn read_to_close(stream: &mut TcpStream) -> String {
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_auth_with_right_token() {
+        let mut stream = connect_with_token(Some("secret"));
+        read_until(&mut stream, "AUTH <token>");
+
+        stream.write_all(b"AUTH secret\r\n").unwrap();
+        read_until(&mut stream, "Authenticated");
+        stream.write_all(b"!clear_registers\n").unwrap();
+        read_until(&mut stream, "Done!");
+    }
+
+    #[test]
+    fn test_auth_with_wrong_token() {
+        let mut stream = connect_with_token(Some("secret"));
+        read_until(&mut stream, "AUTH <token>");
+
+        stream
+            .write_all(b"!clear_registers\nAUTH wrong\nAUTH\n")
+            .unwrap();
+        let output = read_to_close(&mut stream);
+        assert_eq!(output.matches("Authentication failed").count(), 3);
+        assert!(output.contains("Too many failed attempts"), "{:?}", output);
+        assert!(!output.contains("Done!"), "{:?}", output);
+    }
+
+    #[test]
+    fn test_idle_timeout_disconnects() {
+        let mut stream = serve(|client| client.with_idle_timeout(Some(Duration::from_secs(1))));
+        read_until(&mut stream, repl::PROMPT);
+
+        let output = read_to_close(&mut stream);
+        assert!(output.contains("inactivity"), "{:?}", output);
+    }
+
+    #[test]
+    fn test_attached_session_shares_vm() {
+        let vm = Arc::new(Mutex::new(VM::new()));
+        let mut local = REPL::shared(vm.clone());
+        local.run_single("load $0 #100").unwrap();
+
+        let mut stream = serve(move |client| client.with_vm(vm));
+        read_until(&mut stream, repl::PROMPT);
+        stream.write_all(b"!format json\n!registers\n").unwrap();
+        read_until(&mut stream, r#"{"registers":[100,"#);
+    }
+
+    #[test]
+    fn test_sessions_get_fresh_vm_by_default() {
+        let mut stream = connect();
+        read_until(&mut stream, repl::PROMPT);
+        stream.write_all(b"load $0 #100\n").unwrap();
+
+        let mut other = connect();
+        read_until(&mut other, repl::PROMPT);
+        other.write_all(b"!format json\n!registers\n").unwrap();
+        read_until(&mut other, r#"{"registers":[0,"#);
+    }
+
+    #[test]
+    fn test_no_token_configured() {
+        let mut stream = connect();
+        let output = read_until(&mut stream, repl::PROMPT);
+        assert!(!output.contains("Authentication required"), "{:?}", output);
+
+        stream.write_all(b"!clear_registers\n").unwrap();
+        read_until(&mut stream, "Done!");
+    }
+
+    #[test]
+    fn test_remote_load_file() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b".data\n.code\nload $0 #100").unwrap();
+        let mut stream = connect();
+        read_until(&mut stream, repl::PROMPT);
+
+        writeln!(stream, "!load_file {}", file.path().display()).unwrap();
+
+        read_until(&mut stream, "Sending assembled program to VM");
+    }
+
+    #[test]
+    fn test_commands_are_independent() {
+        let mut stream = connect();
+        read_until(&mut stream, repl::PROMPT);
+
+        stream.write_all(b"!clear_registers\r\n").unwrap();
+        read_until(&mut stream, "Done!");
+        stream.write_all(b"!bogus\r\n").unwrap();
+        let output = read_until(&mut stream, "Invalid command!");
+        assert!(!output.contains("Done!"), "{:?}", output);
+        stream.write_all(b"!clear_program\r\n").unwrap();
+        let output = read_until(&mut stream, "Cleared program");
+        assert!(!output.contains("Invalid command!"), "{:?}", output);
+    }
+
+    #[test]
+    fn test_prompt_after_every_command() {
+        let mut stream = connect();
+        stream.write_all(b"!registers\nload $1 ???\n").unwrap();
+
+        let output = read_until(&mut stream, "`???`\n>>> ");
+        assert_eq!(output.matches(repl::PROMPT).count(), 3, "{:?}", output);
+        let responses: Vec<&str> = output.split(repl::PROMPT).collect();
+        assert!(
+            responses[1].ends_with("End of Register Listing\n"),
+            "{:?}",
+            output
+        );
+        assert!(
+            responses[2].starts_with("Unable to parse input"),
+            "{:?}",
+            output
+        );
+        assert_eq!(responses[3], "");
+    }
+
+    #[test]
+    fn test_rapid_commands_keep_lines_intact() {
+        let mut stream = connect();
+        let mut commands = String::from("!clear_registers\n").repeat(100);
+        commands.push_str("!clear_program\n");
+        stream.write_all(commands.as_bytes()).unwrap();
+
+        let output = read_until(&mut stream, "pc reset to 0\n>>> ");
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], repl::REMOTE_BANNER);
+        assert_eq!(lines[lines.len() - 1], repl::PROMPT);
+        for line in &lines[3..lines.len() - 2] {
+            assert!(
+                matches!(
+                    line.trim_start_matches(repl::PROMPT),
+                    "Setting all registers to 0" | "Done!"
+                ),
+                "{:?}",
+                line
+            );
+        }
+        assert_eq!(output.matches("Done!").count(), 100);
+    }
+
+    #[test]
+    fn test_structured_session() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b".data\n.code\nload $0 #100").unwrap();
+        let mut stream = connect();
+        let greeting = read_until(&mut stream, repl::PROMPT);
+        assert!(greeting.contains(STRUCTURED_MODE_OFFER), "{:?}", greeting);
+
+        writeln!(stream, "{}", STRUCTURED_MODE_REQUEST).unwrap();
+        writeln!(stream, "!clear_registers\n!bogus").unwrap();
+        writeln!(stream, "!load_file {}\n!events", file.path().display()).unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let mut messages = vec![];
+        while messages.len() < 13 {
+            let line = lines.next().unwrap().unwrap();
+            messages.push(serde_json::from_str::<RemoteMessage>(&line).unwrap());
+        }
+
+        let variants: Vec<&str> = messages
+            .iter()
+            .map(|message| match message {
+                RemoteMessage::Banner(_) => "banner",
+                RemoteMessage::Prompt(_) => "prompt",
+                RemoteMessage::Output(_) => "output",
+                RemoteMessage::Error(_) => "error",
+                RemoteMessage::Event(_) => "event",
+            })
+            .collect();
+        assert_eq!(
+            variants,
+            [
+                "banner", "prompt", "output", "output", "prompt", "error", "prompt", "output",
+                "output", "prompt", "event", "event", "prompt"
+            ]
+        );
+        assert_eq!(
+            messages[5],
+            RemoteMessage::Error("Invalid command!".to_string())
+        );
+    }
+
+    #[test]
+    fn test_remote_load_file_requires_path() {
+        let mut stream = connect();
+        read_until(&mut stream, repl::PROMPT);
+
+        writeln!(stream, "!load_file").unwrap();
+
+        read_until(&mut stream, "Please pass the path");
+    }
+}
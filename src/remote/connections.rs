@@ -0,0 +1,105 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::remote::stream::Stream;
+
+/// A remote session as seen by the server
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionInfo {
+    pub addr: SocketAddr,
+    pub connected_at: DateTime<Utc>,
+    pub commands: u64, // commands processed so far
+}
+
+struct Entry {
+    info: ConnectionInfo,
+    stream: Box<dyn Stream>, // kept to close the session from outside
+}
+
+/// Registry of the sessions a server is currently serving, shared by every session
+#[derive(Clone, Default)]
+pub struct Connections {
+    entries: Arc<Mutex<Vec<Entry>>>,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Entry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Record a new session; it stays listed until the returned registration is dropped
+    pub fn register(&self, stream: &dyn Stream) -> io::Result<Registration> {
+        let addr = stream.peer_addr()?;
+        self.lock().push(Entry {
+            info: ConnectionInfo {
+                addr,
+                connected_at: Utc::now(),
+                commands: 0,
+            },
+            stream: stream.try_clone()?,
+        });
+        Ok(Registration {
+            connections: self.clone(),
+            addr,
+        })
+    }
+
+    /// Snapshot of every connected session
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        self.lock().iter().map(|entry| entry.info.clone()).collect()
+    }
+
+    /// Close the session connected from `addr`, returning false if there is none
+    pub fn kick(&self, addr: SocketAddr) -> bool {
+        match self.lock().iter().find(|entry| entry.info.addr == addr) {
+            Some(entry) => entry.stream.shutdown().is_ok(),
+            None => false,
+        }
+    }
+
+    /// Close every session, as when the node shuts down, returning how many there were
+    pub fn close_all(&self) -> usize {
+        let entries = self.lock();
+        for entry in entries.iter() {
+            let _ = entry.stream.shutdown();
+        }
+        entries.len()
+    }
+}
+
+/// A session's place in the registry, removed when dropped
+pub struct Registration {
+    connections: Connections,
+    addr: SocketAddr,
+}
+
+impl Registration {
+    pub fn command_processed(&self) {
+        if let Some(entry) = self
+            .connections
+            .lock()
+            .iter_mut()
+            .find(|entry| entry.info.addr == self.addr)
+        {
+            entry.info.commands += 1;
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections
+            .lock()
+            .retain(|entry| entry.info.addr != self.addr);
+    }
+}
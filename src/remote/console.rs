@@ -0,0 +1,209 @@
+use std::{
+    io::{self, BufRead, Write},
+    net::{Shutdown, TcpStream, ToSocketAddrs},
+    sync::mpsc::{channel, Sender},
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    common::{read_frame, w, write_frame, MAX_FRAME_LENGTH},
+    error::{IridiumError, Result},
+    remote::framed::{read_greeting, FRAMED_MODE_OFFER, FRAMED_MODE_REQUEST},
+    repl,
+};
+
+const QUIT_COMMAND: &str = "!quit";
+
+/// Interactive client for a remote REPL: prints everything the server sends to `output` and
+/// forwards lines from `input` until it runs out or `!quit` is entered. Uses framed mode
+/// when the server offers it and plain lines otherwise.
+pub fn run<A, R, W>(addr: A, token: Option<&str>, input: R, mut output: W) -> Result<()>
+where
+    A: ToSocketAddrs,
+    R: BufRead,
+    W: Write + Send + 'static,
+{
+    let mut stream = TcpStream::connect(addr)?;
+    let mut greeting = read_greeting(&mut stream)?;
+    let offers_framing = greeting.contains(FRAMED_MODE_OFFER);
+    let mut authenticated = true;
+    if greeting.ends_with("AUTH <token>\n") {
+        match token {
+            Some(token) => {
+                writeln!(stream, "AUTH {}", token)?;
+                let reply = read_greeting(&mut stream)?;
+                if !reply.contains("Authenticated") {
+                    return Err(IridiumError::StringError(format!(
+                        "Authentication failed: {}",
+                        reply.trim_end()
+                    )));
+                }
+                greeting.push_str(&reply);
+            }
+            // leave it to the user to type AUTH
+            None => authenticated = false,
+        }
+    }
+    w(&mut output, &greeting)?;
+
+    if offers_framing && authenticated {
+        writeln!(stream, "{}", FRAMED_MODE_REQUEST)?;
+        read_frame(&mut stream, MAX_FRAME_LENGTH)?;
+        run_framed(stream, input, output)
+    } else {
+        run_lines(stream, input, output)
+    }
+}
+
+fn run_framed<R: BufRead, W: Write + Send + 'static>(
+    mut stream: TcpStream,
+    input: R,
+    output: W,
+) -> Result<()> {
+    let (done_tx, done_rx) = channel();
+    let reader = spawn_frame_printer(stream.try_clone()?, output, done_tx);
+
+    let mut sent = 0;
+    for line in input.lines() {
+        let line = line?;
+        if line.trim() == QUIT_COMMAND {
+            break;
+        }
+        write_frame(&mut stream, &line)?;
+        sent += 1;
+    }
+    // Every command gets exactly one response frame, so wait for all of them before closing
+    for _ in 0..sent {
+        if done_rx.recv().is_err() {
+            break;
+        }
+    }
+    close(&stream, Shutdown::Both, reader)
+}
+
+fn run_lines<R: BufRead, W: Write + Send + 'static>(
+    mut stream: TcpStream,
+    input: R,
+    mut output: W,
+) -> Result<()> {
+    let mut server = stream.try_clone()?;
+    let reader = thread::spawn(move || {
+        let _ = io::copy(&mut server, &mut output);
+    });
+
+    for line in input.lines() {
+        let line = line?;
+        if line.trim() == QUIT_COMMAND {
+            break;
+        }
+        writeln!(stream, "{}", line)?;
+    }
+    // There is no telling when a response ends, so only stop sending and print whatever
+    // the server has left to say until it hangs up
+    close(&stream, Shutdown::Write, reader)
+}
+
+/// Print each response frame followed by a prompt, acknowledging it on `done`
+fn spawn_frame_printer<W: Write + Send + 'static>(
+    mut stream: TcpStream,
+    mut output: W,
+    done: Sender<()>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        while let Ok(response) = read_frame(&mut stream, MAX_FRAME_LENGTH) {
+            let printed = w(&mut output, &response).and_then(|_| w(&mut output, repl::PROMPT));
+            if printed.is_err() || done.send(()).is_err() {
+                break;
+            }
+        }
+    })
+}
+
+/// Shut the connection down and wait for the reader thread to finish printing
+fn close(stream: &TcpStream, how: Shutdown, reader: JoinHandle<()>) -> Result<()> {
+    stream.shutdown(how)?;
+    reader
+        .join()
+        .map_err(|_| IridiumError::StringError("Remote reader thread panicked".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufReader, Cursor},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::remote::server::Server;
+
+    /// Output sink the test can read after the console has handed it to its reader thread
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedOutput {
+        fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).to_string()
+        }
+    }
+
+    fn start_server(server: Server) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut server = server;
+            server.serve(listener)
+        });
+        addr
+    }
+
+    #[test]
+    fn test_console_uses_framed_mode() {
+        let addr = start_server(Server::new().with_token(Some("secret".to_string())));
+        let output = SharedOutput::default();
+        let input = Cursor::new("!clear_registers\n!format json\n!registers\n!quit\n");
+
+        run(addr, Some("secret"), input, output.clone()).unwrap();
+
+        let output = output.contents();
+        assert!(output.contains(repl::REMOTE_BANNER), "{:?}", output);
+        assert!(output.contains("Done!\n>>> "), "{:?}", output);
+        assert!(output.contains(r#"{"registers":[0,"#), "{:?}", output);
+        assert!(output.ends_with(repl::PROMPT), "{:?}", output);
+    }
+
+    #[test]
+    fn test_console_falls_back_to_lines() {
+        // A server that never offers framing and echoes each line back
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            write!(stream, "{}\n{}", repl::REMOTE_BANNER, repl::PROMPT).unwrap();
+            let mut line = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            while reader.read_line(&mut line).unwrap() > 0 {
+                write!(stream, "echo: {}", line).unwrap();
+                line.clear();
+            }
+        });
+        let output = SharedOutput::default();
+
+        // Ctrl-D: input simply runs out
+        run(addr, None, Cursor::new("FRAMED?\n"), output.clone()).unwrap();
+
+        let output = output.contents();
+        assert!(output.ends_with("echo: FRAMED?\n"), "{:?}", output);
+    }
+}
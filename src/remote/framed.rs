@@ -0,0 +1,176 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+use crate::{
+    common::{read_frame, write_frame, MAX_FRAME_LENGTH},
+    error::{IridiumError, Result},
+    remote::upload::UPLOAD_COMMAND,
+    repl,
+};
+
+/// First line a client sends to switch its session to framed mode
+pub const FRAMED_MODE_REQUEST: &str = "FRAMED";
+/// Greeting line servers that understand framed mode send
+pub const FRAMED_MODE_OFFER: &str = "Framed mode available: send FRAMED";
+
+/// Client side of the framed remote protocol, for scripts that need to know where each
+/// response ends
+pub struct FramedClient {
+    stream: TcpStream,
+}
+
+impl FramedClient {
+    /// Connect to a remote REPL, authenticating if a token is given, and switch to framed mode
+    pub fn connect<A: ToSocketAddrs>(addr: A, token: Option<&str>) -> Result<Self> {
+        let mut stream = TcpStream::connect(addr)?;
+        let greeting = read_greeting(&mut stream)?;
+        if greeting.contains("AUTH <token>") {
+            let token = token.ok_or_else(|| {
+                IridiumError::StringError("Server requires an authentication token".to_string())
+            })?;
+            writeln!(stream, "AUTH {}", token)?;
+            let reply = read_greeting(&mut stream)?;
+            if !reply.contains("Authenticated") {
+                return Err(IridiumError::StringError(format!(
+                    "Authentication failed: {}",
+                    reply.trim_end()
+                )));
+            }
+        }
+
+        writeln!(stream, "{}", FRAMED_MODE_REQUEST)?;
+        read_frame(&mut stream, MAX_FRAME_LENGTH)?;
+        Ok(Self { stream })
+    }
+
+    /// Send a command or line of assembly and wait for its whole response
+    pub fn send(&mut self, command: &str) -> Result<String> {
+        write_frame(&mut self.stream, command)?;
+        read_frame(&mut self.stream, MAX_FRAME_LENGTH)
+    }
+
+    /// Upload a file for `!load_file @<name>` to use, returning the server's response
+    pub fn upload(&mut self, name: &str, contents: &str) -> Result<String> {
+        write_frame(
+            &mut self.stream,
+            &format!("{} {} {}", UPLOAD_COMMAND, name, contents.len()),
+        )?;
+        write_frame(&mut self.stream, contents)?;
+        read_frame(&mut self.stream, MAX_FRAME_LENGTH)
+    }
+}
+
+/// Read the line-mode greeting up to the prompt, or up to the authentication challenge
+pub(crate) fn read_greeting(stream: &mut TcpStream) -> Result<String> {
+    let mut greeting = String::new();
+    let mut byte = [0; 1];
+    while !greeting.ends_with(repl::PROMPT) && !greeting.ends_with("AUTH <token>\n") {
+        if stream.read(&mut byte)? == 0 {
+            return Err(IridiumError::StringError(format!(
+                "Connection closed during greeting: {}",
+                greeting.trim_end()
+            )));
+        }
+        greeting.push(byte[0] as char);
+    }
+    Ok(greeting)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::remote::client::Client;
+
+    /// Serve a single remote session on an ephemeral port and connect to it in framed mode
+    fn connect(token: Option<&'static str>) -> Result<FramedClient> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            Client::new(stream)
+                .unwrap()
+                .with_token(token.map(str::to_string))
+                .run()
+        });
+        FramedClient::connect(addr, token)
+    }
+
+    #[test]
+    fn test_response_is_one_frame() {
+        let mut client = connect(None).unwrap();
+        let response = client.send("!registers").unwrap();
+        assert!(response.starts_with("Listing registers"), "{:?}", response);
+        assert!(
+            response.ends_with("End of Register Listing\n"),
+            "{:?}",
+            response
+        );
+
+        let response = client.send("!clear_registers").unwrap();
+        assert_eq!(response, "Setting all registers to 0\nDone!\n");
+    }
+
+    #[test]
+    fn test_frames_larger_than_a_segment() {
+        let mut client = connect(None).unwrap();
+        let command = format!("!{}", "x".repeat(200_000));
+        assert_eq!(client.send(&command).unwrap(), "Invalid command!\n");
+
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, ".data\n.code\n{}", "load $0 #100\n".repeat(20_000)).unwrap();
+        client
+            .send(&format!("!load_file {}", file.path().display()))
+            .unwrap();
+        let response = client.send("!program").unwrap();
+        assert!(response.len() > 200_000, "{} bytes", response.len());
+        assert!(response.ends_with("End of Program Listing\n"));
+    }
+
+    #[test]
+    fn test_upload_and_load() {
+        let mut client = connect(None).unwrap();
+        let response = client
+            .upload("prog.iasm", ".data\n.code\nload $0 #100")
+            .unwrap();
+        assert!(response.starts_with("Uploaded prog.iasm"), "{:?}", response);
+
+        let response = client.send("!load_file @prog.iasm").unwrap();
+        assert!(
+            response.contains("Sending assembled program"),
+            "{:?}",
+            response
+        );
+        let response = client.send("!format json").unwrap();
+        assert!(response.contains("json"), "{:?}", response);
+        let response = client.send("!registers").unwrap();
+        assert!(response.contains(r#"{"registers":[100,"#), "{:?}", response);
+
+        let response = client.send("!load_file @missing").unwrap();
+        assert!(response.contains("error opening"), "{:?}", response);
+    }
+
+    #[test]
+    fn test_upload_rejects_wrong_size() {
+        let mut client = connect(None).unwrap();
+        write_frame(&mut client.stream, "!upload prog.iasm 100").unwrap();
+        write_frame(&mut client.stream, "load $0 #1").unwrap();
+        let response = read_frame(&mut client.stream, MAX_FRAME_LENGTH).unwrap();
+        assert!(response.contains("size mismatch"), "{:?}", response);
+
+        let response = client.send("!load_file @prog.iasm").unwrap();
+        assert!(response.contains("No uploaded file"), "{:?}", response);
+    }
+
+    #[test]
+    fn test_framed_with_token() {
+        let mut client = connect(Some("secret")).unwrap();
+        let response = client.send("!clear_registers").unwrap();
+        assert!(response.ends_with("Done!\n"), "{:?}", response);
+    }
+}
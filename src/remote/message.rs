@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{error::Result, vm::VMEvent};
+
+/// First line a client sends to switch its session to structured messages
+pub const STRUCTURED_MODE_REQUEST: &str = "STRUCTURED";
+/// Greeting line servers that understand structured messages send
+pub const STRUCTURED_MODE_OFFER: &str = "Structured mode available: send STRUCTURED";
+
+/// One message of a structured remote session, sent as a single JSON line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum RemoteMessage {
+    Banner(String),
+    Prompt(String),
+    Output(Value), // a plain message, or the JSON object of a command when `!format json` is on
+    Error(String),
+    Event(VMEvent),
+}
+
+impl RemoteMessage {
+    /// Serialize into a newline-terminated JSON line
+    pub fn to_line(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)? + "\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_round_trip() {
+        let line = RemoteMessage::Prompt(">>> ".to_string()).to_line().unwrap();
+        assert_eq!(line, "{\"type\":\"prompt\",\"data\":\">>> \"}\n");
+        let message: RemoteMessage = serde_json::from_str(&line).unwrap();
+        assert_eq!(message, RemoteMessage::Prompt(">>> ".to_string()));
+    }
+}
@@ -0,0 +1,102 @@
+use std::{
+    fmt,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use serde::Serialize;
+
+/// Counters describing the remote server's traffic, updated by the server and its sessions
+#[derive(Debug, Default)]
+pub struct RemoteMetrics {
+    connections_accepted: AtomicUsize,
+    active_sessions: AtomicUsize,
+    commands_processed: AtomicUsize,
+    parse_errors: AtomicUsize, // commands or assembly lines the REPL could not understand
+    bytes_written: AtomicU64,
+}
+
+/// Point-in-time copy of the counters, for display
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct MetricsSnapshot {
+    pub connections_accepted: usize,
+    pub active_sessions: usize,
+    pub commands_processed: usize,
+    pub parse_errors: usize,
+    pub bytes_written: u64,
+}
+
+impl RemoteMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            connections_accepted: self.connections_accepted.load(Ordering::SeqCst),
+            active_sessions: self.active_sessions.load(Ordering::SeqCst),
+            commands_processed: self.commands_processed.load(Ordering::SeqCst),
+            parse_errors: self.parse_errors.load(Ordering::SeqCst),
+            bytes_written: self.bytes_written.load(Ordering::SeqCst),
+        }
+    }
+
+    pub(crate) fn active_sessions(&self) -> &AtomicUsize {
+        &self.active_sessions
+    }
+
+    pub(crate) fn connection_accepted(&self) {
+        self.connections_accepted.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Count a processed command, and a parse error too if the REPL did not understand it
+    pub(crate) fn command_processed(&self, understood: bool) {
+        self.commands_processed.fetch_add(1, Ordering::SeqCst);
+        if !understood {
+            self.parse_errors.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl fmt::Display for MetricsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} connections accepted, {} active sessions, {} commands processed, {} parse errors, {} bytes written",
+            self.connections_accepted,
+            self.active_sessions,
+            self.commands_processed,
+            self.parse_errors,
+            self.bytes_written
+        )
+    }
+}
+
+/// Writer that adds everything written through it to the metrics' byte count
+pub struct Metered<W> {
+    inner: W,
+    pub(crate) metrics: Arc<RemoteMetrics>,
+}
+
+impl<W: Write> Metered<W> {
+    pub fn new(inner: W, metrics: Arc<RemoteMetrics>) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+impl<W: Write> Write for Metered<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.metrics
+            .bytes_written
+            .fetch_add(n as u64, Ordering::SeqCst);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -1,2 +1,15 @@
+pub mod allowlist;
 pub mod client;
+pub mod connections;
+pub mod console;
+pub mod framed;
+pub mod message;
+pub mod metrics;
+pub mod pool;
 pub mod server;
+pub mod stream;
+#[cfg(test)]
+pub(crate) mod test_util;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod upload;
@@ -0,0 +1,127 @@
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use log::error;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Fixed set of threads serving queued jobs, started as jobs arrive and reused afterwards.
+/// A job that panics is logged and its thread moves on to the next one
+pub struct WorkerPool {
+    size: usize,
+    queue: Option<Sender<Job>>,
+    jobs: Arc<Mutex<Receiver<Job>>>,
+    workers: Vec<JoinHandle<()>>,
+    started: Arc<AtomicUsize>, // threads started so far, never more than `size`
+    idle: Arc<AtomicUsize>,    // workers waiting for a job
+}
+
+impl WorkerPool {
+    pub fn new(size: usize) -> Self {
+        let (queue, jobs) = mpsc::channel();
+        Self {
+            size: size.max(1),
+            queue: Some(queue),
+            jobs: Arc::new(Mutex::new(jobs)),
+            workers: vec![],
+            started: Arc::new(AtomicUsize::new(0)),
+            idle: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Most threads the pool will start
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Number of threads the pool has started, shared so it can be read while the pool is in use
+    pub fn threads(&self) -> Arc<AtomicUsize> {
+        self.started.clone()
+    }
+
+    /// Queue a job, starting another worker if none is free and the pool isn't full yet
+    pub fn execute(&mut self, job: impl FnOnce() + Send + 'static) {
+        if self.idle.load(Ordering::SeqCst) == 0 && self.workers.len() < self.size {
+            self.spawn_worker();
+        }
+        if let Some(queue) = &self.queue {
+            // workers only stop once the queue is closed, so this can't fail
+            let _ = queue.send(Box::new(job));
+        }
+    }
+
+    fn spawn_worker(&mut self) {
+        let jobs = self.jobs.clone();
+        let idle = self.idle.clone();
+        self.started.fetch_add(1, Ordering::SeqCst);
+        self.workers.push(thread::spawn(move || loop {
+            idle.fetch_add(1, Ordering::SeqCst);
+            let job = jobs.lock().unwrap_or_else(|e| e.into_inner()).recv();
+            idle.fetch_sub(1, Ordering::SeqCst);
+            match job {
+                Ok(job) => {
+                    if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                        error!("Remote session panicked: {}", panic_message(&panic));
+                    }
+                }
+                Err(_) => return,
+            }
+        }));
+    }
+
+    /// Stop taking jobs, let the workers finish everything already queued, and join them
+    pub fn shutdown(&mut self) {
+        self.queue = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_reuses_threads_and_survives_panics() {
+        let mut pool = WorkerPool::new(3);
+        let done = Arc::new(AtomicUsize::new(0));
+        for i in 0..50 {
+            let done = done.clone();
+            pool.execute(move || {
+                if i % 10 == 0 {
+                    panic!("job {} failed", i);
+                }
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        assert!(pool.threads().load(Ordering::SeqCst) <= 3);
+
+        pool.shutdown();
+        assert_eq!(done.load(Ordering::SeqCst), 45);
+    }
+}
@@ -1,12 +1,38 @@
-use std::net::{TcpListener, ToSocketAddrs};
-use std::thread;
+use std::net::{Shutdown, TcpListener, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use log::error;
+use log::{error, info, warn};
 
+use crate::common::w;
 use crate::error::Result;
+use crate::remote::allowlist::{is_allowed, Cidr};
 use crate::remote::client::Client;
+use crate::remote::connections::Connections;
+use crate::remote::metrics::RemoteMetrics;
+use crate::remote::pool::WorkerPool;
+#[cfg(feature = "tls")]
+use crate::remote::tls::TlsStream;
+use crate::vm::VM;
 
-pub struct Server {}
+pub const DEFAULT_MAX_CLIENTS: usize = 16;
+
+pub struct Server {
+    token: Option<String>, // shared secret remote clients must authenticate with
+    idle_timeout: Option<Duration>, // disconnect sessions that stay quiet this long
+    vm: Option<Arc<Mutex<VM>>>, // attach every session to this VM instead of a fresh one
+    max_clients: usize,    // connections beyond this are turned away
+    metrics: Arc<RemoteMetrics>,
+    pool: WorkerPool, // threads serving sessions; connections wait for a free one
+    workers: Option<usize>, // pool size set by with_workers, else it follows max_clients
+    connections: Connections,
+    allowlist: Vec<Cidr>, // networks clients may connect from, everyone when empty
+    staging_dir: Option<PathBuf>, // where sessions stage their uploads, the temporary directory if unset
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>, // wrap accepted connections in TLS when set
+}
 
 impl Default for Server {
     fn default() -> Self {
@@ -16,26 +42,396 @@ impl Default for Server {
 
 impl Server {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            token: None,
+            idle_timeout: None,
+            vm: None,
+            max_clients: DEFAULT_MAX_CLIENTS,
+            metrics: Arc::new(RemoteMetrics::new()),
+            pool: WorkerPool::new(DEFAULT_MAX_CLIENTS),
+            workers: None,
+            connections: Connections::new(),
+            allowlist: vec![],
+            staging_dir: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Require clients to authenticate with the given token before using the REPL
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Disconnect clients that send no command within `timeout`
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Attach every session to the given VM, typically the one the local REPL is using
+    pub fn with_shared_vm(mut self, vm: Arc<Mutex<VM>>) -> Self {
+        self.vm = Some(vm);
+        self
+    }
+
+    /// Only accept clients connecting from one of these networks
+    pub fn with_allowlist(mut self, allowlist: Vec<Cidr>) -> Self {
+        self.allowlist = allowlist;
+        self
+    }
+
+    /// Stage uploads in a directory of each session's own under `dir`
+    pub fn with_staging_dir(mut self, dir: PathBuf) -> Self {
+        self.staging_dir = Some(dir);
+        self
+    }
+
+    /// Limit how many clients may be connected at once. Unless `with_workers` says
+    /// otherwise, each of them is served on a thread of its own
+    pub fn with_max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = max_clients;
+        if self.workers.is_none() {
+            self.pool = WorkerPool::new(max_clients);
+        }
+        self
+    }
+
+    /// Serve sessions on at most this many threads; clients beyond that wait for a free one
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = Some(workers);
+        self.pool = WorkerPool::new(workers);
+        self
+    }
+
+    /// Number of threads serving sessions, shared so it can still be read once the server is running
+    pub fn worker_threads(&self) -> Arc<AtomicUsize> {
+        self.pool.threads()
+    }
+
+    /// Traffic counters, shared so they can still be read once the server is running
+    pub fn metrics(&self) -> Arc<RemoteMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Registry of connected sessions, shared so it can still be read once the server is running
+    pub fn connections(&self) -> Connections {
+        self.connections.clone()
+    }
+
+    /// Serve remote sessions over TLS using the given config
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: Arc<rustls::ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
     }
 
     /// Run the server listening on the given address
     pub fn run<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
+        let result = self.serve(listener);
+        info!("Remote server stopped: {}", self.metrics.snapshot());
+        result
+    }
 
+    /// Accept and serve clients on an already bound listener
+    pub fn serve(&mut self, listener: TcpListener) -> Result<()> {
         for stream in listener.incoming() {
             match stream {
-                Ok(stream) => {
-                    thread::spawn(|| -> Result<()> {
-                        let mut client = Client::new(stream)?;
-                        client.run()?;
-
-                        Ok(())
+                Ok(mut stream) => {
+                    match stream.peer_addr() {
+                        Ok(peer) if is_allowed(&self.allowlist, peer.ip()) => {}
+                        peer => {
+                            warn!(
+                                "Rejected remote connection from {:?}: not in allowlist",
+                                peer
+                            );
+                            let _ = stream.shutdown(Shutdown::Both);
+                            continue;
+                        }
+                    }
+                    let slot = match ClientSlot::acquire(&self.metrics, self.max_clients) {
+                        Some(slot) => slot,
+                        None => {
+                            let _ = w(&mut stream, "Server full, try again later\n");
+                            let _ = stream.shutdown(Shutdown::Both);
+                            continue;
+                        }
+                    };
+                    let token = self.token.clone();
+                    let idle_timeout = self.idle_timeout;
+                    let vm = self.vm.clone();
+                    let connections = self.connections.clone();
+                    let metrics = self.metrics.clone();
+                    let staging_dir = self.staging_dir.clone();
+                    metrics.connection_accepted();
+                    #[cfg(feature = "tls")]
+                    let tls = self.tls.clone();
+                    self.pool.execute(move || {
+                        let _slot = slot;
+                        let session = || -> Result<()> {
+                            #[cfg(feature = "tls")]
+                            let client = match tls {
+                                Some(config) => Client::new(TlsStream::accept(config, stream)?),
+                                None => Client::new(stream),
+                            };
+                            #[cfg(not(feature = "tls"))]
+                            let client = Client::new(stream);
+                            let mut client = client?
+                                .with_token(token)
+                                .with_idle_timeout(idle_timeout)
+                                .with_connections(connections)
+                                .with_metrics(metrics)
+                                .with_staging_dir(staging_dir);
+                            if let Some(vm) = vm {
+                                client = client.with_vm(vm);
+                            }
+                            client.run()
+                        };
+                        if let Err(e) = session() {
+                            error!("Remote session failed: {}", e);
+                        }
                     });
                 }
                 Err(e) => error!("Connection failed: {}", e),
             }
         }
+        // lets sessions already accepted finish before the server is gone
+        self.pool.shutdown();
         Ok(())
     }
 }
+
+/// A connected client's place in the active session count, given back when dropped so the
+/// count stays right however the client thread ends, panics included
+struct ClientSlot {
+    metrics: Arc<RemoteMetrics>,
+}
+
+impl ClientSlot {
+    fn acquire(metrics: &Arc<RemoteMetrics>, max_clients: usize) -> Option<Self> {
+        metrics
+            .active_sessions()
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < max_clients).then_some(count + 1)
+            })
+            .ok()?;
+        Some(Self {
+            metrics: metrics.clone(),
+        })
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.metrics
+            .active_sessions()
+            .fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread;
+
+    use super::*;
+    use crate::remote::test_util::{read_until, wait_for};
+    use crate::repl::{self, REPL};
+
+    fn start(server: Server) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut server = server;
+            server.serve(listener)
+        });
+        addr
+    }
+
+    /// Connect and wait for the prompt, so the session is registered by the time this returns
+    fn connect(addr: SocketAddr) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        read_until(&mut stream, repl::PROMPT);
+        stream
+    }
+
+    #[test]
+    fn test_connections_over_limit_are_refused() {
+        let server = Server::new().with_max_clients(2);
+        let metrics = server.metrics();
+        let addr = start(server);
+
+        let _clients = [connect(addr), connect(addr)];
+        assert_eq!(metrics.snapshot().active_sessions, 2);
+
+        let mut refused = TcpStream::connect(addr).unwrap();
+        refused
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut output = String::new();
+        refused.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "Server full, try again later\n");
+        assert_eq!(metrics.snapshot().active_sessions, 2);
+    }
+
+    #[test]
+    fn test_pool_follows_max_clients() {
+        let server = Server::new().with_workers(2).with_max_clients(40);
+        assert_eq!(server.pool.size(), 2);
+
+        // more clients than the default pool has threads, each served straight away
+        let max_clients = DEFAULT_MAX_CLIENTS + 2;
+        let server = Server::new().with_max_clients(max_clients);
+        assert_eq!(server.pool.size(), max_clients);
+        let metrics = server.metrics();
+        let addr = start(server);
+        let _clients: Vec<_> = (0..max_clients).map(|_| connect(addr)).collect();
+        assert_eq!(metrics.snapshot().active_sessions, max_clients);
+    }
+
+    #[test]
+    fn test_disconnect_ends_session() {
+        let server = Server::new();
+        let metrics = server.metrics();
+        let addr = start(server);
+
+        let stream = connect(addr);
+        assert_eq!(metrics.snapshot().active_sessions, 1);
+        drop(stream);
+
+        wait_for("the session to end", || {
+            metrics.snapshot().active_sessions == 0
+        });
+    }
+
+    #[test]
+    fn test_connections_listed_and_kicked() {
+        let server = Server::new();
+        let connections = server.connections();
+        let addr = start(server);
+        let mut first = connect(addr);
+        let mut second = connect(addr);
+        second.write_all(b"!clear_registers\n").unwrap();
+        read_until(&mut second, "Done!");
+
+        let mut host = REPL::new(VM::new()).with_connections(connections.clone());
+        host.run_single("!connections").unwrap();
+        let listing: String = host.rx_pipe.as_ref().unwrap().try_iter().collect();
+        assert!(listing.contains(&first.local_addr().unwrap().to_string()));
+        assert!(listing.contains(&second.local_addr().unwrap().to_string()));
+
+        host.run_single(&format!(
+            "!connections kick {}",
+            first.local_addr().unwrap()
+        ))
+        .unwrap();
+        let mut rest = vec![];
+        first.read_to_end(&mut rest).unwrap();
+        second.write_all(b"!clear_registers\n").unwrap();
+        read_until(&mut second, "Done!");
+
+        let second_addr = second.local_addr().unwrap();
+        wait_for("the kicked connection to go", || {
+            let list = connections.list();
+            list.len() == 1 && list[0].addr == second_addr && list[0].commands == 2
+        });
+    }
+
+    #[test]
+    fn test_quit_ends_only_that_session() {
+        let addr = start(Server::new());
+        let mut quitting = connect(addr);
+        let mut other = connect(addr);
+
+        quitting.write_all(b"!quit\n").unwrap();
+        let mut output = String::new();
+        quitting.read_to_string(&mut output).unwrap();
+        assert!(output.contains("Farewell!"), "{:?}", output);
+
+        other.write_all(b"!clear_registers\n").unwrap();
+        read_until(&mut other, "Done!");
+        connect(addr);
+    }
+
+    #[test]
+    fn test_worker_threads_stay_bounded() {
+        let server = Server::new().with_workers(4);
+        let threads = server.worker_threads();
+        let addr = start(server);
+
+        for _ in 0..50 {
+            let mut stream = connect(addr);
+            stream.write_all(b"!quit\n").unwrap();
+            let mut output = String::new();
+            stream.read_to_string(&mut output).unwrap();
+            assert!(output.contains("Farewell!"), "{:?}", output);
+        }
+        assert!(threads.load(Ordering::SeqCst) <= 4);
+    }
+
+    #[test]
+    fn test_allowlist_rejects_other_networks() {
+        let allowed = start(Server::new().with_allowlist(vec!["127.0.0.1/32".parse().unwrap()]));
+        connect(allowed);
+
+        let refused = start(Server::new().with_allowlist(vec!["10.0.0.0/8".parse().unwrap()]));
+        let mut stream = TcpStream::connect(refused).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut output = vec![];
+        stream.read_to_end(&mut output).unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_slot_released_when_client_thread_panics() {
+        let metrics = Arc::new(RemoteMetrics::new());
+        let slot = ClientSlot::acquire(&metrics, 1).unwrap();
+        assert!(ClientSlot::acquire(&metrics, 1).is_none());
+
+        let handle = thread::spawn(move || {
+            let _slot = slot;
+            panic!("client thread died");
+        });
+        assert!(handle.join().is_err());
+        assert_eq!(metrics.snapshot().active_sessions, 0);
+    }
+
+    #[test]
+    fn test_metrics_count_sessions() {
+        let server = Server::new();
+        let metrics = server.metrics();
+        let addr = start(server);
+
+        let mut first = connect(addr);
+        first.write_all(b"!clear_registers\n!bogus\n").unwrap();
+        read_until(&mut first, "Invalid command!");
+        let mut second = connect(addr);
+        second.write_all(b"load $1 ???\n").unwrap();
+        read_until(&mut second, "Unable to parse input");
+        drop(first);
+
+        let mut host = REPL::new(VM::new()).with_remote_metrics(metrics.clone());
+        wait_for("the commands to be counted", || {
+            let snapshot = metrics.snapshot();
+            snapshot.active_sessions == 1 && snapshot.commands_processed == 3
+        });
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.connections_accepted, 2);
+        assert_eq!(snapshot.parse_errors, 2);
+        assert!(snapshot.bytes_written > 0);
+
+        host.run_single("!format json").unwrap();
+        host.rx_pipe.as_ref().unwrap().try_iter().count();
+        host.run_single("!remote_metrics").unwrap();
+        let output: String = host.rx_pipe.as_ref().unwrap().try_iter().collect();
+        assert!(output.contains(r#""connections_accepted":2"#), "{}", output);
+    }
+}
@@ -0,0 +1,35 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    time::Duration,
+};
+
+/// A connection a remote session or cluster peer is served over, plain TCP or TLS
+pub trait Stream: Read + Write + Send + Sync {
+    /// Another handle to the same connection, so reads and writes can happen on different threads
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>>;
+    /// Close both directions of the connection
+    fn shutdown(&self) -> io::Result<()>;
+    /// Make blocking reads give up after `timeout`
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    /// Address of the other end of the connection
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
@@ -0,0 +1,28 @@
+//! Helpers shared by the tests that talk to remote sessions and cluster nodes
+
+use std::{
+    io::Read,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Read from the stream until the output contains `needle`
+pub fn read_until<S: Read>(stream: &mut S, needle: &str) -> String {
+    let mut output = String::new();
+    let mut buf = [0; 1024];
+    while !output.contains(needle) {
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n > 0, "connection closed before {:?}: {:?}", needle, output);
+        output.push_str(&String::from_utf8_lossy(&buf[..n]));
+    }
+    output
+}
+
+/// Poll until `done` holds, failing the test after five seconds
+pub fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use rustls::{
+    server::AllowAnyAuthenticatedClient, Certificate, ClientConfig, ClientConnection, Connection,
+    PrivateKey, RootCertStore, ServerConfig, ServerConnection, ServerName,
+};
+use rustls_pemfile::Item;
+
+use crate::{
+    error::{IridiumError, Result},
+    remote::stream::Stream,
+};
+
+/// Read every certificate in a PEM file, failing if there are none
+fn read_certs(cert_path: &Path) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(IridiumError::StringError(format!(
+            "No certificates found in {}",
+            cert_path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+/// Read the first private key in a PEM file, failing if there is none
+fn read_key(key_path: &Path) -> Result<PrivateKey> {
+    rustls_pemfile::read_all(&mut BufReader::new(File::open(key_path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            IridiumError::StringError(format!("No private key found in {}", key_path.display()))
+        })
+}
+
+/// Trust only the certificates in a PEM file
+fn read_roots(ca_path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(ca_path)? {
+        roots.add(&cert)?;
+    }
+    Ok(roots)
+}
+
+/// Build a server config from a PEM certificate chain and private key
+pub fn server_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>> {
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(read_certs(cert_path)?, read_key(key_path)?)?;
+    Ok(Arc::new(config))
+}
+
+/// Build a client config trusting only the certificates in a PEM file
+pub fn client_config(ca_path: &Path) -> Result<Arc<ClientConfig>> {
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(read_roots(ca_path)?)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Build a server config from a PEM certificate chain and private key that only accepts
+/// clients presenting a certificate the same chain vouches for
+pub fn mutual_server_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>> {
+    let verifier = AllowAnyAuthenticatedClient::new(read_roots(cert_path)?).boxed();
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(read_certs(cert_path)?, read_key(key_path)?)?;
+    Ok(Arc::new(config))
+}
+
+/// Build a client config that trusts only the PEM certificate chain at `cert_path` and
+/// presents it, with its private key, to servers asking for a client certificate
+pub fn mutual_client_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ClientConfig>> {
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(read_roots(cert_path)?)
+        .with_client_auth_cert(read_certs(cert_path)?, read_key(key_path)?)?;
+    Ok(Arc::new(config))
+}
+
+/// A TLS session over a TcpStream, from either end. Clones share the session state, so one
+/// handle can block reading the socket while another writes to it
+pub struct TlsStream {
+    conn: Arc<Mutex<Connection>>,
+    sock: TcpStream,
+}
+
+impl TlsStream {
+    /// Perform the TLS handshake with a newly accepted client
+    pub fn accept(config: Arc<ServerConfig>, sock: TcpStream) -> Result<Self> {
+        Self::handshake(ServerConnection::new(config)?.into(), sock)
+    }
+
+    /// Perform the TLS handshake with the server at the other end of `sock`, which must
+    /// present a certificate for `name`
+    pub fn connect(config: Arc<ClientConfig>, name: ServerName, sock: TcpStream) -> Result<Self> {
+        Self::handshake(ClientConnection::new(config, name)?.into(), sock)
+    }
+
+    fn handshake(mut conn: Connection, mut sock: TcpStream) -> Result<Self> {
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)?;
+        }
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            sock,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Send any pending TLS records to the socket
+    fn flush_tls(&self, conn: &mut Connection) -> io::Result<()> {
+        while conn.wants_write() {
+            conn.write_tls(&mut &self.sock)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut raw = [0; 4096];
+        loop {
+            match self.lock().reader().read(buf) {
+                Ok(n) => return Ok(n),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+
+            // Block on the socket without holding the lock so writers can make progress
+            let n = (&self.sock).read(&mut raw)?;
+            if n == 0 {
+                return Ok(0);
+            }
+
+            let mut conn = self.lock();
+            let mut records = &raw[..n];
+            while !records.is_empty() {
+                conn.read_tls(&mut records)?;
+                conn.process_new_packets()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+            self.flush_tls(&mut conn)?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.lock();
+        let n = conn.writer().write(buf)?;
+        self.flush_tls(&mut conn)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut conn = self.lock();
+        conn.writer().flush()?;
+        self.flush_tls(&mut conn)
+    }
+}
+
+impl Stream for TlsStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(Self {
+            conn: self.conn.clone(),
+            sock: self.sock.try_clone()?,
+        }))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        let mut conn = self.lock();
+        conn.send_close_notify();
+        self.flush_tls(&mut conn)?;
+        self.sock.shutdown(Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.sock.peer_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread, time::Duration};
+
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::{
+        remote::{client::Client, test_util::read_until},
+        repl,
+    };
+
+    /// Read from the stream until the output contains `needle`
+    #[test]
+    fn test_tls_round_trip() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let mut cert_file = NamedTempFile::new().unwrap();
+        cert_file
+            .write_all(cert.serialize_pem().unwrap().as_bytes())
+            .unwrap();
+        let mut key_file = NamedTempFile::new().unwrap();
+        key_file
+            .write_all(cert.serialize_private_key_pem().as_bytes())
+            .unwrap();
+        let config = server_config(cert_file.path(), key_file.path()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || -> Result<()> {
+            let (sock, _) = listener.accept()?;
+            Client::new(TlsStream::accept(config, sock)?)?.run()
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(cert.serialize_der().unwrap()))
+            .unwrap();
+        let client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let conn = ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap())
+            .unwrap();
+        let sock = TcpStream::connect(addr).unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut stream = StreamOwned::new(conn, sock);
+
+        let output = read_until(&mut stream, repl::PROMPT);
+        assert!(output.contains(repl::REMOTE_BANNER), "{:?}", output);
+        stream.write_all(b"!clear_registers\n").unwrap();
+        read_until(&mut stream, "Done!");
+    }
+}
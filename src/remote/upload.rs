@@ -0,0 +1,113 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use uuid::Uuid;
+
+use crate::error::{IridiumError, Result};
+
+/// Command that announces an upload: `!upload <name> <size>`, followed by a frame of that size
+pub const UPLOAD_COMMAND: &str = "!upload";
+/// Prefix naming an uploaded file rather than a server path, as in `!load_file @<name>`
+pub const UPLOAD_PREFIX: char = '@';
+/// Largest file a session may upload
+pub const MAX_UPLOAD_SIZE: usize = 1024 * 1024;
+/// Directory under a node's data directory where sessions stage their uploads
+const UPLOADS_DIR_NAME: &str = "uploads";
+
+/// Directory holding one session's uploads, removed with everything in it when dropped
+pub struct Staging {
+    dir: PathBuf,
+}
+
+impl Staging {
+    /// Stage uploads in the system's temporary directory
+    pub fn new() -> Result<Self> {
+        Self::new_in(&env::temp_dir())
+    }
+
+    /// Stage uploads in a directory of their own under `parent`, creating it if needed
+    pub fn new_in(parent: &Path) -> Result<Self> {
+        let dir = parent.join(format!("iridium-upload-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).map_err(|e| {
+            IridiumError::StringError(format!(
+                "Unable to stage uploads in {}: {}",
+                parent.display(),
+                e
+            ))
+        })?;
+        Ok(Self { dir })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Store an uploaded file under `name`, replacing any earlier upload of the same name
+    pub fn store(&self, name: &str, contents: &str) -> Result<()> {
+        if !is_valid_name(name) {
+            return Err(IridiumError::StringError(format!(
+                "Invalid upload name: {}",
+                name
+            )));
+        }
+        fs::write(self.dir.join(name), contents)?;
+        Ok(())
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Where sessions of a node storing its data in `data_dir` stage their uploads
+pub fn uploads_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join(UPLOADS_DIR_NAME)
+}
+
+/// Upload names are plain file names, so they can't reach outside the staging directory
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_staging_removed_on_drop() {
+        let staging = Staging::new().unwrap();
+        staging.store("prog.iasm", ".code\nload $0 #1").unwrap();
+        let dir = staging.path().to_path_buf();
+        assert!(dir.join("prog.iasm").exists());
+        assert!(staging.store("../escape", "").is_err());
+
+        drop(staging);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_staging_in_data_dir() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let staging = Staging::new_in(&uploads_dir(data_dir.path())).unwrap();
+        staging.store("prog.iasm", ".code\nhlt").unwrap();
+        assert!(staging.path().starts_with(data_dir.path().join("uploads")));
+        assert!(staging.path().join("prog.iasm").exists());
+
+        let file = data_dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let err = Staging::new_in(&file).err().unwrap();
+        assert!(
+            err.to_string().contains("Unable to stage uploads in"),
+            "{}",
+            err
+        );
+    }
+}
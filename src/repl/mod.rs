@@ -1,22 +1,48 @@
 pub mod command_parser;
 
 use std::{
-    fs::File,
+    cell::Cell,
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
     io::{self, Read, Write},
-    net::TcpStream,
-    path::Path,
-    sync::mpsc::{self, Receiver, SendError, Sender},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use log::debug;
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use serde_json::{json, Value};
+use uuid::Uuid;
 
 use crate::{
     assembler::{program::Program, symbols::Symbol, Assembler},
-    cluster::cluster_client::ClusterClient,
+    benchmark::{self, Benchmark},
+    cluster::{
+        address,
+        inspect::INSPECT_TIMEOUT,
+        jobs::{Job, Jobs},
+        manager::{Manager, PING_TIMEOUT},
+        message::{NodeMetadata, TaskResult},
+        role::NodeRole,
+        NodeAlias,
+    },
     error::{IridiumError, Result},
-    parse::Parse,
-    scheduler::Scheduler,
-    vm::VM,
+    parse::{parse_complete, underline},
+    remote::{
+        connections::Connections,
+        message::RemoteMessage,
+        metrics::RemoteMetrics,
+        upload::{self, UPLOAD_PREFIX},
+    },
+    report,
+    scheduler::{Schedule, Scheduler, ShutdownSummary, SpawnMode, SpawnOptions},
+    vm::{VMEventType, VM},
 };
 
 use self::command_parser::CommandParser;
@@ -24,100 +50,728 @@ use self::command_parser::CommandParser;
 const COMMAND_PREFIX: char = '!';
 pub static REMOTE_BANNER: &str = "Welcome to Iridium! Let's be productive!";
 pub static PROMPT: &str = ">>> ";
+/// Messages the output pipe holds before its overflow policy applies
+pub const DEFAULT_PIPE_CAPACITY: usize = 1024;
+const PIPE_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+/// How long `!cluster_run` waits for a peer to send back the result of a program
+pub const DEFAULT_CLUSTER_TASK_TIMEOUT: Duration = Duration::from_secs(30);
+/// Node name `!cluster_run any` reports for programs it ran here, with no members to send them to
+pub const LOCAL_NODE: &str = "local";
+const NO_MEMBERS: &str = "No cluster members to run the program on";
+/// How long `!await` waits for a task when not told
+pub const DEFAULT_AWAIT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long tasks spawned here get to finish on quitting before they're cancelled
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+/// Longest the terminal REPL goes without checking for an interrupt while waiting for input
+const INTERRUPT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// File under a node's data directory keeping the commands typed at its terminal
+const HISTORY_FILE_NAME: &str = "history";
+
+/// Where a node storing its data in `data_dir` keeps the commands typed at its terminal
+pub fn history_file(data_dir: &Path) -> PathBuf {
+    data_dir.join(HISTORY_FILE_NAME)
+}
+
+/// Results of programs run on other nodes, by task id, with the node each ran on
+type ClusterResults = Arc<Mutex<HashMap<Uuid, (NodeAlias, TaskResult)>>>;
+
+/// What sending does when the output pipe is full because nobody is reading it fast enough
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OverflowPolicy {
+    #[default]
+    DropWithMarker, // drop the message and report how many were lost ahead of the next one
+    Block(Duration), // wait up to this long for room, then drop as above
+}
+
+/// What a session should do once a line of input has been handled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flow {
+    Continue,
+    Invalid, // the input could not be understood, or what it asked for failed
+    Quit,    // the user asked to end the session
+}
+
+/// How command results are rendered
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json, // one single-line JSON object per message
+}
 
 #[derive(Default)]
 pub struct REPL {
     command_buffer: Vec<String>,
-    vm: VM,
+    history_file: Option<PathBuf>, // where typed commands are kept across restarts
+    vm: Arc<Mutex<VM>>,            // may be shared with other sessions attached to the same VM
     asm: Assembler,
     scheduler: Scheduler,
-    pub tx_pipe: Option<Box<Sender<String>>>,
+    last_spawned: Option<Uuid>, // task `!await` waits for when not given one
+    pub tx_pipe: Option<Box<SyncSender<String>>>,
     pub rx_pipe: Option<Box<Receiver<String>>>,
+    overflow: OverflowPolicy,
+    dropped: Cell<usize>, // messages lost to a full pipe since the last one got through
+    pipe_closed: Cell<bool>, // if the reader has gone away, which is only logged once
+    remote: bool,         // if this REPL serves a remote client rather than the local terminal
+    format: OutputFormat,
+    connections: Option<Connections>, // sessions of the remote server running alongside, if any
+    structured: bool,                 // send every message as a RemoteMessage JSON line
+    remote_metrics: Option<Arc<RemoteMetrics>>, // counters of the remote server running alongside
+    upload_dir: Option<PathBuf>, // where this remote session's uploads are staged, once it has any
+    cluster_results: ClusterResults, // filled in as `!cluster_run` tasks finish
+    cluster_jobs: Arc<Mutex<Jobs>>, // programs fanned out with `!cluster_run all`
+    cluster_task_timeout: Duration,
+    ping_timeout: Duration, // how long `!ping` waits for members to answer
+    following_cluster: Option<Arc<AtomicBool>>, // cleared to stop `!cluster_events follow`
+    interrupt: Option<Arc<AtomicBool>>, // set when the process is told to shut down
+    lines: Option<Receiver<String>>, // lines typed at the terminal, once they are read on a thread
+}
+
+/// Sends messages from other threads through the output pipe, rendered the way the REPL
+/// rendered its own messages when the notifier was made
+struct Notifier {
+    pipe: Option<SyncSender<String>>,
+    format: OutputFormat,
+    structured: bool,
+}
+
+impl Notifier {
+    /// Send a result: `text` in the text format, `value` otherwise
+    fn output(&self, text: String, value: Value) {
+        let line = if self.structured {
+            RemoteMessage::Output(value).to_line()
+        } else if self.format == OutputFormat::Json {
+            Ok(value.to_string() + "\n")
+        } else {
+            Ok(text + "\n")
+        };
+        self.send(line);
+    }
+
+    fn error(&self, msg: String) {
+        let line = if self.structured {
+            RemoteMessage::Error(msg).to_line()
+        } else if self.format == OutputFormat::Json {
+            Ok(json!({ "message": msg }).to_string() + "\n")
+        } else {
+            Ok(msg + "\n")
+        };
+        self.send(line);
+    }
+
+    fn send(&self, line: Result<String>) {
+        let pipe = match &self.pipe {
+            Some(pipe) => pipe,
+            None => return,
+        };
+        match line.map(|line| pipe.try_send(line)) {
+            Ok(Ok(())) | Ok(Err(TrySendError::Disconnected(_))) => {}
+            Ok(Err(TrySendError::Full(_))) => {
+                warn!("REPL output pipe full, dropping a notification")
+            }
+            Err(e) => warn!("Unable to render a notification: {}", e),
+        }
+    }
 }
 
 impl REPL {
     pub fn new(vm: VM) -> REPL {
-        let (tx, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
-        Self {
+        Self::shared(Arc::new(Mutex::new(vm)))
+    }
+
+    /// Create a REPL operating on a VM other sessions may hold too; commands serialize on its lock
+    pub fn shared(vm: Arc<Mutex<VM>>) -> REPL {
+        let (tx, rx) = mpsc::sync_channel(DEFAULT_PIPE_CAPACITY);
+        {
+            // cluster members inspecting this node see the VM as this REPL leaves it
+            let lock = vm.lock().unwrap_or_else(|e| e.into_inner());
+            let mut manager = lock.conn_manager.write().unwrap_or_else(|e| e.into_inner());
+            manager.set_vm(Arc::downgrade(&vm));
+        }
+        // programs spawned here share the cores the VM was given
+        let cores = vm.lock().unwrap_or_else(|e| e.into_inner()).logical_cores;
+        let repl = Self {
             command_buffer: Vec::<String>::new(),
+            history_file: None,
             vm,
             asm: Assembler::new(),
-            scheduler: Scheduler::new(),
+            scheduler: Scheduler::with_workers(cores),
+            last_spawned: None,
             tx_pipe: Some(Box::new(tx)),
             rx_pipe: Some(Box::new(rx)),
+            overflow: OverflowPolicy::default(),
+            dropped: Cell::new(0),
+            pipe_closed: Cell::new(false),
+            remote: false,
+            format: OutputFormat::Text,
+            connections: None,
+            structured: false,
+            remote_metrics: None,
+            upload_dir: None,
+            cluster_results: Arc::new(Mutex::new(HashMap::new())),
+            cluster_jobs: Arc::new(Mutex::new(Jobs::new())),
+            cluster_task_timeout: DEFAULT_CLUSTER_TASK_TIMEOUT,
+            ping_timeout: PING_TIMEOUT,
+            following_cluster: None,
+            interrupt: None,
+            lines: None,
+        };
+        repl.report_finished_tasks();
+        repl
+    }
+
+    /// Replace the output pipe with one holding `capacity` messages, full according to `overflow`
+    pub fn with_output_pipe(mut self, capacity: usize, overflow: OverflowPolicy) -> Self {
+        let (tx, rx) = mpsc::sync_channel(capacity);
+        self.tx_pipe = Some(Box::new(tx));
+        self.rx_pipe = Some(Box::new(rx));
+        self.overflow = overflow;
+        self.report_finished_tasks();
+        self
+    }
+
+    /// Wait this long for the result of a program run with `!cluster_run`
+    pub fn with_cluster_task_timeout(mut self, timeout: Duration) -> Self {
+        self.cluster_task_timeout = timeout;
+        self
+    }
+
+    /// Wait this long for members to answer `!ping`
+    pub fn with_ping_timeout(mut self, timeout: Duration) -> Self {
+        self.ping_timeout = timeout;
+        self
+    }
+
+    /// Start with the commands kept in `path` as the history, and add each command typed to it
+    pub fn with_history_file(mut self, path: PathBuf) -> Self {
+        if let Ok(saved) = fs::read_to_string(&path) {
+            self.command_buffer = saved.lines().map(|line| format!("{}\n", line)).collect();
+        }
+        self.history_file = Some(path);
+        self
+    }
+
+    /// Stop reading commands once `interrupt` is set, as by a signal telling the process to
+    /// shut down
+    pub fn with_interrupt(mut self, interrupt: Arc<AtomicBool>) -> Self {
+        self.interrupt = Some(interrupt);
+        self
+    }
+
+    /// Marks this REPL as serving a remote client, which has no terminal to prompt on
+    pub fn with_remote_session(mut self) -> Self {
+        self.remote = true;
+        self
+    }
+
+    /// Let `!connections` inspect and kick the sessions of this node's remote server
+    pub fn with_connections(mut self, connections: Connections) -> Self {
+        self.connections = Some(connections);
+        self
+    }
+
+    /// Let `!remote_metrics` report the counters of this node's remote server
+    pub fn with_remote_metrics(mut self, metrics: Arc<RemoteMetrics>) -> Self {
+        self.remote_metrics = Some(metrics);
+        self
+    }
+
+    /// Switch a remote session to structured messages, for clients that parse its output
+    pub fn enable_structured_messages(&mut self) {
+        self.structured = true;
+        self.report_finished_tasks();
+    }
+
+    /// Resolve `!load_file @<name>` to files uploaded into `dir`
+    pub fn set_upload_dir(&mut self, dir: PathBuf) {
+        self.upload_dir = Some(dir);
+    }
+
+    /// For sending through the output pipe from other threads, rendered as this REPL renders
+    fn notifier(&self) -> Notifier {
+        Notifier {
+            pipe: self.tx_pipe.as_deref().cloned(),
+            format: self.format,
+            structured: self.structured,
         }
     }
 
+    /// Send a line through the output pipe as each task spawned here stops, without waiting
+    /// for a command. Called again whenever the way this REPL renders output changes
+    fn report_finished_tasks(&self) {
+        let notifier = self.notifier();
+        self.scheduler.set_on_finish(Arc::new(move |task| {
+            notifier.output(task.summary(), json!({ "task_finished": task }))
+        }));
+    }
+
+    /// Runs the programs started with `!spawn` and `!schedule`
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    /// Lock the VM this REPL operates on
+    pub fn vm(&self) -> MutexGuard<'_, VM> {
+        self.vm.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Run the commands typed at the terminal until `!quit`, the end of stdin or an interrupt
     pub fn run(&mut self) -> Result<()> {
+        let (tx, lines) = mpsc::channel();
+        // read on a thread of its own, so an interrupt is noticed while waiting for input
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            loop {
+                let mut buffer = String::new();
+                match stdin.read_line(&mut buffer) {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) if tx.send(buffer).is_err() => return,
+                    Ok(_) => {}
+                }
+            }
+        });
+        self.run_lines(lines)
+    }
+
+    /// Run each line arriving on `lines` as typed at the terminal, until `!quit`, the sender
+    /// hanging up or an interrupt
+    fn run_lines(&mut self, lines: Receiver<String>) -> Result<()> {
+        // kept on the REPL, so commands prompting for more input read it from there too
+        self.lines = Some(lines);
         self.send_message(REMOTE_BANNER.to_string())?;
         self.send_prompt()?;
         loop {
-            // This allocates a new String in which to store whatever the user types each iteration.
-            // TODO: Figure out how allocate this outside of the loop and re-use it every iteration
-            let mut buffer = String::new();
+            if self.is_interrupted() {
+                return self.send_message("Interrupted, shutting down".to_string());
+            }
+            let buffer = match self.next_line() {
+                Ok(buffer) => buffer,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            };
 
-            let stdin = io::stdin();
+            self.remember(&buffer)?;
+
+            if self.run_single(&buffer)? == Flow::Quit {
+                return Ok(());
+            }
+            self.send_prompt()?;
+        }
+    }
+
+    /// The next line typed at the terminal, waiting for it no longer than it takes to notice
+    /// an interrupt
+    fn next_line(&self) -> std::result::Result<String, RecvTimeoutError> {
+        match &self.lines {
+            Some(lines) => lines.recv_timeout(INTERRUPT_CHECK_INTERVAL),
+            None => Err(RecvTimeoutError::Disconnected),
+        }
+    }
+
+    /// Wait for a line typed at the terminal in answer to a prompt. None if the terminal
+    /// closed or the process was interrupted first
+    fn prompted_line(&self) -> Result<Option<String>> {
+        if self.lines.is_none() {
+            // nothing reads the terminal yet, as while an init script runs
+            let mut line = String::new();
+            return match io::stdin().read_line(&mut line)? {
+                0 => Ok(None),
+                _ => Ok(Some(line)),
+            };
+        }
+        while !self.is_interrupted() {
+            match self.next_line() {
+                Ok(line) => return Ok(Some(line)),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(None),
+            }
+        }
+        Ok(None)
+    }
 
-            stdin
-                .read_line(&mut buffer)
-                .expect("Unable to read line from user");
+    fn is_interrupted(&self) -> bool {
+        self.interrupt
+            .as_ref()
+            .is_some_and(|interrupt| interrupt.load(Ordering::SeqCst))
+    }
 
-            let historical_copy = buffer.clone();
-            self.command_buffer.push(historical_copy);
+    /// Execute single command for remote client, telling it whether to keep the session going
+    pub fn run_single(&mut self, buffer: &str) -> Result<Flow> {
+        self.eval(buffer)
+    }
 
-            self.run_single(&buffer)?;
+    /// Execute every line of an init script before the prompt is shown, echoing each one.
+    /// Blank lines and lines starting with '#' are skipped; stops at the first line that fails
+    /// or quits, returning Flow::Quit in the latter case.
+    pub fn run_script(&mut self, path: &Path) -> Result<Flow> {
+        let script = fs::read_to_string(path)?;
+        for (number, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            self.send_message(format!("{}{}", PROMPT, line))?;
+            let flow = self.eval(line).map_err(|e| {
+                IridiumError::StringError(format!(
+                    "Init script {} failed at line {}: {}",
+                    path.display(),
+                    number + 1,
+                    e
+                ))
+            })?;
+            match flow {
+                Flow::Continue => {}
+                Flow::Invalid => {
+                    self.send_message(format!(
+                        "Init script {} failed at line {}: {}",
+                        path.display(),
+                        number + 1,
+                        line
+                    ))?;
+                    break;
+                }
+                Flow::Quit => return Ok(Flow::Quit),
+            }
         }
+
+        Ok(Flow::Continue)
     }
 
-    /// Execute single command for remote client
-    pub fn run_single(&mut self, buffer: &str) -> Result<()> {
+    /// Execute a command or assembly line
+    fn eval(&mut self, buffer: &str) -> Result<Flow> {
         if buffer.starts_with(COMMAND_PREFIX) {
-            self.execute_command(buffer)?;
-        } else {
-            match Program::parse(buffer) {
-                Ok((_, program)) => {
-                    let mut bytes = program.to_bytes(&self.asm.symbols);
-                    self.vm.program.append(&mut bytes);
-                    self.vm.run_once();
+            return self.execute_command(buffer);
+        }
+        match parse_complete::<Program>(buffer) {
+            Ok(program) => {
+                let undeclared = program.undeclared_labels(&self.asm.symbols);
+                if !undeclared.is_empty() {
+                    for (name, span) in undeclared {
+                        let marked = underline(buffer, span.offset, span.len);
+                        self.send_error(format!("Unknown label {}\n{}", name, marked))?;
+                    }
+                    return Ok(Flow::Invalid);
                 }
-                Err(e) => {
-                    self.send_message(format!("Unable to parse input: {:?}", e))?;
-                    self.send_prompt()?;
+                let mut bytes = program.to_bytes(&self.asm.symbols);
+                let mut vm = self.vm();
+                vm.program.append(&mut bytes);
+                let ran = vm.run_once();
+                drop(vm);
+                if let Err(e) = ran {
+                    self.send_error(e.to_string())?;
+                    return Ok(Flow::Invalid);
                 }
-            };
+                Ok(Flow::Continue)
+            }
+            Err(e) => {
+                self.send_error(format!("Unable to parse input: {}", e))?;
+                Ok(Flow::Invalid)
+            }
         }
-        Ok(())
     }
 
-    fn execute_command(&mut self, input: &str) -> Result<()> {
+    fn execute_command(&mut self, input: &str) -> Result<Flow> {
         let args = CommandParser::tokenize(input);
         match args[0] {
-            "!quit" => self.quit(&args[1..])?,
+            "!quit" => return self.quit(&args[1..]),
             "!history" => self.history(&args[1..])?,
             "!program" => self.program(&args[1..])?,
-            "!clear_program" => self.clear_program(&args[1..]),
+            "!clear_program" => self.clear_program(&args[1..])?,
             "!clear_registers" => self.clear_registers(&args[1..])?,
             "!registers" => self.registers(&args[1..])?,
             "!symbols" => self.symbols(&args[1..])?,
-            "!load_file" => self.load_file(&args[1..])?,
+            "!load_file" => return self.load_file(&args[1..]),
+            "!load_bytecode" => self.load_bytecode(&args[1..])?,
+            "!bench" => self.bench(&args[1..])?,
             "!spawn" => self.spawn(&args[1..])?,
+            "!await" => self.await_spawned(&args[1..])?,
+            "!tasks" => self.tasks(&args[1..])?,
+            "!kill" => self.kill(&args[1..])?,
+            "!scheduler_status" => self.scheduler_status(&args[1..])?,
+            "!schedule" => self.schedule(&args[1..])?,
+            "!unschedule" => self.unschedule(&args[1..])?,
             "!start_cluster" => self.start_cluster(&args[1..])?,
             "!join_cluster" => self.join_cluster(&args[1..])?,
+            "!leave_cluster" => self.leave_cluster(&args[1..])?,
             "!cluster_members" => self.cluster_members(&args[1..])?,
+            "!cluster_run" => self.cluster_run(&args[1..])?,
+            "!cluster_results" => self.cluster_results(&args[1..])?,
+            "!cluster_events" => self.cluster_events(&args[1..])?,
+            "!ping" => self.ping(&args[1..])?,
+            "!cluster_inspect" => self.cluster_inspect(&args[1..])?,
+            "!status" => self.status(&args[1..])?,
+            "!events" => self.events(&args[1..])?,
+            "!format" => self.format(&args[1..])?,
+            "!connections" => self.connections(&args[1..])?,
+            "!remote_metrics" => self.remote_metrics(&args[1..])?,
             _ => {
-                self.send_message("Invalid command!".to_string())?;
+                self.send_error("Invalid command!".to_string())?;
+                return Ok(Flow::Invalid);
             }
         };
 
-        Ok(())
+        Ok(Flow::Continue)
     }
 
-    fn quit(&mut self, _args: &[&str]) -> Result<()> {
+    /// Ends only this session; the local terminal exits the process, a remote client disconnects
+    fn quit(&mut self, _args: &[&str]) -> Result<Flow> {
+        self.shutdown()?;
         self.send_message("Farewell! Have a great day!".to_string())?;
-        std::process::exit(0);
+        Ok(Flow::Quit)
+    }
+
+    /// Stop the scheduler of this session, letting spawned tasks still queued or running
+    /// finish within `SHUTDOWN_GRACE` and cancelling the rest, then say what became of them.
+    /// Does nothing more once done
+    pub fn shutdown(&mut self) -> Result<()> {
+        let metrics = self.scheduler.metrics();
+        let outstanding = metrics.running + metrics.queued;
+        if outstanding > 0 {
+            self.send_message(format!(
+                "Waiting up to {:?} for {} tasks to finish",
+                SHUTDOWN_GRACE, outstanding
+            ))?;
+        }
+        let summary = self.scheduler.shutdown(SHUTDOWN_GRACE);
+        if summary == ShutdownSummary::default() {
+            return Ok(());
+        }
+        if self.format == OutputFormat::Json {
+            return self.send_json(json!({ "shutdown": summary }));
+        }
+        self.send_message(format!(
+            "Tasks at shutdown: {} completed, {} cancelled, {} abandoned",
+            summary.completed, summary.cancelled, summary.abandoned
+        ))
+    }
+
+    fn connections(&mut self, args: &[&str]) -> Result<()> {
+        let connections = match &self.connections {
+            Some(connections) => connections,
+            None => return self.send_message("Remote server is not enabled".to_string()),
+        };
+        match args {
+            [] => {
+                let list = connections.list();
+                if self.format == OutputFormat::Json {
+                    return self.send_json(json!({ "connections": list }));
+                }
+                self.send_message("Listing remote connections:".to_string())?;
+                for info in list {
+                    self.send_message(format!(
+                        "{:<22}connected {}  {} commands",
+                        info.addr,
+                        info.connected_at.format("%Y-%m-%d %H:%M:%S"),
+                        info.commands
+                    ))?;
+                }
+                self.send_message("End of Connections Listing".to_string())
+            }
+            ["kick", addr] => match addr.parse() {
+                Ok(addr) if connections.kick(addr) => {
+                    self.send_message(format!("Disconnected {}", addr))
+                }
+                Ok(addr) => self.send_message(format!("No connection from {}", addr)),
+                Err(_) => self.send_message(format!("Invalid address: {}", addr)),
+            },
+            _ => self.send_message("Usage: !connections [kick <addr>]".to_string()),
+        }
+    }
+
+    fn remote_metrics(&mut self, _args: &[&str]) -> Result<()> {
+        let snapshot = match &self.remote_metrics {
+            Some(metrics) => metrics.snapshot(),
+            None => return self.send_message("Remote server is not enabled".to_string()),
+        };
+        if self.format == OutputFormat::Json {
+            return self.send_json(json!({ "remote_metrics": snapshot }));
+        }
+        self.send_message(format!(
+            "{:<22}{}",
+            "connections accepted:", snapshot.connections_accepted
+        ))?;
+        self.send_message(format!(
+            "{:<22}{}",
+            "active sessions:", snapshot.active_sessions
+        ))?;
+        self.send_message(format!(
+            "{:<22}{}",
+            "commands processed:", snapshot.commands_processed
+        ))?;
+        self.send_message(format!("{:<22}{}", "parse errors:", snapshot.parse_errors))?;
+        self.send_message(format!(
+            "{:<22}{}",
+            "bytes written:", snapshot.bytes_written
+        ))
+    }
+
+    /// Run a program on a VM of its own later, `--after` a delay, and again `--every` so
+    /// often, up to `--max` times. Lists the schedules when given nothing
+    fn schedule(&mut self, args: &[&str]) -> Result<()> {
+        if args.is_empty() {
+            return self.list_schedules();
+        }
+        let usage = "Usage: !schedule <file> [--after <delay>] [--every <interval>] [--max <runs>]";
+        let (mut after, mut every, mut max_runs) = (Duration::ZERO, None, None);
+        let mut path = None;
+        let mut flags = args.iter();
+        while let Some(arg) = flags.next() {
+            let parsed = match *arg {
+                "--after" => flags
+                    .next()
+                    .and_then(|v| parse_duration(v))
+                    .map(|d| after = d),
+                "--every" => flags
+                    .next()
+                    .and_then(|v| parse_duration(v))
+                    .filter(|d| !d.is_zero())
+                    .map(|d| every = Some(d)),
+                "--max" => flags
+                    .next()
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .filter(|max| *max > 0)
+                    .map(|max| max_runs = Some(max)),
+                arg if path.is_none() => {
+                    path = Some(arg);
+                    Some(())
+                }
+                _ => None,
+            };
+            if parsed.is_none() {
+                return self.send_message(usage.to_string());
+            }
+        }
+        let path = match path {
+            Some(path) if every.is_some() || max_runs.is_none() => path,
+            _ => return self.send_message(usage.to_string()),
+        };
+
+        let contents = match self.get_data_from_load(&[path])? {
+            Some(contents) => contents,
+            None => return Ok(()),
+        };
+        let program = match Assembler::new().assemble(&contents) {
+            Ok(program) => program,
+            Err(e) => return self.send_error(format!("Unable to parse input: {}", e)),
+        };
+        let mut schedule = Schedule::new(program, path, after);
+        if let Some(every) = every {
+            schedule = schedule.with_repeat(every, max_runs);
+        }
+        match self.scheduler.add_schedule(schedule) {
+            Ok(id) => self.send_message(format!("Scheduled {}", id)),
+            Err(e) => self.send_error(format!("Unable to schedule: {}", e)),
+        }
+    }
+
+    fn list_schedules(&mut self) -> Result<()> {
+        let schedules = self.scheduler.schedules();
+        if self.format == OutputFormat::Json {
+            return self.send_json(json!({ "schedules": schedules }));
+        }
+        self.send_message("Listing schedules:".to_string())?;
+        for schedule in schedules {
+            let every = match schedule.every {
+                Some(every) => format!("every {:?}", every),
+                None => "once".to_string(),
+            };
+            let state = if schedule.active { "active" } else { "stopped" };
+            self.send_message(format!(
+                "{}  {:<8}{:<14}{} runs  {}",
+                schedule.id,
+                state,
+                every,
+                schedule.runs.len(),
+                schedule.description
+            ))?;
+        }
+        self.send_message("End of Schedules Listing".to_string())
+    }
+
+    /// Stop a schedule from starting more runs
+    fn unschedule(&mut self, args: &[&str]) -> Result<()> {
+        let id = match args {
+            [id] => match id.parse::<Uuid>() {
+                Ok(id) => id,
+                Err(_) => return self.send_error(format!("Invalid schedule id {}", id)),
+            },
+            _ => return self.send_message("Usage: !unschedule <schedule_id>".to_string()),
+        };
+        match self.scheduler.unschedule(&id) {
+            Ok(()) => self.send_message(format!("Unscheduled {}", id)),
+            Err(e) => self.send_error(e.to_string()),
+        }
+    }
+
+    /// What the scheduler running `!spawn`ed programs has done so far, and is doing now
+    fn scheduler_status(&mut self, _args: &[&str]) -> Result<()> {
+        let metrics = self.scheduler.metrics();
+        if self.format == OutputFormat::Json {
+            return self.send_json(json!({ "scheduler_status": metrics }));
+        }
+        let counts = [
+            ("workers:", metrics.workers),
+            ("running:", metrics.running),
+            ("queued:", metrics.queued),
+            ("submitted:", metrics.submitted),
+            ("finished:", metrics.finished),
+            ("crashed:", metrics.crashed),
+            ("cancelled:", metrics.cancelled),
+            ("timed out:", metrics.timed_out),
+        ];
+        for (name, count) in counts {
+            self.send_message(format!("{:<22}{}", name, count))?;
+        }
+        for (worker, busy) in metrics.worker_busy.iter().enumerate() {
+            self.send_message(format!(
+                "{:<22}{:?}",
+                format!("worker {} busy:", worker),
+                busy
+            ))?;
+        }
+        Ok(())
+    }
+
+    fn format(&mut self, args: &[&str]) -> Result<()> {
+        self.format = match args.first() {
+            Some(&"json") => OutputFormat::Json,
+            Some(&"text") => OutputFormat::Text,
+            _ => return self.send_message("Usage: !format json|text".to_string()),
+        };
+        self.report_finished_tasks();
+        match self.format {
+            OutputFormat::Json => self.send_json(json!({ "format": "json" })),
+            OutputFormat::Text => self.send_message("Output format set to text".to_string()),
+        }
+    }
+
+    /// Add a typed command to the history, and to the history file if there is one. A file
+    /// that can't be written is reported once, then the history is only kept in memory
+    fn remember(&mut self, command: &str) -> Result<()> {
+        self.command_buffer.push(command.to_string());
+        let Some(path) = &self.history_file else {
+            return Ok(());
+        };
+        if command.trim().is_empty() {
+            return Ok(());
+        }
+        let saved = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| OpenOptions::new().create(true).append(true).open(path))
+            .and_then(|mut file| writeln!(file, "{}", command.trim_end()));
+        if let Err(e) = saved {
+            let context = format!("Unable to save history to {}", path.display());
+            self.history_file = None;
+            self.send_report(&context, &e)?;
+        }
+        Ok(())
     }
 
     fn history(&mut self, _args: &[&str]) -> Result<()> {
+        if self.format == OutputFormat::Json {
+            return self.send_json(json!({ "history": self.command_buffer }));
+        }
         let mut results = vec![];
         for command in &self.command_buffer {
             results.push(command.clone());
@@ -128,9 +782,13 @@ impl REPL {
     }
 
     fn program(&mut self, _args: &[&str]) -> Result<()> {
+        let vm = self.vm();
+        if self.format == OutputFormat::Json {
+            return self.send_json(json!({ "program": vm.program }));
+        }
         self.send_message("Listing instructions currently in VM's program vector: ".to_string())?;
         let mut results = vec![];
-        for instruction in &self.vm.program {
+        for instruction in &vm.program {
             results.push(*instruction)
         }
         self.send_message(format!("{:#?}", results))?;
@@ -139,14 +797,22 @@ impl REPL {
         Ok(())
     }
 
-    fn clear_program(&mut self, _args: &[&str]) {
-        self.vm.program.clear();
+    fn clear_program(&mut self, _args: &[&str]) -> Result<()> {
+        let mut vm = self.vm();
+        vm.clear_program();
+        self.send_message(format!(
+            "Cleared program and read-only data, pc reset to {}",
+            vm.pc()
+        ))?;
+
+        Ok(())
     }
 
     fn clear_registers(&mut self, _args: &[&str]) -> Result<()> {
+        let mut vm = self.vm();
         self.send_message("Setting all registers to 0".to_string())?;
-        for i in 0..self.vm.registers.len() {
-            self.vm.registers[i] = 0;
+        for i in 0..vm.registers.len() {
+            vm.registers[i] = 0;
         }
         self.send_message("Done!".to_string())?;
 
@@ -154,9 +820,13 @@ impl REPL {
     }
 
     fn registers(&mut self, _args: &[&str]) -> Result<()> {
+        let vm = self.vm();
+        if self.format == OutputFormat::Json {
+            return self.send_json(json!({ "registers": vm.registers }));
+        }
         self.send_message("Listing registers and all contents:".to_string())?;
         let mut results = vec![];
-        for register in &self.vm.registers {
+        for register in &vm.registers {
             results.push(*register);
         }
         self.send_message(format!("{:#?}", results))?;
@@ -166,6 +836,9 @@ impl REPL {
     }
 
     fn symbols(&mut self, _args: &[&str]) -> Result<()> {
+        if self.format == OutputFormat::Json {
+            return self.send_json(json!({ "symbols": self.asm.symbols.symbols }));
+        }
         let mut results = vec![];
         for symbol in &self.asm.symbols.symbols {
             results.push(<&Symbol>::clone(&symbol));
@@ -177,145 +850,2315 @@ impl REPL {
         Ok(())
     }
 
-    fn load_file(&mut self, _args: &[&str]) -> Result<()> {
-        let contents = self.get_data_from_load();
-        if let Some(contents) = contents {
-            match self.asm.assemble(&contents) {
-                Ok(mut assembled_program) => {
-                    self.send_message("Sending assembled program to VM".to_string())?;
-                    self.vm.program.append(&mut assembled_program);
-                    self.vm.run();
+    /// Load, assemble and run a file, Flow::Invalid if any of that failed
+    fn load_file(&mut self, args: &[&str]) -> Result<Flow> {
+        match self.get_data_from_load(args)? {
+            Some(contents) => self.load_source(&contents),
+            None => Ok(Flow::Invalid),
+        }
+    }
+
+    /// Assemble source with a fresh assembler, append it to the VM's program and run it.
+    /// Flow::Invalid if it doesn't assemble or crashes
+    fn load_source(&mut self, contents: &str) -> Result<Flow> {
+        self.asm = Assembler::new();
+        match self.asm.assemble(contents) {
+            Ok(mut assembled_program) => {
+                self.send_message("Sending assembled program to VM".to_string())?;
+                let mut vm = self.vm();
+                vm.set_ro_data(self.asm.ro.clone());
+                vm.program.append(&mut assembled_program);
+                vm.run();
+                let crash = vm.error().cloned();
+                drop(vm);
+                if let Some(e) = crash {
+                    self.send_report("Program crashed", &e)?;
+                    return Ok(Flow::Invalid);
                 }
-                Err(errors) => {
-                    if let IridiumError::Assemble(e) = errors {
-                        for error in e {
-                            self.send_message(format!("Unable to parse input: {}", error))?;
-                        }
-                    }
+            }
+            Err(e) => {
+                self.send_report("Unable to parse input", &e)?;
+                return Ok(Flow::Invalid);
+            }
+        }
+
+        Ok(Flow::Continue)
+    }
+
+    /// Report an error with `context` saying what failed, and a line for each cause
+    fn send_report(&self, context: &str, e: &(dyn std::error::Error + 'static)) -> Result<()> {
+        self.send_error(report::error(context, e))
+    }
+
+    fn load_bytecode(&mut self, args: &[&str]) -> Result<()> {
+        let mut vm = self.vm();
+        let path = match args.first() {
+            Some(path) => path,
+            None => {
+                self.send_message("Usage: !load_bytecode <path>".to_string())?;
+                return Ok(());
+            }
+        };
+        let image = match fs::read(path) {
+            Ok(image) => image,
+            Err(e) => {
+                self.send_report(&format!("There was an error reading {}", path), &e)?;
+                return Ok(());
+            }
+        };
+
+        match vm.load_bytecode(image) {
+            Ok(entry_offset) => {
+                self.send_message(format!(
+                    "Loaded {} bytes of bytecode, entry offset {}",
+                    vm.program.len(),
+                    entry_offset
+                ))?;
+                vm.run();
+                if let Some(e) = vm.error() {
+                    self.send_report("Program crashed", e)?;
                 }
             }
+            Err(e) => {
+                self.send_report("Unable to load bytecode", &e)?;
+            }
         }
 
         Ok(())
     }
 
-    fn spawn(&mut self, _args: &[&str]) -> Result<()> {
-        let contents = self.get_data_from_load();
+    fn bench(&mut self, args: &[&str]) -> Result<()> {
+        let usage = "Usage: !bench <path> [iterations]".to_string();
+        let path = match args.first() {
+            Some(path) => path,
+            None => return self.send_message(usage),
+        };
+        let iterations = match args.get(1).map(|n| n.parse::<usize>()) {
+            None => benchmark::DEFAULT_ITERATIONS,
+            Some(Ok(n)) if n > 0 => n,
+            Some(_) => return self.send_message(usage),
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => return self.send_report(&format!("There was an error reading {}", path), &e),
+        };
+        let program = match Assembler::new().assemble(&contents) {
+            Ok(program) => program,
+            Err(e) => return self.send_report("Unable to parse input", &e),
+        };
+        let bench = match Benchmark::run(&program, iterations, 0) {
+            Ok(bench) => bench,
+            Err(e) => return self.send_report(&format!("Unable to benchmark {}", path), &e),
+        };
+
+        let millis = |d: Duration| d.as_secs_f64() * 1000.0;
+        self.send_message(format!("Benchmark of {} ({} iterations)", path, iterations))?;
+        self.send_message(format!("min: {:.3} ms", millis(bench.min())))?;
+        self.send_message(format!("median: {:.3} ms", millis(bench.median())))?;
+        self.send_message(format!("max: {:.3} ms", millis(bench.max())))?;
+        self.send_message(format!("instructions/sec: {:.0}", bench.per_second()))?;
+
+        Ok(())
+    }
+
+    /// Run a program in the background on a VM of its own, or with `--with-state` on a copy
+    /// of this one's registers, heap and program. It is stopped after `--timeout <secs>` if
+    /// given, and runs ahead of others waiting for a worker with `--priority high`
+    fn spawn(&mut self, args: &[&str]) -> Result<()> {
+        let with_state = args.contains(&"--with-state");
+        let args: Vec<&str> = args
+            .iter()
+            .copied()
+            .filter(|arg| *arg != "--with-state")
+            .collect();
+        let (options, args) = match spawn_options(&args) {
+            Some(parsed) => parsed,
+            None => {
+                return self.send_message(
+                    "Usage: !spawn [--with-state] [--timeout <secs>] [--priority low|normal|high] <file>"
+                        .to_string(),
+                )
+            }
+        };
+        let args = args.as_slice();
+        let contents = self.get_data_from_load(args)?;
         self.send_message(format!("Loaded contents: {:#?}", contents))?;
         if let Some(contents) = contents {
-            match self.asm.assemble(&contents) {
+            // assembled on its own, so it neither trips over nor leaves behind labels here
+            match Assembler::new().assemble(&contents) {
                 Ok(mut assembled_program) => {
                     self.send_message("Sending assembled program to VM".to_string())?;
-                    self.vm.program.append(&mut assembled_program);
-                    self.scheduler.get_thread(self.vm.clone());
-                }
-                Err(errors) => {
-                    if let IridiumError::Assemble(e) = errors {
-                        for error in e {
-                            self.send_message(format!("Unable to parse input: {}", error))?;
+                    let mode = if with_state {
+                        let mut snapshot = self.vm().snapshot();
+                        snapshot.program.append(&mut assembled_program);
+                        SpawnMode::CloneState {
+                            snapshot: Box::new(snapshot),
+                        }
+                    } else {
+                        SpawnMode::Fresh {
+                            program: assembled_program,
                         }
+                    };
+                    let description = args.first().unwrap_or(&"program entered at the prompt");
+                    match self.scheduler.submit(mode, description, options) {
+                        Ok(task_id) => {
+                            self.last_spawned = Some(task_id);
+                            self.send_message(format!("Spawned task {}", task_id))?;
+                        }
+                        Err(e) => self.send_report("Unable to spawn", &e)?,
                     }
                 }
+                Err(e) => self.send_report("Unable to parse input", &e)?,
             }
         }
 
         Ok(())
     }
 
-    fn start_cluster(&mut self, _args: &[&str]) -> Result<()> {
-        self.send_message("Started cluster server!".to_string())?;
-        self.vm.bind_cluster_server();
+    /// Wait for a task started with `!spawn`, the last one unless told which, then show how
+    /// it ended. One still running after the timeout is left to finish
+    fn await_spawned(&mut self, args: &[&str]) -> Result<()> {
+        let (task_id, timeout) = match args {
+            [] => (self.last_spawned, None),
+            [id] => (id.parse().ok(), None),
+            [id, secs] => (id.parse().ok(), Some(secs)),
+            _ => return self.send_message("Usage: !await [task_id] [timeout_secs]".to_string()),
+        };
+        let task_id = match (task_id, args.first()) {
+            (Some(task_id), _) => task_id,
+            (None, Some(id)) => return self.send_error(format!("Invalid task id {}", id)),
+            (None, None) => return self.send_error("No task spawned yet".to_string()),
+        };
+        let timeout = match timeout.map(|secs| secs.parse::<u64>()) {
+            None => DEFAULT_AWAIT_TIMEOUT,
+            Some(Ok(secs)) => Duration::from_secs(secs),
+            Some(Err(_)) => {
+                return self.send_error(format!("Invalid timeout {}", args[1]));
+            }
+        };
 
-        Ok(())
+        let outcome = match self.scheduler.wait(&task_id, timeout) {
+            Ok(outcome) => outcome,
+            Err(e) => return self.send_error(e.to_string()),
+        };
+        if self.format == OutputFormat::Json {
+            return self.send_json(json!({ "task": { "id": task_id, "outcome": outcome } }));
+        }
+        let crashed = outcome.events.iter().any(|e| e.event == VMEventType::Crash);
+        let ending = match outcome.events.last().map(|e| &e.event) {
+            _ if crashed => "crashed",
+            Some(VMEventType::Cancelled) => "was cancelled",
+            Some(VMEventType::TimedOut) => "timed out",
+            Some(VMEventType::Interrupted) => "was interrupted",
+            _ => "finished",
+        };
+        self.send_message(format!("Task {} {}", task_id, ending))?;
+        for event in &outcome.events {
+            self.send_message(format!(
+                "{}  {:?}",
+                event.at.format("%Y-%m-%d %H:%M:%S%.3f"),
+                event.event
+            ))?;
+        }
+        // registers still at zero say nothing about what the program did
+        let registers: Vec<String> = outcome
+            .registers
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| **value != 0)
+            .map(|(register, value)| format!("${}={}", register, value))
+            .collect();
+        if registers.is_empty() {
+            self.send_message("registers: all zero".to_string())
+        } else {
+            self.send_message(format!("registers: {}", registers.join(" ")))
+        }
     }
 
-    fn join_cluster(&mut self, args: &[&str]) -> Result<()> {
-        debug!("Joining cluster with VM ID: {:?}", self.vm.alias);
-        self.send_message("Attempting to join cluster...".to_string())?;
-
-        let ip = args[0];
-        let port = args[1];
-
-        let addr = ip.to_owned() + ":" + port;
-        let alias = self.vm.alias.as_ref().unwrap();
-        let _addr = addr.clone();
+    /// Stop a task started with `!spawn` that is still queued or running
+    fn kill(&mut self, args: &[&str]) -> Result<()> {
+        let task_id = match args {
+            [id] => match id.parse::<Uuid>() {
+                Ok(task_id) => task_id,
+                Err(_) => return self.send_error(format!("Invalid task id {}", id)),
+            },
+            _ => return self.send_message("Usage: !kill <task_id>".to_string()),
+        };
+        match self.scheduler.cancel(&task_id) {
+            Ok(()) => self.send_message(format!("Cancelling task {}", task_id)),
+            Err(e) => self.send_error(e.to_string()),
+        }
+    }
 
-        if let Ok(stream) = TcpStream::connect(addr) {
-            self.send_message("Connected to cluster!".to_string())?;
-            let mut cc = ClusterClient::new(stream)?.with_alias(alias.to_string());
-            cc.send_hello()?;
-            self.send_message(format!("Node {} sent hello to server at {}", alias, _addr))?;
-            if let Ok(mut lock) = self.vm.conn_manager.write() {
-                lock.add_client(alias.to_string(), cc);
+    /// List the tasks started with `!spawn` and where each is at, or just the one asked for
+    fn tasks(&mut self, args: &[&str]) -> Result<()> {
+        let detailed = !args.is_empty();
+        let tasks = match args {
+            [] => self.scheduler.list(),
+            [id] => match id.parse().ok().and_then(|id| self.scheduler.status(&id)) {
+                Some(task) => vec![task],
+                None => return self.send_error(format!("No task {}", id)),
+            },
+            _ => return self.send_message("Usage: !tasks [task_id]".to_string()),
+        };
+        if self.format == OutputFormat::Json {
+            return self.send_json(json!({ "tasks": tasks }));
+        }
+        self.send_message("Listing spawned tasks:".to_string())?;
+        let time = |at: Option<DateTime<Utc>>| match at {
+            Some(at) => at.format("%H:%M:%S%.3f").to_string(),
+            None => "-".to_string(),
+        };
+        for task in tasks {
+            let mut line = format!(
+                "{}  {:<9}started {:<14}finished {:<14}{}",
+                task.id,
+                format!("{:?}", task.state),
+                time(task.started_at),
+                time(task.finished_at),
+                task.description
+            );
+            if let Some(panic) = &task.panic {
+                line.push_str(&format!("  (panicked: {})", panic));
+            }
+            self.send_message(line)?;
+            if detailed {
+                for event in &task.events {
+                    self.send_message(format!(
+                        "    {}  {:?}",
+                        event.at.format("%H:%M:%S%.3f"),
+                        event.event
+                    ))?;
+                }
             }
-        } else {
-            self.send_message("Could not connect to cluster!".to_string())?;
         }
-
-        Ok(())
+        self.send_message("End of Tasks Listing".to_string())
     }
 
-    fn cluster_members(&mut self, args: &[&str]) -> Result<()> {
-        self.send_message(format!("Listing Known Nodes:"))?;
-        if let Ok(lock) = self.vm.conn_manager.read() {
-            let cluster_members = lock.get_client_names();
-            self.send_message(format!("{:#?}", cluster_members))?;
+    fn start_cluster(&mut self, _args: &[&str]) -> Result<()> {
+        let mut vm = self.vm();
+        if let Some(alias) = vm.ensure_alias() {
+            self.send_message(format!("No alias set, using {}", alias))?;
+        }
+        match vm.bind_cluster_server() {
+            Ok(addr) => self.send_message(format!("Started cluster server on {}!", addr)),
+            Err(e) => self.send_error(format!("Could not start cluster server: {}", e)),
         }
-
-        Ok(())
     }
 
-    pub fn send_message(&self, msg: String) -> Result<()> {
-        match &self.tx_pipe {
-            Some(pipe) => {
-                pipe.send(msg + "\n")?;
-                Ok(())
-            }
-            None => Err(IridiumError::Send(SendError(
-                "Send pipe not found on repl".to_owned(),
-            ))),
+    fn join_cluster(&mut self, args: &[&str]) -> Result<()> {
+        let (ip, port) = match args {
+            [ip, port] => (*ip, *port),
+            _ => return self.send_message("Usage: !join_cluster <host> <port>".to_string()),
+        };
+        if !self.require_coordinator("!join_cluster")? {
+            return Ok(());
         }
-    }
+        let mut vm = self.vm();
+        if let Some(alias) = vm.ensure_alias() {
+            self.send_message(format!("No alias set, using {}", alias))?;
+        }
+        debug!("Joining cluster with VM ID: {:?}", vm.alias);
+        self.send_message("Attempting to join cluster...".to_string())?;
 
-    pub fn send_prompt(&mut self) -> Result<()> {
-        match &self.tx_pipe {
-            Some(pipe) => {
-                pipe.send(PROMPT.to_owned())?;
-                Ok(())
+        let addr = address::join_host_port(ip, port);
+        // cluster connections report what they receive through the pipe, rendered like the rest
+        let (output, lines) = mpsc::channel::<String>();
+        let joiner = vm.cluster_joiner(Some(output));
+        // joining waits on the network, so the VM is left free for other sessions meanwhile
+        drop(vm);
+        let notifier = self.notifier();
+        thread::spawn(move || {
+            for line in lines {
+                notifier.output(line.clone(), json!({ "cluster": line }));
+            }
+        });
+        match joiner.and_then(|join| join(&addr)) {
+            Ok((server_alias, nodes)) => {
+                self.send_message(format!("Joined cluster through node {}", server_alias))?;
+                for (node, ip, port) in &nodes {
+                    let addr = address::join_host_port(ip, port);
+                    self.send_message(format!("Cluster member {} at {}", node, addr))?;
+                }
             }
-            None => Err(IridiumError::Send(SendError(
-                "Send pipe not found on repl".to_owned(),
-            ))),
+            Err(e) => self.send_error(format!("Could not join cluster: {}", e))?,
         }
+
+        Ok(())
     }
 
-    fn get_data_from_load(&mut self) -> Option<String> {
-        let stdin = io::stdin();
-        print!("Please enter the path to the file you wish to load: ");
-        io::stdout().flush().expect("Unable to flush stdout");
-        let mut tmp = String::new();
+    fn leave_cluster(&mut self, _args: &[&str]) -> Result<()> {
+        let told = self.vm().leave_cluster();
+        self.send_message(format!(
+            "Left the cluster, said goodbye to {} members",
+            told
+        ))
+    }
 
-        stdin
-            .read_line(&mut tmp)
-            .expect("Unable to read line from user");
-        println!("Attempting to load program from file...");
+    fn cluster_members(&mut self, _args: &[&str]) -> Result<()> {
+        let vm = self.vm();
+        let (mut nodes, metadata, mut reconnecting) = match vm.conn_manager.read() {
+            Ok(lock) => (
+                lock.all_nodes(),
+                lock.all_metadata(),
+                lock.get_reconnecting(),
+            ),
+            Err(_) => (vec![], HashMap::new(), vec![]),
+        };
+        nodes.sort_by(|a, b| a.alias.cmp(&b.alias));
+        reconnecting.sort();
+        let unknown = NodeMetadata::default();
+        if self.format == OutputFormat::Json {
+            let mut members: Vec<Value> = nodes
+                .iter()
+                .map(|node| {
+                    let metadata = metadata.get(&node.alias).unwrap_or(&unknown);
+                    json!({
+                        "alias": node.alias,
+                        "host": node.host,
+                        "port": node.port,
+                        "state": "connected",
+                        "connected_at": node.connected_at,
+                        "last_seen": node.last_seen,
+                        "logical_cores": metadata.logical_cores,
+                        "vm_id": metadata.vm_id,
+                        "role": metadata.role.unwrap_or_default(),
+                    })
+                })
+                .collect();
+            members.extend(reconnecting.iter().map(|((alias, host, port), attempts)| {
+                json!({
+                    "alias": alias,
+                    "host": host,
+                    "port": port,
+                    "state": "reconnecting",
+                    "attempts": attempts,
+                })
+            }));
+            return self.send_json(json!({ "cluster_members": members }));
+        }
+        self.send_message("Listing Known Nodes:".to_string())?;
+        for node in &nodes {
+            let metadata = metadata.get(&node.alias).unwrap_or(&unknown);
+            let cores = metadata.logical_cores.map(|n| n.to_string());
+            let vm_id = metadata.vm_id.map(|id| id.to_string());
+            self.send_message(format!(
+                "{}  {}:{}  role: {}  cores: {}  vm: {}  last seen: {}",
+                node.alias,
+                node.host,
+                node.port,
+                metadata.role.unwrap_or_default(),
+                cores.as_deref().unwrap_or("unknown"),
+                vm_id.as_deref().unwrap_or("unknown"),
+                node.last_seen.format("%Y-%m-%d %H:%M:%S")
+            ))?;
+        }
+        for ((alias, host, port), attempts) in &reconnecting {
+            self.send_message(format!(
+                "{}  {}:{}  reconnecting ({} failed attempts)",
+                alias, host, port, attempts
+            ))?;
+        }
+        self.send_message("End of Known Nodes Listing".to_string())
+    }
 
-        let tmp = tmp.trim();
-        let filename = Path::new(&tmp);
-        let mut f = match File::open(filename) {
-            Ok(f) => f,
-            Err(e) => {
-                println!("There was an error opening that file: {:?}", e);
-                return None;
+    /// Assemble a local file and run it on a cluster member, with `any` on the least loaded
+    /// one (here if there are none), or with `all` on every member. The task id is reported
+    /// right away; results are sent through the pipe when they arrive and kept for
+    /// `!cluster_results`
+    fn cluster_run(&mut self, args: &[&str]) -> Result<()> {
+        let (alias, path) = match args {
+            [alias, path] => (alias.to_string(), *path),
+            _ => {
+                return self.send_message("Usage: !cluster_run <alias|any|all> <path>".to_string())
             }
         };
-        let mut contents = String::new();
-        match f.read_to_string(&mut contents) {
-            Ok(_bytes_read) => Some(contents),
-            Err(e) => {
-                println!("there was an error reading that file: {:?}", e);
-                None
-            }
+        if !self.require_coordinator("!cluster_run")? {
+            return Ok(());
+        }
+        let (known, role, runners) = match self.vm().conn_manager.read() {
+            Ok(lock) => (
+                lock.has_client(&alias),
+                lock.role_of(&alias),
+                lock.program_runners().len(),
+            ),
+            Err(_) => (false, NodeRole::default(), 0),
+        };
+        if alias == "all" && runners == 0 {
+            return self.send_error(NO_MEMBERS.to_string());
+        }
+        if !known && alias != "any" && alias != "all" {
+            return self.send_error(format!("No cluster member named {}", alias));
         }
+        if let Some(reason) = role.program_refusal(&alias).filter(|_| known) {
+            return self.send_error(reason);
+        }
+        let contents = match self.get_data_from_load(&[path])? {
+            Some(contents) => contents,
+            None => return Ok(()),
+        };
+        let program = match Assembler::new().assemble(&contents) {
+            Ok(program) => program,
+            Err(IridiumError::Assemble(errors)) => {
+                for error in errors {
+                    self.send_error(format!("Unable to assemble {}: {}", path, error))?;
+                }
+                return Ok(());
+            }
+            Err(e @ IridiumError::Parse(_)) => {
+                return self.send_error(format!("Unable to assemble {}: {}", path, e))
+            }
+            Err(e) => return Err(e),
+        };
+
+        if alias == "all" {
+            return self.run_everywhere(program);
+        }
+        let alias = match alias.as_str() {
+            "any" => self.least_loaded_member(),
+            _ => Some(alias),
+        };
+        let alias = match alias {
+            Some(alias) => alias,
+            None => {
+                let (task_id, result) = self.run_here(program)?;
+                return self.await_task(task_id, LOCAL_NODE.to_string(), result);
+            }
+        };
+        let submitted = {
+            let vm = self.vm();
+            let mut manager = vm.conn_manager.write().unwrap_or_else(|e| e.into_inner());
+            match manager.get_client_mut(&alias) {
+                Some(client) => client.submit(program),
+                None => Err(IridiumError::StringError("it left the cluster".to_string())),
+            }
+        };
+        let (task_id, result) = match submitted {
+            Ok(submitted) => submitted,
+            Err(e) => {
+                return self.send_error(format!("Could not reach cluster member {}: {}", alias, e))
+            }
+        };
+        self.await_task(task_id, alias, result)
+    }
+
+    /// Send a program to every cluster member under one task id, collecting their results as
+    /// a job. Members that don't answer in time are marked failed, and the job is announced
+    /// once none are left pending
+    fn run_everywhere(&mut self, program: Vec<u8>) -> Result<()> {
+        let task_id = Uuid::new_v4();
+        let submitted: Vec<(NodeAlias, Result<Receiver<TaskResult>>)> = {
+            let vm = self.vm();
+            let mut manager = vm.conn_manager.write().unwrap_or_else(|e| e.into_inner());
+            manager
+                .program_runners()
+                .into_iter()
+                .map(|alias| {
+                    let result = match manager.get_client_mut(&alias) {
+                        Some(client) => client.submit_as(task_id, program.clone()),
+                        None => Err(IridiumError::StringError("it left the cluster".to_string())),
+                    };
+                    (alias, result)
+                })
+                .collect()
+        };
+        if submitted.is_empty() {
+            return self.send_error(NO_MEMBERS.to_string());
+        }
+        let nodes: Vec<NodeAlias> = submitted.iter().map(|(alias, _)| alias.clone()).collect();
+        self.cluster_jobs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .start(task_id, nodes.clone());
+        if self.format == OutputFormat::Json {
+            self.send_json(json!({ "task_id": task_id, "nodes": nodes }))?;
+        } else {
+            self.send_message(format!(
+                "Submitted task {} to {} members",
+                task_id,
+                nodes.len()
+            ))?;
+        }
+
+        let timeout = self.cluster_task_timeout;
+        for (alias, submission) in submitted {
+            let jobs = self.cluster_jobs.clone();
+            let notifier = self.notifier();
+            thread::spawn(move || {
+                let outcome = submission
+                    .map_err(|e| format!("could not reach it: {}", e))
+                    .and_then(|result| match result.recv_timeout(timeout) {
+                        Ok(result) => Ok(result),
+                        Err(RecvTimeoutError::Timeout) => {
+                            Err(format!("timed out after {:?}", timeout))
+                        }
+                        Err(RecvTimeoutError::Disconnected) => {
+                            Err("lost connection before it finished".to_string())
+                        }
+                    });
+                let mut jobs = jobs.lock().unwrap_or_else(|e| e.into_inner());
+                let completed = match outcome {
+                    Ok(result) => jobs.finish(&task_id, &alias, result),
+                    Err(reason) => jobs.fail(&task_id, &alias, reason),
+                };
+                let job = match jobs.get(&task_id) {
+                    Some(job) if completed => job.clone(),
+                    _ => return,
+                };
+                drop(jobs);
+                notifier.output(
+                    format!(
+                        "Task {} finished on {} of {} members",
+                        task_id,
+                        job.finished(),
+                        job.nodes.len()
+                    ),
+                    json!({ "cluster_job": job }),
+                );
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether this node's role lets it direct the cluster with `command`, reporting why not
+    /// when it doesn't
+    fn require_coordinator(&self, command: &str) -> Result<bool> {
+        let role = self.vm().role();
+        if role.coordinates() {
+            return Ok(true);
+        }
+        self.send_error(format!(
+            "This node is a {}, {} needs the coordinator role",
+            role, command
+        ))?;
+        Ok(false)
+    }
+
+    /// Pick the cluster member running the fewest programs, asking every member for its load
+    /// first. None without any members
+    fn least_loaded_member(&self) -> Option<NodeAlias> {
+        let manager = self.vm().conn_manager.clone();
+        let members = manager.read().map(|lock| lock.get_client_names());
+        Manager::ping(&manager, &members.unwrap_or_default(), self.ping_timeout);
+        let lock = manager.read().unwrap_or_else(|e| e.into_inner());
+        lock.pick_least_loaded()
+    }
+
+    /// Run a program on this node, as a task like those sent to cluster members
+    fn run_here(&self, program: Vec<u8>) -> Result<(Uuid, Receiver<TaskResult>)> {
+        let task = self.scheduler.execute(program)?;
+        let task_id = Uuid::new_v4();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            // a crashed task drops the sender, which reads as the task being lost
+            if let Ok(vm) = task.recv() {
+                let _ = tx.send(TaskResult {
+                    task_id,
+                    events: vm.events().to_vec(),
+                    registers: vm.registers,
+                });
+            }
+        });
+        Ok((task_id, rx))
+    }
+
+    /// Report a task submitted to `alias`, then its result once it arrives on `result`
+    fn await_task(
+        &mut self,
+        task_id: Uuid,
+        alias: NodeAlias,
+        result: Receiver<TaskResult>,
+    ) -> Result<()> {
+        if self.format == OutputFormat::Json {
+            self.send_json(json!({ "task_id": task_id, "node": alias }))?;
+        } else {
+            self.send_message(format!("Submitted task {} to {}", task_id, alias))?;
+        }
+
+        let notifier = self.notifier();
+        let results = self.cluster_results.clone();
+        let timeout = self.cluster_task_timeout;
+        thread::spawn(move || match result.recv_timeout(timeout) {
+            Ok(result) => {
+                let text = format!(
+                    "Task {} on {} finished: registers {:?}",
+                    task_id, alias, result.registers
+                );
+                let value = json!({ "cluster_result": { "node": alias, "result": result } });
+                // stored first, so the result can be looked up as soon as it is announced
+                results
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(task_id, (alias, result));
+                notifier.output(text, value);
+            }
+            Err(RecvTimeoutError::Timeout) => notifier.error(format!(
+                "Task {} on {} timed out after {:?}",
+                task_id, alias, timeout
+            )),
+            Err(RecvTimeoutError::Disconnected) => notifier.error(format!(
+                "Lost connection to {} before task {} finished",
+                alias, task_id
+            )),
+        });
+
+        Ok(())
+    }
+
+    /// List finished `!cluster_run` tasks and jobs run on every member, or show the result
+    /// of one
+    fn cluster_results(&mut self, args: &[&str]) -> Result<()> {
+        let results = self.cluster_results.clone();
+        let results = results.lock().unwrap_or_else(|e| e.into_inner());
+        let jobs = self.cluster_jobs.clone();
+        let jobs = jobs.lock().unwrap_or_else(|e| e.into_inner());
+        let task_id = match args {
+            [] => {
+                let tasks: Vec<String> = results
+                    .keys()
+                    .chain(jobs.all().iter().map(|job| &job.task_id))
+                    .map(|id| id.to_string())
+                    .collect();
+                if self.format == OutputFormat::Json {
+                    return self.send_json(json!({ "cluster_results": tasks }));
+                }
+                self.send_message("Listing finished cluster tasks:".to_string())?;
+                for (task_id, (alias, _)) in results.iter() {
+                    self.send_message(format!("{}  on {}", task_id, alias))?;
+                }
+                for job in jobs.all() {
+                    self.send_message(format!(
+                        "{}  on {} members, {} finished",
+                        job.task_id,
+                        job.nodes.len(),
+                        job.finished()
+                    ))?;
+                }
+                return self.send_message("End of Cluster Task Listing".to_string());
+            }
+            [task_id] => match task_id.parse::<Uuid>() {
+                Ok(task_id) => task_id,
+                Err(_) => return self.send_error(format!("Invalid task id: {}", task_id)),
+            },
+            _ => return self.send_message("Usage: !cluster_results [task_id]".to_string()),
+        };
+        if let Some(job) = jobs.get(&task_id) {
+            return self.show_job(job);
+        }
+        let (alias, result) = match results.get(&task_id) {
+            Some(found) => found,
+            None => return self.send_error(format!("No result for task {} yet", task_id)),
+        };
+        if self.format == OutputFormat::Json {
+            return self
+                .send_json(json!({ "cluster_result": { "node": alias, "result": result } }));
+        }
+        self.send_message(format!("{:<11}{}", "task:", task_id))?;
+        self.send_message(format!("{:<11}{}", "node:", alias))?;
+        self.send_message(format!("{:<11}{}", "events:", result.events.len()))?;
+        self.send_message(format!("{:<11}{:?}", "registers:", result.registers))
+    }
+
+    /// Show how each member's run of a job's program turned out
+    fn show_job(&self, job: &Job) -> Result<()> {
+        if self.format == OutputFormat::Json {
+            return self.send_json(json!({ "cluster_job": job }));
+        }
+        self.send_message(format!("Results of task {}:", job.task_id))?;
+        for (alias, outcome) in &job.nodes {
+            self.send_message(format!("{:<16}{}", alias, outcome))?;
+        }
+        self.send_message("End of Task Results".to_string())
+    }
+
+    /// Show recent membership changes, or start or stop sending them as they happen. With
+    /// `log`, show the VM events members reported to this node as their coordinator
+    fn cluster_events(&mut self, args: &[&str]) -> Result<()> {
+        match args {
+            [] => {
+                let history = match self.vm().conn_manager.read() {
+                    Ok(lock) => lock.membership_history(),
+                    Err(_) => vec![],
+                };
+                if self.format == OutputFormat::Json {
+                    return self.send_json(json!({ "cluster_events": history }));
+                }
+                self.send_message("Listing cluster membership changes:".to_string())?;
+                for event in history {
+                    self.send_message(event.to_string())?;
+                }
+                self.send_message("End of Cluster Event Listing".to_string())
+            }
+            ["follow"] => {
+                if self.following_cluster.is_some() {
+                    return self.send_message("Already following cluster events".to_string());
+                }
+                let events = {
+                    let vm = self.vm();
+                    let mut manager = vm.conn_manager.write().unwrap_or_else(|e| e.into_inner());
+                    manager.subscribe_membership()
+                };
+                let notifier = self.notifier();
+                let following = Arc::new(AtomicBool::new(true));
+                self.following_cluster = Some(following.clone());
+                thread::spawn(move || {
+                    for event in events {
+                        if !following.load(Ordering::SeqCst) {
+                            return;
+                        }
+                        let value = json!({ "cluster_event": event });
+                        notifier.output(event.to_string(), value);
+                    }
+                });
+                self.send_message("Following cluster events".to_string())
+            }
+            ["log"] => {
+                let reported = match self.vm().conn_manager.read() {
+                    Ok(lock) => lock.event_log(),
+                    Err(_) => vec![],
+                };
+                if self.format == OutputFormat::Json {
+                    return self.send_json(json!({ "cluster_event_log": reported }));
+                }
+                self.send_message("Listing VM events reported by cluster members:".to_string())?;
+                for event in reported {
+                    self.send_message(event.to_string())?;
+                }
+                self.send_message("End of VM Event Listing".to_string())
+            }
+            ["stop"] => match self.following_cluster.take() {
+                Some(following) => {
+                    following.store(false, Ordering::SeqCst);
+                    self.send_message("Stopped following cluster events".to_string())
+                }
+                None => self.send_message("Not following cluster events".to_string()),
+            },
+            _ => self.send_message("Usage: !cluster_events [follow|stop|log]".to_string()),
+        }
+    }
+
+    /// Check right away that a cluster member, or every member, answers, and how quickly
+    fn ping(&mut self, args: &[&str]) -> Result<()> {
+        let manager = self.vm().conn_manager.clone();
+        let aliases = match args {
+            ["all"] => {
+                let mut aliases = manager
+                    .read()
+                    .map(|lock| lock.get_client_names())
+                    .unwrap_or_default();
+                aliases.sort();
+                aliases
+            }
+            [alias] => vec![alias.to_string()],
+            _ => return self.send_message("Usage: !ping <alias|all>".to_string()),
+        };
+        if aliases.is_empty() {
+            return self.send_message("No cluster members to ping".to_string());
+        }
+        let results = Manager::ping(&manager, &aliases, self.ping_timeout);
+        let millis = |rtt: &Duration| rtt.as_secs_f64() * 1000.0;
+        if args != ["all"] {
+            let (alias, rtt) = &results[0];
+            return match rtt {
+                Ok(rtt) if self.format == OutputFormat::Json => {
+                    self.send_json(json!({ "ping": { "alias": alias, "rtt_ms": millis(rtt) } }))
+                }
+                Ok(rtt) => {
+                    self.send_message(format!("Pong from {} in {:.2} ms", alias, millis(rtt)))
+                }
+                Err(e) => self.send_error(e.to_string()),
+            };
+        }
+        if self.format == OutputFormat::Json {
+            let pings: Vec<Value> = results
+                .iter()
+                .map(|(alias, rtt)| match rtt {
+                    Ok(rtt) => json!({ "alias": alias, "rtt_ms": millis(rtt) }),
+                    Err(e) => json!({ "alias": alias, "error": e.to_string() }),
+                })
+                .collect();
+            return self.send_json(json!({ "ping": pings }));
+        }
+        self.send_message(format!("Pinged {} members:", results.len()))?;
+        for (alias, rtt) in &results {
+            let answer = match rtt {
+                Ok(rtt) => format!("{:.2} ms", millis(rtt)),
+                Err(e) => e.to_string(),
+            };
+            self.send_message(format!("{:<16}{}", alias, answer))?;
+        }
+        self.send_message("End of Ping Listing".to_string())
+    }
+
+    /// Show what a cluster member's VM is up to, like `!status` and `!registers` do here
+    fn cluster_inspect(&mut self, args: &[&str]) -> Result<()> {
+        let alias = match args {
+            [alias] => *alias,
+            _ => return self.send_message("Usage: !cluster_inspect <alias>".to_string()),
+        };
+        if !self.require_coordinator("!cluster_inspect")? {
+            return Ok(());
+        }
+        let manager = self.vm().conn_manager.clone();
+        let status = match Manager::inspect(&manager, alias, INSPECT_TIMEOUT) {
+            Ok(status) => status,
+            Err(e) => return self.send_error(e.to_string()),
+        };
+        if self.format == OutputFormat::Json {
+            return self
+                .send_json(json!({ "cluster_inspect": { "node": alias, "status": status } }));
+        }
+        let prefix = format!("[{}]", alias);
+        let lines = [
+            ("alias", status.alias.unwrap_or_default()),
+            ("pc", status.pc.to_string()),
+            ("program length", status.program_length.to_string()),
+            ("registers", format!("{:?}", status.registers)),
+        ];
+        for (key, value) in lines {
+            self.send_message(format!("{} {:<17}{}", prefix, format!("{}:", key), value))?;
+        }
+        for event in &status.events {
+            self.send_message(format!(
+                "{} {:<17}{:?} at {} (app {})",
+                prefix,
+                "event:",
+                event.event,
+                event.at.format("%Y-%m-%d %H:%M:%S"),
+                event.app_id
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    fn status(&mut self, _args: &[&str]) -> Result<()> {
+        let vm = self.vm();
+        let peer_bind = match (vm.peer_host(), &vm.peer_port) {
+            (Some(host), Some(port)) => address::join_host_port(host, port),
+            _ => "not configured".to_string(),
+        };
+        let cluster_clients = match vm.conn_manager.read() {
+            Ok(lock) => lock.client_count(),
+            Err(_) => 0,
+        };
+        let cluster_server = if vm.is_cluster_listening() {
+            "listening"
+        } else {
+            "stopped"
+        };
+        if self.format == OutputFormat::Json {
+            return self.send_json(json!({
+                "status": {
+                    "id": vm.id(),
+                    "alias": vm.alias,
+                    "pc": vm.pc(),
+                    "program_length": vm.program.len(),
+                    "heap_size": vm.heap_size(),
+                    "events": vm.events().len(),
+                    "logical_cores": vm.logical_cores,
+                    "role": vm.role(),
+                    "peer_bind": peer_bind,
+                    "cluster_server": cluster_server,
+                    "cluster_clients": cluster_clients,
+                }
+            }));
+        }
+        let status = [
+            ("id", vm.id().to_string()),
+            ("alias", vm.alias.clone().unwrap_or_default()),
+            ("pc", vm.pc().to_string()),
+            ("program length", vm.program.len().to_string()),
+            ("heap size", vm.heap_size().to_string()),
+            ("events", vm.events().len().to_string()),
+            ("logical cores", vm.logical_cores.to_string()),
+            ("role", vm.role().to_string()),
+            ("peer bind", peer_bind),
+            ("cluster server", cluster_server.to_string()),
+            ("cluster clients", cluster_clients.to_string()),
+        ];
+        for (key, value) in status {
+            self.send_message(format!("{:<17}{}", format!("{}:", key), value))?;
+        }
+
+        Ok(())
+    }
+
+    /// The events of this VM, or of a task started with `!spawn` once it stopped
+    fn events(&mut self, args: &[&str]) -> Result<()> {
+        let events = match args {
+            [] => self.vm().events().to_vec(),
+            [id] => match id.parse().ok().and_then(|id| self.scheduler.status(&id)) {
+                Some(task) if task.state.is_done() => task.events,
+                Some(task) => {
+                    return self.send_error(format!("Task {} is {:?}", id, task.state));
+                }
+                None => return self.send_error(format!("No task {}", id)),
+            },
+            _ => return self.send_message("Usage: !events [task_id]".to_string()),
+        };
+        if self.structured {
+            for event in events {
+                self.send_remote(&RemoteMessage::Event(event))?;
+            }
+            return Ok(());
+        }
+        if self.format == OutputFormat::Json {
+            return self.send_json(json!({ "events": events }));
+        }
+        self.send_message("Listing VM events:".to_string())?;
+        for event in events {
+            self.send_message(event.to_string())?;
+        }
+        self.send_message("End of Events Listing".to_string())?;
+
+        Ok(())
+    }
+
+    /// Sends a message, wrapped in a JSON object when the JSON output format is selected
+    pub fn send_message(&self, msg: String) -> Result<()> {
+        match self.format {
+            OutputFormat::Text if self.structured => self.send_json(Value::String(msg)),
+            OutputFormat::Text => self.send_line(msg),
+            OutputFormat::Json => self.send_json(json!({ "message": msg })),
+        }
+    }
+
+    /// Sends a message reporting that a command failed
+    pub fn send_error(&self, msg: String) -> Result<()> {
+        if self.structured {
+            return self.send_remote(&RemoteMessage::Error(msg));
+        }
+        self.send_message(msg)
+    }
+
+    /// Sends a command result as a single-line JSON object
+    fn send_json(&self, value: Value) -> Result<()> {
+        if self.structured {
+            return self.send_remote(&RemoteMessage::Output(value));
+        }
+        self.send_line(value.to_string())
+    }
+
+    fn send_remote(&self, msg: &RemoteMessage) -> Result<()> {
+        self.send_line(serde_json::to_string(msg)?)
+    }
+
+    fn send_line(&self, msg: String) -> Result<()> {
+        self.send_raw(msg + "\n")
+    }
+
+    pub fn send_prompt(&mut self) -> Result<()> {
+        if self.structured {
+            return self.send_remote(&RemoteMessage::Prompt(PROMPT.to_string()));
+        }
+        self.send_raw(PROMPT.to_owned())
+    }
+
+    /// Queue output on the pipe, applying the overflow policy when it is full. Output is
+    /// discarded once nobody reads the pipe any more, or when there is no pipe at all, so
+    /// losing the reader can't stop the REPL
+    fn send_raw(&self, msg: String) -> Result<()> {
+        let pipe = match &self.tx_pipe {
+            Some(pipe) => pipe,
+            None => {
+                if !self.pipe_closed.replace(true) {
+                    warn!("REPL has no output pipe, discarding output");
+                }
+                return Ok(());
+            }
+        };
+        let dropped = self.dropped.get();
+        if dropped > 0 {
+            let marker = format!("[{} messages dropped: output pipe full]\n", dropped);
+            if !self.queue(pipe, marker) {
+                self.dropped.set(dropped + 1);
+                return Ok(());
+            }
+            self.dropped.set(0);
+        }
+        if !self.queue(pipe, msg) {
+            self.dropped.set(self.dropped.get() + 1);
+        }
+
+        Ok(())
+    }
+
+    /// Put a message on the pipe, returning false if it was full for as long as the policy allows
+    fn queue(&self, pipe: &SyncSender<String>, mut msg: String) -> bool {
+        let deadline = match self.overflow {
+            OverflowPolicy::DropWithMarker => Instant::now(),
+            OverflowPolicy::Block(timeout) => Instant::now() + timeout,
+        };
+        loop {
+            match pipe.try_send(msg) {
+                Ok(()) => return true,
+                Err(TrySendError::Full(unsent)) if Instant::now() < deadline => {
+                    msg = unsent;
+                    thread::sleep(PIPE_RETRY_INTERVAL);
+                }
+                Err(TrySendError::Full(_)) => return false,
+                Err(TrySendError::Disconnected(_)) => {
+                    if !self.pipe_closed.replace(true) {
+                        warn!("REPL output pipe closed, discarding output");
+                    }
+                    return true;
+                }
+            }
+        }
+    }
+
+    /// Read the file named by the first argument. Local sessions are prompted for a path when
+    /// none is given; remote sessions have no terminal to prompt on, so the argument is required
+    fn get_data_from_load(&mut self, args: &[&str]) -> Result<Option<String>> {
+        let tmp = match args.first() {
+            Some(path) => path.to_string(),
+            None if self.remote => {
+                self.send_message("Please pass the path to the file you wish to load".to_string())?;
+                return Ok(None);
+            }
+            None => {
+                self.send_message(
+                    "Please enter the path to the file you wish to load: ".to_string(),
+                )?;
+                match self.prompted_line()? {
+                    Some(path) => path,
+                    None => return Ok(None),
+                }
+            }
+        };
+        self.send_message("Attempting to load program from file...".to_string())?;
+
+        let filename = match tmp.trim().strip_prefix(UPLOAD_PREFIX) {
+            Some(name) => match &self.upload_dir {
+                Some(dir) if upload::is_valid_name(name) => dir.join(name),
+                _ => {
+                    self.send_error(format!("No uploaded file named {}", name))?;
+                    return Ok(None);
+                }
+            },
+            None => PathBuf::from(tmp.trim()),
+        };
+        let mut f = match File::open(&filename) {
+            Ok(f) => f,
+            Err(e) => {
+                let context = format!("There was an error opening {}", filename.display());
+                self.send_report(&context, &e)?;
+                return Ok(None);
+            }
+        };
+        let mut contents = String::new();
+        match f.read_to_string(&mut contents) {
+            Ok(_bytes_read) => Ok(Some(contents)),
+            Err(e) => {
+                let context = format!("There was an error reading {}", filename.display());
+                self.send_report(&context, &e)?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Drop for REPL {
+    fn drop(&mut self) {
+        if let Some(following) = &self.following_cluster {
+            following.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+/// A duration like `200ms`, `30s`, `5m` or `1h`, in seconds when it has no unit
+fn parse_duration(text: &str) -> Option<Duration> {
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let secs = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return None,
+    };
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+/// Split the flags `!spawn` takes from the rest of its arguments. None if one is missing its
+/// value or has one that makes no sense
+fn spawn_options<'a>(args: &[&'a str]) -> Option<(SpawnOptions, Vec<&'a str>)> {
+    let mut options = SpawnOptions::default();
+    let mut rest = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--timeout" => {
+                let timeout = parse_duration(args.next()?).filter(|t| !t.is_zero())?;
+                options = options.with_timeout(timeout);
+            }
+            "--priority" => options = options.with_priority(args.next()?.parse().ok()?),
+            arg => rest.push(arg),
+        }
+    }
+    Some((options, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        net::{SocketAddr, TcpListener, TcpStream},
+    };
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::{
+        assembler::PIE_HEADER_LENGTH,
+        cluster::{cluster_client::ClusterClient, cluster_server::ClusterServer},
+        scheduler::{Priority, TaskState},
+        vm::VMEventType,
+    };
+
+    const TEST_PROGRAM: &str = ".data\nhello: .asciiz 'Hello'\n.code\nload $0 #100";
+
+    /// Collect every message the REPL has sent so far
+    fn drain(repl: &REPL) -> Vec<String> {
+        repl.rx_pipe.as_ref().unwrap().try_iter().collect()
+    }
+
+    fn temp_file(bytes: &[u8]) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(bytes).unwrap();
+        file
+    }
+
+    /// A client that connected to the cluster server at `addr` and said hello
+    fn hello_peer(addr: SocketAddr) -> ClusterClient {
+        let mut peer = ClusterClient::new(TcpStream::connect(addr).unwrap())
+            .unwrap()
+            .with_alias("repl".to_string());
+        peer.send_hello().unwrap();
+        peer.read().unwrap();
+        peer.read_hello_ack().unwrap();
+        peer
+    }
+
+    /// Register a client connected to `stream` under `alias` with the REPL's VM
+    fn add_peer(repl: &REPL, alias: &str, stream: TcpStream) {
+        repl.vm()
+            .conn_manager
+            .write()
+            .unwrap()
+            .add_client(alias.to_string(), ClusterClient::new(stream).unwrap());
+    }
+
+    #[test]
+    fn test_load_bytecode() {
+        let program = Assembler::new().assemble(TEST_PROGRAM).unwrap();
+        let file = temp_file(&program);
+        let mut repl = REPL::new(VM::new());
+
+        repl.run_single(&format!("!load_bytecode {}", file.path().display()))
+            .unwrap();
+
+        let output = drain(&repl).concat();
+        assert!(output.contains("entry offset 70"), "{}", output);
+        assert_eq!(repl.vm().ro_data(), b"Hello\0");
+        assert_eq!(repl.vm().registers[0], 100);
+    }
+
+    #[test]
+    fn test_bench() {
+        let file = temp_file(b".data\n.code\nload $0 #1\nload $1 #2\nadd $0 $1 $2");
+        let mut repl = REPL::new(VM::new());
+
+        repl.run_single(&format!("!bench {} 2", file.path().display()))
+            .unwrap();
+
+        let output = drain(&repl);
+        assert!(output[0].contains("2 iterations"), "{:?}", output);
+        for line in &output[1..] {
+            let value = line.split(':').nth(1).unwrap().trim();
+            let number = value.trim_end_matches(" ms");
+            assert!(number.parse::<f64>().is_ok(), "{}", line);
+        }
+        assert_eq!(output.len(), 5);
+    }
+
+    #[test]
+    fn test_run_script() {
+        let script = temp_file(b"# setup\nload $0 #42\n\n!clear_registers\nload $1 #7\n");
+        let mut repl = REPL::new(VM::new());
+
+        repl.run_script(script.path()).unwrap();
+
+        let output = drain(&repl).concat();
+        assert!(output.contains(">>> !clear_registers"), "{}", output);
+        assert_eq!(repl.vm().registers[0], 0);
+        assert_eq!(repl.vm().registers[1], 7);
+    }
+
+    #[test]
+    fn test_run_script_stops_on_error() {
+        let script = temp_file(b"load $0 #1\nload $1 ???\nload $2 #3\n");
+        let mut repl = REPL::new(VM::new());
+
+        repl.run_script(script.path()).unwrap();
+
+        let output = drain(&repl).concat();
+        assert!(
+            output.contains("failed at line 2: load $1 ???"),
+            "{}",
+            output
+        );
+        assert_eq!(repl.vm().registers[0], 1);
+        assert_eq!(repl.vm().registers[2], 0);
+
+        // a library that isn't there stops it as well
+        let script = temp_file(b"load $0 #1\n!load_file /no/such/library.iasm\nload $2 #3\n");
+        let mut repl = REPL::new(VM::new());
+        repl.run_script(script.path()).unwrap();
+        let output = drain(&repl).concat();
+        assert!(
+            output.contains("failed at line 2: !load_file /no/such/library.iasm"),
+            "{}",
+            output
+        );
+        assert_eq!(repl.vm().registers[2], 0);
+
+        // and so does a line that crashes the VM
+        let script = temp_file(b"load $0 #1\ndiv $0 $1 $2\nload $3 #3\n");
+        let mut repl = REPL::new(VM::new());
+        repl.run_script(script.path()).unwrap();
+        let output = drain(&repl).concat();
+        assert!(
+            output.contains("failed at line 2: div $0 $1 $2"),
+            "{}",
+            output
+        );
+        assert_eq!(repl.vm().registers[3], 0);
+    }
+
+    #[test]
+    fn test_syntax_error_reported() {
+        let mut repl = REPL::new(VM::new());
+        assert_eq!(repl.run_single("load $0 #x1").unwrap(), Flow::Invalid);
+        let output = drain(&repl).concat();
+        assert!(
+            output.starts_with("Unable to parse input: [IR009] Syntax error on line 1"),
+            "{}",
+            output
+        );
+        assert!(output.contains("`#x1`"), "{}", output);
+
+        let file = temp_file(b".data\n.code\nload $0 #1\nload $1 ???");
+        repl.run_single(&format!("!load_file {}", file.path().display()))
+            .unwrap();
+        let output = drain(&repl).concat();
+        assert!(output.contains("line 4, column 9"), "{}", output);
+        assert!(output.contains("`???`"), "{}", output);
+    }
+
+    #[test]
+    fn test_unknown_label_underlined() {
+        let mut repl = REPL::new(VM::new());
+        assert_eq!(repl.run_single("jmpe @nowhere").unwrap(), Flow::Invalid);
+        let output = drain(&repl).concat();
+        assert!(
+            output.contains("Unknown label nowhere\njmpe @nowhere\n     ^^^^^^^^"),
+            "{}",
+            output
+        );
+        assert!(repl.vm().program.is_empty());
+    }
+
+    #[test]
+    fn test_runtime_errors_reported() {
+        let mut repl = REPL::new(VM::new());
+        repl.run_single("load $0 #7").unwrap();
+        repl.run_single("div $0 $1 $2").unwrap();
+        let output = drain(&repl).concat();
+        assert!(
+            output.contains("Division by zero in DIV at byte 4"),
+            "{}",
+            output
+        );
+        assert_eq!(repl.vm().registers[0], 7);
+
+        repl.run_single("!clear_program").unwrap();
+        drain(&repl);
+        repl.load_source(".data\n.code\nload $0 #7\ndiv $0 $1 $2")
+            .unwrap();
+        assert_eq!(
+            drain(&repl),
+            [
+                "Sending assembled program to VM\n",
+                "error: Program crashed\n  caused by: [VM001] Division by zero in DIV at byte 68\n"
+            ]
+        );
+        assert!(repl.vm().error().is_some());
+    }
+
+    #[test]
+    fn test_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let vm = VM::new().with_alias(&"node1".to_string());
+        vm.conn_manager
+            .write()
+            .unwrap()
+            .add_client("peer".to_string(), ClusterClient::new(stream).unwrap());
+        let mut repl = REPL::new(vm);
+
+        repl.run_single("!status").unwrap();
+
+        let output = drain(&repl);
+        assert!(output.contains(&"alias:           node1\n".to_string()));
+        assert!(output.contains(&"cluster clients: 1\n".to_string()));
+    }
+
+    #[test]
+    fn test_clear_program_resets_pc_and_ro_data() {
+        let mut repl = REPL::new(VM::new());
+        repl.load_source(".data\nhi: .asciiz 'Hi'\n.code\nload $0 #1\nload $1 #2")
+            .unwrap();
+        assert_eq!(repl.vm().registers[1], 2);
+
+        repl.run_single("!clear_program").unwrap();
+        assert!(drain(&repl).concat().contains("pc reset to 0"));
+        assert_eq!(repl.vm().pc(), 0);
+        assert!(repl.vm().program.is_empty());
+        assert!(repl.vm().ro_data().is_empty());
+
+        repl.load_source(".data\n.code\nload $2 #3").unwrap();
+        assert_eq!(repl.vm().registers[2], 3);
+        assert_eq!(repl.vm().pc(), PIE_HEADER_LENGTH + 4);
+    }
+
+    #[test]
+    fn test_json_format() {
+        let mut repl = REPL::new(VM::get_test_vm());
+
+        repl.run_single("!format json").unwrap();
+        repl.run_single("!registers").unwrap();
+        let output = drain(&repl);
+        let result: Value = serde_json::from_str(output.last().unwrap()).unwrap();
+        let registers: Vec<i32> = serde_json::from_value(result["registers"].clone()).unwrap();
+        assert_eq!(registers, repl.vm().registers.to_vec());
+
+        repl.run_single("!clear_registers").unwrap();
+        for line in drain(&repl) {
+            let message: Value = serde_json::from_str(&line).unwrap();
+            assert!(message["message"].is_string());
+        }
+
+        repl.run_single("!format text").unwrap();
+        repl.run_single("!registers").unwrap();
+        let output = drain(&repl);
+        assert_eq!(output[1], "Listing registers and all contents:\n");
+    }
+
+    #[test]
+    fn test_load_bytecode_rejects_corrupt_file() {
+        let mut program = Assembler::new().assemble(TEST_PROGRAM).unwrap();
+        let truncated = temp_file(&program[..32]);
+        program[0] = 0;
+        let corrupt = temp_file(&program);
+        let mut repl = REPL::new(VM::new());
+
+        repl.run_single(&format!("!load_bytecode {}", corrupt.path().display()))
+            .unwrap();
+        let output = drain(&repl).concat();
+        assert!(
+            output.contains("[VM009] Bytecode starts with 00 32 31 2d"),
+            "{}",
+            output
+        );
+
+        repl.run_single(&format!("!load_bytecode {}", truncated.path().display()))
+            .unwrap();
+        let output = drain(&repl).concat();
+        assert!(output.contains("[VM008] Bytecode is only 32"), "{}", output);
+        assert!(repl.vm().program.is_empty());
+
+        let source = temp_file(TEST_PROGRAM.as_bytes());
+        repl.run_single(&format!("!load_bytecode {}", source.path().display()))
+            .unwrap();
+        let output = drain(&repl).concat();
+        assert!(output.contains("looks like text"), "{}", output);
+    }
+
+    #[test]
+    fn test_cluster_run() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = ClusterServer::new("peer".to_string(), Arc::new(Default::default()));
+        thread::spawn(move || server.listen_on(listener));
+        let mut repl = REPL::new(VM::new()).with_cluster_task_timeout(Duration::from_secs(5));
+        let peer = hello_peer(addr);
+        repl.vm()
+            .conn_manager
+            .write()
+            .unwrap()
+            .add_client("peer".to_string(), peer);
+        let file = temp_file(TEST_PROGRAM.as_bytes());
+
+        repl.run_single(&format!("!cluster_run peer {}", file.path().display()))
+            .unwrap();
+        // the result may arrive before or after the submission has been read
+        let mut output = String::new();
+        while !output.contains("finished") {
+            let line = repl
+                .rx_pipe
+                .as_ref()
+                .unwrap()
+                .recv_timeout(Duration::from_secs(5));
+            output += &line.unwrap_or_else(|_| panic!("{}", output));
+        }
+        let task_id = output
+            .split("Submitted task ")
+            .nth(1)
+            .and_then(|rest| rest.split(' ').next())
+            .unwrap()
+            .to_string();
+        assert!(
+            output.contains(&format!(
+                "Task {} on peer finished: registers [100, ",
+                task_id
+            )),
+            "{}",
+            output
+        );
+        repl.run_single(&format!("!cluster_results {}", task_id))
+            .unwrap();
+        let output = drain(&repl);
+        assert!(
+            output.contains(&"node:      peer\n".to_string()),
+            "{:?}",
+            output
+        );
+    }
+
+    #[test]
+    fn test_cluster_run_errors() {
+        let mut repl = REPL::new(VM::new()).with_cluster_task_timeout(Duration::from_millis(50));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // a peer that never answers, and one whose connection is already closed
+        add_peer(&repl, "silent", TcpStream::connect(addr).unwrap());
+        let _silent = listener.accept().unwrap();
+        let gone = TcpStream::connect(addr).unwrap();
+        gone.shutdown(std::net::Shutdown::Both).unwrap();
+        add_peer(&repl, "gone", gone);
+        let file = temp_file(TEST_PROGRAM.as_bytes());
+        let invalid = temp_file(b"load $0 #1");
+
+        let mut run = |alias: &str, file: &NamedTempFile| {
+            repl.run_single(&format!("!cluster_run {} {}", alias, file.path().display()))
+                .unwrap();
+            drain(&repl).concat()
+        };
+        assert!(run("ghost", &file).contains("No cluster member named ghost"));
+        assert!(run("silent", &invalid).contains("Unable to assemble"));
+        assert!(run("gone", &file).contains("Could not reach cluster member gone"));
+        assert!(run("silent", &file).contains("Submitted task"));
+        let timed_out = repl
+            .rx_pipe
+            .as_ref()
+            .unwrap()
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+        assert!(timed_out.contains("timed out"), "{}", timed_out);
+    }
+
+    #[test]
+    fn test_full_pipe_drops_with_marker() {
+        let repl = REPL::new(VM::new()).with_output_pipe(2, OverflowPolicy::DropWithMarker);
+        for i in 0..5 {
+            repl.send_message(format!("message {}", i)).unwrap();
+        }
+        assert_eq!(drain(&repl), ["message 0\n", "message 1\n"]);
+
+        repl.send_message("after".to_string()).unwrap();
+        assert_eq!(
+            drain(&repl),
+            ["[3 messages dropped: output pipe full]\n", "after\n"]
+        );
+    }
+
+    #[test]
+    fn test_full_pipe_blocks_until_drained() {
+        let mut repl =
+            REPL::new(VM::new()).with_output_pipe(1, OverflowPolicy::Block(Duration::from_secs(5)));
+        let rx = repl.rx_pipe.take().unwrap();
+        repl.send_message("first".to_string()).unwrap();
+        let reader = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            rx.iter().take(2).collect::<Vec<_>>()
+        });
+
+        repl.send_message("second".to_string()).unwrap();
+        assert_eq!(reader.join().unwrap(), ["first\n", "second\n"]);
+    }
+
+    #[test]
+    fn test_full_pipe_block_times_out() {
+        let repl = REPL::new(VM::new())
+            .with_output_pipe(1, OverflowPolicy::Block(Duration::from_millis(20)));
+        repl.send_message("first".to_string()).unwrap();
+        let started = Instant::now();
+        repl.send_message("second".to_string()).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        assert_eq!(drain(&repl), ["first\n"]);
+    }
+
+    #[test]
+    fn test_send_without_reader_continues() {
+        let mut repl = REPL::new(VM::new());
+        drop(repl.rx_pipe.take());
+
+        repl.send_message("nobody is listening".to_string())
+            .unwrap();
+        repl.run_single("!clear_registers").unwrap();
+    }
+
+    #[test]
+    fn test_commands_run_after_output_lost() {
+        let mut repl = REPL::new(VM::new());
+        repl.run_single("load $0 #5").unwrap();
+        assert_eq!(drain(&repl), Vec::<String>::new());
+
+        // the reader goes away mid-session
+        drop(repl.rx_pipe.take());
+        assert_eq!(repl.run_single("load $1 #6").unwrap(), Flow::Continue);
+        repl.run_single("!registers").unwrap();
+        assert_eq!(repl.run_single("load $1 ???").unwrap(), Flow::Invalid);
+        repl.send_prompt().unwrap();
+
+        // and then the pipe itself
+        repl.tx_pipe = None;
+        repl.run_single("load $2 #7").unwrap();
+        repl.run_single("!clear_program").unwrap();
+        repl.send_error("nobody hears this".to_string()).unwrap();
+        let registers = repl.vm().registers;
+        assert_eq!(registers[..3], [5, 6, 7]);
+        assert!(repl.vm().program.is_empty());
+    }
+
+    #[test]
+    fn test_run_lines_until_interrupted() {
+        let interrupt = Arc::new(AtomicBool::new(false));
+        let mut repl = REPL::new(VM::new()).with_interrupt(interrupt.clone());
+        let (tx, lines) = mpsc::channel();
+        tx.send("load $0 #7\n".to_string()).unwrap();
+        let signal = {
+            let interrupt = interrupt.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                interrupt.store(true, Ordering::SeqCst);
+                // still connected, so only the interrupt ends the loop
+                tx
+            })
+        };
+        repl.run_lines(lines).unwrap();
+        drop(signal.join().unwrap());
+        assert_eq!(repl.vm().registers[0], 7);
+        let output = drain(&repl);
+        assert_eq!(output.last().unwrap(), "Interrupted, shutting down\n");
+
+        // the end of input ends it too
+        let mut repl = REPL::new(VM::new());
+        let (tx, lines) = mpsc::channel();
+        tx.send("load $0 #8\n".to_string()).unwrap();
+        drop(tx);
+        repl.run_lines(lines).unwrap();
+        assert_eq!(repl.vm().registers[0], 8);
+    }
+
+    #[test]
+    fn test_run_lines_prompts_for_load_path() {
+        let file = temp_file(b".data\n.code\nload $0 #9\nhlt\n");
+        let mut repl = REPL::new(VM::new());
+        let (tx, lines) = mpsc::channel();
+        tx.send("!load_file\n".to_string()).unwrap();
+        tx.send(format!("{}\n", file.path().display())).unwrap();
+        drop(tx);
+        repl.run_lines(lines).unwrap();
+        assert_eq!(repl.vm().registers[0], 9);
+        let output = drain(&repl);
+        assert!(
+            !output.iter().any(|line| line.contains("Unable to parse")),
+            "{:?}",
+            output
+        );
+    }
+
+    #[test]
+    fn test_history_file() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let path = history_file(&data_dir.path().join("node"));
+        let mut repl = REPL::new(VM::new()).with_history_file(path.clone());
+        repl.remember("load $0 #5\n").unwrap();
+        repl.remember("\n").unwrap();
+        repl.remember("!registers\n").unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "load $0 #5\n!registers\n"
+        );
+
+        // a restarted REPL picks up where the last one left off
+        let mut repl = REPL::new(VM::new()).with_history_file(path.clone());
+        repl.remember("!history\n").unwrap();
+        assert_eq!(
+            repl.command_buffer,
+            ["load $0 #5\n", "!registers\n", "!history\n"]
+        );
+
+        // a directory in the way of the file is reported once, and the history kept in memory
+        let mut repl = REPL::new(VM::new()).with_history_file(data_dir.path().to_path_buf());
+        repl.remember("hlt\n").unwrap();
+        repl.remember("hlt\n").unwrap();
+        let output = drain(&repl);
+        assert_eq!(output.len(), 1);
+        assert!(
+            output[0].starts_with("error: Unable to save history to"),
+            "{:?}",
+            output
+        );
+        assert_eq!(repl.command_buffer.len(), 2);
+    }
+
+    #[test]
+    fn test_cluster_run_any() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = ClusterServer::new("peer".to_string(), Arc::new(Default::default()));
+        thread::spawn(move || server.listen_on(listener));
+        let mut repl = REPL::new(VM::new()).with_cluster_task_timeout(Duration::from_secs(5));
+        let file = temp_file(TEST_PROGRAM.as_bytes());
+        let run_any = |repl: &mut REPL| {
+            repl.run_single(&format!("!cluster_run any {}", file.path().display()))
+                .unwrap();
+            let mut output = String::new();
+            while !output.contains("finished") {
+                let line = repl
+                    .rx_pipe
+                    .as_ref()
+                    .unwrap()
+                    .recv_timeout(Duration::from_secs(5));
+                output += &line.unwrap_or_else(|_| panic!("{}", output));
+            }
+            output
+        };
+
+        // with nobody to send it to, the program runs here
+        let output = run_any(&mut repl);
+        assert!(
+            output.contains(" on local finished: registers [100, "),
+            "{}",
+            output
+        );
+
+        let peer = hello_peer(addr);
+        repl.vm()
+            .conn_manager
+            .write()
+            .unwrap()
+            .add_client("peer".to_string(), peer);
+        let output = run_any(&mut repl);
+        assert!(
+            output.contains(" on peer finished: registers [100, "),
+            "{}",
+            output
+        );
+        // the peer's pong said how busy it was
+        assert_eq!(
+            repl.vm().conn_manager.read().unwrap().get_load("peer"),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn test_ping() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = ClusterServer::new("peer".to_string(), Arc::new(Default::default()));
+        thread::spawn(move || server.listen_on(listener));
+        // accepts the connection but never answers
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut repl = REPL::new(VM::new()).with_ping_timeout(Duration::from_millis(200));
+        let peer = hello_peer(addr);
+        add_peer(
+            &repl,
+            "silent",
+            TcpStream::connect(silent.local_addr().unwrap()).unwrap(),
+        );
+        repl.vm()
+            .conn_manager
+            .write()
+            .unwrap()
+            .add_client("peer".to_string(), peer);
+
+        repl.run_single("!ping peer").unwrap();
+        let output = drain(&repl).concat();
+        let rtt: f64 = output
+            .strip_prefix("Pong from peer in ")
+            .and_then(|rest| rest.strip_suffix(" ms\n"))
+            .unwrap_or_else(|| panic!("{}", output))
+            .parse()
+            .unwrap();
+        assert!(rtt < 1000.0, "{}", output);
+
+        repl.run_single("!ping silent").unwrap();
+        let output = drain(&repl).concat();
+        assert!(
+            output.contains("silent did not answer within 200 ms"),
+            "{}",
+            output
+        );
+        repl.run_single("!ping nobody").unwrap();
+        let output = drain(&repl).concat();
+        assert!(
+            output.contains("No cluster member named nobody"),
+            "{}",
+            output
+        );
+
+        repl.run_single("!ping all").unwrap();
+        let output = drain(&repl);
+        assert!(output[1].starts_with("peer ") && output[1].ends_with(" ms\n"));
+        assert!(output[2].contains("did not answer"), "{:?}", output);
+    }
+
+    #[test]
+    fn test_cluster_inspect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = REPL::new(VM::new());
+        remote.vm().registers[3] = 42;
+        remote.vm().registers[31] = -7;
+        let mut server = ClusterServer::new("remote".to_string(), remote.vm().conn_manager.clone());
+        thread::spawn(move || server.listen_on(listener));
+        let mut repl = REPL::new(VM::new());
+        let peer = hello_peer(addr);
+        repl.vm()
+            .conn_manager
+            .write()
+            .unwrap()
+            .add_client("remote".to_string(), peer);
+
+        repl.run_single("!format json").unwrap();
+        drain(&repl);
+        repl.run_single("!cluster_inspect remote").unwrap();
+        let output: Value = serde_json::from_str(&drain(&repl).concat()).unwrap();
+        let status = &output["cluster_inspect"]["status"];
+        assert_eq!(output["cluster_inspect"]["node"], "remote");
+        assert_eq!(status["registers"], json!(remote.vm().registers));
+        assert_eq!(status["pc"], 0);
+
+        // a VM held for longer than the member is willing to wait can't be inspected
+        let held = remote.vm();
+        repl.run_single("!format text").unwrap();
+        drain(&repl);
+        repl.run_single("!cluster_inspect remote").unwrap();
+        drop(held);
+        let output = drain(&repl).concat();
+        assert!(
+            output.contains("remote could not be inspected: VM busy"),
+            "{}",
+            output
+        );
+
+        repl.run_single("!cluster_inspect remote").unwrap();
+        let output = drain(&repl);
+        assert!(
+            output.contains(&format!("[remote] {:<17}0\n", "pc:")),
+            "{:?}",
+            output
+        );
+    }
+
+    #[test]
+    fn test_cluster_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut repl = REPL::new(VM::new());
+        repl.run_single("!cluster_events follow").unwrap();
+        assert!(drain(&repl).concat().contains("Following cluster events"));
+
+        add_peer(&repl, "fake", TcpStream::connect(addr).unwrap());
+        repl.vm()
+            .conn_manager
+            .write()
+            .unwrap()
+            .del_client("fake".to_string());
+        let pipe = repl.rx_pipe.as_ref().unwrap();
+        let joined = pipe.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(joined.ends_with("fake joined\n"), "{}", joined);
+        let left = pipe.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(left.ends_with("fake left\n"), "{}", left);
+
+        repl.run_single("!cluster_events stop").unwrap();
+        repl.run_single("!cluster_events").unwrap();
+        let output = drain(&repl).concat();
+        let joined = output.find("fake joined").unwrap();
+        assert!(output[joined..].contains("fake left"), "{}", output);
+    }
+
+    #[test]
+    fn test_cluster_commands_without_configuration() {
+        let mut repl = REPL::new(VM::new());
+        repl.run_single("!start_cluster").unwrap();
+        let output = drain(&repl).concat();
+        assert!(output.contains("No alias set, using "), "{}", output);
+        assert!(
+            output.contains("pass --peer-host/--peer-port"),
+            "{}",
+            output
+        );
+        assert!(repl.vm().alias.is_some());
+
+        repl.run_single("!join_cluster").unwrap();
+        assert_eq!(drain(&repl), ["Usage: !join_cluster <host> <port>\n"]);
+        repl.run_single("!join_cluster 127.0.0.1 1").unwrap();
+        let output = drain(&repl).concat();
+        assert!(
+            output.contains("pass --peer-host/--peer-port"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn test_cluster_run_all() {
+        let mut repl = REPL::new(VM::new()).with_cluster_task_timeout(Duration::from_secs(5));
+        let file = temp_file(TEST_PROGRAM.as_bytes());
+        let run_all = format!("!cluster_run all {}", file.path().display());
+        repl.run_single(&run_all).unwrap();
+        assert_eq!(drain(&repl), ["No cluster members to run the program on\n"]);
+        for alias in ["first", "second"] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut server = ClusterServer::new(alias.to_string(), Arc::new(Default::default()));
+            thread::spawn(move || server.listen_on(listener));
+            let peer = hello_peer(addr);
+            repl.vm()
+                .conn_manager
+                .write()
+                .unwrap()
+                .add_client(alias.to_string(), peer);
+        }
+
+        repl.run_single(&run_all).unwrap();
+        let mut output = String::new();
+        while !output.contains("finished on") {
+            let line = repl
+                .rx_pipe
+                .as_ref()
+                .unwrap()
+                .recv_timeout(Duration::from_secs(5));
+            output += &line.unwrap_or_else(|_| panic!("{}", output));
+        }
+        let task_id = output
+            .split("Submitted task ")
+            .nth(1)
+            .and_then(|rest| rest.split(' ').next())
+            .unwrap_or_else(|| panic!("{}", output))
+            .to_string();
+        assert!(output.contains(" to 2 members\n"), "{}", output);
+        assert!(
+            output.ends_with(&format!("Task {} finished on 2 of 2 members\n", task_id)),
+            "{}",
+            output
+        );
+
+        repl.run_single(&format!("!cluster_results {}", task_id))
+            .unwrap();
+        let output = drain(&repl);
+        assert_eq!(output.len(), 4, "{:?}", output);
+        assert_eq!(output[0], format!("Results of task {}:\n", task_id));
+        for (line, alias) in output[1..3].iter().zip(["first", "second"]) {
+            assert!(line.starts_with(&format!("{:<16}ok ", alias)), "{}", line);
+            assert!(line.contains("$0=100"), "{}", line);
+        }
+        assert_eq!(output[3], "End of Task Results\n");
+    }
+
+    #[test]
+    fn test_worker_refuses_cluster_commands() {
+        let mut worker = REPL::new(VM::new().with_role(NodeRole::Worker));
+        for command in ["!cluster_run any prog.iasm", "!cluster_inspect peer"] {
+            worker.run_single(command).unwrap();
+            let output = drain(&worker).concat();
+            assert!(
+                output.starts_with("This node is a worker, !cluster_"),
+                "{}",
+                output
+            );
+        }
+        worker.run_single("!status").unwrap();
+        let output = drain(&worker);
+        assert!(output.contains(&format!("{:<17}worker\n", "role:")));
+    }
+
+    #[test]
+    fn test_spawn_reports_task_id() {
+        let mut repl = REPL::new(VM::new());
+        let file = temp_file(TEST_PROGRAM.as_bytes());
+        repl.run_single(&format!("!spawn {}", file.path().display()))
+            .unwrap();
+        let output = drain(&repl).concat();
+        let id = output
+            .split("Spawned task ")
+            .nth(1)
+            .and_then(|rest| rest.trim().parse::<Uuid>().ok())
+            .unwrap_or_else(|| panic!("no task id in {:?}", output));
+        let outcome = repl.scheduler.wait(&id, Duration::from_secs(5)).unwrap();
+        assert_eq!(outcome.events.last().unwrap().event, VMEventType::Stop);
+    }
+
+    #[test]
+    fn test_await_spawned_task() {
+        let mut repl = REPL::new(VM::new());
+        let file = temp_file(TEST_PROGRAM.as_bytes());
+        repl.run_single(&format!("!spawn {}", file.path().display()))
+            .unwrap();
+        let id = repl.last_spawned.unwrap();
+        // settled before draining, so its completion line doesn't land in what's awaited
+        repl.scheduler.wait(&id, Duration::from_secs(5)).unwrap();
+        drain(&repl);
+
+        repl.run_single(&format!("!await {} 5", id)).unwrap();
+        let awaited = drain(&repl).concat();
+        let outcome = repl.scheduler.wait(&id, Duration::ZERO).unwrap();
+        assert!(
+            awaited.contains(&format!("Task {} finished", id)),
+            "{}",
+            awaited
+        );
+        for event in &outcome.events {
+            assert!(
+                awaited.contains(&format!("{:?}", event.event)),
+                "{}",
+                awaited
+            );
+        }
+        assert!(
+            awaited.contains(&format!("$0={}", outcome.registers[0])),
+            "{}",
+            awaited
+        );
+
+        // with no id, the most recently spawned task
+        repl.run_single("!await").unwrap();
+        assert_eq!(drain(&repl).concat(), awaited);
+    }
+
+    #[test]
+    fn test_await_unknown_task() {
+        let mut repl = REPL::new(VM::new());
+        repl.run_single("!await").unwrap();
+        assert!(drain(&repl).concat().contains("No task spawned yet"));
+        let id = Uuid::new_v4();
+        repl.run_single(&format!("!await {}", id)).unwrap();
+        assert!(drain(&repl).concat().contains(&format!("No task {}", id)));
+        repl.run_single("!await nonsense").unwrap();
+        assert!(drain(&repl).concat().contains("Invalid task id nonsense"));
+    }
+
+    #[test]
+    fn test_tasks_lists_spawned() {
+        let mut repl = REPL::new(VM::new());
+        let file = temp_file(TEST_PROGRAM.as_bytes());
+        let path = file.path().display().to_string();
+        repl.run_single(&format!("!spawn {}", path)).unwrap();
+        let id = repl.last_spawned.unwrap();
+        repl.scheduler.wait(&id, Duration::from_secs(5)).unwrap();
+        drain(&repl);
+
+        repl.run_single("!tasks").unwrap();
+        let listed = drain(&repl).concat();
+        let line = listed
+            .lines()
+            .find(|line| line.starts_with(&id.to_string()))
+            .unwrap_or_else(|| panic!("{} not listed in {:?}", id, listed));
+        assert!(line.contains("Finished"), "{}", line);
+        assert!(line.ends_with(&path), "{}", line);
+
+        repl.run_single(&format!("!tasks {}", id)).unwrap();
+        assert!(drain(&repl).concat().contains(line));
+        repl.run_single("!format json").unwrap();
+        drain(&repl);
+        repl.run_single("!tasks").unwrap();
+        let json: Value = serde_json::from_str(drain(&repl).concat().trim()).unwrap();
+        assert_eq!(json["tasks"][0]["id"], id.to_string());
+        assert_eq!(json["tasks"][0]["state"], "Finished");
+    }
+
+    #[test]
+    fn test_kill_runaway_task() {
+        let mut repl = REPL::new(VM::new());
+        let file = temp_file(b".data\n.code\nload $1 #2\njmpb $1");
+        repl.run_single(&format!("!spawn {}", file.path().display()))
+            .unwrap();
+        let id = repl.last_spawned.unwrap();
+        drain(&repl);
+
+        repl.run_single(&format!("!kill {}", id)).unwrap();
+        assert!(drain(&repl)
+            .concat()
+            .contains(&format!("Cancelling task {}", id)));
+        repl.run_single(&format!("!await {} 5", id)).unwrap();
+        let awaited = drain(&repl).concat();
+        assert!(
+            awaited.contains(&format!("Task {} was cancelled", id)),
+            "{}",
+            awaited
+        );
+
+        repl.run_single(&format!("!kill {}", id)).unwrap();
+        assert!(drain(&repl).concat().contains("is not running"));
+    }
+
+    #[test]
+    fn test_spawn_with_timeout() {
+        let mut repl = REPL::new(VM::new());
+        let file = temp_file(b".data\n.code\nload $1 #2\njmpb $1");
+        let path = file.path().display().to_string();
+        repl.run_single(&format!("!spawn --timeout 0.2 {}", path))
+            .unwrap();
+        let id = repl.last_spawned.unwrap();
+        repl.run_single(&format!("!await {} 5", id)).unwrap();
+        let awaited = drain(&repl).concat();
+        assert!(
+            awaited.contains(&format!("Task {} timed out", id)),
+            "{}",
+            awaited
+        );
+        let task = repl.scheduler.status(&id).unwrap();
+        assert_eq!(task.state, TaskState::TimedOut);
+        assert_eq!(task.description, path);
+
+        repl.run_single(&format!("!spawn {} --timeout never", path))
+            .unwrap();
+        assert!(drain(&repl).concat().contains("Usage: !spawn"));
+        assert_eq!(repl.last_spawned, Some(id));
+    }
+
+    #[test]
+    fn test_quit_lets_spawned_tasks_finish() {
+        let mut repl = REPL::new(VM::new());
+        let file = temp_file(b".data\n.code\nload $1 #2\njmpb $1");
+        repl.run_single(&format!("!spawn --timeout 0.5 {}", file.path().display()))
+            .unwrap();
+        let id = repl.last_spawned.unwrap();
+        drain(&repl);
+
+        assert_eq!(repl.run_single("!quit").unwrap(), Flow::Quit);
+        let output = drain(&repl).concat();
+        assert!(output.contains("Waiting up to"), "{}", output);
+        assert!(
+            output.contains("Tasks at shutdown: 1 completed, 0 cancelled, 0 abandoned"),
+            "{}",
+            output
+        );
+        assert_eq!(
+            repl.scheduler.status(&id).unwrap().state,
+            TaskState::TimedOut
+        );
+        repl.shutdown().unwrap();
+        assert!(drain(&repl).is_empty());
+    }
+
+    #[test]
+    fn test_schedule_and_unschedule() {
+        let mut repl = REPL::new(VM::new());
+        let file = temp_file(TEST_PROGRAM.as_bytes());
+        let path = file.path().display().to_string();
+        repl.run_single(&format!("!schedule {} --every 20ms --max 3", path))
+            .unwrap();
+        let output = drain(&repl).concat();
+        let id = output
+            .split("Scheduled ")
+            .nth(1)
+            .and_then(|rest| rest.trim().parse::<Uuid>().ok())
+            .unwrap_or_else(|| panic!("no schedule id in {:?}", output));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while repl.scheduler.scheduled(&id).unwrap().active && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(repl.scheduler.scheduled(&id).unwrap().runs.len(), 3);
+
+        repl.run_single(&format!("!schedule {} --after 1h --every 1m", path))
+            .unwrap();
+        let output = drain(&repl).concat();
+        let later: Uuid = output
+            .split("Scheduled ")
+            .nth(1)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        repl.run_single(&format!("!unschedule {}", later)).unwrap();
+        assert!(drain(&repl)
+            .concat()
+            .contains(&format!("Unscheduled {}", later)));
+        assert!(repl.scheduler.scheduled(&later).unwrap().runs.is_empty());
+        repl.run_single(&format!("!unschedule {}", later)).unwrap();
+        assert!(drain(&repl).concat().contains("already stopped"));
+
+        repl.run_single(&format!("!schedule {} --max 2", path))
+            .unwrap();
+        assert!(drain(&repl).concat().contains("Usage: !schedule"));
+        repl.run_single(&format!("!schedule {} --every soon", path))
+            .unwrap();
+        assert!(drain(&repl).concat().contains("Usage: !schedule"));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("1.5"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("3d"), None);
+        assert_eq!(parse_duration("ms"), None);
+    }
+
+    #[test]
+    fn test_spawn_fresh_or_with_state() {
+        let mut repl = REPL::new(VM::new());
+        repl.vm().registers[5] = 42;
+        let file = temp_file(TEST_PROGRAM.as_bytes());
+        let path = file.path().display().to_string();
+
+        repl.run_single(&format!("!spawn {}", path)).unwrap();
+        let fresh = repl.last_spawned.unwrap();
+        let fresh = repl.scheduler.wait(&fresh, Duration::from_secs(5)).unwrap();
+        assert_eq!(fresh.registers[0], 100);
+        assert_eq!(fresh.registers[5], 0);
+
+        repl.run_single(&format!("!spawn --with-state {}", path))
+            .unwrap();
+        let cloned = repl.last_spawned.unwrap();
+        let cloned = repl
+            .scheduler
+            .wait(&cloned, Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(cloned.registers[0], 100);
+        assert_eq!(cloned.registers[5], 42);
+        // spawning leaves this VM as it was
+        assert!(repl.vm().program.is_empty());
+        assert_eq!(repl.vm().registers[0], 0);
+    }
+
+    #[test]
+    fn test_scheduler_status() {
+        let mut repl = REPL::new(VM::new());
+        let file = temp_file(TEST_PROGRAM.as_bytes());
+        repl.run_single(&format!("!spawn {}", file.path().display()))
+            .unwrap();
+        let finished = repl.last_spawned.unwrap();
+        let mut headless = VM::new();
+        headless.program = vec![0; PIE_HEADER_LENGTH];
+        let crashed = repl.scheduler.spawn(headless, "headless");
+        for id in [finished, crashed] {
+            let _ = repl.scheduler.wait(&id, Duration::from_secs(5));
+        }
+        drain(&repl);
+
+        repl.run_single("!scheduler_status").unwrap();
+        let status = drain(&repl).concat();
+        assert!(status.contains(&format!("{:<22}{}", "workers:", repl.scheduler.workers())));
+        assert!(
+            status.contains(&format!("{:<22}2", "submitted:")),
+            "{}",
+            status
+        );
+        assert!(
+            status.contains(&format!("{:<22}1", "finished:")),
+            "{}",
+            status
+        );
+        assert!(
+            status.contains(&format!("{:<22}1", "crashed:")),
+            "{}",
+            status
+        );
+        assert!(status.contains("worker 0 busy:"), "{}", status);
+
+        repl.run_single("!format json").unwrap();
+        drain(&repl);
+        repl.run_single("!scheduler_status").unwrap();
+        let json: Value = serde_json::from_str(drain(&repl).concat().trim()).unwrap();
+        assert_eq!(json["scheduler_status"]["submitted"], 2);
+    }
+
+    #[test]
+    fn test_spawn_with_priority() {
+        let mut repl = REPL::new(VM::new());
+        let file = temp_file(TEST_PROGRAM.as_bytes());
+        let path = file.path().display().to_string();
+        repl.run_single(&format!("!spawn --priority high {}", path))
+            .unwrap();
+        let task = repl.scheduler.status(&repl.last_spawned.unwrap()).unwrap();
+        assert_eq!(task.priority, Priority::High);
+        assert_eq!(task.description, path);
+
+        repl.run_single(&format!("!spawn --priority urgent {}", path))
+            .unwrap();
+        assert!(drain(&repl).concat().contains("Usage: !spawn"));
+        assert_eq!(repl.last_spawned, Some(task.id));
+    }
+
+    #[test]
+    fn test_finished_task_reported() {
+        let mut repl = REPL::new(VM::new());
+        let file = temp_file(TEST_PROGRAM.as_bytes());
+        repl.run_single(&format!("!spawn {}", file.path().display()))
+            .unwrap();
+        let id = repl.last_spawned.unwrap();
+
+        // no command asks for it: the worker sends it as the task stops
+        let pipe = repl.rx_pipe.as_ref().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let line = loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let line = pipe.recv_timeout(left).expect("no completion line");
+            if line.starts_with(&format!("Task {} ", id)) {
+                break line;
+            }
+        };
+        assert!(line.contains("Finished after"), "{}", line);
+        assert!(line.contains("Stop, $0=100"), "{}", line);
+        repl.scheduler.wait(&id, Duration::from_secs(5)).unwrap();
+        assert_eq!(line, repl.scheduler.status(&id).unwrap().summary() + "\n");
+    }
+
+    #[test]
+    fn test_events_of_crashed_task() {
+        let mut repl = REPL::new(VM::new());
+        let mut vm = VM::new();
+        // no header, so the VM crashes as soon as it starts
+        vm.program = vec![0; PIE_HEADER_LENGTH];
+        let id = repl.scheduler.spawn(vm, "crashes");
+        repl.scheduler.wait(&id, Duration::from_secs(5)).unwrap();
+
+        repl.run_single(&format!("!events {}", id)).unwrap();
+        assert!(drain(&repl).concat().contains("Crash"));
+        repl.run_single(&format!("!tasks {}", id)).unwrap();
+        let shown = drain(&repl).concat();
+        assert!(shown.contains("Crashed"), "{}", shown);
+        assert!(shown.contains("  Crash\n"), "{}", shown);
+
+        repl.run_single("!format json").unwrap();
+        drain(&repl);
+        repl.run_single(&format!("!events {}", id)).unwrap();
+        let json: Value = serde_json::from_str(drain(&repl).concat().trim()).unwrap();
+        assert_eq!(json["events"][0]["event"], "Start");
+        assert_eq!(json["events"][1]["event"], "Crash");
+        assert_eq!(json["events"][1]["app_id"], json["events"][0]["app_id"]);
     }
 }
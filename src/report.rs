@@ -0,0 +1,109 @@
+//! Errors formatted for people to read: an `error:` line saying what failed, then what caused
+//! it, one cause to an indented line, as in
+//!
+//! ```text
+//! error: Unable to parse input
+//!   caused by: [ASM003] Label found outside segment at: line 2, column 1
+//!   caused by: [ASM006] Unknown directive: datum at: line 3, column 1
+//! ```
+
+use std::error::Error;
+
+use crate::error::IridiumError;
+
+/// `context` on an `error:` line, followed by `e` and the errors that led to it
+pub fn error(context: &str, e: &(dyn Error + 'static)) -> String {
+    let mut report = format!("error: {}", context);
+    for cause in causes(e) {
+        report.push_str("\n  caused by: ");
+        report.push_str(&cause);
+    }
+    report
+}
+
+/// Each error in the chain starting at `e`, skipping those whose message the one before
+/// already repeats. A program that didn't assemble gives each thing wrong with it
+fn causes(e: &(dyn Error + 'static)) -> Vec<String> {
+    if let Some(IridiumError::Assemble(errors)) = e.downcast_ref::<IridiumError>() {
+        return errors.iter().map(ToString::to_string).collect();
+    }
+    let mut causes = vec![e.to_string()];
+    let mut source = e.source();
+    while let Some(e) = source {
+        let message = e.to_string();
+        if !causes.last().is_some_and(|last| last.contains(&message)) {
+            causes.push(message);
+        }
+        source = e.source();
+    }
+    causes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fmt, io};
+
+    use super::*;
+    use crate::{
+        assembler::Assembler,
+        error::{AssemblerError, Location},
+    };
+
+    #[test]
+    fn test_assemble_failure() {
+        let e = Assembler::new()
+            .assemble(".data\nhello: .asciiz 'Hello'\nhello: .asciiz 'Bye'\n.code\nhlt")
+            .unwrap_err();
+        assert_eq!(
+            error("Unable to parse input", &e),
+            "error: Unable to parse input\n  caused by: [ASM005] Symbol already declared at: line 3, column 1"
+        );
+
+        let e = IridiumError::Assemble(vec![
+            AssemblerError::UnknownDirectiveFound(
+                "datum".to_string(),
+                Location { line: 3, column: 1 },
+            ),
+            AssemblerError::MoreErrors(3),
+        ]);
+        assert_eq!(
+            error("Unable to parse input", &e),
+            "error: Unable to parse input\n  \
+             caused by: [ASM006] Unknown directive: datum at: line 3, column 1\n  \
+             caused by: [ASM007] ... and 3 more errors"
+        );
+    }
+
+    #[derive(Debug)]
+    struct Wrapped(io::Error);
+
+    impl fmt::Display for Wrapped {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "Unable to read the program")
+        }
+    }
+
+    impl Error for Wrapped {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn test_chain() {
+        // the io error's message is already part of the IridiumError's
+        let e = IridiumError::from(io::Error::other("disk on fire"));
+        assert_eq!(
+            error("Unable to start", &e),
+            "error: Unable to start\n  caused by: [IR001] Io Error: disk on fire"
+        );
+
+        let e = Wrapped(io::Error::other("disk on fire"));
+        assert_eq!(
+            error("Unable to run", &e),
+            "error: Unable to run\n  \
+             caused by: Unable to read the program\n  \
+             caused by: disk on fire"
+        );
+    }
+}
@@ -1,22 +1,1205 @@
-use std::thread;
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
 
-use crate::vm::{VMEvent, VM};
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::Serialize;
+use uuid::Uuid;
 
-#[derive(Default)]
+pub use self::timer::Schedule;
+use self::{pool::Pool, timer::Timer};
+use crate::{
+    error::{IridiumError, Result},
+    vm::{VMEvent, VMEventType, VMSnapshot, VM},
+};
+
+mod pool;
+mod timer;
+
+/// Runs VMs on a bounded pool of worker threads, queueing them while every worker is busy
+#[derive(Debug)]
 pub struct Scheduler {
-    next_pid: u32,
-    max_pid: u32,
+    launcher: Launcher,
+    running: Arc<AtomicUsize>, // programs started with `execute` that haven't stopped yet
+    timer: Arc<Timer>,         // starts scheduled programs as they come due
+}
+
+/// What starting a VM takes, shared with the timer starting scheduled ones
+#[derive(Debug, Clone)]
+struct Launcher {
+    pool: Arc<Pool>,
+    event_sink: Arc<Mutex<Option<Sender<VMEvent>>>>, // handed to every VM `execute` or `spawn` starts
+    spawned: Arc<Spawned>,                           // VMs started with `spawn`, by task id
+    counters: Arc<Counters>,                         // of every VM run, however it was started
+    closed: Arc<AtomicBool>, // set once shut down, refusing VMs from then on
+    halt: Arc<AtomicBool>,   // cancels every VM started with `execute`
+}
+
+/// How many VMs the scheduler was given and how those that stopped ended
+#[derive(Debug, Default)]
+struct Counters {
+    submitted: AtomicUsize,
+    finished: AtomicUsize,
+    crashed: AtomicUsize,
+    cancelled: AtomicUsize,
+    timed_out: AtomicUsize,
+}
+
+impl Counters {
+    fn submitted(&self) {
+        self.submitted.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn ended(&self, state: TaskState) {
+        let counter = match state {
+            TaskState::Finished => &self.finished,
+            TaskState::Crashed => &self.crashed,
+            TaskState::Cancelled => &self.cancelled,
+            TaskState::TimedOut => &self.timed_out,
+            TaskState::Queued | TaskState::Running => return,
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// VMs that stopped other than by being cancelled, and those that were cancelled
+    fn stopped(&self) -> (usize, usize) {
+        let on_their_own = self.finished.load(Ordering::SeqCst)
+            + self.crashed.load(Ordering::SeqCst)
+            + self.timed_out.load(Ordering::SeqCst);
+        (on_their_own, self.cancelled.load(Ordering::SeqCst))
+    }
+}
+
+/// How long VMs cancelled at the end of a shutdown get to notice before their workers are
+/// given up on
+const CANCEL_GRACE: Duration = Duration::from_secs(1);
+
+/// What became of the VMs that hadn't stopped when the scheduler was shut down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct ShutdownSummary {
+    pub completed: usize, // stopped on their own within the grace period
+    pub cancelled: usize, // stopped by being cancelled, at the end of it or before
+    pub abandoned: usize, // still running when their workers were given up on
+}
+
+/// Point-in-time copy of the scheduler's counters and its pool, for capacity planning
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SchedulerMetrics {
+    pub submitted: usize,
+    pub finished: usize,
+    pub crashed: usize, // panics included
+    pub cancelled: usize,
+    pub timed_out: usize,
+    pub running: usize,
+    pub queued: usize,
+    pub workers: usize,             // the most that may run at once
+    pub worker_busy: Vec<Duration>, // time spent running VMs, by worker number
+}
+
+/// How soon a task runs when workers are busy: queued tasks of a higher priority run
+/// first, and those of one priority in the order they came. So that a flood of urgent
+/// tasks can't hold the rest back forever, every `pool::STARVATION_LIMIT` tasks run ahead
+/// of an older one, the oldest task queued runs next whatever its priority
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = IridiumError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(IridiumError::StringError(format!(
+                "Invalid priority {}, expected low, normal or high",
+                s
+            ))),
+        }
+    }
+}
+
+/// How a task started with `spawn_with` is run
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpawnOptions {
+    pub timeout: Option<Duration>, // how long it may run once a worker picked it up
+    pub priority: Priority,
+}
+
+impl SpawnOptions {
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+/// What the VM of a task submitted with `submit` starts from
+#[derive(Debug, Clone)]
+pub enum SpawnMode {
+    Fresh { program: Vec<u8> }, // a new VM with nothing but the pre-assembled program
+    CloneState { snapshot: Box<VMSnapshot> }, // registers, heap and program of another VM
+}
+
+impl SpawnMode {
+    fn into_vm(self) -> Result<VM> {
+        match self {
+            SpawnMode::Fresh { program } => {
+                let mut vm = VM::new();
+                vm.load_bytecode(program)?;
+                Ok(vm)
+            }
+            SpawnMode::CloneState { snapshot } => Ok(VM::from_snapshot(*snapshot)),
+        }
+    }
+}
+
+/// Where a VM started with `spawn` is at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum TaskState {
+    Queued, // waiting for a worker
+    Running,
+    Finished,
+    Crashed,   // the VM recorded a crash, or its worker panicked
+    Cancelled, // stopped by `cancel` before the program ended
+    TimedOut,  // stopped for running longer than it was allowed
+}
+
+impl TaskState {
+    /// How a VM that recorded `events` ended, once it stopped without panicking
+    fn ending(events: &[VMEvent]) -> TaskState {
+        if events.iter().any(|e| e.event == VMEventType::Crash) {
+            return TaskState::Crashed;
+        }
+        match events.last().map(|e| &e.event) {
+            Some(VMEventType::Cancelled | VMEventType::Interrupted) => TaskState::Cancelled,
+            Some(VMEventType::TimedOut) => TaskState::TimedOut,
+            _ => TaskState::Finished,
+        }
+    }
+
+    /// Whether the task stopped, for better or worse
+    pub fn is_done(self) -> bool {
+        matches!(
+            self,
+            TaskState::Finished | TaskState::Crashed | TaskState::Cancelled | TaskState::TimedOut
+        )
+    }
+}
+
+/// What the scheduler knows of a task started with `spawn`, kept up to date by the worker
+/// running it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TaskRecord {
+    pub id: Uuid,
+    pub description: String,
+    pub priority: Priority,
+    pub state: TaskState,
+    pub queued_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub events: Vec<VMEvent>,  // once it stopped
+    pub registers: [i32; 32],  // as the VM left them
+    pub panic: Option<String>, // what the worker panicked with, if it did
+}
+
+impl TaskRecord {
+    fn new(id: Uuid, description: String, priority: Priority) -> Self {
+        Self {
+            id,
+            description,
+            priority,
+            state: TaskState::Queued,
+            queued_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+            events: vec![],
+            registers: [0; 32],
+            panic: None,
+        }
+    }
+
+    /// One line saying how the task ended: when, how, and the first registers it set
+    pub fn summary(&self) -> String {
+        let took = match (self.started_at, self.finished_at) {
+            (Some(started), Some(finished)) => (finished - started).to_std().unwrap_or_default(),
+            _ => Duration::ZERO,
+        };
+        let ending = match (&self.panic, self.events.last()) {
+            (Some(panic), _) => format!("panicked: {}", panic),
+            (None, Some(event)) => format!("{:?}", event.event),
+            (None, None) => format!("{:?}", self.state),
+        };
+        let registers: Vec<String> = self
+            .registers
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| **value != 0)
+            .take(2)
+            .map(|(register, value)| format!("${}={}", register, value))
+            .collect();
+        let mut summary = format!(
+            "Task {} {:?} after {:?}, {}",
+            self.id, self.state, took, ending
+        );
+        if !registers.is_empty() {
+            summary.push_str(&format!(", {}", registers.join(" ")));
+        }
+        summary
+    }
+
+    /// How the VM left things, once it stopped
+    pub fn outcome(&self) -> Outcome {
+        Outcome {
+            events: self.events.clone(),
+            registers: self.registers,
+        }
+    }
+}
+
+/// How the VM of a spawned task left things once it stopped
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Outcome {
+    pub events: Vec<VMEvent>, // ending with its stop or crash
+    pub registers: [i32; 32],
+}
+
+/// Told about every spawned task the moment it stops
+pub type OnFinish = Arc<dyn Fn(&TaskRecord) + Send + Sync>;
+
+#[derive(Default)]
+struct Spawned {
+    tasks: Mutex<HashMap<Uuid, TaskRecord>>,
+    cancels: Mutex<HashMap<Uuid, Arc<AtomicBool>>>, // of the tasks not done yet
+    settled: Condvar,                               // notified whenever a task stops running
+    on_finish: Mutex<Option<OnFinish>>,
+}
+
+impl fmt::Debug for Spawned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Spawned")
+            .field("tasks", &self.lock().len())
+            .finish()
+    }
+}
+
+impl Spawned {
+    fn lock(&self) -> MutexGuard<'_, HashMap<Uuid, TaskRecord>> {
+        self.tasks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Record how the task ended, once whoever asked to be told about it has been
+    fn finish(&self, task: TaskRecord) {
+        let on_finish = self
+            .on_finish
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        if let Some(on_finish) = on_finish {
+            on_finish(&task);
+        }
+        let id = task.id;
+        self.update(&id, |record| *record = task);
+    }
+
+    fn update<F: FnOnce(&mut TaskRecord)>(&self, id: &Uuid, f: F) {
+        let mut tasks = self.lock();
+        if let Some(task) = tasks.get_mut(id) {
+            f(task);
+            if task.state.is_done() {
+                self.settled.notify_all();
+            }
+        }
+    }
+}
+
+impl Launcher {
+    fn spawn_with(&self, vm: VM, description: &str, options: SpawnOptions) -> Uuid {
+        self.spawn_running(vm, description, options, VM::run)
+    }
+
+    /// Like `spawn_with`, with `run` standing in for `VM::run`
+    fn spawn_running<R>(&self, vm: VM, description: &str, options: SpawnOptions, run: R) -> Uuid
+    where
+        R: FnOnce(&mut VM) -> Vec<VMEvent> + Send + 'static,
+    {
+        let id = Uuid::new_v4();
+        let mut record = TaskRecord::new(id, description.to_string(), options.priority);
+        if self.closed.load(Ordering::SeqCst) {
+            // never run, but still there to be looked up by the id handed back
+            record.state = TaskState::Cancelled;
+            record.finished_at = Some(record.queued_at);
+            self.spawned.lock().insert(id, record);
+            return id;
+        }
+        self.spawned.lock().insert(id, record);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut cancels = self
+            .spawned
+            .cancels
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        cancels.insert(id, cancel.clone());
+        drop(cancels);
+        let mut vm = vm.with_cancel(cancel);
+        if let Some(timeout) = options.timeout {
+            vm = vm.with_time_limit(timeout);
+        }
+        if let Some(sink) = self.event_sink() {
+            vm = vm.with_event_sink(Some(sink));
+        }
+        let spawned = self.spawned.clone();
+        let counters = self.counters.clone();
+        counters.submitted();
+        let job = Box::new(move || {
+            spawned.update(&id, |task| {
+                task.state = TaskState::Running;
+                task.started_at = Some(Utc::now());
+            });
+            let ran = panic::catch_unwind(AssertUnwindSafe(|| run(&mut vm)));
+            let mut task = match spawned.lock().get(&id) {
+                Some(task) => task.clone(),
+                None => return,
+            };
+            task.finished_at = Some(Utc::now());
+            task.registers = vm.registers;
+            match ran {
+                Ok(events) => {
+                    task.state = TaskState::ending(&events);
+                    task.events = events;
+                }
+                Err(panic) => {
+                    task.state = TaskState::Crashed;
+                    task.events = vm.events().to_vec();
+                    task.panic = Some(panic_message(panic.as_ref()));
+                }
+            }
+            counters.ended(task.state);
+            spawned.finish(task);
+            let mut cancels = spawned.cancels.lock().unwrap_or_else(|e| e.into_inner());
+            cancels.remove(&id);
+        });
+        self.pool.submit_with_priority(job, options.priority);
+        id
+    }
+
+    fn check_open(&self) -> Result<()> {
+        match self.closed.load(Ordering::SeqCst) {
+            true => Err(IridiumError::StringError(
+                "The scheduler was shut down".to_string(),
+            )),
+            false => Ok(()),
+        }
+    }
+
+    fn event_sink(&self) -> Option<Sender<VMEvent>> {
+        self.event_sink
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.timer.close();
+        self.launcher.pool.close();
+    }
 }
 
 impl Scheduler {
+    /// A scheduler with a worker for every CPU
     pub fn new() -> Scheduler {
-        Self {
-            next_pid: 0,
-            max_pid: 50000,
+        Self::with_workers(num_cpus::get())
+    }
+
+    /// A scheduler running at most `workers` VMs at once
+    pub fn with_workers(workers: usize) -> Scheduler {
+        let launcher = Launcher {
+            pool: Pool::new(workers),
+            event_sink: Default::default(),
+            spawned: Default::default(),
+            counters: Default::default(),
+            closed: Default::default(),
+            halt: Default::default(),
+        };
+        let timer = Timer::new(Box::new({
+            let launcher = launcher.clone();
+            move |program, description| {
+                let vm = SpawnMode::Fresh {
+                    program: program.to_vec(),
+                }
+                .into_vm();
+                match vm {
+                    Ok(vm) => Some(launcher.spawn_with(vm, description, Default::default())),
+                    Err(e) => {
+                        error!("Unable to start {}: {}", description, e);
+                        None
+                    }
+                }
+            }
+        }));
+        Scheduler {
+            launcher,
+            running: Default::default(),
+            timer,
+        }
+    }
+
+    /// Run at most `workers` VMs at once from now on
+    pub fn set_workers(&self, workers: usize) {
+        self.launcher.pool.resize(workers);
+    }
+
+    /// How many VMs may run at once
+    pub fn workers(&self) -> usize {
+        self.launcher.pool.size()
+    }
+
+    /// VMs waiting for a worker
+    pub fn queued(&self) -> usize {
+        self.launcher.pool.queued()
+    }
+
+    /// VMs being run right now
+    pub fn running(&self) -> usize {
+        self.launcher.pool.running()
+    }
+
+    /// Run `vm` once a worker is free, returning the id to wait for it or look it up by.
+    /// `description` says what it runs when tasks are listed
+    pub fn spawn(&self, vm: VM, description: &str) -> Uuid {
+        self.spawn_with(vm, description, SpawnOptions::default())
+    }
+
+    /// Like `spawn`, stopping the VM if it runs for longer than `timeout` once a worker
+    /// picked it up
+    pub fn spawn_with_timeout(&self, vm: VM, description: &str, timeout: Duration) -> Uuid {
+        self.spawn_with(
+            vm,
+            description,
+            SpawnOptions::default().with_timeout(timeout),
+        )
+    }
+
+    /// Run a VM started as `mode` says, once a worker is free. Fails if it can't be started,
+    /// like when a fresh one is given a program without a header
+    pub fn submit(
+        &self,
+        mode: SpawnMode,
+        description: &str,
+        options: SpawnOptions,
+    ) -> Result<Uuid> {
+        self.launcher.check_open()?;
+        Ok(self.spawn_with(mode.into_vm()?, description, options))
+    }
+
+    /// Run a pre-assembled program on a VM of its own as a task stopped if it runs for
+    /// longer than `timeout`
+    pub fn submit_with_timeout(&self, program: Vec<u8>, timeout: Duration) -> Result<Uuid> {
+        let description = format!("{} bytes of bytecode", program.len());
+        let options = SpawnOptions::default().with_timeout(timeout);
+        self.submit(SpawnMode::Fresh { program }, &description, options)
+    }
+
+    /// Run a pre-assembled program on a VM of its own once `delay` passed, returning the id
+    /// of the schedule its task is started by
+    pub fn submit_after(&self, program: Vec<u8>, delay: Duration) -> Result<Uuid> {
+        let description = format!("{} bytes of bytecode", program.len());
+        self.add_schedule(Schedule::new(program, &description, delay))
+    }
+
+    /// Run a pre-assembled program on a VM of its own now and every `interval` after, each
+    /// run a task of its own, until it ran `max_runs` times if given or is unscheduled
+    pub fn submit_every(
+        &self,
+        program: Vec<u8>,
+        interval: Duration,
+        max_runs: Option<usize>,
+    ) -> Result<Uuid> {
+        let description = format!("{} bytes of bytecode", program.len());
+        let schedule = Schedule::new(program, &description, Duration::ZERO);
+        self.add_schedule(schedule.with_repeat(interval, max_runs))
+    }
+
+    /// Have the timer start `schedule`'s runs as they come due, returning its id. Fails
+    /// straight away if its program can't be run
+    pub fn add_schedule(&self, schedule: Schedule) -> Result<Uuid> {
+        self.launcher.check_open()?;
+        SpawnMode::Fresh {
+            program: schedule.program().to_vec(),
+        }
+        .into_vm()?;
+        let id = schedule.id;
+        self.timer.add(schedule);
+        Ok(id)
+    }
+
+    /// Stop the schedule `id` from starting any more runs, leaving those started be
+    pub fn unschedule(&self, id: &Uuid) -> Result<()> {
+        if self.timer.stop(id) {
+            return Ok(());
+        }
+        match self.timer.get(id) {
+            Some(_) => Err(IridiumError::StringError(format!(
+                "Schedule {} already stopped",
+                id
+            ))),
+            None => Err(IridiumError::StringError(format!("No schedule {}", id))),
         }
     }
 
-    pub fn get_thread(&self, mut vm: VM) -> thread::JoinHandle<Vec<VMEvent>> {
-        thread::spawn(move || vm.run())
+    /// The schedule `id` and the tasks it started so far, if there is one
+    pub fn scheduled(&self, id: &Uuid) -> Option<Schedule> {
+        self.timer.get(id)
+    }
+
+    /// Every schedule, those to run soonest first and stopped ones last
+    pub fn schedules(&self) -> Vec<Schedule> {
+        self.timer.list()
+    }
+
+    /// Stop taking VMs and starting scheduled ones, give those queued or running up to
+    /// `grace` to stop, then cancel the rest and wait for the workers to stop. VMs handed to
+    /// `spawn` from then on are recorded as cancelled without running, and `submit`,
+    /// `execute` and `add_schedule` fail. Shutting down again finds nothing left to stop
+    pub fn shutdown(&self, grace: Duration) -> ShutdownSummary {
+        self.launcher.closed.store(true, Ordering::SeqCst);
+        self.timer.close();
+        let counters = &self.launcher.counters;
+        let (on_their_own, cancelled) = counters.stopped();
+        let outstanding = counters.submitted.load(Ordering::SeqCst) - on_their_own - cancelled;
+
+        let pool = &self.launcher.pool;
+        if !pool.wait_idle(Instant::now() + grace) {
+            self.launcher.halt.store(true, Ordering::SeqCst);
+            let cancels = self
+                .launcher
+                .spawned
+                .cancels
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            for cancel in cancels.values() {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        }
+        pool.join(Instant::now() + CANCEL_GRACE);
+
+        let (now_on_their_own, now_cancelled) = counters.stopped();
+        let completed = now_on_their_own - on_their_own;
+        let cancelled = now_cancelled - cancelled;
+        ShutdownSummary {
+            completed,
+            cancelled,
+            abandoned: outstanding.saturating_sub(completed + cancelled),
+        }
+    }
+
+    /// Like `spawn`, run as `options` say
+    pub fn spawn_with(&self, vm: VM, description: &str, options: SpawnOptions) -> Uuid {
+        self.launcher.spawn_with(vm, description, options)
+    }
+
+    /// Call `on_finish` with every spawned task the moment it stops, on the worker that ran
+    /// it and before `wait` sees it stopped. It must not block
+    pub fn set_on_finish(&self, on_finish: OnFinish) {
+        let mut current = self
+            .launcher
+            .spawned
+            .on_finish
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        *current = Some(on_finish);
+    }
+
+    /// Stop the task `id` before its next instruction, or before it starts if it's still
+    /// queued. Its state turns to cancelled once the worker notices, which `wait` can await
+    pub fn cancel(&self, id: &Uuid) -> Result<()> {
+        let state = match self.status(id) {
+            Some(task) => task.state,
+            None => return Err(IridiumError::StringError(format!("No task {}", id))),
+        };
+        let cancels = self
+            .launcher
+            .spawned
+            .cancels
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        match cancels.get(id) {
+            Some(cancel) if !state.is_done() => {
+                cancel.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+            _ => Err(IridiumError::StringError(format!(
+                "Task {} is not running ({:?})",
+                id, state
+            ))),
+        }
+    }
+
+    /// Wait up to `timeout` for the task `id` to stop, returning how its VM left things
+    pub fn wait(&self, id: &Uuid, timeout: Duration) -> Result<Outcome> {
+        let deadline = Instant::now() + timeout;
+        let mut tasks = self.launcher.spawned.lock();
+        loop {
+            let task = match tasks.get(id) {
+                Some(task) => task,
+                None => return Err(IridiumError::StringError(format!("No task {}", id))),
+            };
+            if let Some(panic) = &task.panic {
+                return Err(IridiumError::StringError(format!(
+                    "Task {} panicked: {}",
+                    id, panic
+                )));
+            }
+            if task.state.is_done() {
+                return Ok(task.outcome());
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(IridiumError::StringError(format!(
+                    "Task {} still running after {:?}",
+                    id, timeout
+                )));
+            }
+            tasks = self
+                .launcher
+                .spawned
+                .settled
+                .wait_timeout(tasks, left)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    /// The events the VM of task `id` recorded, once it stopped. None while it runs, or if
+    /// there is no such task
+    pub fn result(&self, id: &Uuid) -> Option<Vec<VMEvent>> {
+        let task = self.status(id)?;
+        task.state.is_done().then_some(task.events)
+    }
+
+    /// Where the task `id` is at, if there is one
+    pub fn status(&self, id: &Uuid) -> Option<TaskRecord> {
+        self.launcher.spawned.lock().get(id).cloned()
+    }
+
+    /// Every task started with `spawn`, oldest first
+    pub fn list(&self) -> Vec<TaskRecord> {
+        let mut tasks: Vec<TaskRecord> = self.launcher.spawned.lock().values().cloned().collect();
+        tasks.sort_by_key(|task| task.queued_at);
+        tasks
+    }
+
+    /// Run a pre-assembled program on a VM of its own, handing the VM back once it stops
+    pub fn execute(&self, program: Vec<u8>) -> Result<Receiver<VM>> {
+        self.launcher.check_open()?;
+        let mut vm = VM::new()
+            .with_event_sink(self.launcher.event_sink())
+            .with_cancel(self.launcher.halt.clone());
+        vm.load_bytecode(program)?;
+        let running = Running::start(self.running.clone());
+        let counters = self.launcher.counters.clone();
+        counters.submitted();
+        let (tx, rx) = channel();
+        // a VM that panics drops the sender without sending
+        self.launcher.pool.submit(Box::new(move || {
+            let _running = running;
+            match panic::catch_unwind(AssertUnwindSafe(|| vm.run())) {
+                Ok(events) => {
+                    counters.ended(TaskState::ending(&events));
+                    let _ = tx.send(vm);
+                }
+                Err(panic) => {
+                    counters.ended(TaskState::Crashed);
+                    panic::resume_unwind(panic);
+                }
+            }
+        }));
+        Ok(rx)
+    }
+
+    /// The counts of VMs run so far, however they were started, and what the pool is doing
+    pub fn metrics(&self) -> SchedulerMetrics {
+        let counters = &self.launcher.counters;
+        SchedulerMetrics {
+            submitted: counters.submitted.load(Ordering::SeqCst),
+            finished: counters.finished.load(Ordering::SeqCst),
+            crashed: counters.crashed.load(Ordering::SeqCst),
+            cancelled: counters.cancelled.load(Ordering::SeqCst),
+            timed_out: counters.timed_out.load(Ordering::SeqCst),
+            running: self.launcher.pool.running(),
+            queued: self.launcher.pool.queued(),
+            workers: self.launcher.pool.size(),
+            worker_busy: self.launcher.pool.busy(),
+        }
+    }
+
+    /// Send the events of programs started with `execute` or `spawn` from now on to `sink`,
+    /// as they are recorded
+    pub fn set_event_sink(&self, sink: Sender<VMEvent>) {
+        *self
+            .launcher
+            .event_sink
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(sink);
+    }
+
+    /// Programs started with `execute` that haven't stopped yet, queued ones included
+    pub fn running_tasks(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+/// What a panic was raised with, when it was a message
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match panic.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "unknown cause".to_string(),
+        },
+    }
+}
+
+/// Counts a program as running until dropped, even if its thread panics
+struct Running(Arc<AtomicUsize>);
+
+impl Running {
+    fn start(count: Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        Self(count)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{assembler::Assembler, vm::VMEventType};
+
+    #[test]
+    fn test_spawned_task_events() {
+        let scheduler = Scheduler::new();
+        let mut vm = VM::new();
+        vm.program = Assembler::new()
+            .assemble(".data\n.code\nload $0 #100")
+            .unwrap();
+        let id = scheduler.spawn(vm, "load");
+
+        let outcome = scheduler.wait(&id, Duration::from_secs(5)).unwrap();
+        assert_eq!(outcome.events.last().unwrap().event, VMEventType::Stop);
+        assert_eq!(outcome.registers[0], 100);
+        assert_eq!(scheduler.result(&id), Some(outcome.events));
+        assert!(scheduler.status(&Uuid::new_v4()).is_none());
+        let err = scheduler.wait(&Uuid::new_v4(), Duration::ZERO).unwrap_err();
+        assert!(err.to_string().starts_with("[IR008] No task"), "{}", err);
+    }
+
+    /// Spawn `program` and wait for it to stop, however it does
+    fn run_to_end(scheduler: &Scheduler, program: Vec<u8>, description: &str) -> TaskRecord {
+        let mut vm = VM::new();
+        vm.program = program;
+        let id = scheduler.spawn(vm, description);
+        let _ = scheduler.wait(&id, Duration::from_secs(5));
+        scheduler.status(&id).unwrap()
+    }
+
+    /// Spawn a task whose run panics, and wait for it to stop
+    fn run_panicking(scheduler: &Scheduler) -> TaskRecord {
+        let id =
+            scheduler
+                .launcher
+                .spawn_running(VM::new(), "panics", SpawnOptions::default(), |_| {
+                    panic!("the VM gave up")
+                });
+        let _ = scheduler.wait(&id, Duration::from_secs(5));
+        scheduler.status(&id).unwrap()
+    }
+
+    #[test]
+    fn test_task_records() {
+        let scheduler = Scheduler::with_workers(1);
+        let program = Assembler::new()
+            .assemble(".data\n.code\nload $0 #100")
+            .unwrap();
+        let finished = run_to_end(&scheduler, program.clone(), "finishes");
+        assert_eq!(finished.state, TaskState::Finished);
+        assert_eq!(finished.description, "finishes");
+        assert_eq!(finished.registers[0], 100);
+        assert!(finished.started_at.unwrap() <= finished.finished_at.unwrap());
+        assert!(finished.panic.is_none());
+
+        // no header, so the VM refuses to run it
+        let crashed = run_to_end(&scheduler, vec![0; 64], "crashes");
+        assert_eq!(crashed.state, TaskState::Crashed);
+        assert_eq!(crashed.events.last().unwrap().event, VMEventType::Crash);
+        assert!(crashed.panic.is_none());
+
+        let panicked = run_panicking(&scheduler);
+        assert_eq!(panicked.state, TaskState::Crashed);
+        assert!(panicked.panic.is_some());
+        let err = scheduler.wait(&panicked.id, Duration::ZERO).unwrap_err();
+        assert!(err.to_string().contains("panicked"), "{}", err);
+
+        let listed: Vec<_> = scheduler.list().into_iter().map(|task| task.id).collect();
+        assert_eq!(listed, [finished.id, crashed.id, panicked.id]);
+    }
+
+    #[test]
+    fn test_queued_until_worker_free() {
+        let scheduler = Scheduler::with_workers(1);
+        let (tx, rx) = channel::<()>();
+        // hold the only worker until told to let go
+        scheduler.launcher.pool.submit(Box::new(move || {
+            let _ = rx.recv();
+        }));
+        let mut vm = VM::new();
+        vm.program = Assembler::new()
+            .assemble(".data\n.code\nload $0 #100")
+            .unwrap();
+        let id = scheduler.spawn(vm, "waits");
+        let queued = scheduler.status(&id).unwrap();
+        assert_eq!(queued.state, TaskState::Queued);
+        assert!(queued.started_at.is_none());
+        assert!(scheduler.result(&id).is_none());
+
+        drop(tx);
+        scheduler.wait(&id, Duration::from_secs(5)).unwrap();
+        assert_eq!(scheduler.status(&id).unwrap().state, TaskState::Finished);
+    }
+
+    #[test]
+    fn test_cancel_runaway_task() {
+        let scheduler = Scheduler::with_workers(1);
+        let mut vm = VM::new();
+        // jumps back onto itself forever
+        vm.program = Assembler::new()
+            .assemble(".data\n.code\nload $1 #2\njmpb $1")
+            .unwrap();
+        let id = scheduler.spawn(vm, "loops");
+        let err = scheduler.wait(&id, Duration::from_millis(50)).unwrap_err();
+        assert!(err.to_string().contains("still running"), "{}", err);
+
+        scheduler.cancel(&id).unwrap();
+        let outcome = scheduler.wait(&id, Duration::from_secs(5)).unwrap();
+        assert_eq!(outcome.events.last().unwrap().event, VMEventType::Cancelled);
+        assert_eq!(scheduler.status(&id).unwrap().state, TaskState::Cancelled);
+
+        let err = scheduler.cancel(&id).unwrap_err();
+        assert!(err.to_string().contains("not running"), "{}", err);
+        assert!(scheduler.cancel(&Uuid::new_v4()).is_err());
+    }
+
+    #[test]
+    fn test_high_priority_runs_first() {
+        let scheduler = Scheduler::with_workers(1);
+        // with one worker, tasks finish in the order they run
+        let order = Arc::new(Mutex::new(vec![]));
+        let finished = order.clone();
+        scheduler.set_on_finish(Arc::new(move |task| {
+            finished.lock().unwrap().push(task.description.clone())
+        }));
+        let (tx, rx) = channel::<()>();
+        scheduler.launcher.pool.submit(Box::new(move || {
+            let _ = rx.recv();
+        }));
+        let program = Assembler::new()
+            .assemble(".data\n.code\nload $0 #100")
+            .unwrap();
+        let spawn = |description: &str, priority: Priority| {
+            let mut vm = VM::new();
+            vm.program = program.clone();
+            let options = SpawnOptions::default().with_priority(priority);
+            scheduler.spawn_with(vm, description, options)
+        };
+        let sweeps = [
+            spawn("sweep 1", Priority::Normal),
+            spawn("sweep 2", Priority::Low),
+            spawn("sweep 3", Priority::Normal),
+        ];
+        let urgent = spawn("urgent", Priority::High);
+
+        drop(tx);
+        for id in sweeps.iter().chain([&urgent]) {
+            scheduler.wait(id, Duration::from_secs(5)).unwrap();
+        }
+        assert_eq!(
+            *order.lock().unwrap(),
+            ["urgent", "sweep 1", "sweep 3", "sweep 2"]
+        );
+        assert_eq!(scheduler.status(&urgent).unwrap().priority, Priority::High);
+    }
+
+    #[test]
+    fn test_spawn_modes() {
+        let scheduler = Scheduler::new();
+        let program = Assembler::new()
+            .assemble(".data\n.code\nload $0 #100")
+            .unwrap();
+        let mut parent = VM::new();
+        parent.program = program.clone();
+        parent.run();
+        parent.registers[5] = 42;
+
+        let fresh = SpawnMode::Fresh { program };
+        let fresh = scheduler
+            .submit(fresh, "fresh", SpawnOptions::default())
+            .unwrap();
+        let fresh = scheduler.wait(&fresh, Duration::from_secs(5)).unwrap();
+        assert_eq!(fresh.registers[0], 100);
+        assert_eq!(fresh.registers[5], 0);
+
+        let cloned = SpawnMode::CloneState {
+            snapshot: Box::new(parent.snapshot()),
+        };
+        let cloned = scheduler
+            .submit(cloned, "cloned", SpawnOptions::default())
+            .unwrap();
+        let cloned = scheduler.wait(&cloned, Duration::from_secs(5)).unwrap();
+        assert_eq!(cloned.registers[0], 100);
+        assert_eq!(cloned.registers[5], 42);
+        // the parent's own run stays out of the task's events
+        assert_eq!(cloned.events.len(), 2);
+        assert_ne!(cloned.events[0].app_id, parent.events()[0].app_id);
+
+        let headless = SpawnMode::Fresh {
+            program: vec![0; 64],
+        };
+        assert!(scheduler
+            .submit(headless, "headless", SpawnOptions::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_metrics() {
+        let scheduler = Scheduler::with_workers(2);
+        let program = Assembler::new()
+            .assemble(".data\n.code\nload $0 #100")
+            .unwrap();
+        run_to_end(&scheduler, program.clone(), "finishes");
+        run_to_end(&scheduler, vec![0; 64], "crashes");
+        run_panicking(&scheduler);
+        let executed = scheduler.execute(program).unwrap();
+        executed.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while scheduler.running() > 0 {
+            assert!(Instant::now() < deadline, "workers never went idle");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let metrics = scheduler.metrics();
+        assert_eq!(metrics.submitted, 4);
+        assert_eq!(metrics.finished, 2);
+        assert_eq!(metrics.crashed, 2);
+        assert_eq!((metrics.cancelled, metrics.timed_out), (0, 0));
+        assert_eq!((metrics.running, metrics.queued), (0, 0));
+        assert_eq!(metrics.workers, 2);
+        assert!(!metrics.worker_busy.is_empty() && metrics.worker_busy.len() <= 2);
+        assert!(metrics.worker_busy.iter().sum::<Duration>() > Duration::ZERO);
+    }
+
+    /// Wait for the schedule `id` to stop starting runs
+    fn until_stopped(scheduler: &Scheduler, id: &Uuid) -> Schedule {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let schedule = scheduler.scheduled(id).unwrap();
+            if !schedule.active {
+                return schedule;
+            }
+            assert!(Instant::now() < deadline, "schedule never stopped");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_delayed_and_repeating_runs() {
+        let scheduler = Scheduler::with_workers(2);
+        let program = Assembler::new()
+            .assemble(".data\n.code\nload $0 #100")
+            .unwrap();
+
+        let started = Instant::now();
+        let delayed = scheduler
+            .submit_after(program.clone(), Duration::from_millis(50))
+            .unwrap();
+        let every = scheduler
+            .submit_every(program.clone(), Duration::from_millis(20), Some(3))
+            .unwrap();
+        assert!(scheduler.scheduled(&delayed).unwrap().runs.is_empty());
+
+        let delayed = until_stopped(&scheduler, &delayed);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(delayed.runs.len(), 1);
+        let every = until_stopped(&scheduler, &every);
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        assert_eq!(every.runs.len(), 3);
+        for id in every.runs.iter().chain(&delayed.runs) {
+            let outcome = scheduler.wait(id, Duration::from_secs(5)).unwrap();
+            assert_eq!(outcome.registers[0], 100);
+        }
+        let descriptions: Vec<String> = every
+            .runs
+            .iter()
+            .map(|id| scheduler.status(id).unwrap().description)
+            .collect();
+        assert_eq!(descriptions[2], format!("{} (run 3)", every.description));
+        assert!(scheduler.submit_after(vec![0; 64], Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn test_unschedule_stops_recurrence() {
+        let scheduler = Scheduler::with_workers(1);
+        let program = Assembler::new()
+            .assemble(".data\n.code\nload $0 #100")
+            .unwrap();
+        let id = scheduler
+            .submit_every(program, Duration::from_millis(10), None)
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        scheduler.unschedule(&id).unwrap();
+        let runs = scheduler.scheduled(&id).unwrap().runs.len();
+        assert!(runs >= 2, "only {} runs", runs);
+
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(scheduler.scheduled(&id).unwrap().runs.len(), runs);
+        assert!(!scheduler.schedules()[0].active);
+        let err = scheduler.unschedule(&id).unwrap_err();
+        assert!(err.to_string().contains("already stopped"), "{}", err);
+        assert!(scheduler.unschedule(&Uuid::new_v4()).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_cancels_what_outlives_grace() {
+        let scheduler = Scheduler::with_workers(2);
+        let finishes = Assembler::new()
+            .assemble(".data\n.code\nload $0 #100")
+            .unwrap();
+        let loops = Assembler::new()
+            .assemble(".data\n.code\nload $1 #2\njmpb $1")
+            .unwrap();
+        // stops on its own within the grace period, but not before shutdown starts counting
+        let quick = scheduler
+            .submit(
+                SpawnMode::Fresh {
+                    program: loops.clone(),
+                },
+                "quick",
+                SpawnOptions::default().with_timeout(Duration::from_millis(100)),
+            )
+            .unwrap();
+        let endless = scheduler
+            .submit(
+                SpawnMode::Fresh {
+                    program: loops.clone(),
+                },
+                "endless",
+                Default::default(),
+            )
+            .unwrap();
+        // queued until quick frees its worker, then done long before the grace period ends
+        let short = scheduler
+            .submit(
+                SpawnMode::Fresh { program: finishes },
+                "short",
+                Default::default(),
+            )
+            .unwrap();
+
+        let summary = scheduler.shutdown(Duration::from_millis(500));
+        assert_eq!(
+            summary,
+            ShutdownSummary {
+                completed: 2,
+                cancelled: 1,
+                abandoned: 0,
+            }
+        );
+        assert_eq!(scheduler.status(&quick).unwrap().state, TaskState::TimedOut);
+        assert_eq!(scheduler.status(&short).unwrap().state, TaskState::Finished);
+        assert_eq!(
+            scheduler.status(&endless).unwrap().state,
+            TaskState::Cancelled
+        );
+        assert_eq!(scheduler.running() + scheduler.queued(), 0);
+
+        let refused = scheduler.submit(
+            SpawnMode::Fresh { program: loops },
+            "late",
+            Default::default(),
+        );
+        assert!(refused.is_err());
+        let late = scheduler.spawn(VM::new(), "late");
+        assert_eq!(scheduler.status(&late).unwrap().state, TaskState::Cancelled);
+        assert_eq!(
+            scheduler.shutdown(Duration::ZERO),
+            ShutdownSummary::default()
+        );
+    }
+
+    #[test]
+    fn test_runaway_task_times_out() {
+        let scheduler = Scheduler::with_workers(1);
+        let program = Assembler::new()
+            .assemble(".data\n.code\nload $1 #2\njmpb $1")
+            .unwrap();
+        let started = Instant::now();
+        let id = scheduler
+            .submit_with_timeout(program, Duration::from_millis(200))
+            .unwrap();
+        let outcome = scheduler.wait(&id, Duration::from_secs(5)).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(outcome.events.last().unwrap().event, VMEventType::TimedOut);
+        assert_eq!(scheduler.status(&id).unwrap().state, TaskState::TimedOut);
+
+        assert!(scheduler
+            .submit_with_timeout(vec![1, 2, 3], Duration::from_millis(200))
+            .is_err());
+    }
+
+    #[test]
+    fn test_spawned_events_forwarded() {
+        let scheduler = Scheduler::with_workers(1);
+        let (tx, rx) = channel();
+        scheduler.set_event_sink(tx);
+        let crashed = run_to_end(&scheduler, vec![0; 64], "crashes");
+        let forwarded: Vec<VMEvent> = rx.try_iter().collect();
+        assert_eq!(forwarded, crashed.events);
+        assert_eq!(forwarded.last().unwrap().event, VMEventType::Crash);
     }
 }
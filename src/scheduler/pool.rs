@@ -0,0 +1,325 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::error;
+
+use super::Priority;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Jobs taken ahead of older ones of lower priority before the oldest queued job gets its
+/// turn, whatever its priority
+pub const STARVATION_LIMIT: usize = 8;
+
+/// A fixed number of worker threads running jobs, higher priorities first and in the order
+/// they were submitted within one. Workers are only started once there is work for them,
+/// and jobs wait in a queue while every one of them is busy
+pub struct Pool {
+    state: Mutex<State>,
+    work: Condvar, // notified when a job is queued, the pool resized or closed
+    done: Condvar, // notified when a job is done or a worker stops
+}
+
+/// Queued jobs by priority, each tagged with when it was queued
+#[derive(Default)]
+struct Queue {
+    levels: [VecDeque<(u64, Job)>; 3], // by `Priority`, lowest first
+    next: u64,                         // tag of the next job queued
+    skipped: usize, // jobs taken in a row while an older one of lower priority waited
+}
+
+impl Queue {
+    fn push(&mut self, priority: Priority, job: Job) {
+        self.levels[priority as usize].push_back((self.next, job));
+        self.next += 1;
+    }
+
+    /// The oldest job of the highest priority waiting, or the oldest of all once
+    /// `STARVATION_LIMIT` jobs were taken ahead of it
+    fn pop(&mut self) -> Option<Job> {
+        let highest = self.levels.iter().rposition(|level| !level.is_empty())?;
+        let oldest = (0..self.levels.len())
+            .filter_map(|at| Some((self.levels[at].front()?.0, at)))
+            .min()
+            .map(|(_, at)| at)?;
+        let from = if oldest != highest && self.skipped < STARVATION_LIMIT {
+            self.skipped += 1;
+            highest
+        } else {
+            self.skipped = 0;
+            oldest
+        };
+        self.levels[from].pop_front().map(|(_, job)| job)
+    }
+
+    fn len(&self) -> usize {
+        self.levels.iter().map(VecDeque::len).sum()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    queue: Queue,
+    size: usize,                  // workers allowed at once
+    workers: usize,               // worker threads alive, busy or not
+    idle: usize,                  // workers waiting for a job
+    running: usize,               // jobs being run right now
+    closed: bool, // set once the owner is gone, letting workers stop when the queue empties
+    slots: Vec<bool>, // which worker numbers are taken by a live worker
+    busy: Vec<Duration>, // time spent running jobs, by worker number
+    handles: Vec<JoinHandle<()>>, // of the worker threads started, until joined
+}
+
+impl Pool {
+    pub fn new(size: usize) -> Arc<Pool> {
+        Arc::new(Pool {
+            state: Mutex::new(State {
+                size: size.max(1),
+                ..Default::default()
+            }),
+            work: Condvar::new(),
+            done: Condvar::new(),
+        })
+    }
+
+    /// Queue `job` at normal priority
+    pub fn submit(self: &Arc<Self>, job: Job) {
+        self.submit_with_priority(job, Priority::Normal);
+    }
+
+    /// Queue `job`, starting a worker for it if no idle one is left and the pool has room
+    pub fn submit_with_priority(self: &Arc<Self>, job: Job, priority: Priority) {
+        let mut state = self.lock();
+        state.queue.push(priority, job);
+        self.start_workers(&mut state);
+        self.work.notify_one();
+    }
+
+    /// Allow `size` workers at once from now on. Workers beyond it stop once their job is done
+    pub fn resize(self: &Arc<Self>, size: usize) {
+        let mut state = self.lock();
+        state.size = size.max(1);
+        self.start_workers(&mut state);
+        self.work.notify_all();
+    }
+
+    /// Start workers for queued jobs the idle ones can't take, as far as the size allows
+    fn start_workers(self: &Arc<Self>, state: &mut State) {
+        state.handles.retain(|handle| !handle.is_finished());
+        while state.workers < state.size && state.queue.len() > state.idle {
+            state.workers += 1;
+            // a new worker takes the lowest number free, carrying on the busy time of any
+            // worker that had it before
+            let slot = match state.slots.iter().position(|taken| !taken) {
+                Some(slot) => slot,
+                None => {
+                    state.slots.push(false);
+                    state.busy.push(Duration::ZERO);
+                    state.slots.len() - 1
+                }
+            };
+            state.slots[slot] = true;
+            let pool = self.clone();
+            let handle = thread::spawn(move || pool.work(slot));
+            state.handles.push(handle);
+        }
+    }
+
+    /// Let workers stop once the queue is empty
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.work.notify_all();
+    }
+
+    /// Wait until no job is queued or running, or `deadline` passed. False in the latter case
+    pub fn wait_idle(&self, deadline: Instant) -> bool {
+        let mut state = self.lock();
+        while state.queue.len() > 0 || state.running > 0 {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return false;
+            }
+            state = self
+                .done
+                .wait_timeout(state, left)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        true
+    }
+
+    /// Close the pool and wait until `deadline` for its workers to run what's queued and
+    /// stop, joining those that did. False if some are still running jobs by then
+    pub fn join(&self, deadline: Instant) -> bool {
+        self.close();
+        let mut state = self.lock();
+        while state.workers > 0 {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            state = self
+                .done
+                .wait_timeout(state, left)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        let stopped = state.workers == 0;
+        let (finished, running) = state
+            .handles
+            .drain(..)
+            .partition(|handle| handle.is_finished() || stopped);
+        state.handles = running;
+        drop(state);
+        for handle in finished {
+            let _ = handle.join();
+        }
+        stopped
+    }
+
+    pub fn size(&self) -> usize {
+        self.lock().size
+    }
+
+    /// Jobs waiting for a worker
+    pub fn queued(&self) -> usize {
+        self.lock().queue.len()
+    }
+
+    /// Jobs being run right now
+    pub fn running(&self) -> usize {
+        self.lock().running
+    }
+
+    /// Time spent running jobs, by worker number
+    pub fn busy(&self) -> Vec<Duration> {
+        self.lock().busy.clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Run queued jobs one after the other as worker number `slot`, until the pool shrinks
+    /// or closes
+    fn work(&self, slot: usize) {
+        let mut state = self.lock();
+        loop {
+            if state.workers > state.size {
+                break;
+            }
+            if let Some(job) = state.queue.pop() {
+                state.running += 1;
+                drop(state);
+                let started = Instant::now();
+                // a job that panics takes neither the worker nor the counts with it
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    error!("A scheduled job panicked");
+                }
+                state = self.lock();
+                state.running -= 1;
+                state.busy[slot] += started.elapsed();
+                self.done.notify_all();
+                continue;
+            }
+            if state.closed {
+                break;
+            }
+            state.idle += 1;
+            state = self.work.wait(state).unwrap_or_else(|e| e.into_inner());
+            state.idle -= 1;
+        }
+        state.workers -= 1;
+        state.slots[slot] = false;
+        self.done.notify_all();
+    }
+}
+
+impl fmt::Debug for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.lock();
+        f.debug_struct("Pool")
+            .field("size", &state.size)
+            .field("workers", &state.workers)
+            .field("queued", &state.queue.len())
+            .field("running", &state.running)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_never_more_jobs_than_workers() {
+        let pool = Pool::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let started = Arc::new(Mutex::new(vec![]));
+        for job in 0..4 {
+            let (running, most, started) = (running.clone(), most.clone(), started.clone());
+            pool.submit(Box::new(move || {
+                started.lock().unwrap().push(job);
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(50));
+                running.fetch_sub(1, Ordering::SeqCst);
+            }));
+        }
+        assert_eq!(pool.queued() + pool.running(), 4);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while started.lock().unwrap().len() < 4 || pool.running() > 0 {
+            assert!(Instant::now() < deadline, "jobs never finished");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+        // first in, first out, though the first two start at once
+        let mut started = started.lock().unwrap().clone();
+        started[..2].sort();
+        started[2..].sort();
+        assert_eq!(started, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_oldest_job_not_starved() {
+        let mut queue = Queue::default();
+        let taken = Arc::new(Mutex::new(vec![]));
+        let job = |name: &'static str| -> Job {
+            let taken = taken.clone();
+            Box::new(move || taken.lock().unwrap().push(name))
+        };
+        queue.push(Priority::Low, job("low"));
+        for _ in 0..=STARVATION_LIMIT {
+            queue.push(Priority::High, job("high"));
+        }
+        while let Some(job) = queue.pop() {
+            job();
+        }
+        let taken = taken.lock().unwrap();
+        assert_eq!(taken.len(), STARVATION_LIMIT + 2);
+        assert!(taken[..STARVATION_LIMIT].iter().all(|name| *name == "high"));
+        assert_eq!(taken[STARVATION_LIMIT], "low");
+    }
+
+    #[test]
+    fn test_panicking_job_keeps_worker() {
+        let pool = Pool::new(1);
+        pool.submit(Box::new(|| panic!("job failed")));
+        let (tx, rx) = std::sync::mpsc::channel();
+        pool.submit(Box::new(move || tx.send(()).unwrap()));
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
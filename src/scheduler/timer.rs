@@ -0,0 +1,182 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+/// Starts a run of a scheduled program, returning the id of its task. None if it couldn't
+pub type Launch = Box<dyn Fn(&[u8], &str) -> Option<Uuid> + Send + Sync>;
+
+/// A program run after a delay, and again on an interval if it repeats
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Schedule {
+    pub id: Uuid,
+    pub description: String,
+    pub every: Option<Duration>, // between runs, for one that repeats
+    pub max_runs: Option<usize>, // after which it stops repeating
+    pub runs: Vec<Uuid>,         // tasks started so far, oldest first
+    pub active: bool,            // whether it will run again
+    #[serde(skip)]
+    program: Vec<u8>,
+    #[serde(skip)]
+    next: Instant,
+}
+
+impl Schedule {
+    pub fn new(program: Vec<u8>, description: &str, delay: Duration) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            description: description.to_string(),
+            every: None,
+            max_runs: None,
+            runs: vec![],
+            active: true,
+            program,
+            next: Instant::now() + delay,
+        }
+    }
+
+    /// Run again every `interval` after the first, at most `max_runs` times in all if given
+    pub fn with_repeat(mut self, interval: Duration, max_runs: Option<usize>) -> Self {
+        self.every = Some(interval);
+        self.max_runs = max_runs;
+        self
+    }
+
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    /// Start a run with `launch`, then work out when the next one is due, if any
+    fn run(&mut self, launch: &Launch, now: Instant) {
+        if self.max_runs.is_some_and(|max| self.runs.len() >= max) {
+            self.active = false;
+            return;
+        }
+        let description = format!("{} (run {})", self.description, self.runs.len() + 1);
+        if let Some(task) = launch(&self.program, &description) {
+            self.runs.push(task);
+        }
+        let exhausted = self.max_runs.is_some_and(|max| self.runs.len() >= max);
+        match self.every {
+            // a run the timer was late for isn't made up for with a burst of them
+            Some(every) if !exhausted => self.next = (self.next + every).max(now),
+            _ => self.active = false,
+        }
+    }
+}
+
+/// Moves scheduled programs into the scheduler's queue as they come due, on a thread of its
+/// own started with the first of them
+pub struct Timer {
+    state: Mutex<State>,
+    changed: Condvar, // notified when a schedule is added or stopped, or the timer closed
+    launch: Launch,
+}
+
+#[derive(Default)]
+struct State {
+    schedules: HashMap<Uuid, Schedule>,
+    started: bool,
+    closed: bool,
+}
+
+impl Timer {
+    pub fn new(launch: Launch) -> Arc<Timer> {
+        Arc::new(Timer {
+            state: Default::default(),
+            changed: Condvar::new(),
+            launch,
+        })
+    }
+
+    pub fn add(self: &Arc<Self>, schedule: Schedule) {
+        let mut state = self.lock();
+        state.schedules.insert(schedule.id, schedule);
+        if !state.started {
+            state.started = true;
+            let timer = self.clone();
+            thread::spawn(move || timer.tick());
+        }
+        self.changed.notify_all();
+    }
+
+    /// Stop the schedule `id` from running again. False if there's no such schedule or it
+    /// already stopped
+    pub fn stop(&self, id: &Uuid) -> bool {
+        let mut state = self.lock();
+        match state.schedules.get_mut(id) {
+            Some(schedule) if schedule.active => {
+                schedule.active = false;
+                self.changed.notify_all();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<Schedule> {
+        self.lock().schedules.get(id).cloned()
+    }
+
+    /// Every schedule, those to run soonest first and stopped ones last
+    pub fn list(&self) -> Vec<Schedule> {
+        let mut schedules: Vec<Schedule> = self.lock().schedules.values().cloned().collect();
+        schedules.sort_by_key(|schedule| (!schedule.active, schedule.next));
+        schedules
+    }
+
+    /// Let the timer thread stop, leaving what's still scheduled unrun
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.changed.notify_all();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Start the runs that are due, then sleep until the next one is or something changes
+    fn tick(&self) {
+        let mut state = self.lock();
+        while !state.closed {
+            let now = Instant::now();
+            for schedule in state.schedules.values_mut() {
+                if schedule.active && schedule.next <= now {
+                    schedule.run(&self.launch, now);
+                }
+            }
+            let next = state
+                .schedules
+                .values()
+                .filter(|schedule| schedule.active)
+                .map(|schedule| schedule.next)
+                .min();
+            state = match next {
+                Some(next) => {
+                    let left = next.saturating_duration_since(Instant::now());
+                    self.changed
+                        .wait_timeout(state, left)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self.changed.wait(state).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+}
+
+impl fmt::Debug for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.lock();
+        f.debug_struct("Timer")
+            .field("schedules", &state.schedules.len())
+            .field("started", &state.started)
+            .finish()
+    }
+}
@@ -1,38 +1,119 @@
 use byteorder::{LittleEndian, ReadBytesExt};
 use chrono::{DateTime, Utc};
 use log::debug;
+use serde::{Deserialize, Serialize};
 use std::{
-    f64::EPSILON,
+    fmt, fs,
     io::Cursor,
     net::SocketAddr,
-    sync::{Arc, RwLock},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Sender},
+        Arc, RwLock,
+    },
     thread,
+    time::{Duration, Instant},
 };
 use uuid::Uuid;
 
 use crate::{
     assembler::{PIE_HEADER_LENGTH, PIE_HEADER_PREFIX},
-    cluster::{cluster_server::ClusterServer, manager::Manager},
-    error::Result,
+    cluster::{
+        address,
+        cluster_server::ClusterServer,
+        event_log,
+        gossip::{self, GossipConfig},
+        join::{self, LocalNode},
+        manager::Manager,
+        message::NodeMetadata,
+        persist,
+        reconnect::ReconnectPolicy,
+        role::NodeRole,
+        transport::Transport,
+        NodeAddress, NodeAlias,
+    },
+    error::{HeaderError, IridiumError, Result, VmError, VmErrorKind},
     instruction::Opcode,
+    scheduler::Scheduler,
 };
 
+/// Instructions run between checks of the clock against a time limit
+const TIME_LIMIT_CHECK_INTERVAL: u64 = 1024;
+
 // const DEFAULT_PEER_LISTENING_HOST: &str = "127.0.0.1";
 // const DEFAULT_PEER_LISTENING_PORT: &str = "2254";
 // const DEFAULT_NODE_ALIAS: &str = "";
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum VMEventType {
     Start,
     Stop,
     Crash,
+    Cancelled,
+    TimedOut,
+    Interrupted, // stopped because the process was told to shut down
+}
+
+impl fmt::Display for VMEventType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            VMEventType::Start => "Start",
+            VMEventType::Stop => "Stop",
+            VMEventType::Crash => "Crash",
+            VMEventType::Cancelled => "Cancelled",
+            VMEventType::TimedOut => "TimedOut",
+            VMEventType::Interrupted => "Interrupted",
+        };
+        // so padding given to an event lines up its columns
+        f.pad(name)
+    }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct VMEvent {
-    event: VMEventType,
-    at: DateTime<Utc>,
-    app_id: Uuid,
+    pub event: VMEventType,
+    pub at: DateTime<Utc>,
+    pub app_id: Uuid,
+    #[serde(default)]
+    pub context: Option<String>, // what happened, such as why a crash did
+}
+
+impl fmt::Display for VMEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}  {:<9}  {}",
+            self.at.format("%Y-%m-%d %H:%M:%S%.3f"),
+            self.event,
+            self.app_id
+        )?;
+        if let Some(context) = &self.context {
+            write!(f, "  {}", context)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the VM is at after an instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecutionState {
+    Continue,     // on to the next instruction
+    Halted,       // a HLT instruction ended the program
+    EndOfProgram, // there is no instruction left to run
+}
+
+/// The state a program runs on, without the events, cluster membership and settings of the
+/// VM it was taken from
+#[derive(Clone, Debug, PartialEq)]
+pub struct VMSnapshot {
+    pub registers: [i32; 32],
+    pub float_registers: [f64; 32],
+    pub program: Vec<u8>,
+    pub heap: Vec<u8>,
+    pub ro_data: Vec<u8>,
+    pub remainder: u32,
+    pub equal_flag: bool,
 }
 
 /// Read 32-bit data (instruction), execute, repeat
@@ -48,11 +129,25 @@ pub struct VM {
     ro_data: Vec<u8>,         // read-only section data
     id: Uuid,                 // UUID
     events: Vec<VMEvent>,     // events
+    instruction_count: u64,   // number of instructions executed
     pub logical_cores: usize, // number of CPUs
     pub alias: Option<String>, // An alias that can be specified by the user and used to refer to the Node
     peer_host: Option<String>, // Server address that the VM will bind to for server-to-server communications
     pub peer_port: Option<String>, // Port the server will bind to for server-to-server communications
     pub conn_manager: Arc<RwLock<Manager>>, // Data structure to manage remote clients
+    cluster_listening: Arc<AtomicBool>, // Whether the cluster server is accepting peer connections
+    reconnect_policy: ReconnectPolicy, // How to retry cluster members whose connection drops
+    gossip_config: GossipConfig,   // How often to share membership with cluster members
+    cluster_secret: Option<String>, // Shared secret cluster peers must present in their hello
+    cluster_transport: Transport, // What connections to cluster peers are carried over, in the clear or TLS
+    cluster_scheduler: Arc<Scheduler>, // Runs programs cluster members submit, whichever side connected
+    data_dir: Option<PathBuf>,         // Where cluster membership is remembered across restarts
+    event_sink: Option<Sender<VMEvent>>, // Where recorded events go to be reported to the cluster's coordinator
+    role: NodeRole,                      // What this node does for the cluster
+    cancel: Option<Arc<AtomicBool>>,     // Set to stop the program before its next instruction
+    interrupt: Option<Arc<AtomicBool>>, // Set when the process is told to shut down, stopping it the same way
+    time_limit: Option<Duration>,       // How long a run may take before it is stopped
+    error: Option<VmError>,             // Why the last run crashed, if an instruction failed
 }
 
 impl VM {
@@ -60,7 +155,7 @@ impl VM {
         Self {
             registers: [0; 32],
             float_registers: [0.0; 32],
-            pc: 65,
+            pc: 0,
             program: Vec::new(),
             remainder: 0,
             equal_flag: false,
@@ -68,187 +163,275 @@ impl VM {
             ro_data: Vec::new(),
             id: Uuid::new_v4(),
             events: Vec::new(),
+            instruction_count: 0,
             logical_cores: num_cpus::get(),
             alias: None,
             peer_host: None,
             peer_port: None,
             conn_manager: Arc::new(RwLock::new(Manager::new())),
+            cluster_listening: Arc::new(AtomicBool::new(false)),
+            reconnect_policy: ReconnectPolicy::default(),
+            gossip_config: GossipConfig::default(),
+            cluster_secret: None,
+            cluster_transport: Transport::plain(),
+            cluster_scheduler: Arc::new(Scheduler::new()),
+            data_dir: None,
+            event_sink: None,
+            role: NodeRole::default(),
+            cancel: None,
+            interrupt: None,
+            time_limit: None,
+            error: None,
         }
     }
 
     /// Wraps execution in a loop so it will continue to run until done or there is an error
     /// executing instructions.
     pub fn run(&mut self) -> Vec<VMEvent> {
-        self.events.push(VMEvent {
-            event: VMEventType::Start,
-            at: Utc::now(),
-            app_id: self.id.to_owned(),
-        });
-        // TODO: Should setup custom errors here
-        if !self.verify_header() {
-            self.events.push(VMEvent {
-                event: VMEventType::Crash,
-                at: Utc::now(),
-                app_id: self.id.to_owned(),
-            });
-            println!("Header was incorrect");
+        self.record(VMEventType::Start);
+        self.error = None;
+        if let Err(e) = self.verify_header() {
+            debug!("VM {} refused its program: {}", self.id, e);
+            let e = VmError {
+                kind: e.into(),
+                pc: 0,
+                opcode: None,
+            };
+            self.record_with(VMEventType::Crash, Some(e.to_string()));
+            self.error = Some(e);
             return self.events.clone();
         }
 
         self.pc = 64 + self.get_starting_offset();
-        let mut is_done = None;
-        while is_done.is_none() {
-            is_done = self.execute_instruction();
+        let deadline = self.time_limit.map(|limit| Instant::now() + limit);
+        loop {
+            if self.is_cancelled() {
+                self.record(VMEventType::Cancelled);
+                return self.events.clone();
+            }
+            if self.is_interrupted() {
+                self.record(VMEventType::Interrupted);
+                return self.events.clone();
+            }
+            // reading the clock for every instruction would slow every program down
+            let check = self
+                .instruction_count
+                .is_multiple_of(TIME_LIMIT_CHECK_INTERVAL);
+            if check && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                self.record(VMEventType::TimedOut);
+                return self.events.clone();
+            }
+            match self.execute_instruction() {
+                Ok(ExecutionState::Continue) => {}
+                Ok(ExecutionState::Halted | ExecutionState::EndOfProgram) => break,
+                Err(e) => {
+                    debug!("VM {} crashed: {}", self.id, e);
+                    self.record_with(VMEventType::Crash, Some(e.to_string()));
+                    self.error = Some(e);
+                    return self.events.clone();
+                }
+            }
         }
-        self.events.push(VMEvent {
-            event: VMEventType::Stop,
+        self.record(VMEventType::Stop);
+        self.events.clone()
+    }
+
+    /// Record an event, passing it on to be reported to the coordinator if there is one
+    fn record(&mut self, event: VMEventType) {
+        self.record_with(event, None);
+    }
+
+    fn record_with(&mut self, event: VMEventType, context: Option<String>) {
+        let event = VMEvent {
+            event,
             at: Utc::now(),
             app_id: self.id.to_owned(),
-        });
-        self.events.clone()
+            context,
+        };
+        if let Some(sink) = &self.event_sink {
+            let _ = sink.send(event.clone());
+        }
+        self.events.push(event);
     }
 
-    /// Executes one instruction. Meant to allow for more controlled execution of the VM
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    /// Executes one instruction. Meant to allow for more controlled execution of the VM.
+    /// Fails with what went wrong if the instruction couldn't be carried out
+    pub fn run_once(&mut self) -> Result<ExecutionState> {
+        Ok(self.execute_instruction()?)
     }
 
-    fn execute_instruction(&mut self) -> Option<u32> {
+    fn execute_instruction(&mut self) -> std::result::Result<ExecutionState, VmError> {
         if self.pc >= self.program.len() {
-            return Some(1);
+            return Ok(ExecutionState::EndOfProgram);
         }
-        match self.decode_opcode() {
-            // halt
+        self.instruction_count += 1;
+        let pc = self.pc;
+        let opcode = self.decode_opcode();
+        self.execute(opcode).map_err(|kind| VmError {
+            kind,
+            pc,
+            opcode: (opcode != Opcode::IGL).then_some(opcode),
+        })
+    }
+
+    /// Carry out `opcode`, whose byte was just read
+    fn execute(&mut self, opcode: Opcode) -> std::result::Result<ExecutionState, VmErrorKind> {
+        match opcode {
+            // HLT, padded to a whole instruction so whatever is added after it lines up
             Opcode::HLT => {
-                println!("HLT encountered");
-                return None;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                return Ok(ExecutionState::Halted);
             }
             // LOAD $1 #15
             Opcode::LOAD => {
-                let register = self.next_8_bits() as usize;
-                let number = self.next_16_bits();
+                let register = self.register()?;
+                let number = self.next_16_bits()?;
                 self.registers[register] = number as i32;
             }
             // ADD $0 $1 $2
             Opcode::ADD => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 + register2;
+                let register1 = self.registers[self.register()?];
+                let register2 = self.registers[self.register()?];
+                let sum = register1.checked_add(register2);
+                self.registers[self.register()?] = sum.ok_or(VmErrorKind::Overflow)?;
             }
             // SUB $0 $1 $2
             Opcode::SUB => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 - register2;
+                let register1 = self.registers[self.register()?];
+                let register2 = self.registers[self.register()?];
+                let difference = register1.checked_sub(register2);
+                self.registers[self.register()?] = difference.ok_or(VmErrorKind::Overflow)?;
             }
             // MUL $0 $1 $2
             Opcode::MUL => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 * register2;
+                let register1 = self.registers[self.register()?];
+                let register2 = self.registers[self.register()?];
+                let product = register1.checked_mul(register2);
+                self.registers[self.register()?] = product.ok_or(VmErrorKind::Overflow)?;
             }
             // DIV $0 $1 $2
             Opcode::DIV => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 / register2;
+                let register1 = self.registers[self.register()?];
+                let register2 = self.registers[self.register()?];
+                let destination = self.register()?;
+                if register2 == 0 {
+                    return Err(VmErrorKind::DivideByZero);
+                }
+                // i32::MIN / -1 is the one quotient that doesn't fit
+                let quotient = register1.checked_div(register2);
+                self.registers[destination] = quotient.ok_or(VmErrorKind::Overflow)?;
                 self.remainder = (register1 % register2) as u32;
             }
             // JMP $0
             Opcode::JMP => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc = target as usize;
+                let target = self.registers[self.register()?];
+                self.jump_to(target as i64)?;
             }
             // JMPF $0
             Opcode::JMPF => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc += target as usize;
+                let target = self.registers[self.register()?];
+                self.jump_to(self.pc as i64 + target as i64)?;
             }
             // JMPB $0
             Opcode::JMPB => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc -= target as usize;
+                let target = self.registers[self.register()?];
+                self.jump_to(self.pc as i64 - target as i64)?;
             }
             // EQ $0 $1
             Opcode::EQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.register()?];
+                let register2 = self.registers[self.register()?];
                 self.equal_flag = register1 == register2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             // NEQ $0 $1
             Opcode::NEQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.register()?];
+                let register2 = self.registers[self.register()?];
                 self.equal_flag = register1 != register2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             // GT $0 $1
             Opcode::GT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.register()?];
+                let register2 = self.registers[self.register()?];
                 self.equal_flag = register1 > register2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             // GTE $0 $1
             Opcode::GTE => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.register()?];
+                let register2 = self.registers[self.register()?];
                 self.equal_flag = register1 >= register2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             // LT $0 $1
             Opcode::LT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.register()?];
+                let register2 = self.registers[self.register()?];
                 self.equal_flag = register1 < register2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             // LTE $0 $1
             Opcode::LTE => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.register()?];
+                let register2 = self.registers[self.register()?];
                 self.equal_flag = register1 <= register2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             // ALOC $0
             Opcode::ALOC => {
-                let bytes = self.registers[self.next_8_bits() as usize];
-                let new_end = self.heap.len() as i32 + bytes;
+                let bytes = self.registers[self.register()?];
+                let new_end = self.heap.len() as i64 + bytes as i64;
+                if new_end < 0 {
+                    return Err(VmErrorKind::HeapOutOfBounds {
+                        offset: new_end,
+                        len: self.heap.len(),
+                    });
+                }
                 self.heap.resize(new_end as usize, 0)
             }
             // INC $0
             Opcode::INC => {
-                let position = self.next_8_bits() as usize;
-                self.registers[position] += 1;
-                self.next_8_bits();
-                self.next_8_bits();
+                let position = self.register()?;
+                let next = self.registers[position].checked_add(1);
+                self.registers[position] = next.ok_or(VmErrorKind::Overflow)?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
             }
             // DEC $0
             Opcode::DEC => {
-                let position = self.next_8_bits() as usize;
-                self.registers[position] -= 1;
-                self.next_8_bits();
-                self.next_8_bits();
+                let position = self.register()?;
+                let next = self.registers[position].checked_sub(1);
+                self.registers[position] = next.ok_or(VmErrorKind::Overflow)?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
             }
             // JMPE $0
             Opcode::JMPE => {
                 if self.equal_flag {
-                    let target = self.registers[self.next_8_bits() as usize];
-                    self.pc = target as usize;
+                    let target = self.registers[self.register()?];
+                    self.jump_to(target as i64)?;
                 } else {
-                    // TODO: Fix the bits
+                    self.next_8_bits()?;
+                    self.next_8_bits()?;
+                    self.next_8_bits()?;
                 }
             }
             // PRTS @symbol_name/$0
             Opcode::PRTS => {
-                let starting_offset = self.next_16_bits() as usize;
-                let ending_offset = self.ro_data[starting_offset..]
-                    .iter()
-                    .position(|&x| x != 0)
-                    .unwrap();
-                let result = std::str::from_utf8(&self.ro_data[starting_offset..ending_offset]);
-                match result {
+                let starting_offset = self.next_16_bits()? as usize;
+                self.next_8_bits()?;
+                let out_of_bounds = VmErrorKind::ReadOnlyOutOfBounds {
+                    offset: starting_offset,
+                    len: self.ro_data.len(),
+                };
+                let string = self.ro_data.get(starting_offset..).ok_or(out_of_bounds)?;
+                // a string runs up to the zero byte ending it
+                let length = string.iter().position(|&x| x == 0).ok_or(out_of_bounds)?;
+                match std::str::from_utf8(&string[..length]) {
                     Ok(s) => {
                         print!("{}", s);
                     }
@@ -259,96 +442,96 @@ impl VM {
             }
             // Begin floating point 64-bit instructions
             Opcode::LOADF64 => {
-                let register = self.next_8_bits() as usize;
-                let number = f64::from(self.next_16_bits());
+                let register = self.register()?;
+                let number = f64::from(self.next_16_bits()?);
                 self.float_registers[register] = number;
             }
             Opcode::ADDF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
-                self.float_registers[self.next_8_bits() as usize] = register1 + register2;
+                let register1 = self.float_registers[self.register()?];
+                let register2 = self.float_registers[self.register()?];
+                self.float_registers[self.register()?] = register1 + register2;
             }
             Opcode::SUBF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
-                self.float_registers[self.next_8_bits() as usize] = register1 - register2;
+                let register1 = self.float_registers[self.register()?];
+                let register2 = self.float_registers[self.register()?];
+                self.float_registers[self.register()?] = register1 - register2;
             }
             Opcode::MULF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
-                self.float_registers[self.next_8_bits() as usize] = register1 * register2;
+                let register1 = self.float_registers[self.register()?];
+                let register2 = self.float_registers[self.register()?];
+                self.float_registers[self.register()?] = register1 * register2;
             }
             Opcode::DIVF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
-                self.float_registers[self.next_8_bits() as usize] = register1 / register2;
+                let register1 = self.float_registers[self.register()?];
+                let register2 = self.float_registers[self.register()?];
+                self.float_registers[self.register()?] = register1 / register2;
             }
             Opcode::EQF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
-                self.equal_flag = (register1 - register2).abs() < EPSILON;
-                self.next_8_bits();
+                let register1 = self.float_registers[self.register()?];
+                let register2 = self.float_registers[self.register()?];
+                self.equal_flag = (register1 - register2).abs() < f64::EPSILON;
+                self.next_8_bits()?;
             }
             Opcode::NEQF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
-                self.equal_flag = (register1 - register2).abs() > EPSILON;
-                self.next_8_bits();
+                let register1 = self.float_registers[self.register()?];
+                let register2 = self.float_registers[self.register()?];
+                self.equal_flag = (register1 - register2).abs() > f64::EPSILON;
+                self.next_8_bits()?;
             }
             Opcode::GTF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
+                let register1 = self.float_registers[self.register()?];
+                let register2 = self.float_registers[self.register()?];
                 self.equal_flag = register1 > register2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             Opcode::GTEF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
+                let register1 = self.float_registers[self.register()?];
+                let register2 = self.float_registers[self.register()?];
                 self.equal_flag = register1 >= register2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             Opcode::LTF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
+                let register1 = self.float_registers[self.register()?];
+                let register2 = self.float_registers[self.register()?];
                 self.equal_flag = register1 < register2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             Opcode::LTEF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
+                let register1 = self.float_registers[self.register()?];
+                let register2 = self.float_registers[self.register()?];
                 self.equal_flag = register1 <= register2;
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             Opcode::NOP => {
-                self.next_8_bits();
-                self.next_8_bits();
-                self.next_8_bits();
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
             }
             Opcode::SHL => {
-                let reg_num = self.next_8_bits() as usize;
-                let num_bits = match self.next_8_bits() {
+                let reg_num = self.register()?;
+                let num_bits = match self.next_8_bits()? {
                     0 => 16,
                     other => other,
                 };
                 self.registers[reg_num] = self.registers[reg_num].wrapping_shl(num_bits.into());
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
             // SHR $<reg_num> #<number of bits> shifts to the right by default 16 bits
             Opcode::SHR => {
-                let reg_num = self.next_8_bits() as usize;
-                let num_bits = match self.next_8_bits() {
+                let reg_num = self.register()?;
+                let num_bits = match self.next_8_bits()? {
                     0 => 16,
                     other => other,
                 };
                 self.registers[reg_num] = self.registers[reg_num].wrapping_shr(num_bits.into());
-                self.next_8_bits();
+                self.next_8_bits()?;
             }
-            _ => {
-                println!("Unrecognized opcode found! Terminating!");
-                return Some(1);
+            Opcode::IGL => {
+                return Err(VmErrorKind::UnknownOpcode(self.program[self.pc - 1]));
             }
+            _ => return Err(VmErrorKind::UnsupportedOpcode),
         }
-        None
+        Ok(ExecutionState::Continue)
     }
 
     /// Get starting offset of the section after read-only
@@ -357,6 +540,121 @@ impl VM {
         rdr.read_u32::<LittleEndian>().unwrap() as usize
     }
 
+    /// Replaces the program with a pre-assembled image (header + read-only data + code) and
+    /// splits the read-only section into ro_data. Returns the offset the code section starts at
+    pub fn load_bytecode(&mut self, image: Vec<u8>) -> Result<usize> {
+        check_header(&image)?;
+        let mut rdr = Cursor::new(&image[4..8]);
+        let ro_len = rdr.read_u32::<LittleEndian>()? as usize;
+        let entry_offset = PIE_HEADER_LENGTH + ro_len;
+        if image.len() < entry_offset {
+            return Err(IridiumError::StringError(format!(
+                "Bytecode is truncated: header declares {} bytes of read-only data but only {} are present",
+                ro_len,
+                image.len() - PIE_HEADER_LENGTH
+            )));
+        }
+
+        self.ro_data = image[PIE_HEADER_LENGTH..entry_offset].to_vec();
+        self.program = image;
+        self.pc = entry_offset;
+        Ok(entry_offset)
+    }
+
+    /// Runs the programs cluster members submit to this node
+    pub fn cluster_scheduler(&self) -> &Scheduler {
+        &self.cluster_scheduler
+    }
+
+    /// Unique id of this VM
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Current program counter
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Number of bytes allocated on the heap
+    pub fn heap_size(&self) -> usize {
+        self.heap.len()
+    }
+
+    /// Copy the state a program running on this VM would see
+    pub fn snapshot(&self) -> VMSnapshot {
+        VMSnapshot {
+            registers: self.registers,
+            float_registers: self.float_registers,
+            program: self.program.clone(),
+            heap: self.heap.clone(),
+            ro_data: self.ro_data.clone(),
+            remainder: self.remainder,
+            equal_flag: self.equal_flag,
+        }
+    }
+
+    /// A new VM carrying on from `snapshot`
+    pub fn from_snapshot(snapshot: VMSnapshot) -> VM {
+        VM {
+            registers: snapshot.registers,
+            float_registers: snapshot.float_registers,
+            program: snapshot.program,
+            heap: snapshot.heap,
+            ro_data: snapshot.ro_data,
+            remainder: snapshot.remainder,
+            equal_flag: snapshot.equal_flag,
+            ..VM::new()
+        }
+    }
+
+    /// The instruction that failed, when the last run crashed on one
+    pub fn error(&self) -> Option<&VmError> {
+        self.error.as_ref()
+    }
+
+    /// Events recorded by this VM
+    pub fn events(&self) -> &[VMEvent] {
+        &self.events
+    }
+
+    /// Host the cluster server binds to, if configured
+    pub fn peer_host(&self) -> Option<&str> {
+        self.peer_host.as_deref()
+    }
+
+    /// How cluster members whose connection drops are retried
+    pub fn reconnect_policy(&self) -> &ReconnectPolicy {
+        &self.reconnect_policy
+    }
+
+    /// If the cluster server is currently accepting peer connections
+    pub fn is_cluster_listening(&self) -> bool {
+        self.cluster_listening.load(Ordering::SeqCst)
+    }
+
+    /// Number of instructions executed so far
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    /// Replaces the read-only data used by the loaded program
+    pub fn set_ro_data(&mut self, ro_data: Vec<u8>) {
+        self.ro_data = ro_data;
+    }
+
+    /// Empties the program and its read-only data and resets the program counter
+    pub fn clear_program(&mut self) {
+        self.program.clear();
+        self.ro_data.clear();
+        self.pc = 0;
+    }
+
+    /// Read-only data of the loaded program
+    pub fn ro_data(&self) -> &[u8] {
+        &self.ro_data
+    }
+
     /// Adds an arbitrary byte to the VM's program
     pub fn add_byte(&mut self, b: u8) {
         self.program.push(b);
@@ -390,25 +688,260 @@ impl VM {
         self
     }
 
-    /// Listen for peer connections
-    pub fn bind_cluster_server(&mut self) {
-        let host = self.peer_host.as_ref().unwrap();
-        let port = self.peer_port.as_ref().unwrap();
+    /// Retry cluster members whose connection drops according to `policy`
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = policy;
+        self
+    }
+
+    /// Share membership with cluster members according to `config`
+    pub fn with_gossip_config(mut self, config: GossipConfig) -> Self {
+        self.gossip_config = config;
+        self
+    }
+
+    /// Run at most `cores` programs for cluster members at once
+    pub fn with_logical_cores(mut self, cores: usize) -> Self {
+        self.logical_cores = cores;
+        self.cluster_scheduler.set_workers(cores);
+        self
+    }
+
+    /// Require cluster peers to share `secret`, and present it when joining
+    pub fn with_cluster_secret(mut self, secret: Option<String>) -> Self {
+        self.cluster_secret = secret;
+        self
+    }
+
+    /// Carry connections to cluster peers over `transport`, which every peer must share
+    pub fn with_cluster_transport(mut self, transport: Transport) -> Self {
+        self.cluster_transport = transport;
+        self
+    }
+
+    /// Remember cluster members and reported events under `data_dir`, so members can be
+    /// rejoined after a restart
+    pub fn with_data_dir(mut self, data_dir: PathBuf) -> Self {
+        self.data_dir = Some(data_dir);
+        self
+    }
+
+    /// Where this VM stores its data, if anywhere
+    pub fn data_dir(&self) -> Option<&Path> {
+        self.data_dir.as_deref()
+    }
+
+    /// Take on `role` in the cluster
+    pub fn with_role(mut self, role: NodeRole) -> Self {
+        self.role = role;
+        self
+    }
+
+    /// What this node does for the cluster
+    pub fn role(&self) -> NodeRole {
+        self.role
+    }
+
+    /// Stop running the program, recording it as cancelled, once `cancel` is set
+    pub fn with_cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Stop running the program, recording it as interrupted, once `interrupt` is set. Unlike
+    /// `cancel`, which stops one program, it is shared by everything a signal should stop
+    pub fn with_interrupt(mut self, interrupt: Arc<AtomicBool>) -> Self {
+        self.interrupt = Some(interrupt);
+        self
+    }
+
+    /// Stop a run that takes longer than `limit`, recording it as timed out
+    pub fn with_time_limit(mut self, limit: Duration) -> Self {
+        self.time_limit = Some(limit);
+        self
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+
+    fn is_interrupted(&self) -> bool {
+        self.interrupt
+            .as_ref()
+            .is_some_and(|interrupt| interrupt.load(Ordering::Relaxed))
+    }
+
+    /// Send every event this VM records to `sink` as well
+    pub fn with_event_sink(mut self, sink: Option<Sender<VMEvent>>) -> Self {
+        self.event_sink = sink;
+        self
+    }
+
+    /// Report the events of this VM, and of programs it runs for cluster members, to the
+    /// member `coordinator`. Reporting to its own alias makes this node the coordinator
+    pub fn with_event_coordinator(mut self, coordinator: Option<NodeAlias>) -> Self {
+        if let Some(coordinator) = coordinator {
+            let (sink, events) = channel();
+            event_log::forward(Arc::downgrade(&self.conn_manager), coordinator, events);
+            self.cluster_scheduler.set_event_sink(sink.clone());
+            self.event_sink = Some(sink);
+        }
+        self
+    }
+
+    /// Give this VM an alias made from its id, such as `node-1a2b3c`, if it has none, so it
+    /// can take part in a cluster. Returns the alias if one had to be made up
+    pub fn ensure_alias(&mut self) -> Option<String> {
+        if self.alias.is_some() {
+            return None;
+        }
+        let alias = format!("node-{}", &self.id.simple().to_string()[..6]);
+        self.alias = Some(alias.clone());
+        Some(alias)
+    }
+
+    /// Listen for peer connections, returning the address actually bound. Port 0 lets the
+    /// OS pick a free port, which then becomes this node's peer port
+    pub fn bind_cluster_server(&mut self) -> Result<SocketAddr> {
+        let local = self.local_node()?;
+        let listener = address::bind(&local.host, &local.port)?;
+        let bound = listener.local_addr()?;
+        // members are told the address actually bound, not a name they may resolve otherwise
+        self.peer_host = Some(bound.ip().to_string());
+        self.peer_port = Some(bound.port().to_string());
+        let local = self.local_node()?;
         debug!(
-            "Node {:?} is listening for incoming connections on {}:{}",
-            self.alias, host, port,
+            "Node {} is listening for incoming connections on {}",
+            local.alias, bound
         );
-        let socket_addr = (host.to_owned() + ":" + port)
-            .parse::<SocketAddr>()
-            .unwrap();
         let conn_manager = self.conn_manager.clone();
-        let alias = self.alias.clone().unwrap();
+        let alias = local.alias.clone();
+        let listening = self.cluster_listening.clone();
+        let metadata = self.node_metadata();
+        let secret = self.cluster_secret.clone();
+        let scheduler = self.cluster_scheduler.clone();
+        let transport = self.cluster_transport.clone();
+        self.start_membership(local);
+        Manager::supervise(&self.conn_manager);
         debug!("Spawning listening thread");
         thread::spawn(move || -> Result<()> {
-            let mut server = ClusterServer::new(alias, conn_manager);
-            server.listen(socket_addr)?;
+            let mut server = ClusterServer::new(alias, conn_manager)
+                .with_listening_flag(listening)
+                .with_metadata(metadata)
+                .with_secret(secret)
+                .with_scheduler(scheduler)
+                .with_transport(transport);
+            server.listen_on(listener)?;
             Ok(())
         });
+        Ok(bound)
+    }
+
+    /// What this node tells the cluster about itself
+    pub fn node_metadata(&self) -> NodeMetadata {
+        NodeMetadata {
+            logical_cores: Some(self.logical_cores),
+            vm_id: Some(self.id),
+            role: Some(self.role),
+        }
+    }
+
+    /// Join the cluster through the node at `addr` and connect to every member it lists.
+    /// What those connections receive is reported on `output`, or printed without one
+    pub fn join_cluster(
+        &self,
+        addr: &str,
+        output: Option<Sender<String>>,
+    ) -> Result<(NodeAlias, Vec<NodeAddress>)> {
+        self.cluster_joiner(output)?(addr)
+    }
+
+    /// Something that joins the cluster through a node the way `join_cluster` does, without
+    /// borrowing the VM, so joining can go on in the background while the VM is in use
+    pub fn cluster_joiner(
+        &self,
+        output: Option<Sender<String>>,
+    ) -> Result<impl Fn(&str) -> Result<(NodeAlias, Vec<NodeAddress>)> + Send + 'static> {
+        let mut local = self.local_node()?;
+        local.output = output;
+        self.start_membership(local.clone());
+        Manager::supervise(&self.conn_manager);
+        let manager = self.conn_manager.clone();
+        Ok(move |addr: &str| join::join(&local, &manager, addr))
+    }
+
+    /// Reconnect to the members saved in the data directory, in the background and with the
+    /// usual backoff. Returns how many are being tried
+    pub fn rejoin_cluster(&self) -> Result<usize> {
+        let data_dir = self.data_dir.as_ref().ok_or_else(|| {
+            IridiumError::StringError("Rejoining a cluster needs a data directory".to_string())
+        })?;
+        let local = self.local_node()?;
+        let saved = persist::load(&persist::membership_file(data_dir));
+        self.start_membership(local.clone());
+        Manager::supervise(&self.conn_manager);
+        let rejoining = saved
+            .into_iter()
+            .filter(|member| member.alias != local.alias)
+            .filter(|member| {
+                let (alias, host, port) = (&member.alias, &member.host, &member.port);
+                Manager::reconnect_to(
+                    &self.conn_manager,
+                    alias.clone(),
+                    host.clone(),
+                    port.clone(),
+                )
+            })
+            .count();
+        Ok(rejoining)
+    }
+
+    /// Say goodbye to every cluster member and disconnect from them, returning how many were
+    /// told. Best-effort: members that don't take the message quickly are dropped regardless
+    pub fn leave_cluster(&self) -> usize {
+        Manager::leave_cluster(&self.conn_manager)
+    }
+
+    /// How this node introduces itself to peers
+    fn local_node(&self) -> Result<LocalNode> {
+        let alias = self.alias.as_ref().ok_or_else(|| {
+            IridiumError::StringError("No alias set, pass --node-alias".to_string())
+        })?;
+        let (host, port) = match (&self.peer_host, &self.peer_port) {
+            (Some(host), Some(port)) => (host, port),
+            _ => {
+                return Err(IridiumError::StringError(
+                    "No peer bind configured, pass --peer-host/--peer-port".to_string(),
+                ))
+            }
+        };
+        Ok(LocalNode {
+            alias: alias.clone(),
+            host: host.clone(),
+            port: port.clone(),
+            metadata: self.node_metadata(),
+            scheduler: self.cluster_scheduler.clone(),
+            output: None,
+            secret: self.cluster_secret.clone(),
+            transport: self.cluster_transport.clone(),
+        })
+    }
+
+    /// Let the manager reconnect to lost members and gossip with the rest as `local`
+    fn start_membership(&self, local: LocalNode) {
+        if let Ok(mut manager) = self.conn_manager.write() {
+            manager.set_local_node(local);
+            manager.enable_reconnect(self.reconnect_policy.clone());
+        }
+        gossip::start(&self.conn_manager, self.gossip_config.clone());
+        if let Some(data_dir) = &self.data_dir {
+            persist::start(&self.conn_manager, persist::membership_file(data_dir));
+            if let Ok(mut manager) = self.conn_manager.write() {
+                manager.set_event_log_file(event_log::event_log_file(data_dir));
+            }
+        }
     }
 
     /// Decode current opcode and increment program counter
@@ -419,25 +952,51 @@ impl VM {
     }
 
     /// Read next 8 bits
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
+    fn next_8_bits(&mut self) -> std::result::Result<u8, VmErrorKind> {
+        let result = *self
+            .program
+            .get(self.pc)
+            .ok_or(VmErrorKind::PcOutOfBounds {
+                target: self.pc as i64,
+                len: self.program.len(),
+            })?;
         self.pc += 1;
-        result
+        Ok(result)
     }
 
     /// Read next 16 bits
-    fn next_16_bits(&mut self) -> u16 {
-        let result = ((self.program[self.pc] as u16) << 8) | self.program[self.pc + 1] as u16;
-        self.pc += 2;
-        result
+    fn next_16_bits(&mut self) -> std::result::Result<u16, VmErrorKind> {
+        let high = self.next_8_bits()? as u16;
+        Ok((high << 8) | self.next_8_bits()? as u16)
+    }
+
+    /// Read the number of a register, which must be one of the 32 there are
+    fn register(&mut self) -> std::result::Result<usize, VmErrorKind> {
+        match self.next_8_bits()? {
+            register if (register as usize) < self.registers.len() => Ok(register as usize),
+            register => Err(VmErrorKind::RegisterOutOfRange(register)),
+        }
+    }
+
+    /// Carry on from `target`, which may be the end of the program but not past it
+    fn jump_to(&mut self, target: i64) -> std::result::Result<(), VmErrorKind> {
+        if target < 0 || target > self.program.len() as i64 {
+            return Err(VmErrorKind::PcOutOfBounds {
+                target,
+                len: self.program.len(),
+            });
+        }
+        self.pc = target as usize;
+        Ok(())
     }
 
     /// Processes the header of bytecode the VM wants to execute
-    fn verify_header(&self) -> bool {
-        self.program[0..4] == PIE_HEADER_PREFIX
+    fn verify_header(&self) -> std::result::Result<(), HeaderError> {
+        check_header(&self.program)
     }
 
     /// Prepend header to the body
+    #[cfg(test)]
     fn prepend_header(mut b: Vec<u8>) -> Vec<u8> {
         let mut prepension = vec![];
         for byte in PIE_HEADER_PREFIX.into_iter() {
@@ -451,6 +1010,57 @@ impl VM {
     }
 }
 
+/// Create the data directory `dir` if it doesn't exist yet and make sure files can be
+/// written in it
+pub fn prepare_data_dir(dir: &Path) -> Result<()> {
+    let probe = dir.join(format!(".write-test-{}", Uuid::new_v4()));
+    fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&probe, b""))
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|e| {
+            IridiumError::StringError(format!(
+                "Data directory {} is not writable: {}",
+                dir.display(),
+                e
+            ))
+        })
+}
+
+/// Make sure `alias` can name a node. Aliases are embedded in addresses and cluster
+/// messages, so they can't be empty or contain whitespace or ':'
+pub fn check_alias(alias: &str) -> Result<()> {
+    if alias.is_empty() {
+        return Err(IridiumError::StringError(
+            "A node alias can't be empty".to_string(),
+        ));
+    }
+    if alias.contains(|c: char| c.is_whitespace() || c == ':') {
+        return Err(IridiumError::StringError(format!(
+            "Node alias `{}` can't contain whitespace or ':'",
+            alias
+        )));
+    }
+    Ok(())
+}
+
+/// Whether `image` starts with a header the VM can run
+pub(crate) fn check_header(image: &[u8]) -> std::result::Result<(), HeaderError> {
+    let too_short = Err(HeaderError::TooShort { len: image.len() });
+    // the prefix is checked before the rest is missed, as it tells more of what the file is
+    let Some(prefix) = image.get(..PIE_HEADER_PREFIX.len()) else {
+        return too_short;
+    };
+    let mut found = [0; 4];
+    found.copy_from_slice(prefix);
+    if found != PIE_HEADER_PREFIX {
+        return Err(HeaderError::BadMagic { found });
+    }
+    if image.len() < PIE_HEADER_LENGTH {
+        return too_short;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -468,8 +1078,11 @@ mod tests {
     fn test_prts_opcode() {
         let mut test_vm = VM::get_test_vm();
         test_vm.ro_data.append(&mut vec![72, 101, 108, 108, 111, 0]);
-        test_vm.program = vec![21, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.program = vec![21, 0, 0, 0, 5, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        // the padding after the offset is skipped too, so the next instruction is read whole
+        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.run_once().unwrap(), ExecutionState::Halted);
     }
 
     #[test]
@@ -481,10 +1094,23 @@ mod tests {
     #[test]
     fn test_opcode_hlt() {
         let mut test_vm = VM::new();
-        let test_bytes = vec![0, 0, 0, 0];
+        let test_bytes = vec![5, 0, 0, 0];
         test_vm.program = test_bytes;
+        assert_eq!(test_vm.run_once().unwrap(), ExecutionState::Halted);
+        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.run_once().unwrap(), ExecutionState::EndOfProgram);
+    }
+
+    #[test]
+    fn test_hlt_ends_run() {
+        let mut test_vm = VM::new();
+        test_vm.program = VM::prepend_header(vec![0, 0, 0, 1, 5, 0, 0, 0, 0, 0, 0, 2]);
         test_vm.run();
-        assert_eq!(test_vm.pc, 1);
+        assert_eq!(test_vm.registers[0], 1);
+        assert_eq!(test_vm.pc, PIE_HEADER_LENGTH + 8);
+        let events: Vec<_> = test_vm.events().iter().map(|e| e.event.clone()).collect();
+        assert_eq!(events, [VMEventType::Start, VMEventType::Stop]);
+        assert!(test_vm.error().is_none());
     }
 
     #[test]
@@ -492,7 +1118,8 @@ mod tests {
         let mut test_vm = VM::new();
         let test_bytes = vec![200, 0, 0, 0];
         test_vm.program = test_bytes;
-        test_vm.run();
+        let err = test_vm.run_once().unwrap_err();
+        assert!(err.to_string().contains("Unknown opcode 200"), "{}", err);
         assert_eq!(test_vm.pc, 1);
     }
 
@@ -501,7 +1128,7 @@ mod tests {
         let mut test_vm = VM::get_test_vm();
         test_vm.registers[0] = 1;
         test_vm.program = vec![6, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 1);
     }
 
@@ -510,21 +1137,34 @@ mod tests {
         let mut test_vm = VM::get_test_vm();
         test_vm.registers[0] = 2;
         test_vm.program = vec![7, 0, 0, 0, 6, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 4);
     }
 
+    #[test]
+    fn test_jmpe_opcode() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.registers[0] = 8;
+        test_vm.program = vec![15, 0, 0, 0, 15, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        // not taken, so the operand and padding are skipped
+        assert_eq!(test_vm.pc, 4);
+        test_vm.equal_flag = true;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 8);
+    }
+
     #[test]
     fn test_eq_opcode() {
         let mut test_vm = VM::get_test_vm();
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
         test_vm.program = vec![9, 0, 1, 0, 9, 0, 1, 0];
-        test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.equal_flag);
         test_vm.registers[1] = 20;
-        test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.equal_flag);
     }
 
     #[test]
@@ -533,7 +1173,7 @@ mod tests {
         test_vm.registers[0] = 7;
         test_vm.equal_flag = true;
         test_vm.program = vec![15, 0, 0, 0, 17, 0, 0, 0, 17, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 7);
     }
 
@@ -541,8 +1181,304 @@ mod tests {
     fn test_aloc_opcode() {
         let mut test_vm = VM::get_test_vm();
         test_vm.registers[0] = 1024;
-        test_vm.program = vec![16, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.program = vec![17, 0, 0, 0];
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.heap.len(), 1024);
     }
+
+    /// Run the instruction `bytes` on a test VM, returning how it failed
+    fn failure(bytes: Vec<u8>) -> VmError {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = VM::prepend_header(bytes);
+        test_vm.run();
+        assert_eq!(test_vm.events().last().unwrap().event, VMEventType::Crash);
+        test_vm.error().cloned().unwrap()
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let start = PIE_HEADER_LENGTH;
+        let err = failure(vec![0, 2, 0, 0, 4, 0, 2, 3]);
+        assert_eq!(err.kind, VmErrorKind::DivideByZero);
+        assert_eq!((err.pc, err.opcode), (start + 4, Some(Opcode::DIV)));
+        assert_eq!(
+            err.to_string(),
+            format!("[VM001] Division by zero in DIV at byte {}", start + 4)
+        );
+
+        let err = failure(vec![1, 0, 40, 2]);
+        assert_eq!(err.kind, VmErrorKind::RegisterOutOfRange(40));
+        assert_eq!(err.opcode, Some(Opcode::ADD));
+
+        // jumping past the end, or back before the start
+        let err = failure(vec![0, 0, 0xff, 0xff, 6, 0, 0, 0]);
+        let len = start + 8;
+        assert_eq!(
+            err.kind,
+            VmErrorKind::PcOutOfBounds {
+                target: 0xffff,
+                len
+            }
+        );
+        let err = failure(vec![0, 0, 0x03, 0xe8, 8, 0, 0, 0]);
+        let target = start as i64 + 6 - 1000;
+        assert_eq!(err.kind, VmErrorKind::PcOutOfBounds { target, len });
+        // an instruction cut short
+        let err = failure(vec![0, 0, 0]);
+        let (target, len) = (start as i64 + 3, start + 3);
+        assert_eq!(err.kind, VmErrorKind::PcOutOfBounds { target, len });
+        assert_eq!(err.opcode, Some(Opcode::LOAD));
+
+        let mut test_vm = VM::get_test_vm();
+        test_vm.registers[2] = -1;
+        test_vm.program = VM::prepend_header(vec![17, 2, 0, 0]);
+        test_vm.run();
+        let err = test_vm.error().unwrap();
+        assert_eq!(
+            err.kind,
+            VmErrorKind::HeapOutOfBounds { offset: -1, len: 0 }
+        );
+
+        let err = failure(vec![21, 0, 0, 0]);
+        assert_eq!(
+            err.kind,
+            VmErrorKind::ReadOnlyOutOfBounds { offset: 0, len: 0 }
+        );
+
+        let err = failure(vec![200, 0, 0, 0]);
+        assert_eq!(err.kind, VmErrorKind::UnknownOpcode(200));
+        assert_eq!(err.opcode, None);
+        assert_eq!(
+            err.to_string(),
+            format!("[VM006] Unknown opcode 200 at byte {}", start)
+        );
+
+        let err = failure(vec![44, 0, 0, 0]);
+        assert_eq!(err.kind, VmErrorKind::UnsupportedOpcode);
+        assert_eq!(err.opcode, Some(Opcode::PUSH));
+    }
+
+    #[test]
+    fn test_overflow() {
+        // the same in every build, rather than a panic in debug and wrapping in release
+        let overflows = |registers: [i32; 2], bytes: Vec<u8>| {
+            let mut test_vm = VM::new();
+            test_vm.registers[..2].copy_from_slice(&registers);
+            test_vm.program = VM::prepend_header(bytes);
+            test_vm.run();
+            assert_eq!(test_vm.events().last().unwrap().event, VMEventType::Crash);
+            test_vm.error().cloned().unwrap()
+        };
+        let err = overflows([i32::MAX, 1], vec![1, 0, 1, 2]);
+        assert_eq!(
+            (err.kind, err.opcode),
+            (VmErrorKind::Overflow, Some(Opcode::ADD))
+        );
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "[VM010] Integer overflow in ADD at byte {}",
+                PIE_HEADER_LENGTH
+            )
+        );
+        let err = overflows([i32::MIN, -1], vec![4, 0, 1, 2]);
+        assert_eq!(
+            (err.kind, err.opcode),
+            (VmErrorKind::Overflow, Some(Opcode::DIV))
+        );
+        let err = overflows([i32::MIN, 1], vec![2, 0, 1, 2]);
+        assert_eq!(err.opcode, Some(Opcode::SUB));
+        let err = overflows([65535, 65535], vec![3, 0, 0, 0]);
+        assert_eq!(err.opcode, Some(Opcode::MUL));
+        let err = overflows([i32::MAX, 0], vec![18, 0, 0, 0]);
+        assert_eq!(err.opcode, Some(Opcode::INC));
+
+        let mut test_vm = VM::new();
+        test_vm.registers[..2].copy_from_slice(&[i32::MIN, 1]);
+        test_vm.program = VM::prepend_header(vec![4, 0, 1, 2]);
+        test_vm.run();
+        assert!(test_vm.error().is_none());
+        assert_eq!(test_vm.registers[2], i32::MIN);
+    }
+
+    #[test]
+    fn test_crash_event_display() {
+        let mut test_vm = VM::new();
+        test_vm.program = VM::prepend_header(vec![0, 0, 0, 7, 4, 0, 1, 2]);
+        test_vm.run();
+        let mut crash = test_vm.events().last().unwrap().clone();
+        assert_eq!(
+            crash.context.as_deref(),
+            Some("[VM001] Division by zero in DIV at byte 68")
+        );
+        crash.at = "2026-10-16T09:30:05.250Z".parse().unwrap();
+        crash.app_id = "9a3f1c2e-7b4d-4e8a-b6c1-0d2e3f4a5b6c".parse().unwrap();
+        assert_eq!(
+            crash.to_string(),
+            "2026-10-16 09:30:05.250  Crash      9a3f1c2e-7b4d-4e8a-b6c1-0d2e3f4a5b6c  \
+             [VM001] Division by zero in DIV at byte 68"
+        );
+        crash.event = VMEventType::Stop;
+        crash.context = None;
+        assert_eq!(
+            crash.to_string(),
+            "2026-10-16 09:30:05.250  Stop       9a3f1c2e-7b4d-4e8a-b6c1-0d2e3f4a5b6c"
+        );
+    }
+
+    #[test]
+    fn test_header_errors() {
+        let mut test_vm = VM::new();
+        for (program, expected) in [
+            (vec![45, 50], HeaderError::TooShort { len: 2 }),
+            (PIE_HEADER_PREFIX.to_vec(), HeaderError::TooShort { len: 4 }),
+            (
+                b".data\n.code".to_vec(),
+                HeaderError::BadMagic { found: *b".dat" },
+            ),
+        ] {
+            test_vm.program = program;
+            assert_eq!(test_vm.verify_header(), Err(expected));
+            let events: Vec<_> = test_vm.run().into_iter().map(|e| e.event).collect();
+            assert_eq!(events.last(), Some(&VMEventType::Crash));
+            assert_eq!(test_vm.error().unwrap().kind, VmErrorKind::Header(expected));
+        }
+        assert_eq!(
+            HeaderError::TooShort { len: 4 }.to_string(),
+            "[VM008] Bytecode is only 4 (0x4) bytes, too short for the 64 (0x40) byte header"
+        );
+        assert_eq!(
+            test_vm.error().unwrap().to_string(),
+            "[VM009] Bytecode starts with 2e 64 61 74 instead of the header prefix 2d 32 31 2d \
+             (it looks like text; assembly source has to be assembled first)"
+        );
+        let found = [0, 0xff, 0x10, 0x7f];
+        assert_eq!(
+            HeaderError::BadMagic { found }.to_string(),
+            "[VM009] Bytecode starts with 00 ff 10 7f instead of the header prefix 2d 32 31 2d"
+        );
+
+        test_vm.program = VM::prepend_header(vec![]);
+        assert_eq!(test_vm.verify_header(), Ok(()));
+        let err = test_vm.load_bytecode(vec![0; 70]).unwrap_err();
+        assert_eq!(err.code(), "VM009");
+    }
+
+    #[test]
+    fn test_bind_cluster_server_to_ephemeral_port() {
+        let bind = |alias: &str| {
+            VM::new()
+                .with_alias(&alias.to_string())
+                .with_cluster_bind(&"127.0.0.1".to_string(), &"0".to_string())
+        };
+        let mut first = bind("first");
+        let addr = first.bind_cluster_server().unwrap();
+        assert_ne!(addr.port(), 0);
+        assert_eq!(first.peer_port, Some(addr.port().to_string()));
+
+        let mut second = bind("second");
+        let second_addr = second.bind_cluster_server().unwrap();
+        assert_ne!(second_addr.port(), addr.port());
+        let (alias, _) = second.join_cluster(&addr.to_string(), None).unwrap();
+        assert_eq!(alias, "first");
+        // the hello carried the port that was actually bound, not 0
+        let nodes = first.conn_manager.read().unwrap().get_nodes();
+        assert_eq!(
+            nodes,
+            [(
+                "second".to_string(),
+                "127.0.0.1".to_string(),
+                second_addr.port().to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_cluster_over_ipv6_and_hostnames() {
+        let bind = |alias: &str, host: &str| {
+            VM::new()
+                .with_alias(&alias.to_string())
+                .with_cluster_bind(&host.to_string(), &"0".to_string())
+        };
+        let mut first = bind("first", "::1");
+        let addr = first.bind_cluster_server().unwrap();
+        assert!(addr.is_ipv6());
+        let mut second = bind("second", "localhost");
+        let second_addr = second.bind_cluster_server().unwrap();
+        // members are told the resolved address, not the name
+        assert_eq!(
+            second.peer_host(),
+            Some(second_addr.ip().to_string().as_str())
+        );
+
+        let port = addr.port().to_string();
+        let (alias, _) = second
+            .join_cluster(&address::join_host_port("::1", &port), None)
+            .unwrap();
+        assert_eq!(alias, "first");
+        let client = second.conn_manager.read().unwrap();
+        let listen = client.get_client("first").unwrap().peer_listen_addr();
+        assert_eq!(listen.unwrap(), ("::1".to_string(), port));
+
+        let mut third = bind("third", "127.0.0.1");
+        third.bind_cluster_server().unwrap();
+        let err = third
+            .join_cluster("no-such-node.invalid:2254", None)
+            .unwrap_err();
+        assert!(err.to_string().contains("Could not resolve"), "{}", err);
+    }
+
+    #[test]
+    fn test_prepare_data_dir() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("var").join("iridium");
+        prepare_data_dir(&dir).unwrap();
+        assert!(dir.is_dir());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        let file = root.path().join("file");
+        fs::write(&file, "").unwrap();
+        let err = prepare_data_dir(&file.join("iridium")).unwrap_err();
+        assert!(err.to_string().contains("is not writable"), "{}", err);
+    }
+
+    #[test]
+    fn test_aliases() {
+        let mut vm = VM::new();
+        let alias = vm.ensure_alias().unwrap();
+        assert_eq!(alias, format!("node-{}", &vm.id.simple().to_string()[..6]));
+        assert_eq!(vm.alias.as_ref(), Some(&alias));
+        assert_eq!(vm.ensure_alias(), None);
+
+        let mut vm = VM::new().with_alias(&"edge".to_string());
+        assert_eq!(vm.ensure_alias(), None);
+        assert_eq!(vm.alias.as_deref(), Some("edge"));
+
+        assert!(check_alias("edge-1").is_ok());
+        for bad in ["", "edge 1", "edge\t1", "edge:1"] {
+            assert!(check_alias(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_interrupt_mid_run() {
+        let interrupt = Arc::new(AtomicBool::new(false));
+        let mut vm = VM::new().with_interrupt(interrupt.clone());
+        vm.load_bytecode(
+            crate::assembler::Assembler::new()
+                .assemble(".data\n.code\nload $1 #2\njmpb $1")
+                .unwrap(),
+        )
+        .unwrap();
+        let signal = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            interrupt.store(true, Ordering::SeqCst);
+        });
+        let events = vm.run();
+        signal.join().unwrap();
+        let types: Vec<_> = events.iter().map(|e| e.event.clone()).collect();
+        assert_eq!(types, [VMEventType::Start, VMEventType::Interrupted]);
+        assert!(vm.error().is_none());
+        assert!(vm.instruction_count() > 1);
+    }
 }
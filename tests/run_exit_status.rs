@@ -0,0 +1,44 @@
+//! The exit status the run subcommand really ends the process with, as a shell sees it
+
+use std::{fs, path::Path, process::Command};
+
+use tempfile::TempDir;
+
+/// Assemble `source` with the binary, run what it wrote and return the exit status
+fn exit_status(dir: &Path, source: &str) -> Option<i32> {
+    let input = dir.join("program.iasm");
+    let output = dir.join("program.bin");
+    fs::write(&input, source).unwrap();
+    let assembled = Command::new(env!("CARGO_BIN_EXE_iridium"))
+        .arg("assemble")
+        .arg(&input)
+        .arg("-o")
+        .arg(&output)
+        .output()
+        .unwrap();
+    assert!(assembled.status.success(), "{:?}", assembled);
+    let ran = Command::new(env!("CARGO_BIN_EXE_iridium"))
+        .arg("run")
+        .arg(&output)
+        .output()
+        .unwrap();
+    ran.status.code()
+}
+
+#[test]
+fn test_run_exit_status() {
+    let dir = TempDir::new().unwrap();
+    let with_0 = |value: u16| exit_status(dir.path(), &format!(".data\n.code\nload $0 #{}", value));
+    assert_eq!(with_0(0), Some(0));
+    assert_eq!(with_0(42), Some(42));
+    assert_eq!(with_0(100), Some(100));
+    // past 100 are iridium's own codes, and 256 would wrap round to 0
+    for value in [101, 102, 103, 256] {
+        assert_eq!(with_0(value), Some(104), "$0 = {}", value);
+    }
+    let negative = ".data\n.code\nload $1 #1\nsub $0 $1 $0";
+    assert_eq!(exit_status(dir.path(), negative), Some(104));
+
+    let crash = ".data\n.code\nload $0 #1\ndiv $0 $1 $2";
+    assert_eq!(exit_status(dir.path(), crash), Some(102));
+}